@@ -26,6 +26,8 @@
             espup
             probe-rs
             bun
+            # local broker for the MQTT tests of trichter-core
+            mosquitto
           ];
 
           # Dependencies used during runtime
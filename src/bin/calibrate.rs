@@ -0,0 +1,199 @@
+//! Guided calibration of the flow sensor.
+//!
+//! Flash this binary and pour a known volume through the funnel
+//! `REQUIRED_POURS` times, at about the same flow rate. After every pour the
+//! light turns white until the true volume is entered through the management
+//! interface, e.g.
+//!
+//! ```text
+//! curl -u trichter:<management password> -d volume_ml=480 http://<device>/calibration
+//! ```
+//!
+//! Every accepted pour blinks the blue LED once per pour collected so far, a
+//! rejected one lights up red. Once enough consistent pours are collected the
+//! derived K-factor is merged into the stored curve, replacing the point at
+//! about the same flow rate, signalled by cyan, and picked up by the main
+//! firmware on its next boot. Calibrating at several flow rates builds up a
+//! multi-point curve.
+
+#![no_std]
+#![no_main]
+#![deny(
+    clippy::mem_forget,
+    reason = "mem::forget is generally not safe to do with esp_hal types, especially those \
+    holding buffers for the duration of a data transfer."
+)]
+
+use defmt::{error, info, warn};
+use embassy_executor::Spawner;
+use embassy_time::Timer;
+use esp_alloc as _;
+use esp_hal::{
+    rng::Rng,
+    timer::{systimer::SystemTimer, timg::TimerGroup},
+};
+use esp_wifi::{init, EspWifiController};
+use trichter::{
+    calibration,
+    config::calibration::{IDLE_TIMEOUT_MS, MAX_DEVIATION, MIN_PULSES, REQUIRED_POURS},
+    driver::{indicator_lights::IndicatorLights, pulse::PulseInput},
+    management::management_task,
+    mk_static, settings,
+    system::System,
+};
+use trichter_core::{
+    calibration::Calibration,
+    calibration_run::{CalibrationRun, Pour},
+    meter::SessionLimits,
+};
+use {esp_backtrace as _, esp_println as _};
+
+extern crate alloc;
+
+// This creates a default app-descriptor required by the esp-idf bootloader.
+// For more information see: <https://docs.espressif.com/projects/esp-idf/en/stable/esp32/api-reference/system/app_image_format.html#application-description>
+esp_bootloader_esp_idf::esp_app_desc!();
+
+#[esp_hal_embassy::main]
+async fn main(spawner: Spawner) {
+    let peripherals = System::init_peripherals();
+
+    let mut indicators = IndicatorLights::new(
+        peripherals.GPIO46,
+        peripherals.GPIO0,
+        peripherals.GPIO45,
+        peripherals.GPIO9,
+    );
+
+    let rng = Rng::new(peripherals.RNG);
+    let timer1 = TimerGroup::new(peripherals.TIMG0);
+    let wifi_init = &*mk_static!(
+        EspWifiController<'static>,
+        init(timer1.timer0, rng, peripherals.RADIO_CLK).unwrap()
+    );
+    let timer0 = SystemTimer::new(peripherals.SYSTIMER);
+    let mut system = System::builder(timer0.alarm0)
+        .with_storage()
+        .with_sensor(PulseInput::gpio(peripherals.GPIO48))
+        .with_wifi(wifi_init, peripherals.WIFI, peripherals.BT)
+        .build();
+
+    let mut storage = system
+        .storage
+        .take()
+        .expect("calibration requires the settings partition");
+    let mut sensor = system.sensor.take().expect("sensor was not initialized");
+
+    indicators.initialization_complete().await;
+
+    // the volumes are entered through the management interface, the settings
+    // partition stays with the calibration
+    let stack = system
+        .wifi
+        .take()
+        .unwrap()
+        .connect_to_hotspot(rng, spawner)
+        .await;
+    spawner
+        .spawn(management_task(stack, None))
+        .expect("management task fits into the task arena");
+
+    let mut run = CalibrationRun::new(MIN_PULSES, MAX_DEVIATION, REQUIRED_POURS);
+    let point = loop {
+        info!(
+            "Pour a known volume ({}/{} pours)",
+            run.pours().len() + 1,
+            run.required_pours()
+        );
+
+        let settings = settings::current();
+        let res = sensor
+            .mesaure_session(
+                SessionLimits {
+                    idle_timeout_us: IDLE_TIMEOUT_MS * 1_000,
+                    ..settings.limits()
+                },
+                &mut indicators,
+            )
+            .await;
+        if !res.is_valid() {
+            warn!("Rejected pour: {:?}", res.outcome);
+            indicators.calibration_rejected();
+            Timer::after_secs(2).await;
+            continue;
+        }
+
+        info!(
+            "Measured {} pulses in {} ms, enter the poured volume",
+            res.pulses,
+            res.duration_ms()
+        );
+        indicators.calibration_awaiting_volume();
+        let pour = Pour {
+            pulses: res.pulses,
+            duration_us: res.duration.as_micros(),
+            volume_ml: calibration::poured_volume_ml().await,
+        };
+        match run.add_pour(pour) {
+            Ok(pours) => {
+                info!(
+                    "Accepted pour {}: {} pulses/L",
+                    pours,
+                    pour.pulses_per_litre()
+                );
+                indicators.calibration_progress(pours).await;
+            }
+            Err(e) => {
+                warn!("Rejected pour: {:?}", e);
+                indicators.calibration_rejected();
+                Timer::after_secs(2).await;
+                continue;
+            }
+        }
+
+        if run.pours().len() < run.required_pours() {
+            continue;
+        }
+
+        let outliers = run.reject_outliers();
+        if outliers > 0 {
+            warn!("Dropped {} inconsistent pours", outliers);
+            indicators.calibration_rejected();
+            Timer::after_secs(2).await;
+        }
+
+        if let Ok(point) = run.estimate() {
+            break point;
+        }
+    };
+
+    info!(
+        "Measured {} pulses/L at {} Hz",
+        point.pulses_per_litre, point.frequency_hz
+    );
+
+    let calibration = match storage.load::<Calibration>() {
+        Some(mut calibration) => {
+            calibration
+                .merge(point)
+                .expect("estimate is a valid calibration point");
+            calibration
+        }
+        None => Calibration::new(&[point]).expect("estimate is a valid calibration"),
+    };
+    info!("Calibration curve: {:?}", calibration.points());
+    match storage.store(&calibration) {
+        Ok(()) => {
+            info!("Calibration stored");
+            indicators.calibration_complete();
+        }
+        Err(e) => {
+            error!("Failed to store calibration: {:?}", e);
+            indicators.error();
+        }
+    }
+
+    loop {
+        Timer::after_secs(60).await;
+    }
+}
@@ -0,0 +1,147 @@
+//! Head-to-head duel between two funnels.
+//!
+//! The funnel on GPIO48 is channel 0, the one on GPIO47 channel 1. Every duel
+//! starts with a countdown on the indicator lights (red, then yellow), both
+//! sides may start drinking once it turns green. Drinking before that is a
+//! false start. The outcome is published once both sides finished. A build
+//! with fewer sensors measures single sessions like the main binary instead.
+
+#![no_std]
+#![no_main]
+#![deny(
+    clippy::mem_forget,
+    reason = "mem::forget is generally not safe to do with esp_hal types, especially those \
+    holding buffers for the duration of a data transfer."
+)]
+
+use defmt::{error, info};
+use embassy_executor::Spawner;
+use embassy_time::{Duration, Timer};
+use esp_alloc as _;
+use esp_hal::{
+    rng::Rng,
+    timer::{systimer::SystemTimer, timg::TimerGroup},
+};
+use esp_wifi::{init, EspWifiController};
+use trichter::{
+    driver::{indicator_lights::IndicatorLights, pulse::PulseInput, sensor::SensorDriver},
+    management::management_task,
+    mk_static,
+    mqtt::mqtt_task,
+    publisher::{self, publish_task, Publication},
+    settings,
+    system::System,
+    telemetry::telemetry_task,
+    wifi::check_endpoints,
+};
+use {esp_backtrace as _, esp_println as _};
+
+extern crate alloc;
+
+// This creates a default app-descriptor required by the esp-idf bootloader.
+// For more information see: <https://docs.espressif.com/projects/esp-idf/en/stable/esp32/api-reference/system/app_image_format.html#application-description>
+esp_bootloader_esp_idf::esp_app_desc!();
+
+/// Pause between the end of a duel and the next countdown.
+const REMATCH_DELAY: Duration = Duration::from_secs(10);
+
+#[esp_hal_embassy::main]
+async fn main(spawner: Spawner) {
+    let peripherals = System::init_peripherals();
+    esp_alloc::heap_allocator!(size: 72 * 1024);
+
+    let mut indicators = IndicatorLights::new(
+        peripherals.GPIO46,
+        peripherals.GPIO0,
+        peripherals.GPIO45,
+        peripherals.GPIO9,
+    );
+
+    let rng = Rng::new(peripherals.RNG);
+    let timer1 = TimerGroup::new(peripherals.TIMG0);
+    let wifi_init = &*mk_static!(
+        EspWifiController<'static>,
+        init(timer1.timer0, rng, peripherals.RADIO_CLK).unwrap()
+    );
+    let timer0 = SystemTimer::new(peripherals.SYSTIMER);
+    let mut system = System::builder(timer0.alarm0)
+        .with_storage()
+        .with_sensors([
+            PulseInput::gpio(peripherals.GPIO48),
+            PulseInput::gpio(peripherals.GPIO47),
+        ])
+        .with_wifi(wifi_init, peripherals.WIFI, peripherals.BT)
+        .build();
+
+    let wifi = system.wifi.take().unwrap();
+
+    indicators.initialization_complete().await;
+
+    check_endpoints();
+    let stack = wifi.connect_to_hotspot(rng, spawner).await;
+    spawner
+        .spawn(telemetry_task(stack, rng))
+        .expect("telemetry task fits into the task arena");
+    spawner
+        .spawn(mqtt_task(stack, rng))
+        .expect("mqtt task fits into the task arena");
+    spawner
+        .spawn(management_task(stack, system.storage.take()))
+        .expect("management task fits into the task arena");
+
+    spawner
+        .spawn(publish_task(stack, rng, system.queue.take()))
+        .expect("publish task fits into the task arena");
+
+    // static instead of on the task arena, the channels are large
+    let sensor = mk_static!(
+        SensorDriver<'static>,
+        system.sensor.take().expect("sensor was not initialized")
+    );
+    loop {
+        info!("Starting duel countdown...");
+
+        let settings = settings::current();
+        let mut duel = match sensor
+            .measure_duel(settings.limits(), &mut indicators)
+            .await
+        {
+            Ok(duel) => duel,
+            Err(e) => {
+                error!("cannot run duels, measuring single sessions: {:?}", e);
+                break;
+            }
+        };
+        let traces = duel
+            .sessions
+            .each_mut()
+            .map(|session| session.as_mut().and_then(|session| session.trace.take()));
+        publisher::submit(Publication::Duel(duel));
+        for (channel, trace) in traces.into_iter().enumerate() {
+            if let Some(trace) = trace {
+                publisher::submit(Publication::Trace {
+                    channel: channel as u8,
+                    trace,
+                });
+            }
+        }
+
+        Timer::after(REMATCH_DELAY).await;
+    }
+
+    loop {
+        let settings = settings::current();
+        let mut session = sensor
+            .mesaure_session(settings.limits(), &mut indicators)
+            .await;
+        if session.is_valid() && session.volume_ml < settings.min_volume_ml {
+            continue;
+        }
+        let channel = session.channel;
+        let trace = session.trace.take();
+        publisher::submit(Publication::Session(session));
+        if let Some(trace) = trace {
+            publisher::submit(Publication::Trace { channel, trace });
+        }
+    }
+}
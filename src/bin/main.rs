@@ -8,7 +8,7 @@
 
 use defmt::info;
 use embassy_executor::Spawner;
-use embassy_time::{Duration, Timer};
+use embassy_time::Timer;
 use esp_alloc as _;
 use esp_hal::{
     rng::Rng,
@@ -16,13 +16,15 @@ use esp_hal::{
 };
 use esp_wifi::{init, EspWifiController};
 use trichter::{
-    driver::{
-        indicator_lights::IndicatorLights,
-        sensor::{SessionResult, StartupWindow},
-    },
-    mk_static, ok_or_panic,
+    driver::{indicator_lights::IndicatorLights, pulse::PulseInput, sensor::SensorDriver},
+    management::management_task,
+    mk_static,
+    mqtt::mqtt_task,
+    publisher::{self, publish_task, Publication},
+    settings,
     system::System,
-    wifi::SessionResultClient,
+    telemetry::telemetry_task,
+    wifi::check_endpoints,
 };
 use {esp_backtrace as _, esp_println as _};
 
@@ -52,7 +54,8 @@ async fn main(spawner: Spawner) {
     );
     let timer0 = SystemTimer::new(peripherals.SYSTIMER);
     let mut system = System::builder(timer0.alarm0)
-        .with_sensor(peripherals.GPIO48)
+        .with_storage()
+        .with_sensor(PulseInput::gpio(peripherals.GPIO48))
         .with_wifi(wifi_init, peripherals.WIFI, peripherals.BT)
         .build();
 
@@ -60,26 +63,52 @@ async fn main(spawner: Spawner) {
 
     indicators.initialization_complete().await;
 
+    check_endpoints();
     let stack = wifi.connect_to_hotspot(rng, spawner).await;
+    spawner
+        .spawn(telemetry_task(stack, rng))
+        .expect("telemetry task fits into the task arena");
+    spawner
+        .spawn(mqtt_task(stack, rng))
+        .expect("mqtt task fits into the task arena");
+    spawner
+        .spawn(management_task(stack, system.storage.take()))
+        .expect("management task fits into the task arena");
 
-    let mut result_client = ok_or_panic(SessionResultClient::new(stack).await, &mut indicators);
+    spawner
+        .spawn(publish_task(stack, rng, system.queue.take()))
+        .expect("publish task fits into the task arena");
 
-    let mut sensor = system.sensor.take().expect("sensor was not initialized");
+    // static instead of on the task arena, the channels are large
+    let sensor = mk_static!(
+        SensorDriver<'static>,
+        system.sensor.take().expect("sensor was not initialized")
+    );
     loop {
         info!("Waiting for session to start...");
 
-        let res = sensor
-            .mesaure_session(
-                StartupWindow::default(),
-                Duration::from_millis(500),
-                &mut indicators,
-            )
+        let settings = settings::current();
+        let mut res = sensor
+            .mesaure_session(settings.limits(), &mut indicators)
             .await;
         info!(
-            "Measured for {}ms with a flow rate of {}L/min",
-            res.rate,
-            res.duration.as_millis()
+            "Channel {}: measured {}ml in {}ms with a flow rate of {}L/min",
+            res.channel,
+            res.volume_ml,
+            res.duration_ms(),
+            res.rate
         );
-        ok_or_panic(result_client.publish_result(res).await, &mut indicators);
+        if !res.is_valid() {
+            indicators.overrun_warning();
+        } else if res.volume_ml < settings.min_volume_ml {
+            info!("Discarding session below {}ml", settings.min_volume_ml);
+            continue;
+        }
+        let channel = res.channel;
+        let trace = res.trace.take();
+        publisher::submit(Publication::Session(res));
+        if let Some(trace) = trace {
+            publisher::submit(Publication::Trace { channel, trace });
+        }
     }
 }
@@ -0,0 +1,29 @@
+//! The volume of a calibration pour, entered through the management
+//! interface once the pour was measured.
+
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, signal::Signal};
+
+/// Only set while the calibration waits for a volume, so volumes entered
+/// outside of a calibration are refused instead of applied to the next pour.
+static AWAITING: AtomicBool = AtomicBool::new(false);
+static VOLUME_ML: Signal<CriticalSectionRawMutex, u32> = Signal::new();
+
+/// Waits for the true volume of the pour that was just measured.
+pub async fn poured_volume_ml() -> u32 {
+    VOLUME_ML.reset();
+    AWAITING.store(true, Ordering::Relaxed);
+    let volume_ml = VOLUME_ML.wait().await;
+    AWAITING.store(false, Ordering::Relaxed);
+    volume_ml
+}
+
+/// Hands the volume to a waiting calibration, false if there is none.
+pub fn enter_volume_ml(volume_ml: u32) -> bool {
+    if !AWAITING.load(Ordering::Relaxed) {
+        return false;
+    }
+    VOLUME_ML.signal(volume_ml);
+    true
+}
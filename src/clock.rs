@@ -0,0 +1,36 @@
+//! Wall clock time, as told by the backend.
+//!
+//! The device has no battery backed clock, so it asks the backend for the
+//! time before it signs the first request, see
+//! [`HttpClient::sync_clock`](crate::wifi::HttpClient::sync_clock). The answer
+//! is signed with the device's key, so nobody else can set the clock and have
+//! the device sign requests for a time of their choosing. It is also the
+//! clock the timestamps of signed requests are checked against.
+
+use core::cell::Cell;
+
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex};
+use embassy_time::Instant;
+
+/// Unix time at boot, `None` until the backend told the time.
+static BOOT_UNIX_SECS: Mutex<CriticalSectionRawMutex, Cell<Option<u64>>> =
+    Mutex::new(Cell::new(None));
+
+/// Sets the clock to `unix_secs`, as of now.
+pub fn sync(unix_secs: u64) {
+    let boot = unix_secs.saturating_sub(Instant::now().as_secs());
+    BOOT_UNIX_SECS.lock(|current| current.set(Some(boot)));
+}
+
+/// Forgets the time, e.g. because the backend rejected a timestamp. It is
+/// synced again before the next signed request.
+pub fn unsync() {
+    BOOT_UNIX_SECS.lock(|current| current.set(None));
+}
+
+/// Seconds since the Unix epoch, `None` before the clock was synced.
+pub fn unix_secs() -> Option<u64> {
+    BOOT_UNIX_SECS
+        .lock(Cell::get)
+        .map(|boot| boot + Instant::now().as_secs())
+}
@@ -1,10 +1,55 @@
 pub mod remote {
-    use core::net::Ipv4Addr;
-    pub const REMOTE_ENDPOINT: (Ipv4Addr, u16) = (Ipv4Addr::new(4, 231, 40, 213), 80);
-    // would ofc be better to derive from REMOTE_ENDPOINT
-    pub const REMOTE_ENDPOINT_STR: &str = "4.231.40.213";
+    use trichter_core::x509::Pin;
+
+    /// The backend, resources are relative to its path. `https://` publishes
+    /// over TLS 1.3 and needs `TLS_PIN`, plain `http://` is only meant for
+    /// local development backends. Hosts given by name are resolved with
+    /// the DNS server from DHCP.
+    pub const REMOTE_URL: &str = "http://4.231.40.213";
+    /// What the backend's certificate is pinned to for `https://`, e.g.
+    /// `Some(Pin::Ca(include_bytes!("../certs/ca.der")))` for the CA that
+    /// issued it, or `Pin::PublicKey` with the fingerprint of its key. Either
+    /// way the certificate has to be issued for the host of `REMOTE_URL`.
+    pub const TLS_PIN: Option<Pin<'static>> = None;
+    /// smoltcp does not report the TTL of DNS records, resolved addresses
+    /// are used for this long, or until connecting to them fails.
+    pub const DNS_TTL_SECS: u64 = 300;
     pub const RESULT_RESOURCE: &str = "/api/v1/runs";
-    pub const REMOTE_DEV_SECRET: &str = "dHJpY2h0ZXI6c3VwZXItc2FmZS1wYXNzd29yZA==";
+    pub const TELEMETRY_RESOURCE: &str = "/api/v1/telemetry";
+    pub const DUEL_RESOURCE: &str = "/api/v1/duels";
+    pub const TRACE_RESOURCE: &str = "/api/v1/traces";
+    /// Tells the time, signed with the key of the device.
+    pub const TIME_RESOURCE: &str = "/api/v1/time";
+}
+
+pub mod mqtt {
+    use trichter_core::{mqtt::Version, x509::Pin};
+
+    /// The broker used instead of the backend once the transport is switched
+    /// to MQTT, `mqtts://` connects over TLS 1.3 and needs `TLS_PIN`.
+    pub const BROKER_URL: &str = "mqtt://192.168.4.2";
+    /// What the broker's certificate is pinned to, like `remote::TLS_PIN`.
+    pub const TLS_PIN: Option<Pin<'static>> = None;
+    pub const VERSION: Version = Version::V311;
+    /// What the device logs in to the broker with, `None` for its device ID
+    /// and the broker password derived from its key. Either way the broker
+    /// must not allow anonymous clients, see [`crate::mqtt`].
+    pub const USERNAME: Option<&str> = None;
+    pub const PASSWORD: Option<&str> = None;
+    /// `{device}` is replaced by the device ID, which is also the client ID.
+    pub const RESULT_TOPIC: &str = "trichter/{device}/runs";
+    pub const DUEL_TOPIC: &str = "trichter/{device}/duels";
+    pub const TRACE_TOPIC: &str = "trichter/{device}/traces";
+    pub const TELEMETRY_TOPIC: &str = "trichter/{device}/telemetry";
+    pub const STATUS_TOPIC: &str = "trichter/{device}/status";
+    pub const COMMAND_TOPIC: &str = "trichter/{device}/commands";
+    /// The broker drops the connection if nothing was sent for 1.5 times
+    /// this long, so the device pings it when idle.
+    pub const KEEP_ALIVE_SECS: u16 = 60;
+    /// The status is published on connect and then this often.
+    pub const STATUS_INTERVAL_SECS: u64 = 300;
+    /// The largest command the device accepts.
+    pub const MAX_INCOMING_LEN: usize = 512;
 }
 
 pub mod wifi {
@@ -13,6 +58,86 @@ pub mod wifi {
 }
 
 pub mod sensor {
-    pub const STARTUP_DURATION_MS: u64 = 200;
-    pub const STARTUP_REQUIRED_PULSES: u32 = 5;
+    use trichter_core::overrun::SteadyFlow;
+
+    /// Sessions holding the same rate (within the tolerance) for `windows`
+    /// windows are cut off as continuous flow, 0 disables the detection.
+    /// The defaults are the ones the golden traces are checked against.
+    pub const STEADY_FLOW: SteadyFlow = SteadyFlow::DEFAULT;
+    /// Glitch filter of the PCNT backend in APB cycles (80 MHz), at most 1023.
+    pub const PCNT_FILTER_CYCLES: u16 = 1023;
+}
+
+pub mod health {
+    /// How often the level of each sensor line is sampled.
+    pub const SAMPLE_INTERVAL_MS: u64 = 1_000;
+    /// A line low for this long without pulses is stuck.
+    pub const STUCK_LOW_SECS: u64 = 10;
+    /// No pulses at all for this long during an active event is suspicious.
+    pub const SILENCE_HOURS: u64 = 2;
+    /// The sensor cannot produce more pulses than this, see the datasheet.
+    pub const MAX_FREQUENCY_HZ: u32 = 500;
+}
+
+pub mod management {
+    /// The management interface listens on this TCP port.
+    pub const PORT: u16 = 80;
+}
+
+pub mod telemetry {
+    /// Events are published once this many are queued...
+    pub const BATCH_SIZE: usize = 10;
+    /// ...or this long after the first event of a batch.
+    pub const FLUSH_INTERVAL_SECS: u64 = 60;
+    /// Without events, an empty batch goes out this long after the last one,
+    /// so the depth of the result queue is reported regardless.
+    pub const HEARTBEAT_INTERVAL_SECS: u64 = 300;
+}
+
+pub mod publish {
+    /// Results handed to the publish task but not yet stored.
+    pub const QUEUE_LEN: usize = 4;
+    /// Results kept in RAM if the result queue partition is unavailable.
+    pub const MEMORY_QUEUE_LEN: usize = 8;
+    /// A request is aborted if it did not complete in this time.
+    pub const REQUEST_TIMEOUT_SECS: u64 = 15;
+    /// Idle connections are reused for at most this long, or shorter if the
+    /// server announces a lower keep-alive timeout.
+    pub const IDLE_TIMEOUT_SECS: u64 = 30;
+    /// The delay between retries doubles from `INITIAL_BACKOFF_MS` up to
+    /// `MAX_BACKOFF_MS`. Failures are escalated to errors in the log after
+    /// `MAX_RETRIES`, but retried until they succeed.
+    pub const INITIAL_BACKOFF_MS: u64 = 1_000;
+    pub const MAX_BACKOFF_MS: u64 = 60_000;
+    pub const MAX_RETRIES: u32 = 8;
+    /// Largest body kept in the result queue, a trace of a full capture
+    /// takes about 4.5KB. The buffer to read entries back is static.
+    pub const MAX_ENTRY_LEN: usize = 6 * 1024;
+}
+
+pub mod foam {
+    use trichter_core::foam::FoamLimits;
+
+    /// Windows of pulse intervals that differ by more than the irregularity
+    /// limit on average are foam or air. The defaults are the ones the golden
+    /// traces are checked against.
+    pub const LIMITS: FoamLimits = FoamLimits::DEFAULT;
+    /// How much a foam pulse counts towards the volume, 0 excludes foam.
+    pub const FOAM_WEIGHT_PERCENT: u32 = trichter_core::foam::FOAM_WEIGHT_PERCENT;
+}
+
+pub mod duel {
+    pub const COUNTDOWN_STEPS: u32 = 3;
+    pub const COUNTDOWN_STEP_MS: u64 = 1_000;
+    /// Sides still drinking this long after "go" did not finish.
+    pub const TIMEOUT_SECS: u64 = 60;
+}
+
+pub mod calibration {
+    pub const REQUIRED_POURS: usize = 3;
+    pub const MIN_PULSES: u32 = 100;
+    /// Allowed relative deviation of a pour from the median K-factor.
+    pub const MAX_DEVIATION: f32 = 0.05;
+    /// Pouring by hand is less steady than drinking, so allow longer gaps.
+    pub const IDLE_TIMEOUT_MS: u64 = 2_000;
 }
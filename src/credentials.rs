@@ -0,0 +1,28 @@
+//! The [`DeviceCredentials`] of this device, loaded at boot and provisioned
+//! once through the management interface.
+//!
+//! Credentials the backend revoked are dropped until the device is
+//! provisioned again, the stored ones are only replaced by the new ones.
+
+use core::cell::Cell;
+
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex};
+use trichter_core::credentials::DeviceCredentials;
+
+/// `None` until the device is provisioned, nothing can be published then.
+static CREDENTIALS: Mutex<CriticalSectionRawMutex, Cell<Option<DeviceCredentials>>> =
+    Mutex::new(Cell::new(None));
+
+pub fn current() -> Option<DeviceCredentials> {
+    CREDENTIALS.lock(Cell::get)
+}
+
+pub fn provision(credentials: DeviceCredentials) {
+    CREDENTIALS.lock(|current| current.set(Some(credentials)));
+}
+
+/// Drops the credentials after the backend revoked them, nothing is signed
+/// with them any more and the device can be provisioned again.
+pub fn revoke() {
+    CREDENTIALS.lock(|current| current.set(None));
+}
@@ -74,4 +74,73 @@ impl IndicatorLights {
         self.rgb_led_blue.set_high();
         self.rgb_led_red.set_high();
     }
+
+    pub async fn calibration_progress(&mut self, pours: usize) {
+        self.stop_session();
+        for _ in 0..pours {
+            self.rgb_led_blue.set_low();
+            Timer::after_millis(150).await;
+            self.rgb_led_blue.set_high();
+            Timer::after_millis(150).await;
+        }
+    }
+
+    /// White, the pour was measured and its volume has to be entered.
+    pub fn calibration_awaiting_volume(&mut self) {
+        self.rgb_led_red.set_low();
+        self.rgb_led_green.set_low();
+        self.rgb_led_blue.set_low();
+    }
+
+    pub fn calibration_rejected(&mut self) {
+        self.rgb_led_red.set_low();
+
+        self.rgb_led_green.set_high();
+        self.rgb_led_blue.set_high();
+    }
+
+    pub fn calibration_complete(&mut self) {
+        self.rgb_led_green.set_low();
+        self.rgb_led_blue.set_low();
+
+        self.rgb_led_red.set_high();
+    }
+
+    /// Red while `remaining` steps are left, yellow on the last one.
+    pub fn countdown(&mut self, remaining: u32) {
+        self.rgb_led_red.set_low();
+        self.rgb_led_blue.set_high();
+        if remaining > 1 {
+            self.rgb_led_green.set_high();
+        } else {
+            self.rgb_led_green.set_low();
+        }
+    }
+
+    pub fn go(&mut self) {
+        self.start_session();
+    }
+
+    /// Yellow, the session was cut off. Stays on until the flow stopped and
+    /// the next session is awaited.
+    pub fn overrun_warning(&mut self) {
+        self.rgb_led_red.set_low();
+        self.rgb_led_green.set_low();
+
+        self.rgb_led_blue.set_high();
+    }
+
+    /// Magenta and the onboard LED, a sensor line looks broken.
+    pub fn sensor_fault(&mut self) {
+        self.onboard_led.set_high();
+        self.rgb_led_red.set_low();
+        self.rgb_led_blue.set_low();
+
+        self.rgb_led_green.set_high();
+    }
+
+    /// Clears the fault, the session LEDs are left to the session.
+    pub fn sensor_ok(&mut self) {
+        self.onboard_led.set_low();
+    }
 }
@@ -1,2 +1,3 @@
 pub mod indicator_lights;
+pub mod pulse;
 pub mod sensor;
@@ -0,0 +1,259 @@
+use alloc::boxed::Box;
+use core::{
+    cell::RefCell,
+    future::pending,
+    sync::atomic::{AtomicU32, Ordering},
+};
+use defmt::debug;
+use embassy_futures::select::{select, Either};
+use embassy_sync::{
+    blocking_mutex::{raw::CriticalSectionRawMutex, Mutex},
+    signal::Signal,
+};
+use embassy_time::{Instant, Timer};
+use esp_hal::{
+    gpio::{Event, Input, InputConfig, InputPin, Pull},
+    handler,
+    pcnt::{
+        channel::EdgeMode,
+        unit::{Events, Unit},
+        Pcnt,
+    },
+    peripherals::PCNT,
+};
+
+use trichter_core::source::{PulseEvent, PulseSource};
+
+use crate::config::sensor::PCNT_FILTER_CYCLES;
+
+/// Where the [`SensorDriver`](super::sensor::SensorDriver) gets its pulses from.
+pub enum PulseInput<'d> {
+    /// Every rising edge wakes the task through a GPIO interrupt.
+    Gpio(Input<'d>),
+    /// Rising edges are counted by the PCNT peripheral, which interrupts on
+    /// every new count.
+    Pcnt(PcntPulseSource<'d>),
+}
+
+impl<'d> PulseInput<'d> {
+    pub fn gpio(pin: impl InputPin + 'd) -> Self {
+        let mut input = Input::new(pin, InputConfig::default().with_pull(Pull::Up));
+        input.listen(Event::RisingEdge);
+        Self::Gpio(input)
+    }
+
+    /// `unit` is one of the units returned by [`init_pcnt`].
+    pub fn pcnt<const NUM: usize>(unit: Unit<'static, NUM>, pin: impl InputPin + 'd) -> Self {
+        Self::Pcnt(PcntPulseSource::new(unit, pin))
+    }
+
+    /// Drops pulses that arrived while nobody was waiting.
+    pub fn clear(&mut self) {
+        match self {
+            Self::Gpio(input) => input.clear_interrupt(),
+            Self::Pcnt(source) => source.clear(),
+        }
+    }
+
+    /// Current level of the sensor line.
+    pub fn is_low(&self) -> bool {
+        match self {
+            Self::Gpio(input) => input.is_low(),
+            Self::Pcnt(source) => source.input.is_low(),
+        }
+    }
+
+    /// Waits for new pulses and returns how many arrived.
+    pub async fn wait_for_pulses(&mut self) -> u32 {
+        match self {
+            Self::Gpio(input) => {
+                input.wait_for_rising_edge().await;
+                1
+            }
+            Self::Pcnt(source) => source.wait_for_pulses().await,
+        }
+    }
+}
+
+impl PulseSource for PulseInput<'_> {
+    async fn wait(&mut self, deadline_us: Option<u64>) -> PulseEvent {
+        let deadline = async {
+            match deadline_us {
+                Some(deadline_us) => Timer::at(Instant::from_micros(deadline_us)).await,
+                None => pending().await,
+            }
+        };
+        let event = select(self.wait_for_pulses(), deadline).await;
+        let at_us = Instant::now().as_micros();
+        match event {
+            Either::First(count) => PulseEvent::Pulses { at_us, count },
+            Either::Second(_) => PulseEvent::Deadline { at_us },
+        }
+    }
+
+    fn clear(&mut self) {
+        PulseInput::clear(self);
+    }
+}
+
+/// Units of the PCNT peripheral.
+const UNITS: usize = 4;
+
+/// The counter restarts at zero once it reaches this value.
+const HIGH_LIMIT: i16 = i16::MAX;
+
+/// The units of all PCNT sources, shared with the interrupt handler.
+static COUNTERS: Mutex<CriticalSectionRawMutex, RefCell<[Option<Box<dyn Counter>>; UNITS]>> =
+    Mutex::new(RefCell::new([None, None, None, None]));
+/// How often each unit reached `HIGH_LIMIT` and restarted at zero.
+static OVERFLOWS: [AtomicU32; UNITS] = [const { AtomicU32::new(0) }; UNITS];
+/// Signalled by the interrupt handler when a unit counted new pulses.
+static PULSES: [Signal<CriticalSectionRawMutex, ()>; UNITS] = [const { Signal::new() }; UNITS];
+
+/// Takes the PCNT peripheral and routes its interrupt to the pulse sources,
+/// its units go to [`PulseInput::pcnt`].
+pub fn init_pcnt(pcnt: PCNT<'static>) -> Pcnt<'static> {
+    let mut pcnt = Pcnt::new(pcnt);
+    pcnt.set_interrupt_handler(pcnt_interrupt);
+    pcnt
+}
+
+#[handler]
+fn pcnt_interrupt() {
+    COUNTERS.lock(|counters| {
+        for (unit, counter) in counters.borrow().iter().enumerate() {
+            if let Some(counter) = counter {
+                service(unit, counter.as_ref());
+            }
+        }
+    });
+}
+
+/// Handles a pending interrupt of `unit`, returns false if there was none.
+fn service(unit: usize, counter: &dyn Counter) -> bool {
+    let Some(events) = counter.take_interrupt() else {
+        return false;
+    };
+    if events.high_limit {
+        OVERFLOWS[unit].fetch_add(1, Ordering::Relaxed);
+    }
+    PULSES[unit].signal(());
+    true
+}
+
+/// Erases the unit number so every PCNT unit fits into [`COUNTERS`].
+trait Counter: Send {
+    fn value(&self) -> u32;
+    /// Clears a pending interrupt and returns its events.
+    fn take_interrupt(&self) -> Option<Events>;
+    fn set_threshold(&self, value: Option<i16>);
+}
+
+impl<const NUM: usize> Counter for Unit<'static, NUM> {
+    fn value(&self) -> u32 {
+        Unit::value(self).max(0) as u32
+    }
+
+    fn take_interrupt(&self) -> Option<Events> {
+        if !self.interrupt_is_set() {
+            return None;
+        }
+        let events = self.events();
+        self.reset_interrupt();
+        Some(events)
+    }
+
+    fn set_threshold(&self, value: Option<i16>) {
+        self.set_threshold0(value);
+    }
+}
+
+/// Counts pulses in hardware, so edges are not lost while the CPU is busy
+/// with WiFi. A threshold interrupt one above the current count wakes the
+/// task on the next pulse, so pulses are timestamped as exactly as on the
+/// GPIO input. Pulses that arrive while the task is busy are reported
+/// together.
+pub struct PcntPulseSource<'d> {
+    unit: usize,
+    input: Input<'d>,
+    seen: u32,
+}
+
+impl<'d> PcntPulseSource<'d> {
+    pub fn new<const NUM: usize>(unit: Unit<'static, NUM>, pin: impl InputPin + 'd) -> Self {
+        let input = Input::new(pin, InputConfig::default().with_pull(Pull::Up));
+
+        unit.set_high_limit(Some(HIGH_LIMIT))
+            .expect("high limit is within range");
+        unit.set_filter(Some(PCNT_FILTER_CYCLES))
+            .expect("filter threshold is within range");
+        unit.clear();
+
+        unit.channel0.set_edge_signal(input.peripheral_input());
+        unit.channel0
+            .set_input_mode(EdgeMode::Hold, EdgeMode::Increment);
+
+        unit.listen();
+        unit.resume();
+        COUNTERS.lock(|counters| counters.borrow_mut()[NUM] = Some(Box::new(unit)));
+        debug!("pcnt unit {} counting", NUM);
+
+        Self {
+            unit: NUM,
+            input,
+            seen: 0,
+        }
+    }
+
+    /// Runs `f` with the unit of this source, interrupts are held meanwhile.
+    fn with_counter<R>(&self, f: impl FnOnce(&dyn Counter) -> R) -> R {
+        COUNTERS.lock(|counters| {
+            let counters = counters.borrow();
+            f(counters[self.unit].as_deref().expect("unit is registered"))
+        })
+    }
+
+    /// Pulses counted since creation.
+    pub fn count(&mut self) -> u32 {
+        let unit = self.unit;
+        self.with_counter(|counter| loop {
+            // an interrupt pending after reading the value may be a wrap
+            // around the value does not include, so read it again
+            let value = counter.value();
+            if !service(unit, counter) {
+                return OVERFLOWS[unit]
+                    .load(Ordering::Relaxed)
+                    .wrapping_mul(HIGH_LIMIT as u32)
+                    .wrapping_add(value);
+            }
+        })
+    }
+
+    pub fn clear(&mut self) {
+        self.seen = self.count();
+        PULSES[self.unit].reset();
+    }
+
+    pub async fn wait_for_pulses(&mut self) -> u32 {
+        loop {
+            let total = self.count();
+            let new = total.wrapping_sub(self.seen);
+            if new > 0 {
+                self.seen = total;
+                return new;
+            }
+
+            // the high limit interrupts on its own
+            let armed = self.with_counter(|counter| {
+                let value = counter.value();
+                let next = value as i16 + 1;
+                counter.set_threshold((next < HIGH_LIMIT).then_some(next));
+                // a pulse in between would never reach the threshold
+                counter.value() == value
+            });
+            if armed {
+                PULSES[self.unit].wait().await;
+            }
+        }
+    }
+}
@@ -1,164 +1,633 @@
-use alloc::vec::Vec;
-use core::sync::atomic::{AtomicU32, Ordering};
-use defmt::{debug, info};
+use alloc::{boxed::Box, string::String};
+use core::{
+    cell::{Cell, RefCell},
+    future::{pending, poll_fn, Future},
+    pin::{pin, Pin},
+    task::Poll,
+};
+use defmt::{debug, info, warn, Format};
 use embassy_futures::select::{select, Either};
-use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, mutex::Mutex};
 use embassy_time::{Duration, Instant, Timer};
-use esp_hal::gpio::{Event, Input, InputConfig, InputPin};
+use heapless::Vec;
 
-use crate::config::sensor::{STARTUP_DURATION_MS, STARTUP_REQUIRED_PULSES};
+use trichter_core::{
+    calibration::Calibration,
+    capture::PulseCapture,
+    duel::{Duel, DuelOutcome, SIDES},
+    foam::FoamAnalysis,
+    gulps::MAX_GULPS,
+    health::{HealthLimits, HealthMonitor},
+    meter::{MeasuredSession, MeterEvent, SessionLimits, SessionMeter},
+    profile::FlowProfile,
+    session::{SessionEvent, SessionOutcome, SessionState},
+    settings::SessionSettings,
+    source::{PulseEvent, PulseSource},
+};
 
-use super::indicator_lights::IndicatorLights;
+use crate::{
+    config::{
+        duel::{COUNTDOWN_STEPS, COUNTDOWN_STEP_MS, TIMEOUT_SECS},
+        foam::{FOAM_WEIGHT_PERCENT, LIMITS as FOAM},
+        health::{MAX_FREQUENCY_HZ, SAMPLE_INTERVAL_MS, SILENCE_HOURS, STUCK_LOW_SECS},
+        sensor::STEADY_FLOW,
+    },
+    health, settings, telemetry,
+};
 
-pub struct SensorDriver<'d> {
-    pub input: Input<'d>,
+use super::{indicator_lights::IndicatorLights, pulse::PulseInput};
+
+pub use trichter_core::{meter::SessionLimits, session::StartupWindow};
+
+pub const MAX_CHANNELS: usize = 4;
+
+const HEALTH_LIMITS: HealthLimits = HealthLimits {
+    stuck_low_us: STUCK_LOW_SECS * 1_000_000,
+    silence_us: SILENCE_HOURS * 3_600 * 1_000_000,
+    window_us: 1_000_000,
+    max_frequency_hz: MAX_FREQUENCY_HZ,
+};
+
+/// A single funnel with its own pulse input, counters and session state.
+pub struct SensorChannel<'d> {
+    index: u8,
+    input: PulseInput<'d>,
+    meter: SessionMeter,
+    health: HealthMonitor,
+    healthy: bool,
+    next_sample: Instant,
 }
 
-static PULSE_COUNT: AtomicU32 = AtomicU32::new(0);
+enum ChannelEvent {
+    Pulses(PulseEvent),
+    HealthChanged,
+}
 
-impl<'d> SensorDriver<'d> {
-    pub fn new(pin: impl InputPin + 'd) -> Self {
-        let mut inp = Input::new(
-            pin,
-            InputConfig::default().with_pull(esp_hal::gpio::Pull::Up),
-        );
-        inp.listen(Event::RisingEdge);
-        debug!("sensor driver initialized");
-        SensorDriver { input: inp }
+impl<'d> SensorChannel<'d> {
+    fn new(index: u8, input: PulseInput<'d>) -> Self {
+        Self {
+            index,
+            input,
+            meter: SessionMeter::new(SessionSettings::default().limits(), STEADY_FLOW, FOAM),
+            health: HealthMonitor::new(HEALTH_LIMITS, Instant::now().as_micros()),
+            healthy: true,
+            next_sample: Instant::now(),
+        }
     }
 
-    pub async fn mesaure_session(
-        &mut self,
-        startup_window: StartupWindow,
-        idle_timeout: Duration,
-        indicators: &mut IndicatorLights,
-    ) -> SessionResult {
-        let mut first: Instant;
-        let mut last: Instant;
-        self.input.clear_interrupt();
+    pub fn index(&self) -> u8 {
+        self.index
+    }
+
+    pub fn state(&self) -> SessionState {
+        self.meter.state()
+    }
+
+    pub fn is_healthy(&self) -> bool {
+        self.healthy
+    }
+
+    /// Neither in a session nor waiting for the flow of an overrun to stop.
+    fn is_idle(&self) -> bool {
+        self.meter.state() == SessionState::Idle && !self.meter.is_draining()
+    }
+
+    fn status(&self) -> ChannelStatus {
+        ChannelStatus {
+            healthy: self.healthy,
+            idle: self.is_idle(),
+        }
+    }
+
+    /// Applies the session limits and drops stale pulses, unless a session
+    /// is already in progress on this channel or the flow of an overrun
+    /// session did not stop yet.
+    fn arm(&mut self, limits: SessionLimits) {
+        if self.is_idle() {
+            self.reset(limits);
+        }
+    }
+
+    /// Like [`Self::arm`], but also drops a session in progress.
+    fn reset(&mut self, limits: SessionLimits) {
+        self.meter.set_capture(settings::capture_enabled());
+        self.meter.restart(limits, Instant::now().as_micros());
+        self.input.clear();
+    }
+
+    /// Waits for new pulses or the next meter deadline, whatever comes first.
+    ///
+    /// The line level is sampled for the health monitor in between, which
+    /// only interrupts the wait if the health of the channel changed.
+    async fn next_event(&mut self) -> ChannelEvent {
         loop {
-            indicators.await_session();
-            self.input.wait_for_rising_edge().await;
-            indicators.startup_session();
-            first = Instant::now();
-            last = first;
-            PULSE_COUNT.store(1, Ordering::Relaxed);
-
-            let startup_deadline = first + startup_window.length;
-
-            while Instant::now() < startup_deadline {
-                let remaining = startup_deadline - Instant::now();
-                match select(self.input.wait_for_rising_edge(), Timer::after(remaining)).await {
-                    Either::First(_) => {
-                        let now = Instant::now();
-                        PULSE_COUNT.fetch_add(1, Ordering::Relaxed);
-                        last = now;
-                    }
-                    Either::Second(_) => {
-                        info!(
-                            "StartUp Window not fullfilled, received {} pulses in {} ms",
-                            PULSE_COUNT.load(Ordering::Relaxed),
-                            startup_window.length.as_millis()
-                        );
+            let deadline = self.meter.deadline();
+            let event = select(self.input.wait(deadline), Timer::at(self.next_sample)).await;
+            match event {
+                Either::First(event) => return ChannelEvent::Pulses(event),
+                Either::Second(_) => {
+                    if self.sample_health() {
+                        return ChannelEvent::HealthChanged;
                     }
                 }
             }
+        }
+    }
+
+    /// Samples the line level, returns true if the channel became healthy or
+    /// unhealthy.
+    fn sample_health(&mut self) -> bool {
+        let now = Instant::now();
+        let now_us = now.as_micros();
+        self.next_sample = now + Duration::from_millis(SAMPLE_INTERVAL_MS);
+
+        self.health.set_event_active(health::event_active(), now_us);
+        self.health.on_sample(now_us, self.input.is_low());
+        let status = self.health.status(now_us);
+        health::report(self.index, status);
+
+        if status.is_healthy() == self.healthy {
+            return false;
+        }
+        self.healthy = status.is_healthy();
+        if self.healthy {
+            info!("Channel {}: sensor healthy again", self.index);
+        } else {
+            warn!("Channel {}: sensor unhealthy: {:?}", self.index, status);
+        }
+        true
+    }
+
+    /// Processes an event from [`Self::next_event`], returning the session
+    /// once it finished.
+    fn handle(
+        &mut self,
+        event: PulseEvent,
+        indicators: &mut IndicatorLights,
+    ) -> Option<MeasuredSession> {
+        if let PulseEvent::Pulses { at_us, count } = event {
+            self.health.on_pulses(at_us, count);
+        }
+
+        let index = self.index;
+        self.meter.on_event(event, &mut |event| match event {
+            MeterEvent::StartingUp => indicators.startup_session(),
+            MeterEvent::Running => indicators.start_session(),
+            MeterEvent::FalseStart(false_start) => {
+                info!(
+                    "Channel {}: StartUp Window not fullfilled, received {} pulses in {} ms",
+                    index,
+                    false_start.pulses,
+                    false_start.window_us / 1_000
+                );
+                telemetry::report(index, SessionEvent::FalseStart(false_start));
+            }
+            MeterEvent::FlowStopped => {
+                info!("Channel {}: flow stopped after the overrun", index);
+                indicators.await_session();
+            }
+        })
+    }
+
+    /// Turns a measured session into a result.
+    fn finish(&self, measured: MeasuredSession, calibration: &Calibration) -> SessionResult {
+        let session = measured.session;
+        let duration = Duration::from_micros(session.duration_us());
+        if let SessionOutcome::Overrun(reason) = measured.outcome {
+            warn!("Channel {}: session overran ({:?})", self.index, reason);
+        }
+        info!(
+            "Channel {}: Pulses: {}, Rate: {}, DurationMs: {}, Glitches: {}",
+            self.index,
+            session.pulses,
+            calibration.flow_rate(session.pulses, duration.as_micros()),
+            duration.as_millis(),
+            measured.glitches,
+        );
+        if measured.foam.is_foamy() {
+            info!(
+                "Channel {}: {} of {} pulses look like foam ({}% confidence)",
+                self.index,
+                measured.foam.foam_pulses,
+                session.pulses,
+                measured.foam.confidence_percent,
+            );
+        }
+        let mut result = SessionResult::new(self.index, measured, calibration);
+        result.trace = self.meter.capture().map(PulseTrace::new);
+        result
+    }
+}
 
-            let seen = PULSE_COUNT.load(Ordering::Relaxed);
-            if seen >= startup_window.pulses {
+pub struct SensorDriver<'d> {
+    pub channels: Vec<SensorChannel<'d>, MAX_CHANNELS>,
+    pub calibration: Calibration,
+}
+
+impl<'d> SensorDriver<'d> {
+    pub fn new(inputs: impl IntoIterator<Item = PulseInput<'d>>) -> Self {
+        let mut channels = Vec::new();
+        for (index, input) in inputs.into_iter().enumerate() {
+            if channels
+                .push(SensorChannel::new(index as u8, input))
+                .is_err()
+            {
+                warn!("only {} sensor channels are supported", MAX_CHANNELS);
                 break;
             }
         }
-        indicators.start_session();
-        loop {
-            let edge_fut = self.input.wait_for_rising_edge();
-            let timeout_fut = Timer::after(idle_timeout);
-
-            match select(edge_fut, timeout_fut).await {
-                Either::First(_) => {
-                    PULSE_COUNT.fetch_add(1, Ordering::Relaxed);
-                    last = Instant::now();
-                    self.input.clear_interrupt();
+        debug!("sensor driver initialized with {} channels", channels.len());
+        SensorDriver {
+            channels,
+            calibration: Calibration::default(),
+        }
+    }
+
+    /// Waits until any channel finished a session and returns it.
+    ///
+    /// Sessions in progress on other channels are not affected, they are
+    /// picked up again by the next call.
+    pub async fn mesaure_session(
+        &mut self,
+        limits: SessionLimits,
+        indicators: &mut IndicatorLights,
+    ) -> SessionResult {
+        for channel in self.channels.iter_mut() {
+            channel.arm(limits);
+        }
+        // the overrun warning stays on until the flow stopped
+        if self.channels.iter().all(SensorChannel::is_idle) {
+            indicators.await_session();
+        }
+
+        let (index, measured) = self.next_session(|_| true, indicators).await;
+        indicators.stop_session();
+        self.channels[index].finish(measured, &self.calibration)
+    }
+
+    /// Runs a duel between channel 0 and 1.
+    ///
+    /// A countdown of `COUNTDOWN_STEPS` plays on the indicator lights, a pulse
+    /// on either side before "go" is a false start. Sides that have not
+    /// finished within `TIMEOUT_SECS` after "go" did not finish.
+    pub async fn measure_duel(
+        &mut self,
+        limits: SessionLimits,
+        indicators: &mut IndicatorLights,
+    ) -> Result<DuelResult, DuelError> {
+        if self.channels.len() < SIDES {
+            return Err(DuelError::TooFewChannels(self.channels.len()));
+        }
+        for channel in self.channels.iter_mut() {
+            channel.reset(limits);
+        }
+
+        let step = Duration::from_millis(COUNTDOWN_STEP_MS);
+        let go = Instant::now() + step * COUNTDOWN_STEPS;
+        let mut duel = Duel::new(go.as_micros());
+        for remaining in (1..=COUNTDOWN_STEPS).rev() {
+            indicators.countdown(remaining);
+            self.watch_false_starts(&mut duel, go - step * (remaining - 1))
+                .await;
+        }
+        indicators.go();
+        info!("Duel started");
+
+        // pulses before "go" must not count towards a session
+        for channel in self.channels.iter_mut() {
+            channel.reset(limits);
+        }
+
+        let mut sessions: [Option<SessionResult>; SIDES] = [None, None];
+        let timeout = Timer::at(go + Duration::from_secs(TIMEOUT_SECS));
+        let mut timeout = pin!(timeout);
+        while duel.outcome().is_none() {
+            let next =
+                self.next_session(|index| index < SIDES && duel.is_pending(index), indicators);
+            let finished = match select(next, timeout.as_mut()).await {
+                Either::First(finished) => Some(finished),
+                Either::Second(_) => None,
+            };
+
+            match finished {
+                Some((index, measured)) => {
+                    match measured.outcome {
+                        SessionOutcome::Completed => duel.on_finish(index, &measured.session),
+                        SessionOutcome::Overrun(_) => duel.on_overrun(index),
+                    }
+                    sessions[index] =
+                        Some(self.channels[index].finish(measured, &self.calibration));
                 }
-                Either::Second(_) => break,
+                None => duel.on_timeout(),
             }
         }
         indicators.stop_session();
 
-        let pulses = PULSE_COUNT.load(Ordering::Relaxed);
-        let duration = last - first;
-        let rate = Self::pulses_to_flow(pulses, duration);
+        // abandon sessions of sides that did not finish in time
+        for channel in self.channels.iter_mut() {
+            channel.reset(limits);
+        }
+
+        let outcome = duel.outcome().expect("duel is decided");
         info!(
-            "Pulses: {}, Rate: {}, DurationMs: {}",
-            pulses,
-            rate,
-            duration.as_millis(),
+            "Duel finished, winner: {}, margin: {} ms",
+            outcome.winner,
+            outcome.margin_us.map(|margin| margin / 1_000)
         );
-        SessionResult::new(duration, rate)
-    }
-
-    pub async fn measure_duration(&mut self, duration: Duration) -> f32 {
-        self.input.listen(Event::RisingEdge);
-        PULSE_COUNT.store(0, Ordering::Relaxed);
-        let start = Instant::now();
-        while Instant::now() - start < duration {
-            if self.input.is_interrupt_set() {
-                PULSE_COUNT.fetch_add(1, Ordering::Relaxed);
-                self.input.clear_interrupt();
-                self.input.listen(Event::RisingEdge);
-            }
+        Ok(DuelResult {
+            go_us: duel.go_us(),
+            outcome,
+            sessions,
+        })
+    }
+
+    /// Records false starts on the duel sides until `until`.
+    ///
+    /// Every side waits for pulses in its own future for the whole step, so
+    /// no wait is dropped while the other side counts pulses.
+    async fn watch_false_starts(&mut self, duel: &mut Duel, until: Instant) {
+        let duel = RefCell::new(duel);
+        let mut sides: Vec<_, SIDES> = self
+            .channels
+            .iter_mut()
+            .take(SIDES)
+            .enumerate()
+            .map(|(side, channel)| {
+                let duel = &duel;
+                Box::pin(async move {
+                    loop {
+                        let count = channel.input.wait_for_pulses().await;
+                        let now_us = Instant::now().as_micros();
+                        let accepted = channel.meter.filter_mut().accept(now_us, count);
+                        if accepted > 0 && duel.borrow_mut().on_pulse(side, now_us) {
+                            info!("Channel {}: false start", side);
+                        }
+                    }
+                })
+            })
+            .collect();
+        select(first_ready(&mut sides), Timer::at(until)).await;
+    }
+
+    /// Feeds pulses of all `active` channels into their detectors until one
+    /// of them finished a session.
+    ///
+    /// Every channel runs in its own future until the session finished, the
+    /// channels only share the indicators and what they show about their
+    /// health.
+    async fn next_session(
+        &mut self,
+        active: impl Fn(usize) -> bool,
+        indicators: &mut IndicatorLights,
+    ) -> (usize, MeasuredSession) {
+        let statuses: Vec<Cell<ChannelStatus>, MAX_CHANNELS> = self
+            .channels
+            .iter()
+            .map(|channel| Cell::new(channel.status()))
+            .collect();
+        let indicators = RefCell::new(indicators);
+        let mut sessions: Vec<_, MAX_CHANNELS> = self
+            .channels
+            .iter_mut()
+            .enumerate()
+            .map(|(index, channel)| {
+                let active = active(index);
+                let statuses = &statuses;
+                let indicators = &indicators;
+                Box::pin(async move {
+                    if !active {
+                        return pending().await;
+                    }
+                    loop {
+                        match channel.next_event().await {
+                            ChannelEvent::Pulses(event) => {
+                                let measured = channel.handle(event, &mut indicators.borrow_mut());
+                                statuses[index].set(channel.status());
+                                if let Some(measured) = measured {
+                                    return measured;
+                                }
+                            }
+                            ChannelEvent::HealthChanged => {
+                                statuses[index].set(channel.status());
+                                show_health(statuses, &mut indicators.borrow_mut());
+                            }
+                        }
+                    }
+                })
+            })
+            .collect();
+        first_ready(&mut sessions).await
+    }
+
+    /// Mean flow rate on `channel` over `duration`.
+    pub async fn measure_duration(&mut self, channel: usize, duration: Duration) -> f32 {
+        let channel = &mut self.channels[channel];
+        let filter = channel.meter.filter_mut();
+        filter.reset(Instant::now().as_micros());
+        channel.input.clear();
+        let deadline = Instant::now() + duration;
+        let mut pulses = 0;
+        while let PulseEvent::Pulses { at_us, count } =
+            channel.input.wait(Some(deadline.as_micros())).await
+        {
+            pulses += filter.accept(at_us, count);
         }
-        let pulses = PULSE_COUNT.load(Ordering::Relaxed);
-        Self::pulses_to_flow(pulses, duration)
+        self.pulses_to_flow(pulses, duration)
     }
 
-    pub fn pulses_to_flow(pulses: u32, duration: Duration) -> f32 {
-        let window_s = duration.as_micros() as f32 / 1_000_000.0;
-        let pulses_per_sec = pulses as f32 / window_s;
-        pulses_per_sec / 6.6
+    pub fn pulses_to_flow(&self, pulses: u32, duration: Duration) -> f32 {
+        self.calibration.flow_rate(pulses, duration.as_micros())
     }
 }
 
-pub struct StartupWindow {
-    pub pulses: u32,
-    pub length: Duration,
+/// What the indicators show about a channel.
+#[derive(Clone, Copy)]
+struct ChannelStatus {
+    healthy: bool,
+    idle: bool,
 }
 
-impl StartupWindow {
-    pub fn new(pulses: u32, window_ms: u64) -> Self {
-        Self {
-            pulses,
-            length: Duration::from_millis(window_ms),
-        }
+fn show_health(statuses: &[Cell<ChannelStatus>], indicators: &mut IndicatorLights) {
+    if statuses.iter().any(|status| !status.get().healthy) {
+        indicators.sensor_fault();
+        return;
+    }
+    indicators.sensor_ok();
+    if statuses.iter().all(|status| status.get().idle) {
+        indicators.await_session();
     }
 }
 
-impl Default for StartupWindow {
-    fn default() -> Self {
-        Self {
-            pulses: STARTUP_REQUIRED_PULSES,
-            length: Duration::from_millis(STARTUP_DURATION_MS),
+/// Waits until any of `futures` is ready and returns its index and output.
+async fn first_ready<F: Future + Unpin>(futures: &mut [F]) -> (usize, F::Output) {
+    poll_fn(|cx| {
+        for (index, future) in futures.iter_mut().enumerate() {
+            if let Poll::Ready(output) = Pin::new(future).poll(cx) {
+                return Poll::Ready((index, output));
+            }
         }
-    }
+        Poll::Pending
+    })
+    .await
+}
+
+#[derive(Debug, Clone, Copy, Format)]
+pub enum DuelError {
+    /// A duel needs a sensor channel for each of the `SIDES`, the driver
+    /// only has this many.
+    TooFewChannels(usize),
+}
+
+pub struct DuelResult {
+    /// Timestamp of "go" in microseconds since boot.
+    pub go_us: u64,
+    pub outcome: DuelOutcome,
+    /// Sessions of the sides that finished, indexed by channel.
+    pub sessions: [Option<SessionResult>; SIDES],
 }
 
 pub struct SessionResult {
+    /// Index of the sensor channel (funnel) the session was measured on.
+    pub channel: u8,
+    pub outcome: SessionOutcome,
+    pub pulses: u32,
+    /// Time from the first to the last pulse plus one mean pulse interval.
     pub duration: Duration,
+    /// Mean flow rate in L/min, derived from `volume_ml`.
     pub rate: f32,
-    pub volume: f32,
+    /// Volume of the liquid, foam only counts with `FOAM_WEIGHT_PERCENT`.
+    pub volume_ml: u32,
+    /// Highest flow rate of a single profile bucket in L/min.
+    pub peak_rate: f32,
+    pub time_to_peak: Duration,
+    /// Longest pause between two gulps, zero for a single gulp.
+    pub longest_pause: Duration,
+    pub profile: FlowProfile,
+    pub foam: FoamAnalysis,
+    /// The first `MAX_GULPS` gulps, `gulp_count` includes the rest.
+    pub gulps: Vec<GulpResult, MAX_GULPS>,
+    pub gulp_count: u32,
+    /// Pulses rejected as noise while waiting for and during the session.
+    pub glitches: u32,
+    /// Raw pulses of the session, only if the capture is enabled.
+    pub trace: Option<PulseTrace>,
 }
 
 impl SessionResult {
-    pub fn new(duration: Duration, rate: f32) -> Self {
-        info!("Got {} ms", duration.as_millis());
-        let minutes = duration.as_millis() as f32 / 60_000.0;
-        info!("Leads to {} minutes", minutes);
-        let volume = rate * minutes;
+    pub fn new(channel: u8, measured: MeasuredSession, calibration: &Calibration) -> Self {
+        let MeasuredSession {
+            session,
+            outcome,
+            profile,
+            foam,
+            gulps,
+            glitches,
+        } = measured;
+        let pulses = session.pulses;
+        let duration = Duration::from_micros(session.duration_us());
+        info!("Got {} pulses in {} us", pulses, duration.as_micros());
+        let duration_us = duration.as_micros();
+        let volume_ml =
+            calibration.volume_ml(foam.counted_pulses(FOAM_WEIGHT_PERCENT), duration_us);
+        let rate = if duration_us == 0 {
+            0.0
+        } else {
+            // ml/us -> L/min
+            volume_ml as f32 * 60_000.0 / duration_us as f32
+        };
+
+        let gulp_count = gulps.count();
+        let longest_pause = Duration::from_micros(gulps.longest_pause_us());
+        // a single pulse has no interval of its own, it flows for the mean
+        // pulse interval of the session
+        let pulse_interval_us = duration_us / pulses.max(1) as u64;
+        let gulps = gulps
+            .gulps()
+            .iter()
+            .map(|gulp| GulpResult {
+                start: Duration::from_micros(gulp.start_us),
+                end: Duration::from_micros(gulp.end_us),
+                pulses: gulp.pulses,
+                volume_ml: calibration.volume_ml(
+                    foam.counted_pulses_within(
+                        gulp.start_us,
+                        gulp.end_us,
+                        gulp.pulses,
+                        FOAM_WEIGHT_PERCENT,
+                    ),
+                    match gulp.duration_us() {
+                        0 => pulse_interval_us,
+                        duration_us => duration_us,
+                    },
+                ),
+            })
+            .collect();
+
+        let peak_pulses = profile.peak().map_or(0, |(_, count)| count as u32);
+        let peak_rate = calibration.flow_rate(peak_pulses, profile.bucket_us());
 
         Self {
+            channel,
+            outcome,
+            pulses,
             duration,
             rate,
-            volume,
+            volume_ml,
+            peak_rate,
+            time_to_peak: Duration::from_micros(profile.time_to_peak_us()),
+            longest_pause,
+            profile,
+            foam,
+            gulps,
+            gulp_count,
+            glitches,
+            trace: None,
+        }
+    }
+
+    /// Overrun sessions are published, but do not count as a run.
+    pub fn is_valid(&self) -> bool {
+        self.outcome == SessionOutcome::Completed
+    }
+
+    pub fn duration_ms(&self) -> u64 {
+        self.duration.as_millis()
+    }
+
+    /// Volume in litres.
+    pub fn volume(&self) -> f32 {
+        self.volume_ml as f32 / 1_000.0
+    }
+}
+
+/// A gulp of a [`SessionResult`], relative to its first pulse.
+pub struct GulpResult {
+    pub start: Duration,
+    pub end: Duration,
+    pub pulses: u32,
+    /// Volume of the gulp on its own, foam only counts with
+    /// `FOAM_WEIGHT_PERCENT` like for the session.
+    pub volume_ml: u32,
+}
+
+/// An encoded [`PulseCapture`], see there for the format.
+pub struct PulseTrace {
+    /// Timestamp of the first captured pulse in microseconds since boot.
+    pub first_us: u64,
+    pub pulses: usize,
+    /// Pulses dropped at the start because the capture was full.
+    pub dropped: u32,
+    pub deltas: String,
+}
+
+impl PulseTrace {
+    fn new(capture: &PulseCapture) -> Self {
+        let mut deltas = String::new();
+        let _ = capture.encode(&mut deltas);
+        Self {
+            first_us: capture.first_us().unwrap_or_default(),
+            pulses: capture.pulses(),
+            dropped: capture.dropped(),
+            deltas,
         }
     }
 }
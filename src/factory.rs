@@ -0,0 +1,12 @@
+//! The [`FactorySecret`] of this device, burned into the user data block of
+//! the eFuses at the factory, e.g. with
+//! `espefuse.py burn_block_data BLOCK_USR_DATA secret.bin`.
+
+use esp_hal::efuse::{Efuse, BLOCK_USR_DATA};
+use trichter_core::factory::{FactorySecret, SECRET_LEN};
+
+/// `None` if no secret was burned, the management interface stays locked
+/// then.
+pub fn secret() -> Option<FactorySecret> {
+    FactorySecret::new(Efuse::read_field_le::<[u8; SECRET_LEN]>(BLOCK_USR_DATA))
+}
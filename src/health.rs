@@ -0,0 +1,39 @@
+//! Latest sensor [`HealthStatus`] of every channel and whether an event is
+//! running, shared between the sensor driver and the management interface.
+
+use core::{
+    cell::Cell,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex};
+use trichter_core::health::HealthStatus;
+
+use crate::driver::sensor::MAX_CHANNELS;
+
+/// Devices are switched on when an event starts, so it is active from boot.
+static EVENT_ACTIVE: AtomicBool = AtomicBool::new(true);
+static STATUS: Mutex<CriticalSectionRawMutex, Cell<[Option<HealthStatus>; MAX_CHANNELS]>> =
+    Mutex::new(Cell::new([None; MAX_CHANNELS]));
+
+pub fn event_active() -> bool {
+    EVENT_ACTIVE.load(Ordering::Relaxed)
+}
+
+/// Silence on the sensor lines is only reported while an event is active.
+pub fn set_event_active(active: bool) {
+    EVENT_ACTIVE.store(active, Ordering::Relaxed);
+}
+
+pub fn report(channel: u8, status: HealthStatus) {
+    STATUS.lock(|cell| {
+        let mut channels = cell.get();
+        channels[channel as usize] = Some(status);
+        cell.set(channels);
+    });
+}
+
+/// Status per channel, `None` for channels that have not been sampled yet.
+pub fn channels() -> [Option<HealthStatus>; MAX_CHANNELS] {
+    STATUS.lock(|cell| cell.get())
+}
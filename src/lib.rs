@@ -5,9 +5,21 @@ use alloc::fmt;
 use driver::indicator_lights::IndicatorLights;
 use esp_hal::gpio::{Output, OutputConfig, OutputPin};
 
+pub mod calibration;
+pub mod clock;
 pub mod config;
+pub mod credentials;
 pub mod driver;
+pub mod factory;
+pub mod health;
+pub mod management;
+pub mod mqtt;
+pub mod publisher;
+pub mod settings;
+pub mod storage;
 pub mod system;
+pub mod telemetry;
+pub mod tls;
 pub mod wifi;
 
 extern crate alloc;
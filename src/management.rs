@@ -0,0 +1,454 @@
+//! A tiny HTTP interface on the device to inspect and change the
+//! [`SessionSettings`] at runtime and to check on the sensors.
+//!
+//! ```text
+//! GET  /settings                                   current settings as JSON
+//! POST /settings  idle_timeout_ms=800&min_pulse_interval_us=1500
+//! GET  /status                                     sensor health, result queue and connections
+//! POST /event     active=false                     start or end an event
+//! POST /capture   enabled=true                     raw pulse capture on or off
+//! POST /credentials device_id=trichter-0042&key=…&token=…  provision the device
+//! POST /transport transport=mqtt                   publish to the broker, or `http`
+//! POST /calibration volume_ml=480                  true volume of the pour just measured
+//! ```
+//!
+//! Updates are validated, persisted to the settings partition and applied
+//! from the next session on. Requests have to carry the management password
+//! derived from the device's [`FactorySecret`] as basic auth, without a
+//! factory secret every request is refused. Unlike the settings, the pulse
+//! capture is not persisted and off after a reboot. Credentials have to come
+//! with the provisioning token of the device, which only the backend knows.
+//! They can only be provisioned once, or again after the backend revoked
+//! them, and are never shown. The transport applies to the next publish,
+//! results queued in the meantime go out the new way. Volumes are only
+//! accepted while the calibration firmware waits for one.
+
+use alloc::{format, string::String};
+use core::{
+    fmt::Write as _,
+    num::{IntErrorKind, ParseIntError},
+};
+use defmt::{error, info, warn};
+use embassy_net::{tcp::TcpSocket, Stack};
+use embassy_time::{Duration, Instant};
+use embedded_io_async::Write as _;
+use trichter_core::{
+    connection::{ConnectionState, ConnectionStats},
+    credentials::DeviceCredentials,
+    endpoint::Transport,
+    factory::FactorySecret,
+    settings::SessionSettings,
+};
+
+use crate::{
+    calibration, clock,
+    config::management::PORT,
+    credentials, factory, health, mk_static, mqtt, publisher, settings,
+    storage::SettingsStore,
+    wifi::{self, json_option},
+};
+
+const MAX_REQUEST_LEN: usize = 1024;
+
+#[embassy_executor::task]
+pub async fn management_task(stack: Stack<'static>, mut storage: Option<SettingsStore>) {
+    let rx_buffer = mk_static!([u8; 1024], [0; 1024]);
+    let tx_buffer = mk_static!([u8; 1024], [0; 1024]);
+    let request = mk_static!([u8; MAX_REQUEST_LEN], [0; MAX_REQUEST_LEN]);
+    if storage.is_none() {
+        warn!("no settings storage, changes are lost on reboot");
+    }
+    let secret = factory::secret();
+    if secret.is_none() {
+        error!("no factory secret, refusing all management requests");
+    }
+
+    loop {
+        let mut socket = TcpSocket::new(stack, rx_buffer, tx_buffer);
+        socket.set_timeout(Some(Duration::from_secs(10)));
+        if let Err(e) = socket.accept(PORT).await {
+            error!("management accept failed: {:?}", e);
+            continue;
+        }
+
+        let response = match read_request(&mut socket, request).await {
+            Ok(len) => handle(&request[..len], secret.as_ref(), storage.as_mut()),
+            Err(ReadError::TooLarge) => response(413, "{\"error\": \"request too large\"}"),
+            Err(ReadError::Malformed) => response(400, "{\"error\": \"malformed request\"}"),
+        };
+        if let Err(e) = socket.write_all(response.as_bytes()).await {
+            error!("failed to send management response: {:?}", e);
+        }
+        let _ = socket.flush().await;
+        socket.close();
+    }
+}
+
+enum ReadError {
+    /// The request ended early or its header is not UTF-8.
+    Malformed,
+    /// The request, or the body it announces, does not fit into the buffer.
+    TooLarge,
+}
+
+/// Reads the header and, if announced, the body of a request. Returns the
+/// total length.
+///
+/// This runs before the request is authorized, so the announced length is
+/// checked against `buf` before anything is added to it.
+async fn read_request(socket: &mut TcpSocket<'_>, buf: &mut [u8]) -> Result<usize, ReadError> {
+    let mut len = 0;
+    loop {
+        if let Some(header_end) = find(&buf[..len], b"\r\n\r\n") {
+            let header =
+                core::str::from_utf8(&buf[..header_end]).map_err(|_| ReadError::Malformed)?;
+            let total = content_length(header)?
+                .checked_add(header_end + 4)
+                .filter(|&total| total <= buf.len())
+                .ok_or(ReadError::TooLarge)?;
+            if len >= total {
+                return Ok(total);
+            }
+        }
+        if len == buf.len() {
+            return Err(ReadError::TooLarge);
+        }
+        match socket.read(&mut buf[len..]).await {
+            Ok(0) | Err(_) => return Err(ReadError::Malformed),
+            Ok(n) => len += n,
+        }
+    }
+}
+
+fn handle(
+    request: &[u8],
+    secret: Option<&FactorySecret>,
+    storage: Option<&mut SettingsStore>,
+) -> String {
+    let Ok(request) = core::str::from_utf8(request) else {
+        return response(400, "{\"error\": \"malformed request\"}");
+    };
+    let (header, body) = request.split_once("\r\n\r\n").unwrap_or((request, ""));
+    let mut lines = header.lines();
+    let mut request_line = lines.next().unwrap_or_default().split(' ');
+    let (method, path) = (request_line.next(), request_line.next());
+
+    let authorized = secret.filter(|secret| {
+        lines
+            .filter_map(|line| line.split_once(':'))
+            .any(|(name, value)| {
+                name.eq_ignore_ascii_case("authorization") && secret.authorizes(value)
+            })
+    });
+    let Some(secret) = authorized else {
+        return response(401, "{\"error\": \"unauthorized\"}");
+    };
+
+    match (method, path) {
+        (Some("GET"), Some("/settings")) => response(200, &settings_json(&settings::current())),
+        (Some("POST"), Some("/settings")) => match settings::current().update(body) {
+            Ok(updated) => {
+                if let Some(storage) = storage {
+                    if let Err(e) = storage.store(&updated) {
+                        error!("failed to persist settings: {:?}", e);
+                        return response(500, "{\"error\": \"failed to persist settings\"}");
+                    }
+                }
+                settings::apply(updated);
+                info!("session settings changed: {:?}", updated);
+                response(200, &settings_json(&updated))
+            }
+            Err(e) => response(400, &format!("{{\"error\": \"{:?}\"}}", e)),
+        },
+        (Some("GET"), Some("/status")) => response(200, &status_json()),
+        (Some("POST"), Some("/event")) => match body.trim() {
+            "active=true" | "active=1" => {
+                health::set_event_active(true);
+                response(200, &status_json())
+            }
+            "active=false" | "active=0" => {
+                health::set_event_active(false);
+                response(200, &status_json())
+            }
+            _ => response(400, "{\"error\": \"expected active=true or active=false\"}"),
+        },
+        (Some("POST"), Some("/capture")) => match body.trim() {
+            "enabled=true" | "enabled=1" => {
+                settings::set_capture_enabled(true);
+                response(200, &status_json())
+            }
+            "enabled=false" | "enabled=0" => {
+                settings::set_capture_enabled(false);
+                response(200, &status_json())
+            }
+            _ => response(
+                400,
+                "{\"error\": \"expected enabled=true or enabled=false\"}",
+            ),
+        },
+        (Some("POST"), Some("/credentials")) => provision(body, secret, storage),
+        (Some("POST"), Some("/transport")) => select_transport(body, storage),
+        (Some("POST"), Some("/calibration")) => enter_volume(body),
+        (
+            _,
+            Some(
+                "/settings" | "/status" | "/event" | "/capture" | "/credentials" | "/transport"
+                | "/calibration",
+            ),
+        ) => response(405, "{\"error\": \"method not allowed\"}"),
+        _ => response(404, "{\"error\": \"not found\"}"),
+    }
+}
+
+/// Stores the credentials of an unprovisioned device.
+fn provision(body: &str, secret: &FactorySecret, storage: Option<&mut SettingsStore>) -> String {
+    if credentials::current().is_some() {
+        return response(409, "{\"error\": \"already provisioned\"}");
+    }
+    let device_credentials = match DeviceCredentials::parse(body, secret) {
+        Ok(device_credentials) => device_credentials,
+        Err(e) => return response(400, &format!("{{\"error\": \"{:?}\"}}", e)),
+    };
+    // without storage the device would be unprovisioned after a reboot
+    let Some(storage) = storage else {
+        return response(500, "{\"error\": \"no storage for credentials\"}");
+    };
+    if let Err(e) = storage.store(&device_credentials) {
+        error!("failed to persist credentials: {:?}", e);
+        return response(500, "{\"error\": \"failed to persist credentials\"}");
+    }
+    credentials::provision(device_credentials);
+    info!("provisioned as {}", device_credentials.device_id());
+    response(
+        200,
+        &format!("{{\"device_id\": \"{}\"}}", device_credentials.device_id()),
+    )
+}
+
+/// Switches and persists the transport results are published with.
+fn select_transport(body: &str, storage: Option<&mut SettingsStore>) -> String {
+    let Some(transport) = body
+        .trim()
+        .strip_prefix("transport=")
+        .and_then(Transport::parse)
+    else {
+        return response(
+            400,
+            "{\"error\": \"expected transport=http or transport=mqtt\"}",
+        );
+    };
+    if let Some(storage) = storage {
+        if let Err(e) = storage.store(&transport) {
+            error!("failed to persist transport: {:?}", e);
+            return response(500, "{\"error\": \"failed to persist transport\"}");
+        }
+    }
+    settings::set_transport(transport);
+    info!("publishing with {}", transport.name());
+    response(200, &status_json())
+}
+
+/// Passes the volume of the last pour on to the calibration.
+fn enter_volume(body: &str) -> String {
+    let volume_ml = body
+        .trim()
+        .strip_prefix("volume_ml=")
+        .and_then(|volume| volume.parse::<u32>().ok())
+        .filter(|volume_ml| *volume_ml > 0);
+    let Some(volume_ml) = volume_ml else {
+        return response(400, "{\"error\": \"expected volume_ml=<ml>\"}");
+    };
+    if !calibration::enter_volume_ml(volume_ml) {
+        return response(409, "{\"error\": \"no pour waiting for its volume\"}");
+    }
+    info!("calibration pour was {} ml", volume_ml);
+    response(200, &format!("{{\"volume_ml\": {}}}", volume_ml))
+}
+
+fn response(status: u16, body: &str) -> String {
+    let reason = match status {
+        200 => "OK",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        409 => "Conflict",
+        _ => "Internal Server Error",
+    };
+    format!(
+        "\
+        HTTP/1.1 {} {}\r\n\
+        Content-Type: application/json\r\n\
+        Content-Length: {}\r\n\
+        Connection: close\r\n\
+        \r\n\
+        {}\
+        ",
+        status,
+        reason,
+        body.len(),
+        body
+    )
+}
+
+fn settings_json(settings: &SessionSettings) -> String {
+    format!(
+        "\
+        {{\
+            \"idle_timeout_ms\": {},\
+            \"startup_pulses\": {},\
+            \"startup_window_ms\": {},\
+            \"max_duration_ms\": {},\
+            \"min_volume_ml\": {},\
+            \"min_pulse_interval_us\": {}\
+        }}\
+        ",
+        settings.idle_timeout_ms,
+        settings.startup_pulses,
+        settings.startup_window_ms,
+        settings.max_duration_ms,
+        settings.min_volume_ml,
+        settings.min_pulse_interval_us,
+    )
+}
+
+/// Sensor health, event and transport state, the depth of the result queue
+/// with the parked results and both connections, also published to the
+/// status topic of the broker.
+pub(crate) fn status_json() -> String {
+    let now_us = Instant::now().as_micros();
+    let mut channels = String::new();
+    for (channel, status) in health::channels().iter().enumerate() {
+        let Some(status) = status else {
+            continue;
+        };
+        if !channels.is_empty() {
+            channels.push(',');
+        }
+        let _ = write!(
+            channels,
+            "{{\
+                \"channel\": {},\
+                \"healthy\": {},\
+                \"stuck_low\": {},\
+                \"no_pulses\": {},\
+                \"implausible_frequency\": {},\
+                \"pulses\": {},\
+                \"last_pulse_s_ago\": {},\
+                \"peak_frequency_hz\": {}\
+            }}",
+            channel,
+            status.is_healthy(),
+            status.stuck_low,
+            status.no_pulses,
+            status.implausible_frequency,
+            status.pulses,
+            json_option(
+                status
+                    .last_pulse_us
+                    .map(|at_us| now_us.saturating_sub(at_us) / 1_000_000)
+            ),
+            status.peak_frequency_hz,
+        );
+    }
+
+    format!(
+        "\
+        {{\
+            \"uptime_s\": {},\
+            \"unix_time\": {},\
+            \"device_id\": {},\
+            \"event_active\": {},\
+            \"capture_enabled\": {},\
+            \"channels\": [{}],\
+            \"queue_depth\": {},\
+            \"parked\": {},\
+            \"transport\": \"{}\",\
+            \"backend\": {},\
+            \"broker\": {}\
+        }}\
+        ",
+        now_us / 1_000_000,
+        json_option(clock::unix_secs()),
+        json_option(credentials::current().map(|c| format!("\"{}\"", c.device_id()))),
+        health::event_active(),
+        settings::capture_enabled(),
+        channels,
+        publisher::queue_depth(),
+        publisher::parked(),
+        settings::transport().name(),
+        connection_json(
+            wifi::remote_endpoint().is_ok_and(|remote| remote.tls.is_some()),
+            &wifi::result_connection(),
+            now_us
+        ),
+        connection_json(
+            mqtt::broker_endpoint().is_ok_and(|broker| broker.tls.is_some()),
+            &mqtt::broker_connection(),
+            now_us
+        ),
+    )
+}
+
+fn connection_json(tls: bool, connection: &ConnectionStats, now_us: u64) -> String {
+    format!(
+        "\
+        {{\
+            \"tls\": {},\
+            \"state\": \"{}\",\
+            \"connects\": {},\
+            \"requests\": {},\
+            \"reused\": {},\
+            \"failures\": {},\
+            \"connection_requests\": {},\
+            \"connected_s_ago\": {},\
+            \"last_used_s_ago\": {}\
+        }}\
+        ",
+        tls,
+        match connection.state {
+            ConnectionState::Closed => "closed",
+            ConnectionState::Idle => "idle",
+            ConnectionState::Busy => "busy",
+        },
+        connection.connects,
+        connection.requests,
+        connection.reused,
+        connection.failures,
+        connection.connection_requests,
+        json_option(
+            connection
+                .connected_at_us
+                .map(|at_us| now_us.saturating_sub(at_us) / 1_000_000)
+        ),
+        json_option(
+            connection
+                .last_used_us
+                .map(|at_us| now_us.saturating_sub(at_us) / 1_000_000)
+        ),
+    )
+}
+
+/// The announced body length, 0 without one.
+fn content_length(header: &str) -> Result<usize, ReadError> {
+    let Some((_, value)) = header
+        .lines()
+        .filter_map(|line| line.split_once(':'))
+        .find(|(name, _)| name.eq_ignore_ascii_case("content-length"))
+    else {
+        return Ok(0);
+    };
+    value
+        .trim()
+        .parse()
+        .map_err(|e: ParseIntError| match e.kind() {
+            IntErrorKind::PosOverflow => ReadError::TooLarge,
+            _ => ReadError::Malformed,
+        })
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
@@ -0,0 +1,761 @@
+//! MQTT transport, for venues with a local broker instead of the backend.
+//!
+//! While the [`Transport`] is MQTT, the [`mqtt_task`] keeps a connection to
+//! `BROKER_URL` and [`publish`] sends what would otherwise be posted to the
+//! backend to the topics in [`config::mqtt`](crate::config::mqtt).
+//! Everything goes out with QoS 1, a publish only succeeds once the broker
+//! acknowledged it. Results and duels are retained, so new subscribers get
+//! the last one right away.
+//!
+//! The status is published retained on connect and every
+//! `STATUS_INTERVAL_SECS`. If the connection is lost, the broker replaces it
+//! with the will, `{"online": false}`. Commands on the command topic do what
+//! the same requests to the management interface do:
+//!
+//! ```text
+//! event active=true       start or end an event
+//! capture enabled=false   raw pulse capture on or off
+//! status                  publish the status right away
+//! ```
+//!
+//! The client ID is the device ID, so the device only connects once it is
+//! provisioned. Unlike requests to the backend, messages are not signed, the
+//! broker authenticates the connection instead. The device always logs in,
+//! as its device ID with the [broker password](DeviceCredentials::broker_password)
+//! derived from its key unless `USERNAME` and `PASSWORD` are configured. The
+//! broker has to refuse anonymous clients and only let a device publish to
+//! and subscribe to its own topics, otherwise anybody on the network can
+//! send it commands.
+
+use alloc::{boxed::Box, format, string::String, vec, vec::Vec};
+use core::{
+    cell::{Cell, RefCell},
+    future::{self, poll_fn, Future},
+    pin::pin,
+};
+use defmt::{debug, info, warn, Format};
+use embassy_futures::select::{select, select4, Either, Either4};
+use embassy_net::{
+    dns,
+    tcp::{self, ConnectError, TcpSocket},
+    Stack,
+};
+use embassy_sync::{
+    blocking_mutex::{raw::CriticalSectionRawMutex, Mutex},
+    channel::Channel,
+    mutex::Mutex as AsyncMutex,
+    signal::Signal,
+};
+use embassy_time::{with_timeout, Duration, Instant, Timer};
+use embedded_io_async::{ErrorType, Read, Write};
+use embedded_tls::TlsError;
+use esp_hal::rng::Rng;
+use trichter_core::{
+    backoff::Backoff,
+    connection::{Connection, ConnectionStats},
+    credentials::DeviceCredentials,
+    endpoint::{AddressCache, Scheme, Transport},
+    mqtt::{
+        self, Command, CommandError, Connect, KeepAlive, KeepAliveAction, Message, MqttError,
+        Packet, QoS, PUBLISH_OVERHEAD,
+    },
+};
+
+use crate::{
+    config::{
+        mqtt::{
+            BROKER_URL, COMMAND_TOPIC, KEEP_ALIVE_SECS, MAX_INCOMING_LEN, PASSWORD,
+            STATUS_INTERVAL_SECS, STATUS_TOPIC, TLS_PIN, USERNAME, VERSION,
+        },
+        publish::{INITIAL_BACKOFF_MS, MAX_BACKOFF_MS, REQUEST_TIMEOUT_SECS},
+        remote::DNS_TTL_SECS,
+    },
+    credentials, health, management, mk_static, settings,
+    tls::{SessionError, Tls},
+    wifi::{Endpoint, EndpointError, Resolver, SharedAddresses},
+};
+
+/// The status while the device is not connected.
+const OFFLINE: &str = "{\"online\": false}";
+/// Everything but a PUBLISH is encoded into a buffer of this size.
+const CONTROL_PACKET_LEN: usize = 256;
+
+struct Request {
+    id: u32,
+    topic: &'static str,
+    payload: String,
+    retain: bool,
+}
+
+static REQUESTS: Channel<CriticalSectionRawMutex, Request, 1> = Channel::new();
+/// The outcome of the request with the ID.
+static DONE: Signal<CriticalSectionRawMutex, (u32, Result<(), BrokerError>)> = Signal::new();
+/// Serializes callers of [`publish`], holds the ID of the last request.
+static LAST_REQUEST: AsyncMutex<CriticalSectionRawMutex, u32> = AsyncMutex::new(0);
+static CONNECTION: Mutex<CriticalSectionRawMutex, Cell<ConnectionStats>> =
+    Mutex::new(Cell::new(ConnectionStats::new()));
+static BROKER_ADDRESSES: SharedAddresses =
+    Mutex::new(RefCell::new(AddressCache::new(DNS_TTL_SECS * 1_000_000)));
+
+/// State of the connection to the broker, as of the last change.
+pub fn broker_connection() -> ConnectionStats {
+    CONNECTION.lock(Cell::get)
+}
+
+/// The broker as configured in `BROKER_URL` and `TLS_PIN`.
+pub fn broker_endpoint() -> Result<Endpoint, EndpointError> {
+    Endpoint::new(BROKER_URL, TLS_PIN, [Scheme::Mqtt, Scheme::Mqtts])
+}
+
+/// Publishes `payload` with QoS 1 to `topic`, a template from
+/// [`config::mqtt`](crate::config::mqtt), and waits until the broker
+/// acknowledged it.
+///
+/// Fails right away with [`BrokerError::Disconnected`] while there is no
+/// connection to the broker.
+pub async fn publish(topic: &'static str, payload: &str, retain: bool) -> Result<(), BrokerError> {
+    let mut last_request = LAST_REQUEST.lock().await;
+    *last_request = last_request.wrapping_add(1);
+    let id = *last_request;
+    let request = Request {
+        id,
+        topic,
+        payload: String::from(payload),
+        retain,
+    };
+
+    let timeout = Duration::from_secs(REQUEST_TIMEOUT_SECS);
+    with_timeout(timeout, async {
+        REQUESTS.send(request).await;
+        loop {
+            // outcomes of earlier requests that timed out are stale
+            let (done, result) = DONE.wait().await;
+            if done == id {
+                return result;
+            }
+        }
+    })
+    .await
+    .unwrap_or(Err(BrokerError::Timeout))
+}
+
+#[derive(Debug, Format)]
+pub enum BrokerError {
+    /// There is no connection to the broker at the moment.
+    Disconnected,
+    /// The broker could not be resolved.
+    Dns(dns::Error),
+    Connect(ConnectError),
+    /// No answer within `REQUEST_TIMEOUT_SECS`, or the broker went silent
+    /// for longer than the keep alive.
+    Timeout,
+    Io(tcp::Error),
+    /// The TLS handshake failed or the session broke, including certificates
+    /// that do not match the pin.
+    Tls(TlsError),
+    /// The broker closed the connection.
+    Closed,
+    /// The broker sent a malformed or unexpected packet.
+    Mqtt(MqttError),
+    /// The broker refused the connection, subscription or publish with this
+    /// reason code.
+    Refused(u8),
+}
+
+impl From<tcp::Error> for BrokerError {
+    fn from(e: tcp::Error) -> Self {
+        Self::Io(e)
+    }
+}
+
+impl From<TlsError> for BrokerError {
+    fn from(e: TlsError) -> Self {
+        Self::Tls(e)
+    }
+}
+
+impl From<SessionError> for BrokerError {
+    fn from(e: SessionError) -> Self {
+        Self::Tls(e.0)
+    }
+}
+
+impl BrokerError {
+    /// Whether publishing the same message again later may succeed.
+    ///
+    /// Only a payload the broker refuses as too large (0x95) or malformed
+    /// (0x99) is rejected for good. Everything else is a problem with the
+    /// connection or the broker, and the connection is set up again before
+    /// the retry.
+    pub fn is_transient(&self) -> bool {
+        !matches!(self, Self::Refused(0x95 | 0x99))
+    }
+}
+
+/// Keeps the connection to the broker while the transport is MQTT and
+/// publishes what is handed to [`publish`].
+///
+/// A lost connection is reconnected with the same backoff as failed
+/// publishes. Must only be spawned once, the socket buffers are static.
+#[embassy_executor::task]
+pub async fn mqtt_task(stack: Stack<'static>, mut rng: Rng) {
+    let rx_buffer = mk_static!([u8; 1024], [0; 1024]);
+    let tx_buffer = mk_static!([u8; 4096], [0; 4096]);
+    let mut socket = TcpSocket::new(stack, rx_buffer, tx_buffer);
+    if KEEP_ALIVE_SECS > 0 {
+        // the pings keep a healthy connection from timing out
+        socket.set_timeout(Some(Duration::from_secs(2 * KEEP_ALIVE_SECS as u64)));
+    }
+
+    let Ok(broker) = broker_endpoint() else {
+        // logged at boot, there is nothing to connect to
+        refuse_until(future::pending::<()>()).await;
+        return;
+    };
+    let mut resolver = Resolver::new(stack, broker.url, &BROKER_ADDRESSES);
+    // the record buffers are large, they are only allocated once needed
+    let mut tls = None;
+    let mut connection = Connection::new(u64::MAX);
+    let mut backoff = Backoff::new(INITIAL_BACKOFF_MS, MAX_BACKOFF_MS, u32::MAX);
+
+    loop {
+        let device_credentials = refuse_until(ready(stack)).await;
+        if tls.is_none() {
+            tls = broker.tls(rng);
+        }
+
+        let connects = connection.stats().connects;
+        let result = connect(
+            &mut socket,
+            &mut resolver,
+            tls.as_mut(),
+            &device_credentials,
+            &mut connection,
+        )
+        .await;
+        if connection.stats().connects > connects {
+            backoff.reset();
+        }
+        match result {
+            Ok(()) => {
+                connection.closed();
+                socket.close();
+                info!("disconnected from broker");
+            }
+            Err(e) => {
+                connection.failed();
+                socket.abort();
+                // cannot run out, there is no limit on the attempts
+                let delay_ms = backoff
+                    .next_delay_ms(rng.random())
+                    .unwrap_or(MAX_BACKOFF_MS);
+                warn!(
+                    "connection to broker failed, reconnecting in {}ms: {:?}",
+                    delay_ms, e
+                );
+                CONNECTION.lock(|stats| stats.set(connection.stats()));
+                refuse_until(Timer::after_millis(delay_ms)).await;
+            }
+        }
+        CONNECTION.lock(|stats| stats.set(connection.stats()));
+    }
+}
+
+/// Waits until the transport is MQTT and the device can connect, returns
+/// the credentials it connects with.
+async fn ready(stack: Stack<'_>) -> DeviceCredentials {
+    loop {
+        if settings::transport() == Transport::Mqtt {
+            match credentials::current() {
+                Some(device_credentials) => {
+                    stack.wait_config_up().await;
+                    return device_credentials;
+                }
+                None => debug!("device is not provisioned, not connecting to broker"),
+            }
+        }
+        // credentials are provisioned without notice
+        select(settings::transport_changed(), Timer::after_secs(10)).await;
+    }
+}
+
+/// Fails requests with [`BrokerError::Disconnected`] until `until`
+/// completes.
+async fn refuse_until<T>(until: impl Future<Output = T>) -> T {
+    let mut until = pin!(until);
+    loop {
+        match select(REQUESTS.receive(), &mut until).await {
+            Either::First(request) => {
+                DONE.signal((request.id, Err(BrokerError::Disconnected)));
+            }
+            Either::Second(output) => return output,
+        }
+    }
+}
+
+/// Connects to the broker and serves requests until the connection is lost,
+/// or closes it once the transport is switched away from MQTT.
+async fn connect(
+    socket: &mut TcpSocket<'_>,
+    resolver: &mut Resolver<'_>,
+    tls: Option<&mut Tls>,
+    device_credentials: &DeviceCredentials,
+    connection: &mut Connection,
+) -> Result<(), BrokerError> {
+    socket.abort();
+    let remote = resolver.resolve().await.map_err(BrokerError::Dns)?;
+    if let Err(e) = socket.connect(remote).await {
+        resolver.expire();
+        return Err(BrokerError::Connect(e));
+    }
+    debug!("connected to {}", remote);
+
+    let socket = RefCell::new(socket);
+    let socket = SharedSocket(&socket);
+    // the session state is large, keep it out of the task arena
+    match tls {
+        None => Box::pin(Link::new(socket, socket, device_credentials, connection).run()).await,
+        Some(tls) => {
+            Box::pin(async move {
+                let mut session = tls.open(socket).await?;
+                Link::new(&mut session, socket, device_credentials, connection)
+                    .run()
+                    .await
+            })
+            .await
+        }
+    }
+}
+
+/// The socket of a [`Link`], shared by the session on it and the wait for
+/// incoming data. Every poll borrows the socket for just that poll, so each
+/// of the futures can be dropped at any point.
+#[derive(Clone, Copy)]
+struct SharedSocket<'s, 't>(&'s RefCell<&'s mut TcpSocket<'t>>);
+
+impl SharedSocket<'_, '_> {
+    /// Waits until the socket has data or was closed, without reading.
+    async fn wait_read_ready(&self) {
+        poll_fn(|cx| pin!(self.0.borrow().wait_read_ready()).poll(cx)).await
+    }
+}
+
+impl ErrorType for SharedSocket<'_, '_> {
+    type Error = tcp::Error;
+}
+
+impl Read for SharedSocket<'_, '_> {
+    async fn read(&mut self, buf: &mut [u8]) -> Result<usize, tcp::Error> {
+        poll_fn(|cx| pin!(self.0.borrow_mut().read(buf)).poll(cx)).await
+    }
+}
+
+impl Write for SharedSocket<'_, '_> {
+    async fn write(&mut self, buf: &[u8]) -> Result<usize, tcp::Error> {
+        poll_fn(|cx| pin!(self.0.borrow_mut().write(buf)).poll(cx)).await
+    }
+
+    async fn flush(&mut self) -> Result<(), tcp::Error> {
+        poll_fn(|cx| pin!(self.0.borrow_mut().flush()).poll(cx)).await
+    }
+}
+
+/// A packet from the broker, without the borrows.
+enum Incoming {
+    ConnAck(u8),
+    PubAck {
+        packet_id: u16,
+        code: u8,
+    },
+    SubAck {
+        packet_id: u16,
+        code: u8,
+    },
+    PingResp,
+    /// The device only subscribes to its command topic, so every message is
+    /// a command.
+    Command {
+        packet_id: Option<u16>,
+        command: Result<Command, CommandError>,
+    },
+    Disconnect(u8),
+}
+
+impl Incoming {
+    fn decode(packet: &[u8]) -> Result<Self, BrokerError> {
+        Ok(
+            match mqtt::decode(VERSION, packet).map_err(BrokerError::Mqtt)? {
+                Packet::ConnAck { code, .. } => Self::ConnAck(code),
+                Packet::Publish {
+                    message, packet_id, ..
+                } => Self::Command {
+                    packet_id,
+                    command: Command::parse(message.payload),
+                },
+                Packet::PubAck { packet_id, code } => Self::PubAck { packet_id, code },
+                Packet::SubAck { packet_id, code } => Self::SubAck { packet_id, code },
+                Packet::PingResp => Self::PingResp,
+                Packet::Disconnect { code } => Self::Disconnect(code),
+            },
+        )
+    }
+}
+
+/// An MQTT session on a connection, over TCP or TLS.
+struct Link<'d, 't, S> {
+    io: S,
+    /// The TCP socket below `io`, to wait for incoming data.
+    socket: SharedSocket<'d, 't>,
+    device_credentials: &'d DeviceCredentials,
+    device_id: &'d str,
+    connection: &'d mut Connection,
+    keep_alive: KeepAlive,
+    rx: Vec<u8>,
+    rx_len: usize,
+    /// The last read filled `rx`, `io` may hold more than the socket.
+    buffered: bool,
+    /// Bytes left of an incoming packet that is too large.
+    discard: usize,
+    packet_id: u16,
+    status_at: Instant,
+}
+
+impl<'d, 't, S> Link<'d, 't, S>
+where
+    S: Read + Write,
+    BrokerError: From<<S as ErrorType>::Error>,
+{
+    fn new(
+        io: S,
+        socket: SharedSocket<'d, 't>,
+        device_credentials: &'d DeviceCredentials,
+        connection: &'d mut Connection,
+    ) -> Self {
+        Self {
+            io,
+            socket,
+            device_credentials,
+            device_id: device_credentials.device_id(),
+            connection,
+            keep_alive: KeepAlive::new(KEEP_ALIVE_SECS, Instant::now().as_micros()),
+            rx: vec![0; MAX_INCOMING_LEN],
+            rx_len: 0,
+            buffered: false,
+            discard: 0,
+            packet_id: 0,
+            status_at: Instant::now(),
+        }
+    }
+
+    async fn run(mut self) -> Result<(), BrokerError> {
+        let status_topic = topic(STATUS_TOPIC, self.device_id);
+        let password = self.device_credentials.broker_password();
+        let connect = Connect {
+            client_id: self.device_id,
+            keep_alive_secs: KEEP_ALIVE_SECS,
+            clean_start: true,
+            will: Some(Message {
+                topic: &status_topic,
+                payload: OFFLINE.as_bytes(),
+                qos: QoS::AtLeastOnce,
+                retain: true,
+            }),
+            username: Some(USERNAME.unwrap_or(self.device_id)),
+            password: Some(PASSWORD.unwrap_or(&password).as_bytes()),
+        };
+        let mut packet = [0; CONTROL_PACKET_LEN];
+        let len =
+            mqtt::encode_connect(VERSION, &connect, &mut packet).map_err(BrokerError::Mqtt)?;
+        self.send(&packet[..len]).await?;
+        let code = self
+            .answer(|incoming| match incoming {
+                Incoming::ConnAck(code) => Some(*code),
+                _ => None,
+            })
+            .await?;
+        if code != 0 {
+            return Err(BrokerError::Refused(code));
+        }
+        self.connection.connected(Instant::now().as_micros());
+        CONNECTION.lock(|stats| stats.set(self.connection.stats()));
+        info!("connected to broker as {}", self.device_id);
+
+        self.subscribe().await?;
+        self.status_at = Instant::now();
+        loop {
+            let keep_alive_at = match self.keep_alive.poll(Instant::now().as_micros()) {
+                KeepAliveAction::Wait(at_us) => {
+                    Instant::from_micros(at_us.min(Instant::MAX.as_micros()))
+                }
+                KeepAliveAction::Ping | KeepAliveAction::Dead => Instant::now(),
+            };
+            let tick_at = keep_alive_at.min(self.status_at);
+
+            match select4(
+                REQUESTS.receive(),
+                self.wait_incoming(),
+                Timer::at(tick_at),
+                settings::transport_changed(),
+            )
+            .await
+            {
+                Either4::First(request) => {
+                    let topic = topic(request.topic, self.device_id);
+                    match self
+                        .publish(&topic, request.payload.as_bytes(), request.retain)
+                        .await
+                    {
+                        Ok(code) if code < 0x80 => DONE.signal((request.id, Ok(()))),
+                        Ok(code) => DONE.signal((request.id, Err(BrokerError::Refused(code)))),
+                        Err(e) => {
+                            // the caller retries once the connection is back
+                            DONE.signal((request.id, Err(BrokerError::Disconnected)));
+                            return Err(e);
+                        }
+                    }
+                }
+                Either4::Second(()) => {
+                    let timeout = Duration::from_secs(REQUEST_TIMEOUT_SECS);
+                    let incoming = with_timeout(timeout, self.receive())
+                        .await
+                        .unwrap_or(Err(BrokerError::Timeout))?;
+                    self.handle(incoming).await?
+                }
+                Either4::Third(()) => self.tick().await?,
+                Either4::Fourth(Transport::Mqtt) => {}
+                Either4::Fourth(Transport::Http) => return self.disconnect().await,
+            }
+        }
+    }
+
+    async fn subscribe(&mut self) -> Result<(), BrokerError> {
+        let filter = topic(COMMAND_TOPIC, self.device_id);
+        let packet_id = self.next_packet_id();
+        let mut packet = [0; CONTROL_PACKET_LEN];
+        let len =
+            mqtt::encode_subscribe(VERSION, packet_id, &filter, QoS::AtLeastOnce, &mut packet)
+                .map_err(BrokerError::Mqtt)?;
+        self.send(&packet[..len]).await?;
+        let code = self
+            .answer(|incoming| match incoming {
+                Incoming::SubAck {
+                    packet_id: id,
+                    code,
+                } if *id == packet_id => Some(*code),
+                _ => None,
+            })
+            .await?;
+        if code >= 0x80 {
+            warn!(
+                "broker refused the subscription to {}, commands are unavailable: {:#x}",
+                filter.as_str(),
+                code
+            );
+        }
+        Ok(())
+    }
+
+    /// Publishes with QoS 1 and waits for the acknowledgement, returns its
+    /// reason code.
+    async fn publish(
+        &mut self,
+        topic: &str,
+        payload: &[u8],
+        retain: bool,
+    ) -> Result<u8, BrokerError> {
+        let packet_id = self.next_packet_id();
+        let message = Message {
+            topic,
+            payload,
+            qos: QoS::AtLeastOnce,
+            retain,
+        };
+        let mut packet = vec![0; PUBLISH_OVERHEAD + topic.len() + payload.len()];
+        let len = mqtt::encode_publish(VERSION, &message, packet_id, false, &mut packet)
+            .map_err(BrokerError::Mqtt)?;
+
+        self.connection.request_started(Instant::now().as_micros());
+        self.send(&packet[..len]).await?;
+        let code = self
+            .answer(|incoming| match incoming {
+                Incoming::PubAck {
+                    packet_id: id,
+                    code,
+                } if *id == packet_id => Some(*code),
+                _ => None,
+            })
+            .await?;
+        self.connection
+            .response_received(Instant::now().as_micros(), true, None);
+        CONNECTION.lock(|stats| stats.set(self.connection.stats()));
+        debug!("published to {} ({:#x})", topic, code);
+        Ok(code)
+    }
+
+    /// Pings the broker and publishes the status when they are due.
+    async fn tick(&mut self) -> Result<(), BrokerError> {
+        let now = Instant::now();
+        match self.keep_alive.poll(now.as_micros()) {
+            KeepAliveAction::Wait(_) => {}
+            KeepAliveAction::Ping => {
+                let mut packet = [0; 2];
+                let len = mqtt::encode_pingreq(&mut packet).map_err(BrokerError::Mqtt)?;
+                self.send(&packet[..len]).await?;
+                self.keep_alive.pinged(now.as_micros());
+            }
+            KeepAliveAction::Dead => return Err(BrokerError::Timeout),
+        }
+
+        if now >= self.status_at {
+            self.status_at = now + Duration::from_secs(STATUS_INTERVAL_SECS);
+            let status = format!(
+                "{{\"online\": true, \"status\": {}}}",
+                management::status_json()
+            );
+            let topic = topic(STATUS_TOPIC, self.device_id);
+            let code = self.publish(&topic, status.as_bytes(), true).await?;
+            if code >= 0x80 {
+                warn!("broker refused the status: {:#x}", code);
+            }
+        }
+        Ok(())
+    }
+
+    /// Marks the device offline and ends the session, the broker does not
+    /// publish the will then.
+    async fn disconnect(&mut self) -> Result<(), BrokerError> {
+        let topic = topic(STATUS_TOPIC, self.device_id);
+        self.publish(&topic, OFFLINE.as_bytes(), true).await?;
+        let mut packet = [0; 2];
+        let len = mqtt::encode_disconnect(&mut packet).map_err(BrokerError::Mqtt)?;
+        self.send(&packet[..len]).await
+    }
+
+    /// Acts on a packet that is not the answer to a request.
+    async fn handle(&mut self, incoming: Incoming) -> Result<(), BrokerError> {
+        match incoming {
+            Incoming::Command { packet_id, command } => {
+                if let Some(packet_id) = packet_id {
+                    let mut packet = [0; 4];
+                    let len =
+                        mqtt::encode_puback(packet_id, &mut packet).map_err(BrokerError::Mqtt)?;
+                    self.send(&packet[..len]).await?;
+                }
+                match command {
+                    Ok(command) => self.apply(command),
+                    Err(e) => warn!("ignoring invalid command: {:?}", e),
+                }
+            }
+            // the keep alive already took note of it
+            Incoming::PingResp => {}
+            Incoming::PubAck { .. } | Incoming::SubAck { .. } | Incoming::ConnAck(_) => {
+                return Err(BrokerError::Mqtt(MqttError::Unexpected));
+            }
+            Incoming::Disconnect(code) => {
+                warn!("broker closed the connection: {:#x}", code);
+                return Err(BrokerError::Closed);
+            }
+        }
+        Ok(())
+    }
+
+    fn apply(&mut self, command: Command) {
+        info!("received command: {:?}", command);
+        match command {
+            Command::Event { active } => health::set_event_active(active),
+            Command::Capture { enabled } => settings::set_capture_enabled(enabled),
+            Command::Status => {}
+        }
+        // the status shows the effect of every command
+        self.status_at = Instant::now();
+    }
+
+    /// Waits for the answer `matches` picks, handling everything else that
+    /// arrives in the meantime.
+    async fn answer<T>(
+        &mut self,
+        matches: impl Fn(&Incoming) -> Option<T>,
+    ) -> Result<T, BrokerError> {
+        let timeout = Duration::from_secs(REQUEST_TIMEOUT_SECS);
+        with_timeout(timeout, async {
+            loop {
+                let incoming = self.receive().await?;
+                match matches(&incoming) {
+                    Some(answer) => return Ok(answer),
+                    None => self.handle(incoming).await?,
+                }
+            }
+        })
+        .await
+        .unwrap_or(Err(BrokerError::Timeout))
+    }
+
+    async fn send(&mut self, packet: &[u8]) -> Result<(), BrokerError> {
+        self.io.write_all(packet).await?;
+        self.io.flush().await?;
+        self.keep_alive.sent(Instant::now().as_micros());
+        Ok(())
+    }
+
+    /// Waits until there is something to [`receive`](Self::receive).
+    ///
+    /// Unlike receiving, waiting can be cancelled at any point. A read over
+    /// TLS that is cancelled halfway through a record corrupts the session,
+    /// so the link only races this wait against its other work.
+    async fn wait_incoming(&self) {
+        let complete = match mqtt::packet_len(&self.rx[..self.rx_len]) {
+            Ok(Some(len)) => len <= self.rx_len,
+            Ok(None) => false,
+            // for receive to report
+            Err(_) => true,
+        };
+        if !complete && !self.buffered {
+            self.socket.wait_read_ready().await;
+        }
+    }
+
+    /// Reads the next packet. Cancelling it is only safe over plain TCP or
+    /// when the connection is dropped right after, see
+    /// [`wait_incoming`](Self::wait_incoming).
+    async fn receive(&mut self) -> Result<Incoming, BrokerError> {
+        loop {
+            let buffered = &self.rx[..self.rx_len];
+            if let Some(len) = mqtt::packet_len(buffered).map_err(BrokerError::Mqtt)? {
+                if len > self.rx.len() {
+                    warn!("dropping a packet of {} bytes from the broker", len);
+                    self.discard = len - self.rx_len;
+                    self.rx_len = 0;
+                } else if len <= self.rx_len {
+                    let incoming = Incoming::decode(&self.rx[..len]);
+                    self.rx.copy_within(len..self.rx_len, 0);
+                    self.rx_len -= len;
+                    self.keep_alive.received();
+                    return incoming;
+                }
+            }
+
+            let room = self.rx.len() - self.rx_len;
+            let n = self.io.read(&mut self.rx[self.rx_len..]).await?;
+            if n == 0 {
+                return Err(BrokerError::Closed);
+            }
+            self.buffered = n == room;
+            let discarded = n.min(self.discard);
+            self.discard -= discarded;
+            self.rx
+                .copy_within(self.rx_len + discarded..self.rx_len + n, self.rx_len);
+            self.rx_len += n - discarded;
+        }
+    }
+
+    fn next_packet_id(&mut self) -> u16 {
+        // 0 is not a valid packet ID
+        self.packet_id = self.packet_id.checked_add(1).unwrap_or(1);
+        self.packet_id
+    }
+}
+
+/// A topic from its template in [`config::mqtt`](crate::config::mqtt).
+fn topic(template: &str, device_id: &str) -> String {
+    template.replace("{device}", device_id)
+}
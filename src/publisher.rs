@@ -0,0 +1,454 @@
+//! Publishing of results to the backend, or the broker.
+//!
+//! Results are handed to the publish task through [`submit`], which never
+//! blocks the measurement. The task stores them in the [`ResultQueue`] in
+//! flash first and publishes from there once the network is up, so results
+//! survive dropped hotspots and reboots. An entry is only removed once the
+//! backend answered with a 2xx or the broker acknowledged it. Entries
+//! rejected for good are parked: logged, counted and kept in flash, but no
+//! longer retried. Without the queue partition results are kept in RAM
+//! instead. Every entry goes out with the [`Transport`] selected at the time.
+
+use alloc::{string::String, vec::Vec};
+use core::{
+    future::Future,
+    pin::pin,
+    sync::atomic::{AtomicU32, Ordering},
+};
+use defmt::{error, info, warn, Format};
+use embassy_futures::select::{select, Either};
+use embassy_net::Stack;
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, channel::Channel};
+use embassy_time::{Duration, Instant, Timer};
+use esp_hal::rng::Rng;
+use heapless::Deque;
+use trichter_core::{backoff::Backoff, endpoint::Transport, queue::EntryId};
+
+use crate::{
+    config::{
+        mqtt::{DUEL_TOPIC, RESULT_TOPIC, TRACE_TOPIC},
+        publish::{
+            INITIAL_BACKOFF_MS, MAX_BACKOFF_MS, MAX_ENTRY_LEN, MAX_RETRIES, MEMORY_QUEUE_LEN,
+            QUEUE_LEN,
+        },
+        remote::{DUEL_RESOURCE, RESULT_RESOURCE, TRACE_RESOURCE},
+    },
+    driver::sensor::{DuelResult, PulseTrace, SessionResult},
+    mk_static,
+    mqtt::{self, BrokerError},
+    settings,
+    storage::ResultQueue,
+    wifi::{
+        duel_body, remote_endpoint, result_body, trace_body, RequestError, SessionResultClient,
+    },
+};
+
+static SUBMITTED: Channel<CriticalSectionRawMutex, Publication, QUEUE_LEN> = Channel::new();
+static DEPTH: AtomicU32 = AtomicU32::new(0);
+static PARKED: AtomicU32 = AtomicU32::new(0);
+
+/// Something to send to the backend.
+pub enum Publication {
+    Session(SessionResult),
+    Duel(DuelResult),
+    Trace { channel: u8, trace: PulseTrace },
+}
+
+impl Publication {
+    fn kind(&self) -> Kind {
+        match self {
+            Self::Session(_) => Kind::Session,
+            Self::Duel(_) => Kind::Duel,
+            Self::Trace { .. } => Kind::Trace,
+        }
+    }
+
+    fn body(&self) -> String {
+        match self {
+            Self::Session(result) => result_body(result),
+            Self::Duel(duel) => duel_body(duel),
+            Self::Trace { channel, trace } => trace_body(*channel, trace),
+        }
+    }
+}
+
+/// What a queued body is, stored as the first byte of an entry.
+#[derive(Debug, Clone, Copy, Format)]
+enum Kind {
+    Session = 0,
+    Duel = 1,
+    Trace = 2,
+}
+
+impl Kind {
+    fn resource(self) -> &'static str {
+        match self {
+            Self::Session => RESULT_RESOURCE,
+            Self::Duel => DUEL_RESOURCE,
+            Self::Trace => TRACE_RESOURCE,
+        }
+    }
+
+    fn topic(self) -> &'static str {
+        match self {
+            Self::Session => RESULT_TOPIC,
+            Self::Duel => DUEL_TOPIC,
+            Self::Trace => TRACE_TOPIC,
+        }
+    }
+
+    /// The broker keeps the last result and duel for new subscribers.
+    fn retained(self) -> bool {
+        matches!(self, Self::Session | Self::Duel)
+    }
+
+    fn from_byte(byte: u8) -> Option<Self> {
+        match byte {
+            0 => Some(Self::Session),
+            1 => Some(Self::Duel),
+            2 => Some(Self::Trace),
+            _ => None,
+        }
+    }
+}
+
+/// Queues a publication for the publish task, never blocks the caller.
+///
+/// If the task is too far behind the publication is dropped.
+pub fn submit(publication: Publication) {
+    let kind = publication.kind();
+    if SUBMITTED.try_send(publication).is_err() {
+        warn!("publish queue is full, dropping {}", kind);
+    }
+}
+
+/// Results waiting to be published, as of the last change.
+pub fn queue_depth() -> u32 {
+    DEPTH.load(Ordering::Relaxed)
+}
+
+/// Results the backend or the broker rejected for good, as of the last
+/// change.
+pub fn parked() -> u32 {
+    PARKED.load(Ordering::Relaxed)
+}
+
+/// An entry of the [`Outbox`], which may be dropped from its front while it
+/// is published.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum OutboxId {
+    Flash(EntryId),
+    /// Entries removed from the front before this one.
+    Memory(u32),
+}
+
+/// Bodies waiting to be published, oldest first.
+enum Outbox {
+    Flash {
+        queue: ResultQueue,
+        buf: &'static mut [u8],
+    },
+    Memory {
+        entries: Deque<(Kind, String), MEMORY_QUEUE_LEN>,
+        removed: u32,
+        parked: u32,
+    },
+}
+
+impl Outbox {
+    fn new(queue: Option<ResultQueue>) -> Self {
+        match queue {
+            Some(queue) => {
+                // room for the kind and the payload padded to 4 bytes
+                let buf = mk_static!([u8; MAX_ENTRY_LEN + 4], [0; MAX_ENTRY_LEN + 4]);
+                Self::Flash { queue, buf }
+            }
+            None => {
+                warn!("no result queue, unpublished results are lost on reboot");
+                Self::Memory {
+                    entries: Deque::new(),
+                    removed: 0,
+                    parked: 0,
+                }
+            }
+        }
+    }
+
+    fn len(&self) -> u32 {
+        match self {
+            Self::Flash { queue, .. } => queue.len(),
+            Self::Memory { entries, .. } => entries.len() as u32,
+        }
+    }
+
+    fn parked(&self) -> u32 {
+        match self {
+            Self::Flash { queue, .. } => queue.parked(),
+            Self::Memory { parked, .. } => *parked,
+        }
+    }
+
+    fn push(&mut self, publication: Publication) {
+        let kind = publication.kind();
+        let body = publication.body();
+        match self {
+            Self::Flash { queue, .. } => {
+                if body.len() + 1 > MAX_ENTRY_LEN {
+                    error!("{} of {} bytes is too large to store", kind, body.len());
+                    return;
+                }
+                let mut entry = Vec::with_capacity(body.len() + 1);
+                entry.push(kind as u8);
+                entry.extend_from_slice(body.as_bytes());
+                let dropped = queue.dropped();
+                if let Err(e) = queue.push(&entry) {
+                    error!("failed to store {}: {:?}", kind, e);
+                } else if queue.dropped() > dropped {
+                    warn!(
+                        "result queue is full, dropped {} old entries",
+                        queue.dropped() - dropped
+                    );
+                }
+            }
+            Self::Memory {
+                entries, removed, ..
+            } => {
+                if entries.is_full() {
+                    warn!("result queue is full, dropping the oldest entry");
+                    entries.pop_front();
+                    *removed = removed.wrapping_add(1);
+                }
+                // cannot fail, there is room now
+                let _ = entries.push_back((kind, body));
+            }
+        }
+        DEPTH.store(self.len(), Ordering::Relaxed);
+        // parked entries go once their block is reused
+        PARKED.store(self.parked(), Ordering::Relaxed);
+    }
+
+    fn front(&mut self) -> Option<(OutboxId, Kind, String)> {
+        match self {
+            Self::Flash { queue, buf } => loop {
+                let len = match queue.peek(buf) {
+                    Ok(len) => len?,
+                    Err(e) => {
+                        error!("failed to read result queue: {:?}", e);
+                        return None;
+                    }
+                };
+                let kind = buf.first().copied().and_then(Kind::from_byte);
+                let body = buf
+                    .get(1..len)
+                    .and_then(|body| core::str::from_utf8(body).ok());
+                if let (Some(kind), Some(body)) = (kind, body) {
+                    let id = match queue.front_id() {
+                        Ok(id) => id?,
+                        Err(e) => {
+                            error!("failed to read result queue: {:?}", e);
+                            return None;
+                        }
+                    };
+                    return Some((OutboxId::Flash(id), kind, String::from(body)));
+                }
+                warn!("parking malformed queue entry");
+                if let Err(e) = queue.park() {
+                    error!("failed to park entry of result queue: {:?}", e);
+                    return None;
+                }
+                PARKED.store(queue.parked(), Ordering::Relaxed);
+            },
+            Self::Memory {
+                entries, removed, ..
+            } => {
+                let (kind, body) = entries.front().cloned()?;
+                Some((OutboxId::Memory(*removed), kind, body))
+            }
+        }
+    }
+
+    /// Removes the entry `id` once it was published, unless it was dropped
+    /// in the meantime. Returns whether it was still there.
+    fn pop(&mut self, id: OutboxId) -> bool {
+        let popped = self.remove(id, false);
+        DEPTH.store(self.len(), Ordering::Relaxed);
+        popped
+    }
+
+    /// Sets the entry `id` aside, it was rejected for good. In flash it is
+    /// kept until its block is reused, in RAM only the log keeps it. Returns
+    /// whether it was still there.
+    fn park(&mut self, id: OutboxId) -> bool {
+        let parked = self.remove(id, true);
+        DEPTH.store(self.len(), Ordering::Relaxed);
+        PARKED.store(self.parked(), Ordering::Relaxed);
+        parked
+    }
+
+    fn remove(&mut self, id: OutboxId, park: bool) -> bool {
+        match (self, id) {
+            (Self::Flash { queue, .. }, OutboxId::Flash(id)) => {
+                let removed = if park {
+                    queue.park_entry(id)
+                } else {
+                    queue.pop_entry(id)
+                };
+                removed.unwrap_or_else(|e| {
+                    error!("failed to remove entry from result queue: {:?}", e);
+                    false
+                })
+            }
+            (
+                Self::Memory {
+                    entries,
+                    removed,
+                    parked,
+                },
+                OutboxId::Memory(id),
+            ) if *removed == id && !entries.is_empty() => {
+                entries.pop_front();
+                *removed = removed.wrapping_add(1);
+                if park {
+                    *parked += 1;
+                }
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Stores submitted publications until `until` completes.
+    async fn store_until<T>(&mut self, until: impl Future<Output = T>) -> T {
+        let mut until = pin!(until);
+        loop {
+            match select(SUBMITTED.receive(), &mut until).await {
+                Either::First(publication) => self.push(publication),
+                Either::Second(output) => return output,
+            }
+        }
+    }
+}
+
+/// Why an entry or telemetry could not be published, over either
+/// transport.
+#[derive(Debug, Format)]
+pub enum PublishError {
+    /// The endpoint of the transport is misconfigured, see the log at boot.
+    Unconfigured,
+    Http(RequestError),
+    Mqtt(BrokerError),
+}
+
+impl From<RequestError> for PublishError {
+    fn from(e: RequestError) -> Self {
+        Self::Http(e)
+    }
+}
+
+impl From<BrokerError> for PublishError {
+    fn from(e: BrokerError) -> Self {
+        Self::Mqtt(e)
+    }
+}
+
+impl PublishError {
+    /// Whether publishing the same entry again later may succeed, see
+    /// [`RequestError::is_transient`] and [`BrokerError::is_transient`]. A
+    /// misconfigured endpoint is retried as well, entries are kept until the
+    /// firmware is fixed.
+    pub fn is_transient(&self) -> bool {
+        match self {
+            Self::Unconfigured => true,
+            Self::Http(e) => e.is_transient(),
+            Self::Mqtt(e) => e.is_transient(),
+        }
+    }
+}
+
+/// Stores submitted publications and publishes them one by one.
+///
+/// Transient failures are retried with an exponential backoff, jittered by
+/// `rng` so that several devices do not retry in lockstep. Entries the
+/// backend rejects for good are parked with their body in the log, they
+/// would block the queue otherwise. Flash is written from this task, erasing
+/// a block stalls the executor for a moment.
+#[embassy_executor::task]
+pub async fn publish_task(stack: Stack<'static>, mut rng: Rng, queue: Option<ResultQueue>) {
+    let mut outbox = Outbox::new(queue);
+    DEPTH.store(outbox.len(), Ordering::Relaxed);
+    PARKED.store(outbox.parked(), Ordering::Relaxed);
+    // a broken REMOTE_URL was logged at boot
+    let mut client = remote_endpoint()
+        .ok()
+        .map(|remote| SessionResultClient::new(stack, remote, rng));
+    let mut backoff = Backoff::new(INITIAL_BACKOFF_MS, MAX_BACKOFF_MS, u32::MAX);
+
+    loop {
+        while let Ok(publication) = SUBMITTED.try_receive() {
+            outbox.push(publication);
+        }
+        let Some((id, kind, body)) = outbox.front() else {
+            let publication = SUBMITTED.receive().await;
+            outbox.push(publication);
+            continue;
+        };
+
+        outbox.store_until(stack.wait_config_up()).await;
+        let result = outbox
+            .store_until(async {
+                match settings::transport() {
+                    Transport::Http => match &mut client {
+                        Some(client) => client
+                            .post(kind.resource(), &body)
+                            .await
+                            .map_err(PublishError::Http),
+                        None => Err(PublishError::Unconfigured),
+                    },
+                    Transport::Mqtt => mqtt::publish(kind.topic(), &body, kind.retained())
+                        .await
+                        .map_err(PublishError::Mqtt),
+                }
+            })
+            .await;
+        // the queue may have overflowed in the meantime, so the entry is
+        // only removed if it is still there
+        let Err(e) = result else {
+            if !outbox.pop(id) {
+                warn!("{} was dropped from the full queue while publishing", kind);
+            }
+            info!("published {}, {} left", kind, outbox.len());
+            backoff.reset();
+            continue;
+        };
+        if !e.is_transient() {
+            error!(
+                "backend rejected {}, parking it: {:?}, body: {}",
+                kind, e, body
+            );
+            outbox.park(id);
+            backoff.reset();
+            continue;
+        }
+
+        // cannot run out, there is no limit on the attempts
+        let delay_ms = backoff
+            .next_delay_ms(rng.random())
+            .unwrap_or(MAX_BACKOFF_MS);
+        if backoff.attempts() > MAX_RETRIES {
+            error!(
+                "failed to publish {} {} times, retrying in {}ms: {:?}",
+                kind,
+                backoff.attempts(),
+                delay_ms,
+                e
+            );
+        } else {
+            warn!(
+                "failed to publish {}, retrying in {}ms: {:?}",
+                kind, delay_ms, e
+            );
+        }
+        let retry_at = Instant::now() + Duration::from_millis(delay_ms);
+        outbox.store_until(Timer::at(retry_at)).await;
+    }
+}
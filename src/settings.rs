@@ -0,0 +1,56 @@
+//! The [`SessionSettings`] in effect, shared between the measurement loop and
+//! the management interface, and the [`Transport`] results are published
+//! with.
+
+use core::{
+    cell::Cell,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use embassy_sync::{
+    blocking_mutex::{raw::CriticalSectionRawMutex, Mutex},
+    signal::Signal,
+};
+use trichter_core::{endpoint::Transport, settings::SessionSettings};
+
+/// `None` until settings were loaded or changed, which means the defaults.
+static SETTINGS: Mutex<CriticalSectionRawMutex, Cell<Option<SessionSettings>>> =
+    Mutex::new(Cell::new(None));
+/// Raw pulse capture is a diagnostic mode and not persisted.
+static CAPTURE_ENABLED: AtomicBool = AtomicBool::new(false);
+static TRANSPORT: Mutex<CriticalSectionRawMutex, Cell<Transport>> =
+    Mutex::new(Cell::new(Transport::Http));
+static TRANSPORT_CHANGED: Signal<CriticalSectionRawMutex, Transport> = Signal::new();
+
+pub fn current() -> SessionSettings {
+    SETTINGS.lock(|settings| settings.get()).unwrap_or_default()
+}
+
+/// Replaces the settings, they are picked up from the next session on.
+pub fn apply(settings: SessionSettings) {
+    SETTINGS.lock(|current| current.set(Some(settings)));
+}
+
+pub fn capture_enabled() -> bool {
+    CAPTURE_ENABLED.load(Ordering::Relaxed)
+}
+
+/// Enables or disables the raw pulse capture from the next session on.
+pub fn set_capture_enabled(enabled: bool) {
+    CAPTURE_ENABLED.store(enabled, Ordering::Relaxed);
+}
+
+pub fn transport() -> Transport {
+    TRANSPORT.lock(Cell::get)
+}
+
+/// Switches the transport, the next publish already uses it.
+pub fn set_transport(transport: Transport) {
+    TRANSPORT.lock(|current| current.set(transport));
+    TRANSPORT_CHANGED.signal(transport);
+}
+
+/// Waits for the transport to be switched, there must only be one waiter.
+pub async fn transport_changed() -> Transport {
+    TRANSPORT_CHANGED.wait().await
+}
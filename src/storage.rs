@@ -0,0 +1,149 @@
+use defmt::{debug, Format};
+use embedded_storage::{
+    nor_flash::{NorFlash, ReadNorFlash},
+    ReadStorage, Storage,
+};
+use esp_bootloader_esp_idf::partitions;
+use esp_storage::FlashStorage;
+use trichter_core::{
+    queue::{Flash, FlashQueue, QueueError},
+    record::{self, Persist, RECORD_OVERHEAD},
+};
+
+const PARTITION_LABEL: &str = "settings";
+const QUEUE_PARTITION_LABEL: &str = "results";
+/// Blocks of the result queue, a block has to hold the largest duel result.
+const QUEUE_BLOCK_SIZE: u32 = 16 * 1024;
+/// Every slot gets its own flash sector so records can be rewritten independently.
+const SLOT_SIZE: u32 = 4096;
+const MAX_RECORD_LEN: usize = 256;
+
+#[derive(Debug, Format)]
+pub enum StorageError {
+    PartitionTable,
+    MissingPartition,
+    SlotOutOfRange,
+    RecordTooLarge,
+    Flash,
+}
+
+/// Offset and size of the data partition called `label`.
+fn find_partition(flash: &mut FlashStorage, label: &str) -> Result<(u32, u32), StorageError> {
+    let mut pt_mem = [0u8; partitions::PARTITION_TABLE_MAX_LEN];
+    let pt = partitions::read_partition_table(flash, &mut pt_mem)
+        .map_err(|_| StorageError::PartitionTable)?;
+    let partition = pt
+        .iter()
+        .find(|p| p.label_as_str() == label)
+        .ok_or(StorageError::MissingPartition)?;
+
+    debug!(
+        "{} partition at {:#x} ({} bytes)",
+        label,
+        partition.offset(),
+        partition.len()
+    );
+    Ok((partition.offset(), partition.len()))
+}
+
+/// Small typed records in the `settings` data partition (see `partitions.csv`).
+pub struct SettingsStore {
+    flash: FlashStorage,
+    offset: u32,
+    size: u32,
+}
+
+impl SettingsStore {
+    pub fn new() -> Result<Self, StorageError> {
+        let mut flash = FlashStorage::new();
+        let (offset, size) = find_partition(&mut flash, PARTITION_LABEL)?;
+
+        Ok(Self {
+            flash,
+            offset,
+            size,
+        })
+    }
+
+    /// Reads the record of `T`, `None` if the slot is empty or corrupt.
+    pub fn load<T: Persist>(&mut self) -> Option<T> {
+        let addr = self.slot_address(T::SLOT).ok()?;
+        let len = (T::MAX_LEN + RECORD_OVERHEAD).min(MAX_RECORD_LEN);
+
+        let mut buf = [0u8; MAX_RECORD_LEN];
+        ReadStorage::read(&mut self.flash, addr, &mut buf[..len]).ok()?;
+        let payload = record::decode(T::SLOT, &buf[..len])?;
+        T::decode(payload)
+    }
+
+    pub fn store<T: Persist>(&mut self, value: &T) -> Result<(), StorageError> {
+        let addr = self.slot_address(T::SLOT)?;
+
+        let mut payload = [0u8; MAX_RECORD_LEN - RECORD_OVERHEAD];
+        let len = value
+            .encode(&mut payload)
+            .ok_or(StorageError::RecordTooLarge)?;
+
+        let mut buf = [0u8; MAX_RECORD_LEN];
+        let len = record::encode(T::SLOT, &payload[..len], &mut buf)
+            .ok_or(StorageError::RecordTooLarge)?;
+
+        Storage::write(&mut self.flash, addr, &buf[..len]).map_err(|_| StorageError::Flash)
+    }
+
+    fn slot_address(&self, slot: u8) -> Result<u32, StorageError> {
+        let start = slot as u32 * SLOT_SIZE;
+        if start + SLOT_SIZE > self.size {
+            return Err(StorageError::SlotOutOfRange);
+        }
+        Ok(self.offset + start)
+    }
+}
+
+/// The `results` data partition as raw NOR flash for the [`ResultQueue`].
+pub struct PartitionFlash {
+    flash: FlashStorage,
+    offset: u32,
+    size: u32,
+}
+
+impl Flash for PartitionFlash {
+    type Error = StorageError;
+
+    const ERASE_SIZE: u32 = <FlashStorage as NorFlash>::ERASE_SIZE as u32;
+
+    fn capacity(&self) -> u32 {
+        self.size
+    }
+
+    fn read(&mut self, offset: u32, buf: &mut [u8]) -> Result<(), StorageError> {
+        ReadNorFlash::read(&mut self.flash, self.offset + offset, buf)
+            .map_err(|_| StorageError::Flash)
+    }
+
+    fn write(&mut self, offset: u32, data: &[u8]) -> Result<(), StorageError> {
+        NorFlash::write(&mut self.flash, self.offset + offset, data)
+            .map_err(|_| StorageError::Flash)
+    }
+
+    fn erase(&mut self, from: u32, to: u32) -> Result<(), StorageError> {
+        NorFlash::erase(&mut self.flash, self.offset + from, self.offset + to)
+            .map_err(|_| StorageError::Flash)
+    }
+}
+
+/// Results waiting to be published, kept across reboots (see
+/// [`FlashQueue`]).
+pub type ResultQueue = FlashQueue<PartitionFlash>;
+
+pub fn open_result_queue() -> Result<ResultQueue, QueueError<StorageError>> {
+    let mut flash = FlashStorage::new();
+    let (offset, size) =
+        find_partition(&mut flash, QUEUE_PARTITION_LABEL).map_err(QueueError::Flash)?;
+    let flash = PartitionFlash {
+        flash,
+        offset,
+        size,
+    };
+    FlashQueue::open(flash, QUEUE_BLOCK_SIZE)
+}
@@ -1,17 +1,31 @@
-use defmt::{debug, info};
+use defmt::{debug, info, warn};
 use esp_hal::{
     clock::CpuClock,
-    gpio::InputPin,
     peripherals::{self, Peripherals},
     timer::systimer::Alarm,
 };
 use esp_wifi::EspWifiController;
+use trichter_core::{
+    calibration::Calibration, credentials::DeviceCredentials, endpoint::Transport,
+    settings::SessionSettings,
+};
 
-use crate::{driver::sensor::SensorDriver, wifi::WifiManager};
+use crate::{
+    credentials,
+    driver::{
+        pulse::PulseInput,
+        sensor::{SensorDriver, MAX_CHANNELS},
+    },
+    settings,
+    storage::{open_result_queue, ResultQueue, SettingsStore},
+    wifi::WifiManager,
+};
 
 pub struct System<'a> {
     pub wifi: Option<WifiManager<'a>>,
     pub sensor: Option<SensorDriver<'a>>,
+    pub storage: Option<SettingsStore>,
+    pub queue: Option<ResultQueue>,
 }
 
 impl System<'_> {
@@ -28,6 +42,8 @@ impl System<'_> {
 pub struct SystemBuilder {
     wifi: Option<WifiManager<'static>>,
     sensor: Option<SensorDriver<'static>>,
+    storage: Option<SettingsStore>,
+    queue: Option<ResultQueue>,
 }
 
 impl SystemBuilder {
@@ -40,6 +56,8 @@ impl SystemBuilder {
         Self {
             wifi: None,
             sensor: None,
+            storage: None,
+            queue: None,
         }
     }
 
@@ -55,16 +73,72 @@ impl SystemBuilder {
         self
     }
 
-    pub fn with_sensor(mut self, pin: impl InputPin + 'static) -> Self {
-        self.sensor = Some(SensorDriver::new(pin));
+    /// Either [`PulseInput::gpio`] or [`PulseInput::pcnt`] for hardware counting.
+    pub fn with_sensor(self, input: PulseInput<'static>) -> Self {
+        self.with_sensors([input])
+    }
+
+    /// One channel per input, numbered in the given order. At most
+    /// [`MAX_CHANNELS`] inputs are used.
+    pub fn with_sensors(mut self, inputs: impl IntoIterator<Item = PulseInput<'static>>) -> Self {
+        self.sensor = Some(SensorDriver::new(inputs));
         self
     }
 
-    pub fn build(self) -> System<'static> {
+    pub fn with_storage(mut self) -> Self {
+        match SettingsStore::new() {
+            Ok(storage) => self.storage = Some(storage),
+            Err(e) => warn!("settings storage unavailable: {:?}", e),
+        }
+        match open_result_queue() {
+            Ok(queue) => {
+                info!("{} results waiting to be published", queue.len());
+                self.queue = Some(queue);
+            }
+            Err(e) => warn!("result queue unavailable: {:?}", e),
+        }
+        self
+    }
+
+    pub fn build(mut self) -> System<'static> {
+        if let Some(storage) = self.storage.as_mut() {
+            match storage.load::<SessionSettings>() {
+                Some(session_settings) => {
+                    info!("loaded session settings: {:?}", session_settings);
+                    settings::apply(session_settings);
+                }
+                None => info!("no stored session settings, using defaults"),
+            }
+        }
+        if let Some(storage) = self.storage.as_mut() {
+            match storage.load::<DeviceCredentials>() {
+                Some(device_credentials) => {
+                    info!("loaded credentials: {:?}", device_credentials);
+                    credentials::provision(device_credentials);
+                }
+                None => warn!("device is not provisioned, results are kept until it is"),
+            }
+            if let Some(transport) = storage.load::<Transport>() {
+                info!("publishing with {}", transport.name());
+                settings::set_transport(transport);
+            }
+        }
+        if let (Some(storage), Some(sensor)) = (self.storage.as_mut(), self.sensor.as_mut()) {
+            match storage.load::<Calibration>() {
+                Some(calibration) => {
+                    info!("loaded calibration: {:?}", calibration.points());
+                    sensor.calibration = calibration;
+                }
+                None => info!("no stored calibration, using datasheet K-factor"),
+            }
+        }
+
         info!("system initialized");
         System {
             wifi: self.wifi,
             sensor: self.sensor,
+            storage: self.storage,
+            queue: self.queue,
         }
     }
 }
@@ -0,0 +1,149 @@
+use alloc::string::String;
+use core::{
+    fmt::Write as _,
+    sync::atomic::{AtomicU32, Ordering},
+};
+use defmt::{error, info};
+use embassy_futures::select::{select, Either};
+use embassy_net::Stack;
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, channel::Channel};
+use embassy_time::{Duration, Instant, Timer};
+use esp_hal::rng::Rng;
+use heapless::Vec;
+use trichter_core::{endpoint::Transport, session::SessionEvent};
+
+use crate::{
+    config::{
+        mqtt::TELEMETRY_TOPIC,
+        remote::TELEMETRY_RESOURCE,
+        telemetry::{BATCH_SIZE, FLUSH_INTERVAL_SECS, HEARTBEAT_INTERVAL_SECS},
+    },
+    mk_static, mqtt,
+    publisher::{self, PublishError},
+    settings,
+    wifi::{remote_endpoint, HttpClient, RESPONSE_BUFFER_LEN},
+};
+
+static EVENTS: Channel<CriticalSectionRawMutex, ChannelEvent, 16> = Channel::new();
+static DROPPED: AtomicU32 = AtomicU32::new(0);
+
+/// An event together with the sensor channel it happened on.
+struct ChannelEvent {
+    channel: u8,
+    event: SessionEvent,
+}
+
+/// Queues an event for the telemetry task, never blocks the caller.
+///
+/// If the queue is full the event is dropped and counted instead.
+pub fn report(channel: u8, event: SessionEvent) {
+    if EVENTS.try_send(ChannelEvent { channel, event }).is_err() {
+        DROPPED.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Collects reported events and publishes them in batches, either once
+/// `BATCH_SIZE` events are queued or `FLUSH_INTERVAL_SECS` after the first
+/// event of a batch. Batches go to the telemetry topic of the broker while
+/// the transport is MQTT. Every batch carries the depth of the result queue
+/// and the parked results, without events an empty batch is sent
+/// `HEARTBEAT_INTERVAL_SECS` after the last one.
+///
+/// The client to the backend is kept for the lifetime of the task, so the
+/// connection and the TLS record buffers are reused between flushes.
+#[embassy_executor::task]
+pub async fn telemetry_task(stack: Stack<'static>, mut rng: Rng) {
+    let rx_buffer = mk_static!([u8; 1024], [0; 1024]);
+    let tx_buffer = mk_static!([u8; 2048], [0; 2048]);
+    let response = mk_static!([u8; RESPONSE_BUFFER_LEN], [0; RESPONSE_BUFFER_LEN]);
+    // a broken REMOTE_URL was logged at boot
+    let mut client = remote_endpoint().ok().map(|remote| {
+        HttpClient::new(stack, remote.url, rx_buffer, tx_buffer).with_tls(remote.tls(rng))
+    });
+
+    let heartbeat = Duration::from_secs(HEARTBEAT_INTERVAL_SECS);
+    let mut batch: Vec<ChannelEvent, BATCH_SIZE> = Vec::new();
+    let mut flush_at = Instant::now();
+    let mut heartbeat_at = Instant::now() + heartbeat;
+    loop {
+        let until = if batch.is_empty() {
+            heartbeat_at
+        } else {
+            flush_at
+        };
+        let event = match select(EVENTS.receive(), Timer::at(until)).await {
+            Either::First(event) => Some(event),
+            Either::Second(_) => None,
+        };
+
+        if let Some(event) = event {
+            if batch.is_empty() {
+                flush_at = Instant::now() + Duration::from_secs(FLUSH_INTERVAL_SECS);
+            }
+            // cannot fail, full batches are flushed right away
+            let _ = batch.push(event);
+            if !batch.is_full() {
+                continue;
+            }
+        }
+
+        let body = events_body(&batch, DROPPED.swap(0, Ordering::Relaxed));
+        match publish(client.as_mut(), &mut rng, response, &body).await {
+            Ok(()) => info!("published {} telemetry events", batch.len()),
+            Err(e) => error!("failed to publish telemetry: {:?}", e),
+        }
+        batch.clear();
+        heartbeat_at = Instant::now() + heartbeat;
+    }
+}
+
+async fn publish(
+    client: Option<&mut HttpClient<'_>>,
+    rng: &mut Rng,
+    response: &mut [u8],
+    body: &str,
+) -> Result<(), PublishError> {
+    if settings::transport() == Transport::Mqtt {
+        return Ok(mqtt::publish(TELEMETRY_TOPIC, body, false).await?);
+    }
+    let client = client.ok_or(PublishError::Unconfigured)?;
+
+    Ok(client
+        .post_signed(TELEMETRY_RESOURCE, body, rng, response)
+        .await?)
+}
+
+fn events_body(events: &[ChannelEvent], dropped: u32) -> String {
+    let mut body = String::from("{\"events\": [");
+    for (i, event) in events.iter().enumerate() {
+        if i > 0 {
+            body.push(',');
+        }
+        match event.event {
+            SessionEvent::FalseStart(false_start) => {
+                let _ = write!(
+                    body,
+                    "{{\
+                        \"type\": \"false_start\",\
+                        \"channel\": {},\
+                        \"at_ms\": {},\
+                        \"pulses\": {},\
+                        \"window_ms\": {}\
+                    }}",
+                    event.channel,
+                    false_start.at_us / 1_000,
+                    false_start.pulses,
+                    false_start.window_us / 1_000,
+                );
+            }
+        }
+    }
+    let _ = write!(
+        body,
+        "], \"dropped\": {}, \"queue_depth\": {}, \"parked\": {}}}",
+        dropped,
+        publisher::queue_depth(),
+        publisher::parked()
+    );
+    body
+}
@@ -0,0 +1,280 @@
+//! TLS 1.3 towards the backend.
+//!
+//! Instead of a set of root certificates the server's certificate is checked
+//! against the [`Pin`] of the deployment, see
+//! [`TLS_PIN`](crate::config::remote::TLS_PIN) for the backend and
+//! [`mqtt::TLS_PIN`](crate::config::mqtt::TLS_PIN) for the broker: either the
+//! fingerprint of its public key or a CA certificate the chain has to lead up
+//! to. Only P-256 ECDSA
+//! certificates and TLS_AES_128_GCM_SHA256 are supported, which keeps the
+//! handshake cheap enough for the device. The leaf certificate also has to be
+//! issued for the host of the URL.
+//!
+//! embedded-tls speaks embedded-io 0.7 while the sockets of embassy-net speak
+//! 0.6, a [`TlsSession`] translates between the two.
+
+use alloc::{vec, vec::Vec};
+use defmt::{debug, warn, Format};
+use embedded_io_async::{ErrorType, Read, Write};
+use embedded_io_async_07 as io07;
+use embedded_tls::{
+    Aes128GcmSha256, CertificateEntryRef, CertificateRef, CertificateVerifyRef, CryptoProvider,
+    SignatureScheme, TlsConfig, TlsConnection, TlsContext, TlsError, TlsVerifier,
+};
+use esp_hal::rng::Rng;
+use p256::ecdsa::{signature::Verifier, Signature, VerifyingKey};
+use rand_core::{CryptoRng, CryptoRngCore, RngCore};
+use sha2::{Digest, Sha256};
+use trichter_core::x509::{self, oid, verify_chain, Pin};
+
+/// Room for a record of the maximum size of 16 KiB plus its overhead, the
+/// server decides how large its records are.
+const READ_BUFFER_LEN: usize = 16_640;
+/// Our records are split at this size.
+const WRITE_BUFFER_LEN: usize = 4_096;
+/// The longest DNS name.
+const MAX_HOST_LEN: usize = 253;
+
+/// Prefix of the message signed in the server's CertificateVerify, followed
+/// by the transcript hash (RFC 8446, section 4.4.3).
+const CERTIFICATE_VERIFY_CONTEXT: &[u8] = b"TLS 1.3, server CertificateVerify\0";
+
+#[derive(Debug, Clone, Copy)]
+pub struct TlsSettings {
+    /// Sent as SNI, the leaf certificate has to be issued for it.
+    pub server_name: &'static str,
+    pub pin: Pin<'static>,
+}
+
+/// What a client needs to open TLS sessions, the record buffers are
+/// allocated once and reused for every session.
+///
+/// They take about 20KB of the heap, so every task that talks TLS creates
+/// one and keeps it for good.
+pub struct Tls {
+    settings: TlsSettings,
+    rng: Rng,
+    read_buf: Vec<u8>,
+    write_buf: Vec<u8>,
+}
+
+impl Tls {
+    pub fn new(settings: TlsSettings, rng: Rng) -> Self {
+        Self {
+            settings,
+            rng,
+            read_buf: vec![0; READ_BUFFER_LEN],
+            write_buf: vec![0; WRITE_BUFFER_LEN],
+        }
+    }
+
+    /// Performs the handshake on `socket`, which has to be connected.
+    pub async fn open<'a, S: Read + Write + 'a>(
+        &'a mut self,
+        socket: S,
+    ) -> Result<TlsSession<'a, S>, TlsError> {
+        let config = TlsConfig::new().with_server_name(self.settings.server_name);
+        let provider = PinnedProvider {
+            rng: TlsRng(self.rng),
+            verifier: PinVerifier {
+                pin: self.settings.pin,
+                host: None,
+                leaf: None,
+            },
+        };
+
+        let mut session = TlsConnection::new(Io(socket), &mut self.read_buf, &mut self.write_buf);
+        session.open(TlsContext::new(&config, provider)).await?;
+        debug!("TLS session with {} established", self.settings.server_name);
+        Ok(TlsSession(session))
+    }
+}
+
+/// An open TLS session on a socket.
+pub struct TlsSession<'a, S: Read + Write + 'a>(TlsConnection<'a, Io<S>, Aes128GcmSha256>);
+
+impl<S: Read + Write> TlsSession<'_, S> {
+    /// Sends the close notification, the socket stays open.
+    pub async fn close(self) -> Result<(), TlsError> {
+        self.0.close().await.map(drop).map_err(|(_, e)| e)
+    }
+}
+
+/// A failure of an open [`TlsSession`].
+#[derive(Debug, Format)]
+pub struct SessionError(pub TlsError);
+
+impl embedded_io_async::Error for SessionError {
+    fn kind(&self) -> embedded_io_async::ErrorKind {
+        embedded_io_async::ErrorKind::Other
+    }
+}
+
+impl<S: Read + Write> ErrorType for TlsSession<'_, S> {
+    type Error = SessionError;
+}
+
+impl<S: Read + Write> Read for TlsSession<'_, S> {
+    async fn read(&mut self, buf: &mut [u8]) -> Result<usize, SessionError> {
+        self.0.read(buf).await.map_err(SessionError)
+    }
+}
+
+impl<S: Read + Write> Write for TlsSession<'_, S> {
+    async fn write(&mut self, buf: &[u8]) -> Result<usize, SessionError> {
+        self.0.write(buf).await.map_err(SessionError)
+    }
+
+    async fn flush(&mut self) -> Result<(), SessionError> {
+        self.0.flush().await.map_err(SessionError)
+    }
+}
+
+/// A socket as embedded-tls expects it.
+struct Io<S>(S);
+
+impl<S: ErrorType> io07::ErrorType for Io<S> {
+    // the kinds of the two versions do not convert, and the session only
+    // reports that the socket failed anyway
+    type Error = io07::ErrorKind;
+}
+
+impl<S: Read> io07::Read for Io<S> {
+    async fn read(&mut self, buf: &mut [u8]) -> Result<usize, io07::ErrorKind> {
+        self.0.read(buf).await.map_err(|_| io07::ErrorKind::Other)
+    }
+}
+
+impl<S: Write> io07::Write for Io<S> {
+    async fn write(&mut self, buf: &[u8]) -> Result<usize, io07::ErrorKind> {
+        self.0.write(buf).await.map_err(|_| io07::ErrorKind::Other)
+    }
+
+    async fn flush(&mut self) -> Result<(), io07::ErrorKind> {
+        self.0.flush().await.map_err(|_| io07::ErrorKind::Other)
+    }
+}
+
+/// The hardware RNG. It is a true random source while the radio is running,
+/// which it is whenever there is a connection to secure.
+struct TlsRng(Rng);
+
+impl RngCore for TlsRng {
+    fn next_u32(&mut self) -> u32 {
+        self.0.random()
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        (self.0.random() as u64) << 32 | self.0.random() as u64
+    }
+
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        self.0.read(dest);
+    }
+
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand_core::Error> {
+        self.fill_bytes(dest);
+        Ok(())
+    }
+}
+
+impl CryptoRng for TlsRng {}
+
+struct PinnedProvider {
+    rng: TlsRng,
+    verifier: PinVerifier,
+}
+
+impl CryptoProvider for PinnedProvider {
+    type CipherSuite = Aes128GcmSha256;
+    type Signature = &'static [u8];
+
+    fn rng(&mut self) -> impl CryptoRngCore {
+        &mut self.rng
+    }
+
+    fn verifier(&mut self) -> Result<&mut impl TlsVerifier<Self::CipherSuite>, TlsError> {
+        Ok(&mut self.verifier)
+    }
+}
+
+struct PinVerifier {
+    pin: Pin<'static>,
+    /// The host the leaf certificate has to be issued for.
+    host: Option<heapless::String<MAX_HOST_LEN>>,
+    /// The key of the accepted leaf certificate and the transcript hash up to
+    /// the certificate, for checking the CertificateVerify that follows.
+    leaf: Option<(VerifyingKey, [u8; 32])>,
+}
+
+impl TlsVerifier<Aes128GcmSha256> for PinVerifier {
+    fn set_hostname_verification(&mut self, hostname: &str) -> Result<(), TlsError> {
+        let host = heapless::String::try_from(hostname).map_err(|_| TlsError::InsufficientSpace)?;
+        self.host = Some(host);
+        Ok(())
+    }
+
+    fn verify_certificate(
+        &mut self,
+        transcript: &Sha256,
+        cert: CertificateRef,
+    ) -> Result<(), TlsError> {
+        // always set, the session is opened with the server name
+        let host = self.host.as_deref().ok_or(TlsError::InvalidCertificate)?;
+        let mut chain = heapless::Vec::<&[u8], 16>::new();
+        for entry in cert.entries.iter() {
+            if let CertificateEntryRef::X509(der) = entry {
+                // cannot fail, there are at most as many entries
+                let _ = chain.push(der);
+            }
+        }
+
+        let leaf = verify_chain(&self.pin, host, &chain, is_signed_by).map_err(|e| {
+            warn!("server certificate rejected: {:?}", e);
+            TlsError::InvalidCertificate
+        })?;
+        let key = p256_key(&leaf).ok_or_else(|| {
+            warn!("server key is not a P-256 key");
+            TlsError::InvalidCertificate
+        })?;
+        self.leaf = Some((key, transcript.clone().finalize().into()));
+        Ok(())
+    }
+
+    fn verify_signature(&mut self, verify: CertificateVerifyRef) -> Result<(), TlsError> {
+        let (key, transcript) = self.leaf.take().ok_or(TlsError::InvalidCertificate)?;
+        if verify.signature_scheme != SignatureScheme::EcdsaSecp256r1Sha256 {
+            return Err(TlsError::InvalidSignatureScheme);
+        }
+        let signature =
+            Signature::from_der(verify.signature).map_err(|_| TlsError::InvalidSignature)?;
+
+        let mut message = [0x20; 64 + CERTIFICATE_VERIFY_CONTEXT.len() + 32];
+        message[64..64 + CERTIFICATE_VERIFY_CONTEXT.len()]
+            .copy_from_slice(CERTIFICATE_VERIFY_CONTEXT);
+        message[64 + CERTIFICATE_VERIFY_CONTEXT.len()..].copy_from_slice(&transcript);
+        key.verify(&message, &signature)
+            .map_err(|_| TlsError::InvalidSignature)
+    }
+}
+
+/// Whether the key of `issuer` made the signature of `certificate`.
+fn is_signed_by(certificate: &x509::Certificate, issuer: &x509::Certificate) -> bool {
+    if certificate.signature_algorithm != oid::ECDSA_WITH_SHA256 {
+        return false;
+    }
+    let (Some(key), Ok(signature)) = (p256_key(issuer), Signature::from_der(certificate.signature))
+    else {
+        return false;
+    };
+    key.verify(certificate.tbs, &signature).is_ok()
+}
+
+fn p256_key(certificate: &x509::Certificate) -> Option<VerifyingKey> {
+    if certificate.key_algorithm != oid::EC_PUBLIC_KEY
+        || certificate.key_curve != Some(oid::PRIME256V1)
+    {
+        return None;
+    }
+    VerifyingKey::from_sec1_bytes(certificate.public_key).ok()
+}
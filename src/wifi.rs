@@ -1,17 +1,31 @@
 use crate::{
+    clock,
     config::{
-        remote::{REMOTE_DEV_SECRET, REMOTE_ENDPOINT, REMOTE_ENDPOINT_STR, RESULT_RESOURCE},
+        publish::{IDLE_TIMEOUT_SECS, REQUEST_TIMEOUT_SECS},
+        remote::{DNS_TTL_SECS, REMOTE_URL, TIME_RESOURCE, TLS_PIN},
         wifi::{PASSWORD, SSID},
     },
-    driver::sensor::SessionResult,
-    mk_static,
+    credentials,
+    driver::sensor::{DuelResult, PulseTrace, SessionResult},
+    factory, mk_static,
+    tls::{SessionError, Tls, TlsSettings},
 };
-use alloc::format;
-use defmt::{debug, error, info};
+use alloc::{boxed::Box, format, string::String};
+use core::{
+    cell::{Cell, RefCell},
+    fmt::Write as _,
+};
+use defmt::{debug, error, info, warn, Format};
 use embassy_executor::Spawner;
-use embassy_net::{tcp::TcpSocket, Runner, Stack, StackResources};
-use embassy_time::{Duration, Timer};
-use embedded_io_async::Write as _;
+use embassy_net::{
+    dns::{self, DnsQueryType},
+    tcp::{self, ConnectError, State, TcpSocket},
+    IpAddress, IpEndpoint, Runner, Stack, StackResources,
+};
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex};
+use embassy_time::{with_timeout, Duration, Instant, Timer};
+use embedded_io_async::{ErrorType, Read, Write};
+use embedded_tls::TlsError;
 use esp_hal::{
     peripherals::{self},
     rng::Rng,
@@ -23,6 +37,18 @@ use esp_wifi::{
     },
     EspWifiController,
 };
+use trichter_core::{
+    connection::{Connection, ConnectionStats},
+    duel::SideResult,
+    endpoint::{AddressCache, Scheme, Url, UrlError},
+    foam::FlowKind,
+    http::{parse_response, response_len, HttpError, HttpResponse},
+    session::{OverrunReason, SessionOutcome},
+    x509::Pin,
+};
+
+/// Responses of the backend are small, anything larger is rejected.
+pub const RESPONSE_BUFFER_LEN: usize = 2048;
 
 pub struct WifiManager<'d> {
     interfaces: Interfaces<'d>,
@@ -59,12 +85,16 @@ impl WifiManager<'static> {
         let (stack, runner) = embassy_net::new(
             self.interfaces.sta,
             dhcp_config,
-            mk_static!(StackResources<3>, StackResources::<3>::new()),
+            mk_static!(StackResources<5>, StackResources::<5>::new()),
             seed,
         );
 
-        spawner.spawn(connection(self.wifi_controller)).ok();
-        spawner.spawn(net_task(runner)).ok();
+        spawner
+            .spawn(connection(self.wifi_controller))
+            .expect("connection task fits into the task arena");
+        spawner
+            .spawn(net_task(runner))
+            .expect("net task fits into the task arena");
 
         loop {
             if stack.is_link_up() {
@@ -87,106 +117,816 @@ impl WifiManager<'static> {
     }
 }
 
+/// Why `REMOTE_URL` or `BROKER_URL` cannot be used.
+#[derive(Debug, Clone, Copy, Format)]
+pub enum EndpointError {
+    Url(UrlError),
+    /// A valid URL, but for the other transport.
+    Scheme(Scheme),
+    /// `https://` or `mqtts://` without a TLS pin, there would be nothing to
+    /// check the server against.
+    NoPin,
+}
+
+/// A server from the configuration together with how it is secured.
+#[derive(Debug, Clone, Copy)]
+pub struct Endpoint {
+    pub url: Url<'static>,
+    /// `None` for plain HTTP and MQTT.
+    pub tls: Option<TlsSettings>,
+}
+
+impl Endpoint {
+    /// Checks that `url` is valid, has one of `schemes` and is pinned if
+    /// it is secure.
+    pub fn new(
+        url: &'static str,
+        pin: Option<Pin<'static>>,
+        schemes: [Scheme; 2],
+    ) -> Result<Self, EndpointError> {
+        let url = Url::parse(url).map_err(EndpointError::Url)?;
+        if !schemes.contains(&url.scheme) {
+            return Err(EndpointError::Scheme(url.scheme));
+        }
+        let tls = match (url.scheme.is_secure(), pin) {
+            (false, _) => None,
+            (true, Some(pin)) => Some(TlsSettings {
+                server_name: url.host,
+                pin,
+            }),
+            (true, None) => return Err(EndpointError::NoPin),
+        };
+        Ok(Self { url, tls })
+    }
+
+    /// Allocates what is needed to talk TLS to the server, if anything.
+    pub fn tls(&self, rng: Rng) -> Option<Tls> {
+        self.tls.map(|settings| Tls::new(settings, rng))
+    }
+}
+
+/// The backend as configured in `REMOTE_URL` and `TLS_PIN`.
+pub fn remote_endpoint() -> Result<Endpoint, EndpointError> {
+    Endpoint::new(REMOTE_URL, TLS_PIN, [Scheme::Http, Scheme::Https])
+}
+
+/// Logs what is wrong with the configured endpoints, once at boot.
+///
+/// The tasks do not panic over a broken configuration, they just cannot
+/// reach the server: results stay queued and the management interface keeps
+/// working, so the device can be updated.
+pub fn check_endpoints() {
+    if let Err(e) = remote_endpoint() {
+        error!(
+            "REMOTE_URL cannot be used, nothing is sent to the backend: {:?}",
+            e
+        );
+    }
+    if let Err(e) = crate::mqtt::broker_endpoint() {
+        error!(
+            "BROKER_URL cannot be used, nothing is sent to the broker: {:?}",
+            e
+        );
+    }
+}
+
+/// Addresses shared by all resolvers of the same host.
+pub type SharedAddresses = Mutex<CriticalSectionRawMutex, RefCell<AddressCache<IpAddress>>>;
+
+/// The backend's address, shared by the publish and the telemetry task.
+static REMOTE_ADDRESSES: SharedAddresses =
+    Mutex::new(RefCell::new(AddressCache::new(DNS_TTL_SECS * 1_000_000)));
+
+/// Resolves the host of a [`Url`], the address is cached for
+/// `DNS_TTL_SECS` in `addresses`, which all resolvers of the host share.
+pub struct Resolver<'a> {
+    stack: Stack<'a>,
+    url: Url<'a>,
+    addresses: &'a SharedAddresses,
+}
+
+impl<'a> Resolver<'a> {
+    pub fn new(stack: Stack<'a>, url: Url<'a>, addresses: &'a SharedAddresses) -> Self {
+        Self {
+            stack,
+            url,
+            addresses,
+        }
+    }
+
+    pub fn url(&self) -> &Url<'a> {
+        &self.url
+    }
+
+    /// The address and port of the host, resolved again once the cached
+    /// address expired.
+    ///
+    /// If resolving fails the last known address is tried anyway, a flaky
+    /// DNS server should not keep results from being published.
+    pub async fn resolve(&mut self) -> Result<IpEndpoint, dns::Error> {
+        if let Some(address) = self.url.ipv4() {
+            return Ok(IpEndpoint::new(IpAddress::Ipv4(address), self.url.port));
+        }
+        let now_us = Instant::now().as_micros();
+        if let Some(address) = self.addresses.lock(|cache| cache.borrow().fresh(now_us)) {
+            return Ok(IpEndpoint::new(address, self.url.port));
+        }
+
+        let result = self
+            .stack
+            .dns_query(self.url.host, DnsQueryType::A)
+            .await
+            .and_then(|addresses| addresses.first().copied().ok_or(dns::Error::Failed));
+        let address = match (result, self.addresses.lock(|cache| cache.borrow().last())) {
+            (Ok(address), _) => {
+                debug!("resolved {} to {}", self.url.host, address);
+                self.addresses
+                    .lock(|cache| cache.borrow_mut().store(address, now_us));
+                address
+            }
+            (Err(e), Some(address)) => {
+                warn!(
+                    "failed to resolve {}, trying {} again: {:?}",
+                    self.url.host, address, e
+                );
+                address
+            }
+            (Err(e), None) => return Err(e),
+        };
+        Ok(IpEndpoint::new(address, self.url.port))
+    }
+
+    /// Forces the host to be resolved again, e.g. because connecting to its
+    /// address failed and it may have moved.
+    pub fn expire(&mut self) {
+        self.addresses.lock(|cache| cache.borrow_mut().expire());
+    }
+}
+
+/// An HTTP/1.1 client for one backend, optionally over TLS.
+///
+/// Plain connections are kept alive between requests. Over TLS every request
+/// gets a session of its own, the record buffers are shared and cannot be
+/// held by a session between requests.
 pub struct HttpClient<'a> {
     socket: TcpSocket<'a>,
+    resolver: Resolver<'a>,
+    connection: Connection,
+    tls: Option<Tls>,
 }
 
 impl<'a> HttpClient<'a> {
-    pub async fn connect(
+    /// Creates a client for the backend without connecting, the first
+    /// request resolves the host and connects.
+    pub fn new(
         stack: Stack<'a>,
-        remote: (embassy_net::Ipv4Address, u16),
-    ) -> Result<Self, embassy_net::tcp::ConnectError> {
-        static mut RX: [u8; 4096] = [0; 4096];
-        static mut TX: [u8; 4096] = [0; 4096];
+        url: Url<'a>,
+        rx_buffer: &'a mut [u8],
+        tx_buffer: &'a mut [u8],
+    ) -> Self {
+        let mut socket = TcpSocket::new(stack, rx_buffer, tx_buffer);
+        socket.set_timeout(Some(Duration::from_secs(10)));
 
-        let rx_buffer = unsafe { &mut RX };
-        let tx_buffer = unsafe { &mut TX };
+        Self {
+            socket,
+            resolver: Resolver::new(stack, url, &REMOTE_ADDRESSES),
+            connection: Connection::new(IDLE_TIMEOUT_SECS * 1_000_000),
+            tls: None,
+        }
+    }
 
-        let mut socket = TcpSocket::new(stack, rx_buffer, tx_buffer);
+    /// Secures requests with `tls`, `None` keeps them in plain HTTP.
+    pub fn with_tls(mut self, tls: Option<Tls>) -> Self {
+        self.tls = tls;
+        self
+    }
 
-        socket.set_timeout(Some(Duration::from_secs(10)));
-        socket.connect(remote).await?;
+    pub fn url(&self) -> &Url<'a> {
+        self.resolver.url()
+    }
 
-        Ok(Self { socket })
+    pub fn stats(&self) -> ConnectionStats {
+        self.connection.stats()
     }
 
-    pub async fn request(&mut self, req: &str) -> Result<(), embassy_net::tcp::Error> {
-        use embedded_io_async::Write;
-        self.socket.write_all(req.as_bytes()).await?;
+    /// Drops the current connection, if any, and connects again.
+    async fn reconnect(&mut self) -> Result<(), RequestError> {
+        self.socket.abort();
+        let remote = match self.resolver.resolve().await {
+            Ok(remote) => remote,
+            Err(e) => {
+                self.connection.failed();
+                return Err(RequestError::Dns(e));
+            }
+        };
+        match self.socket.connect(remote).await {
+            Ok(()) => {
+                debug!("connected to {}", remote);
+                self.connection.connected(Instant::now().as_micros());
+                Ok(())
+            }
+            Err(e) => {
+                self.resolver.expire();
+                self.connection.failed();
+                Err(RequestError::Connect(e))
+            }
+        }
+    }
 
-        let mut buf = [0; 1024];
+    /// Drops the connection without waiting for the remote, e.g. after a
+    /// request was cancelled and left it in an unknown state.
+    pub fn abort(&mut self) {
+        self.socket.abort();
+        self.connection.failed();
+    }
 
-        let mut n = 0;
-        loop {
-            n += match self.socket.read(&mut buf).await {
-                Ok(0) => {
-                    info!("read EOF");
-                    break;
-                }
-                Ok(n) => n,
-                Err(e) => {
-                    error!("failed to read response: {:?}", e);
-                    break;
-                }
+    /// Posts the JSON `body` to `resource` below the base path of the
+    /// backend, signed with the credentials of the device. Only a 2xx status
+    /// counts as success, the response is read into `buf`.
+    ///
+    /// The clock is synced first if it is not. Anybody on the way can answer
+    /// with a 401, so it only makes the device sync the clock again before
+    /// the next attempt. Whether the backend still accepts the credentials
+    /// is up to [`sync_clock`](Self::sync_clock).
+    pub async fn post_signed(
+        &mut self,
+        resource: &str,
+        body: &str,
+        rng: &mut Rng,
+        buf: &mut [u8],
+    ) -> Result<(), RequestError> {
+        if clock::unix_secs().is_none() {
+            self.sync_clock(rng, buf).await?;
+        }
+        let request = signed_post(self.url(), resource, body, rng)?;
+        let status = self.request(request.as_str(), buf).await?.status;
+        match status {
+            200..=299 => Ok(()),
+            401 => {
+                warn!("request was rejected as unauthorized, syncing the clock again");
+                clock::unsync();
+                Err(RequestError::Client(status))
             }
+            _ => Err(RequestError::status(status)),
         }
+    }
 
-        info!("recieved {} bytes:", n);
-        info!("Response: {}", core::str::from_utf8(&buf[..n]).unwrap());
+    /// Asks the backend for the time and sets the clock.
+    ///
+    /// The request carries the device ID and a random nonce. The backend
+    /// answers with its time in `X-Trichter-Timestamp` and signs it like a
+    /// request of the device to the time resource with that nonce and an
+    /// empty body, see [`trichter_core::credentials`]. If the backend no
+    /// longer accepts the credentials, it answers with a 401 and proves it
+    /// with the revocation for the nonce in `X-Trichter-Revocation`, see
+    /// [`trichter_core::factory`]. Only then the credentials are revoked,
+    /// any other 401 is retried like a server error.
+    pub async fn sync_clock(&mut self, rng: &mut Rng, buf: &mut [u8]) -> Result<(), RequestError> {
+        let credentials = credentials::current().ok_or(RequestError::Unprovisioned)?;
+        let path = format!("{}{}", self.url().base_path, TIME_RESOURCE);
+        let mut nonce = [0; 16];
+        rng.read(&mut nonce);
+        let nonce = hex(&nonce);
+        let request = format!(
+            "\
+            GET {} HTTP/1.1\r\n\
+            Host: {}\r\n\
+            Connection: keep-alive\r\n\
+            X-Trichter-Device: {}\r\n\
+            X-Trichter-Nonce: {}\r\n\
+            \r\n\
+            ",
+            path,
+            self.url().authority,
+            credentials.device_id(),
+            nonce,
+        );
 
+        let response = self.request(request.as_str(), buf).await?;
+        if response.status == 401 {
+            let revoked = factory::secret()
+                .zip(response.header("X-Trichter-Revocation"))
+                .is_some_and(|(secret, revocation)| {
+                    secret.verifies_revocation(credentials.device_id(), &nonce, revocation)
+                });
+            if revoked {
+                error!("backend revoked the credentials, provision the device again");
+                credentials::revoke();
+            } else {
+                warn!("time request was rejected without a valid revocation");
+            }
+        }
+        if !response.is_success() {
+            return Err(RequestError::status(response.status));
+        }
+        let timestamp = response
+            .header("X-Trichter-Timestamp")
+            .and_then(|timestamp| timestamp.trim().parse().ok());
+        let signature = response.header("X-Trichter-Signature");
+        let (Some(timestamp), Some(signature)) = (timestamp, signature) else {
+            warn!("backend did not sign its time");
+            return Err(RequestError::Unsynced);
+        };
+        if !credentials.verify(timestamp, &nonce, &path, b"", signature) {
+            warn!("time with an invalid signature, not syncing the clock");
+            return Err(RequestError::Unsynced);
+        }
+        clock::sync(timestamp);
+        info!("clock synced to {}", timestamp);
         Ok(())
     }
+
+    /// Sends `req` and reads the response into `buf`.
+    ///
+    /// An idle connection is reused if the server allows it and the idle
+    /// timeout did not expire, otherwise the client connects first. If the
+    /// server closed a reused connection in the meantime, the request is sent
+    /// once more on a new one. Fails if the response does not fit into `buf`,
+    /// the status is checked by the caller.
+    pub async fn request<'b>(
+        &mut self,
+        req: &str,
+        buf: &'b mut [u8],
+    ) -> Result<HttpResponse<'b>, RequestError> {
+        let now_us = Instant::now().as_micros();
+        if self.socket.state() != State::Established || !self.connection.is_reusable(now_us) {
+            self.reconnect().await?;
+        }
+
+        let reused = self.connection.request_started(now_us);
+        let mut result = self.exchange(req, buf).await;
+        if reused && matches!(result, Err(RequestError::Io(_) | RequestError::Closed)) {
+            debug!("reused connection was closed, reconnecting");
+            self.reconnect().await?;
+            self.connection.request_started(Instant::now().as_micros());
+            result = self.exchange(req, buf).await;
+        }
+        let len = match result {
+            Ok(len) => len,
+            Err(e) => {
+                self.abort();
+                return Err(e);
+            }
+        };
+
+        let response = match parse_response(&mut buf[..len]) {
+            Ok(response) => response,
+            Err(e) => {
+                self.abort();
+                return Err(RequestError::Http(e));
+            }
+        };
+        info!(
+            "Response: {} {} ({} bytes)",
+            response.status,
+            response.reason,
+            response.body.len()
+        );
+        if let Some(body) = response.body_str() {
+            debug!("Response body: {}", body);
+        }
+
+        // the TLS session ended with the request
+        let keep_alive = response.keep_alive() && self.tls.is_none();
+        self.connection.response_received(
+            Instant::now().as_micros(),
+            keep_alive,
+            response.keep_alive_timeout_secs(),
+        );
+        if !keep_alive {
+            self.socket.close();
+        }
+        Ok(response)
+    }
+
+    /// Sends `req` on the connected socket, in a new TLS session if enabled,
+    /// and returns the length of the response.
+    async fn exchange(&mut self, req: &str, buf: &mut [u8]) -> Result<usize, RequestError> {
+        let Some(tls) = &mut self.tls else {
+            return exchange(&mut self.socket, req, buf).await;
+        };
+        let socket = &mut self.socket;
+        // the handshake state is large, keep it out of the task arena
+        Box::pin(async move {
+            let mut session = tls.open(socket).await?;
+            let result = exchange(&mut session, req, buf).await;
+            if let Err(e) = session.close().await {
+                debug!("failed to close TLS session: {:?}", e);
+            }
+            result
+        })
+        .await
+    }
+}
+
+/// Writes `req` and reads until the response is complete, returns its length.
+async fn exchange<S>(io: &mut S, req: &str, buf: &mut [u8]) -> Result<usize, RequestError>
+where
+    S: Read + Write,
+    RequestError: From<<S as ErrorType>::Error>,
+{
+    io.write_all(req.as_bytes()).await?;
+    io.flush().await?;
+
+    let mut len = 0;
+    loop {
+        if let Some(complete) = response_len(&buf[..len]).map_err(RequestError::Http)? {
+            return Ok(complete);
+        }
+        if len == buf.len() {
+            return Err(RequestError::TooLarge);
+        }
+        match io.read(&mut buf[len..]).await? {
+            0 if len == 0 => return Err(RequestError::Closed),
+            0 => {
+                debug!("read EOF");
+                return Ok(len);
+            }
+            n => len += n,
+        }
+    }
+}
+
+#[derive(Debug, Format)]
+pub enum RequestError {
+    /// The host could not be resolved.
+    Dns(dns::Error),
+    Connect(ConnectError),
+    /// No complete response within `REQUEST_TIMEOUT_SECS`.
+    Timeout,
+    Io(tcp::Error),
+    /// The TLS handshake failed or the session broke, including certificates
+    /// that do not match the pin.
+    Tls(TlsError),
+    /// The server closed the connection without responding.
+    Closed,
+    Http(HttpError),
+    /// The response did not fit into the buffer.
+    TooLarge,
+    /// The backend failed to handle the request (5xx).
+    Server(u16),
+    /// The backend rejected the request (4xx and anything else unexpected).
+    Client(u16),
+    /// The device has no credentials to sign the request with, not yet or
+    /// not any more since the backend revoked them.
+    Unprovisioned,
+    /// The clock is not synced, the backend did not tell a time signed with
+    /// the device's key.
+    Unsynced,
+}
+
+impl From<tcp::Error> for RequestError {
+    fn from(e: tcp::Error) -> Self {
+        Self::Io(e)
+    }
+}
+
+impl From<TlsError> for RequestError {
+    fn from(e: TlsError) -> Self {
+        Self::Tls(e)
+    }
+}
+
+impl From<SessionError> for RequestError {
+    fn from(e: SessionError) -> Self {
+        Self::Tls(e.0)
+    }
+}
+
+impl RequestError {
+    /// Classifies an unsuccessful status code.
+    pub fn status(status: u16) -> Self {
+        match status {
+            500..=599 => Self::Server(status),
+            _ => Self::Client(status),
+        }
+    }
+
+    /// Whether sending the same request again later may succeed.
+    ///
+    /// Network trouble, timeouts, missing or truncated responses, server
+    /// errors, 408 and 429 are transient. So are TLS failures, which are
+    /// never caused by the request, missing credentials, an unsynced clock
+    /// and a 401, after which [`HttpClient::post_signed`] syncs the clock
+    /// again. Malformed or oversized responses and other client errors will
+    /// not go away by retrying.
+    pub fn is_transient(&self) -> bool {
+        match self {
+            Self::Dns(_)
+            | Self::Connect(_)
+            | Self::Timeout
+            | Self::Io(_)
+            | Self::Tls(_)
+            | Self::Closed
+            | Self::Server(_)
+            | Self::Unprovisioned
+            | Self::Unsynced => true,
+            Self::Http(e) => *e == HttpError::Incomplete,
+            Self::TooLarge => false,
+            Self::Client(status) => matches!(status, 401 | 408 | 429),
+        }
+    }
+}
+
+/// A POST of the JSON `body` to `resource` below the base path of `url`,
+/// signed with the credentials of the device (see
+/// [`trichter_core::credentials`]). Nothing is signed before the clock is
+/// synced.
+fn signed_post(
+    url: &Url,
+    resource: &str,
+    body: &str,
+    rng: &mut Rng,
+) -> Result<String, RequestError> {
+    let credentials = credentials::current().ok_or(RequestError::Unprovisioned)?;
+    let path = format!("{}{}", url.base_path, resource);
+    let timestamp = clock::unix_secs().ok_or(RequestError::Unsynced)?;
+    let mut nonce = [0; 16];
+    rng.read(&mut nonce);
+    let nonce = hex(&nonce);
+    let signature = credentials.sign(timestamp, &nonce, &path, body.as_bytes());
+
+    Ok(format!(
+        "\
+        POST {} HTTP/1.1\r\n\
+        Host: {}\r\n\
+        Content-Type: application/json\r\n\
+        Content-Length: {}\r\n\
+        Connection: keep-alive\r\n\
+        X-Trichter-Device: {}\r\n\
+        X-Trichter-Timestamp: {}\r\n\
+        X-Trichter-Nonce: {}\r\n\
+        X-Trichter-Signature: {}\r\n\
+        \r\n\
+        {}\
+        ",
+        path,
+        url.authority,
+        body.len(),
+        credentials.device_id(),
+        timestamp,
+        nonce,
+        hex(&signature),
+        body
+    ))
+}
+
+fn hex(bytes: &[u8]) -> String {
+    let mut hex = String::with_capacity(bytes.len() * 2);
+    for byte in bytes {
+        let _ = write!(hex, "{:02x}", byte);
+    }
+    hex
+}
+
+static CONNECTION: Mutex<CriticalSectionRawMutex, Cell<ConnectionStats>> =
+    Mutex::new(Cell::new(ConnectionStats::new()));
+
+/// State of the connection results are published on, as of the last request.
+pub fn result_connection() -> ConnectionStats {
+    CONNECTION.lock(Cell::get)
 }
 
 pub struct SessionResultClient<'a> {
     http_client: HttpClient<'a>,
+    response: &'a mut [u8],
+    rng: Rng,
 }
 
 impl<'a> SessionResultClient<'a> {
-    pub async fn new(stack: Stack<'a>) -> Result<Self, embassy_net::tcp::ConnectError> {
-        let http_client = HttpClient::connect(stack, REMOTE_ENDPOINT).await?;
+    /// Creates the client for `remote`, it connects on the first publish.
+    ///
+    /// Must only be called once, the socket buffers are static.
+    pub fn new(stack: Stack<'a>, remote: Endpoint, rng: Rng) -> Self {
+        let rx_buffer = mk_static!([u8; 4096], [0; 4096]);
+        let tx_buffer = mk_static!([u8; 4096], [0; 4096]);
+        let response = mk_static!([u8; RESPONSE_BUFFER_LEN], [0; RESPONSE_BUFFER_LEN]);
+        let http_client =
+            HttpClient::new(stack, remote.url, rx_buffer, tx_buffer).with_tls(remote.tls(rng));
 
-        Ok(Self { http_client })
+        Self {
+            http_client,
+            response,
+            rng,
+        }
     }
 
-    pub async fn publish_result(
-        &mut self,
-        result: SessionResult,
-    ) -> Result<(), embassy_net::tcp::Error> {
-        let body = format!(
-            "\
-            {{\
-                \"rate\": {},\
-                \"duration\": {},\
-                \"volume\": {}\
-            }}\
-            ",
-            result.rate,
-            (result.duration.as_millis() as f32) / 1000.0,
-            result.volume,
+    /// Posts a JSON `body` to `resource` below the base path of the backend,
+    /// see [`HttpClient::post_signed`].
+    pub async fn post(&mut self, resource: &str, body: &str) -> Result<(), RequestError> {
+        let timeout = Duration::from_secs(REQUEST_TIMEOUT_SECS);
+        let result = with_timeout(
+            timeout,
+            self.http_client
+                .post_signed(resource, body, &mut self.rng, self.response),
+        )
+        .await;
+        if result.is_err() {
+            // the request was cancelled midway, start over next time
+            self.http_client.abort();
+        }
+        CONNECTION.lock(|stats| stats.set(self.http_client.stats()));
+        result.unwrap_or(Err(RequestError::Timeout))
+    }
+}
+
+pub fn trace_body(channel: u8, trace: &PulseTrace) -> String {
+    format!(
+        "\
+        {{\
+            \"channel\": {},\
+            \"first_us\": {},\
+            \"pulses\": {},\
+            \"dropped\": {},\
+            \"encoding\": \"leb128+base64\",\
+            \"deltas_us\": \"{}\"\
+        }}\
+        ",
+        channel, trace.first_us, trace.pulses, trace.dropped, trace.deltas,
+    )
+}
+
+pub fn duel_body(duel: &DuelResult) -> String {
+    let mut sides = String::new();
+    for (channel, (side, session)) in duel
+        .outcome
+        .sides
+        .iter()
+        .zip(duel.sessions.iter())
+        .enumerate()
+    {
+        if channel > 0 {
+            sides.push(',');
+        }
+        let (status, finish_ms, early_ms) = match *side {
+            SideResult::Pending => ("pending", None, None),
+            SideResult::FalseStart { at_us } => (
+                "false_start",
+                None,
+                Some(duel.go_us.saturating_sub(at_us) / 1_000),
+            ),
+            SideResult::Finished { time_us } => ("finished", Some(time_us / 1_000), None),
+            SideResult::DidNotFinish => ("did_not_finish", None, None),
+        };
+        let session = session
+            .as_ref()
+            .map(result_body)
+            .unwrap_or_else(|| String::from("null"));
+        let _ = write!(
+            sides,
+            "{{\
+                \"channel\": {},\
+                \"status\": \"{}\",\
+                \"finish_ms\": {},\
+                \"early_ms\": {},\
+                \"session\": {}\
+            }}",
+            channel,
+            status,
+            json_option(finish_ms),
+            json_option(early_ms),
+            session,
         );
+    }
 
-        let request = format!(
-            "\
-            POST {} HTTP/1.1\r\n\
-            Host: {} \r\n\
-            Authorization: Basic {}\r\n\
-            Content-Type: application/json\r\n\
-            Content-Length: {}\r\n\
-            \r\n\
-            {}\
-            ",
-            RESULT_RESOURCE,
-            REMOTE_ENDPOINT_STR,
-            REMOTE_DEV_SECRET,
-            body.len(),
-            body
+    format!(
+        "\
+        {{\
+            \"winner\": {},\
+            \"margin_ms\": {},\
+            \"sides\": [{}]\
+        }}\
+        ",
+        json_option(duel.outcome.winner),
+        json_option(duel.outcome.margin_us.map(|margin| margin / 1_000)),
+        sides,
+    )
+}
+
+pub fn result_body(result: &SessionResult) -> String {
+    let mut profile = String::new();
+    for (i, pulses) in result.profile.buckets().iter().enumerate() {
+        if i > 0 {
+            profile.push(',');
+        }
+        let _ = write!(profile, "{}", pulses);
+    }
+
+    let mut segments = String::new();
+    for (i, segment) in result.foam.segments().iter().enumerate() {
+        if i > 0 {
+            segments.push(',');
+        }
+        let _ = write!(
+            segments,
+            "{{\
+                \"kind\": \"{}\",\
+                \"start_ms\": {},\
+                \"end_ms\": {},\
+                \"pulses\": {}\
+            }}",
+            match segment.kind {
+                FlowKind::Liquid => "liquid",
+                FlowKind::Foam => "foam",
+            },
+            segment.start_us / 1_000,
+            segment.end_us / 1_000,
+            segment.pulses,
         );
+    }
 
-        info!("Would send request: {:?}", request);
-        self.http_client.request(request.as_str()).await?;
+    let mut gulps = String::new();
+    for (i, gulp) in result.gulps.iter().enumerate() {
+        if i > 0 {
+            gulps.push(',');
+        }
+        let _ = write!(
+            gulps,
+            "{{\
+                \"start_ms\": {},\
+                \"end_ms\": {},\
+                \"pulses\": {},\
+                \"volume_ml\": {}\
+            }}",
+            gulp.start.as_millis(),
+            gulp.end.as_millis(),
+            gulp.pulses,
+            gulp.volume_ml,
+        );
+    }
 
-        Ok(())
+    format!(
+        "\
+        {{\
+            \"channel\": {},\
+            \"valid\": {},\
+            \"overrun_reason\": {},\
+            \"rate\": {},\
+            \"duration\": {},\
+            \"volume\": {},\
+            \"duration_ms\": {},\
+            \"volume_ml\": {},\
+            \"peak_rate\": {},\
+            \"time_to_peak\": {},\
+            \"longest_pause\": {},\
+            \"glitches\": {},\
+            \"profile\": {{\
+                \"bucket_ms\": {},\
+                \"truncated\": {},\
+                \"pulses\": [{}]\
+            }},\
+            \"foam\": {{\
+                \"liquid_pulses\": {},\
+                \"foam_pulses\": {},\
+                \"confidence\": {},\
+                \"variation\": {},\
+                \"irregularity\": {},\
+                \"truncated\": {},\
+                \"segments\": [{}]\
+            }},\
+            \"gulps\": {{\
+                \"count\": {},\
+                \"truncated\": {},\
+                \"segments\": [{}]\
+            }}\
+        }}\
+        ",
+        result.channel,
+        result.is_valid(),
+        match result.outcome {
+            SessionOutcome::Completed => "null",
+            SessionOutcome::Overrun(OverrunReason::MaxDuration) => "\"max_duration\"",
+            SessionOutcome::Overrun(OverrunReason::ContinuousFlow) => "\"continuous_flow\"",
+        },
+        result.rate,
+        (result.duration_ms() as f32) / 1000.0,
+        result.volume(),
+        result.duration_ms(),
+        result.volume_ml,
+        result.peak_rate,
+        (result.time_to_peak.as_millis() as f32) / 1000.0,
+        (result.longest_pause.as_millis() as f32) / 1000.0,
+        result.glitches,
+        result.profile.bucket_us() / 1_000,
+        result.profile.truncated(),
+        profile,
+        result.foam.liquid_pulses,
+        result.foam.foam_pulses,
+        result.foam.confidence_percent as f32 / 100.0,
+        result.foam.variation_percent as f32 / 100.0,
+        result.foam.irregularity_percent as f32 / 100.0,
+        result.foam.truncated(),
+        segments,
+        result.gulp_count,
+        result.gulp_count as usize > result.gulps.len(),
+        gulps,
+    )
+}
+
+pub(crate) fn json_option<T: core::fmt::Display>(value: Option<T>) -> String {
+    match value {
+        Some(value) => format!("{}", value),
+        None => String::from("null"),
     }
 }
 
@@ -0,0 +1,105 @@
+//! Bounded exponential backoff with jitter for retrying failed requests.
+//!
+//! The step doubles with every attempt up to `max_ms`. The actual delay is
+//! drawn from the upper half of the step ("equal jitter"), so devices that
+//! failed at the same moment do not retry in lockstep, but still wait at
+//! least half a step.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Backoff {
+    initial_ms: u64,
+    max_ms: u64,
+    max_attempts: u32,
+    attempts: u32,
+}
+
+impl Backoff {
+    pub const fn new(initial_ms: u64, max_ms: u64, max_attempts: u32) -> Self {
+        Self {
+            initial_ms,
+            max_ms,
+            max_attempts,
+            attempts: 0,
+        }
+    }
+
+    pub fn reset(&mut self) {
+        self.attempts = 0;
+    }
+
+    /// Retries handed out since the last reset.
+    pub fn attempts(&self) -> u32 {
+        self.attempts
+    }
+
+    /// Delay before the next retry based on `random`, `None` once all
+    /// `max_attempts` retries are used up.
+    pub fn next_delay_ms(&mut self, random: u32) -> Option<u64> {
+        if self.attempts >= self.max_attempts {
+            return None;
+        }
+        let step = 1u64
+            .checked_shl(self.attempts)
+            .map_or(u64::MAX, |factor| self.initial_ms.saturating_mul(factor))
+            .min(self.max_ms);
+        self.attempts += 1;
+
+        let half = step / 2;
+        Some(half + random as u64 % (step - half + 1))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn delays_grow_up_to_the_limit() {
+        let mut backoff = Backoff::new(1_000, 10_000, 6);
+        let max: Vec<_> = (0..6)
+            .map(|_| backoff.next_delay_ms(u32::MAX).unwrap())
+            .collect();
+        let mut backoff = Backoff::new(1_000, 10_000, 6);
+        let min: Vec<_> = (0..6).map(|_| backoff.next_delay_ms(0).unwrap()).collect();
+
+        assert_eq!(min, [500, 1_000, 2_000, 4_000, 5_000, 5_000]);
+        assert!(max
+            .iter()
+            .zip([1_000, 2_000, 4_000, 8_000, 10_000, 10_000])
+            .all(|(delay, step)| *delay <= step && *delay > step / 2));
+    }
+
+    #[test]
+    fn jitter_stays_within_the_step() {
+        let mut seed: u32 = 7;
+        for _ in 0..1_000 {
+            seed = seed.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+            let mut backoff = Backoff::new(1_000, 60_000, 3);
+            backoff.next_delay_ms(seed);
+            backoff.next_delay_ms(seed);
+            let delay = backoff.next_delay_ms(seed).unwrap();
+            assert!((2_000..=4_000).contains(&delay));
+        }
+    }
+
+    #[test]
+    fn attempts_are_bounded() {
+        let mut backoff = Backoff::new(1_000, 60_000, 2);
+        assert!(backoff.next_delay_ms(0).is_some());
+        assert!(backoff.next_delay_ms(0).is_some());
+        assert_eq!(backoff.next_delay_ms(0), None);
+        assert_eq!(backoff.attempts(), 2);
+
+        backoff.reset();
+        assert_eq!(backoff.next_delay_ms(0), Some(500));
+    }
+
+    #[test]
+    fn huge_attempt_counts_do_not_overflow() {
+        let mut backoff = Backoff::new(u64::MAX / 2, u64::MAX, 100);
+        for _ in 0..100 {
+            assert!(backoff.next_delay_ms(u32::MAX).is_some());
+        }
+    }
+}
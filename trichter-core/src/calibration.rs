@@ -0,0 +1,374 @@
+//! Conversion of pulse counts into volume and flow rate.
+//!
+//! Cheap hall sensors do not have a constant K-factor, the pulses per litre
+//! drift with the flow rate. A [`Calibration`] therefore holds a curve of
+//! pulses-per-litre values keyed by pulse frequency and interpolates linearly
+//! between them. Outside of the curve the nearest point is used.
+//!
+//! New points from a calibration run are [merged](Calibration::merge) into the
+//! curve, replacing the points measured at about the same frequency.
+
+use heapless::Vec;
+
+use crate::record::Persist;
+
+pub const MAX_CALIBRATION_POINTS: usize = 8;
+/// Points closer than this to a merged point, relative to its frequency, are
+/// replaced by it.
+pub const MERGE_DISTANCE: f32 = 0.1;
+
+/// K-factor from the sensor datasheet: `F = 6.6 * Q` with `Q` in L/min.
+pub const DATASHEET_PULSES_PER_LITRE: f32 = 6.6 * 60.0;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct CalibrationPoint {
+    pub frequency_hz: f32,
+    pub pulses_per_litre: f32,
+}
+
+impl CalibrationPoint {
+    pub fn new(frequency_hz: f32, pulses_per_litre: f32) -> Self {
+        Self {
+            frequency_hz,
+            pulses_per_litre,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum CalibrationError {
+    Empty,
+    TooManyPoints,
+    /// Frequencies must be finite, non negative and strictly ascending.
+    InvalidFrequency,
+    /// Pulses per litre must be finite and positive.
+    InvalidFactor,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Calibration {
+    points: Vec<CalibrationPoint, MAX_CALIBRATION_POINTS>,
+}
+
+impl Calibration {
+    pub fn new(points: &[CalibrationPoint]) -> Result<Self, CalibrationError> {
+        if points.is_empty() {
+            return Err(CalibrationError::Empty);
+        }
+
+        let mut previous: Option<f32> = None;
+        for point in points {
+            if !point.frequency_hz.is_finite()
+                || point.frequency_hz < 0.0
+                || previous.is_some_and(|f| point.frequency_hz <= f)
+            {
+                return Err(CalibrationError::InvalidFrequency);
+            }
+            if !point.pulses_per_litre.is_finite() || point.pulses_per_litre <= 0.0 {
+                return Err(CalibrationError::InvalidFactor);
+            }
+            previous = Some(point.frequency_hz);
+        }
+
+        let points = Vec::from_slice(points).map_err(|_| CalibrationError::TooManyPoints)?;
+        Ok(Self { points })
+    }
+
+    /// A flat curve with the same K-factor for every flow rate.
+    pub fn constant(pulses_per_litre: f32) -> Result<Self, CalibrationError> {
+        Self::new(&[CalibrationPoint::new(0.0, pulses_per_litre)])
+    }
+
+    pub fn points(&self) -> &[CalibrationPoint] {
+        &self.points
+    }
+
+    /// Adds `point` to the curve. Points within [`MERGE_DISTANCE`] of its
+    /// frequency are replaced, and if the curve is full the point nearest to
+    /// it makes room.
+    pub fn merge(&mut self, point: CalibrationPoint) -> Result<(), CalibrationError> {
+        // validates the point on its own
+        Self::new(&[point])?;
+
+        let f = point.frequency_hz;
+        self.points
+            .retain(|p| (p.frequency_hz - f).abs() > f * MERGE_DISTANCE);
+        if self.points.is_full() {
+            let nearest = (0..self.points.len())
+                .min_by(|&a, &b| {
+                    let distance = |i: usize| (self.points[i].frequency_hz - f).abs();
+                    distance(a).total_cmp(&distance(b))
+                })
+                .expect("a full curve has points");
+            self.points.remove(nearest);
+        }
+        let index = self.points.partition_point(|p| p.frequency_hz < f);
+        self.points
+            .insert(index, point)
+            .map_err(|_| CalibrationError::TooManyPoints)
+    }
+
+    /// Interpolated K-factor at a pulse frequency.
+    pub fn pulses_per_litre(&self, frequency_hz: f32) -> f32 {
+        let first = self.points[0];
+        if frequency_hz <= first.frequency_hz {
+            return first.pulses_per_litre;
+        }
+
+        for pair in self.points.windows(2) {
+            let (low, high) = (pair[0], pair[1]);
+            if frequency_hz <= high.frequency_hz {
+                let t = (frequency_hz - low.frequency_hz) / (high.frequency_hz - low.frequency_hz);
+                return low.pulses_per_litre + t * (high.pulses_per_litre - low.pulses_per_litre);
+            }
+        }
+
+        self.points[self.points.len() - 1].pulses_per_litre
+    }
+
+    /// K-factor at the mean frequency of `pulses` within `duration_us`, in
+    /// milli-pulses per litre.
+    pub fn milli_pulses_per_litre(&self, pulses: u32, duration_us: u64) -> u64 {
+        let k = self.pulses_per_litre(frequency(pulses, duration_us));
+        ((k * 1_000.0 + 0.5) as u64).max(1)
+    }
+
+    /// Volume in millilitres of `pulses` spread over `duration_us`, rounded
+    /// to the nearest millilitre.
+    ///
+    /// The K-factor is taken at the mean pulse frequency of the whole span,
+    /// everything after that is integer math.
+    pub fn volume_ml(&self, pulses: u32, duration_us: u64) -> u32 {
+        let k = self.milli_pulses_per_litre(pulses, duration_us);
+        ((pulses as u64 * 1_000_000 + k / 2) / k) as u32
+    }
+
+    /// Mean flow rate in L/min of `pulses` spread over `duration_us`.
+    pub fn flow_rate(&self, pulses: u32, duration_us: u64) -> f32 {
+        if duration_us == 0 {
+            return 0.0;
+        }
+        let k = self.pulses_per_litre(frequency(pulses, duration_us));
+        frequency(pulses, duration_us) / k * 60.0
+    }
+}
+
+impl Default for Calibration {
+    fn default() -> Self {
+        let mut points = Vec::new();
+        points
+            .push(CalibrationPoint::new(0.0, DATASHEET_PULSES_PER_LITRE))
+            .unwrap();
+        Self { points }
+    }
+}
+
+/// Pulse frequency in Hz, infinite for an empty span.
+pub fn frequency(pulses: u32, duration_us: u64) -> f32 {
+    if duration_us == 0 {
+        return f32::INFINITY;
+    }
+    pulses as f32 * 1_000_000.0 / duration_us as f32
+}
+
+impl Persist for Calibration {
+    const SLOT: u8 = 0;
+    const MAX_LEN: usize = 1 + MAX_CALIBRATION_POINTS * 8;
+
+    fn encode(&self, buf: &mut [u8]) -> Option<usize> {
+        let len = 1 + self.points.len() * 8;
+        if buf.len() < len {
+            return None;
+        }
+
+        buf[0] = self.points.len() as u8;
+        for (point, chunk) in self.points.iter().zip(buf[1..len].chunks_exact_mut(8)) {
+            chunk[..4].copy_from_slice(&point.frequency_hz.to_le_bytes());
+            chunk[4..].copy_from_slice(&point.pulses_per_litre.to_le_bytes());
+        }
+        Some(len)
+    }
+
+    fn decode(buf: &[u8]) -> Option<Self> {
+        let count = *buf.first()? as usize;
+        if count > MAX_CALIBRATION_POINTS || buf.len() < 1 + count * 8 {
+            return None;
+        }
+
+        let mut points: Vec<CalibrationPoint, MAX_CALIBRATION_POINTS> = Vec::new();
+        for chunk in buf[1..1 + count * 8].chunks_exact(8) {
+            let f = f32::from_le_bytes(chunk[..4].try_into().ok()?);
+            let k = f32::from_le_bytes(chunk[4..].try_into().ok()?);
+            points.push(CalibrationPoint::new(f, k)).ok()?;
+        }
+        Self::new(&points).ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(actual: f32, expected: f32) {
+        assert!(
+            (actual - expected).abs() < 1e-4,
+            "expected {expected}, got {actual}"
+        );
+    }
+
+    fn curve() -> Calibration {
+        Calibration::new(&[
+            CalibrationPoint::new(10.0, 420.0),
+            CalibrationPoint::new(50.0, 400.0),
+            CalibrationPoint::new(100.0, 380.0),
+        ])
+        .unwrap()
+    }
+
+    #[test]
+    fn default_matches_datasheet_constant() {
+        let calibration = Calibration::default();
+        // 66 pulses per second over one minute is 6.6 * 10 -> 10 L/min
+        assert_close(calibration.flow_rate(66 * 60, 60_000_000), 10.0);
+        assert_eq!(calibration.volume_ml(396, 2_000_000), 1_000);
+    }
+
+    #[test]
+    fn interpolates_between_points() {
+        let calibration = curve();
+        assert_close(calibration.pulses_per_litre(30.0), 410.0);
+        assert_close(calibration.pulses_per_litre(75.0), 390.0);
+        assert_close(calibration.pulses_per_litre(50.0), 400.0);
+    }
+
+    #[test]
+    fn clamps_outside_of_curve() {
+        let calibration = curve();
+        assert_close(calibration.pulses_per_litre(0.0), 420.0);
+        assert_close(calibration.pulses_per_litre(500.0), 380.0);
+        assert_close(calibration.pulses_per_litre(f32::INFINITY), 380.0);
+    }
+
+    #[test]
+    fn volume_uses_mean_frequency() {
+        let calibration = curve();
+        // 150 pulses in 3s -> 50 Hz -> 400 pulses/L
+        assert_eq!(calibration.volume_ml(150, 3_000_000), 375);
+        assert_close(calibration.flow_rate(150, 3_000_000), 150.0 / 400.0 * 20.0);
+    }
+
+    #[test]
+    fn volume_is_exact_for_large_counts() {
+        let calibration = Calibration::default();
+        // 100 L worth of pulses, f32 would already be off by a few ml here
+        assert_eq!(calibration.volume_ml(39_600_000, 600_000_000), 100_000_000);
+        assert_eq!(calibration.volume_ml(39_601, 1_000_000), 100_003);
+    }
+
+    #[test]
+    fn volume_rounds_to_nearest_ml() {
+        let calibration = Calibration::default();
+        // 1 pulse is 2.525 ml, 3 pulses are 7.576 ml
+        assert_eq!(calibration.volume_ml(1, 1_000_000), 3);
+        assert_eq!(calibration.volume_ml(3, 1_000_000), 8);
+        assert_eq!(calibration.volume_ml(0, 1_000_000), 0);
+    }
+
+    #[test]
+    fn zero_duration_has_no_rate() {
+        assert_close(Calibration::default().flow_rate(10, 0), 0.0);
+    }
+
+    #[test]
+    fn rejects_invalid_curves() {
+        assert_eq!(Calibration::new(&[]), Err(CalibrationError::Empty));
+        assert_eq!(
+            Calibration::new(&[
+                CalibrationPoint::new(10.0, 400.0),
+                CalibrationPoint::new(10.0, 410.0),
+            ]),
+            Err(CalibrationError::InvalidFrequency)
+        );
+        assert_eq!(
+            Calibration::constant(0.0),
+            Err(CalibrationError::InvalidFactor)
+        );
+        assert_eq!(
+            Calibration::constant(f32::NAN),
+            Err(CalibrationError::InvalidFactor)
+        );
+        let too_many = [CalibrationPoint::new(0.0, 1.0); MAX_CALIBRATION_POINTS + 1];
+        let too_many: std::vec::Vec<_> = too_many
+            .iter()
+            .enumerate()
+            .map(|(i, p)| CalibrationPoint::new(i as f32, p.pulses_per_litre))
+            .collect();
+        assert_eq!(
+            Calibration::new(&too_many),
+            Err(CalibrationError::TooManyPoints)
+        );
+    }
+
+    #[test]
+    fn merges_points_into_the_curve() {
+        let mut calibration = curve();
+        calibration
+            .merge(CalibrationPoint::new(30.0, 415.0))
+            .unwrap();
+        // 52 Hz is the same flow rate as the 50 Hz point
+        calibration
+            .merge(CalibrationPoint::new(52.0, 405.0))
+            .unwrap();
+        let frequencies: std::vec::Vec<f32> = calibration
+            .points()
+            .iter()
+            .map(|p| p.frequency_hz)
+            .collect();
+        assert_eq!(frequencies, [10.0, 30.0, 52.0, 100.0]);
+        assert_close(calibration.pulses_per_litre(52.0), 405.0);
+
+        assert_eq!(
+            calibration.merge(CalibrationPoint::new(20.0, -1.0)),
+            Err(CalibrationError::InvalidFactor)
+        );
+        assert_eq!(calibration.points().len(), 4);
+    }
+
+    #[test]
+    fn merging_into_a_full_curve_replaces_the_nearest_point() {
+        let points: std::vec::Vec<_> = (0..MAX_CALIBRATION_POINTS)
+            .map(|i| CalibrationPoint::new(i as f32 * 100.0, 400.0))
+            .collect();
+        let mut calibration = Calibration::new(&points).unwrap();
+        calibration
+            .merge(CalibrationPoint::new(180.0, 390.0))
+            .unwrap();
+        assert_eq!(calibration.points().len(), MAX_CALIBRATION_POINTS);
+        assert_eq!(calibration.points()[2], CalibrationPoint::new(180.0, 390.0));
+        assert!(calibration
+            .points()
+            .windows(2)
+            .all(|w| w[0].frequency_hz < w[1].frequency_hz));
+    }
+
+    #[test]
+    fn persist_roundtrip() {
+        let calibration = curve();
+        let mut buf = [0; Calibration::MAX_LEN];
+        let len = calibration.encode(&mut buf).unwrap();
+        assert_eq!(Calibration::decode(&buf[..len]), Some(calibration));
+    }
+
+    #[test]
+    fn decode_rejects_garbage() {
+        assert_eq!(Calibration::decode(&[]), None);
+        assert_eq!(Calibration::decode(&[2, 0, 0]), None);
+        // a single point with a negative factor
+        let mut buf = [0u8; 9];
+        buf[0] = 1;
+        buf[5..].copy_from_slice(&(-1.0f32).to_le_bytes());
+        assert_eq!(Calibration::decode(&buf), None);
+    }
+}
@@ -0,0 +1,250 @@
+//! Derivation of a K-factor from pours of a known volume.
+//!
+//! Every pour yields one pulses-per-litre sample. Pours that are too short to
+//! be meaningful are rejected right away, pours that disagree with the median
+//! of the others are dropped by [`CalibrationRun::reject_outliers`]. The
+//! remaining samples are averaged into a single [`CalibrationPoint`].
+
+use heapless::Vec;
+
+use crate::calibration::{frequency, CalibrationPoint};
+
+pub const MAX_POURS: usize = 16;
+
+/// A pour of `volume_ml` that produced `pulses` within `duration_us`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Pour {
+    pub pulses: u32,
+    pub duration_us: u64,
+    pub volume_ml: u32,
+}
+
+impl Pour {
+    pub fn pulses_per_litre(&self) -> f32 {
+        self.pulses as f32 * 1_000.0 / self.volume_ml as f32
+    }
+
+    pub fn frequency_hz(&self) -> f32 {
+        frequency(self.pulses, self.duration_us)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum PourRejection {
+    TooFewPulses,
+    NoVolume,
+    /// A zero duration has no usable pulse frequency.
+    NoDuration,
+    Full,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum EstimateError {
+    NotEnoughPours,
+}
+
+pub struct CalibrationRun {
+    pours: Vec<Pour, MAX_POURS>,
+    min_pulses: u32,
+    max_deviation: f32,
+    required_pours: usize,
+}
+
+impl CalibrationRun {
+    /// `max_deviation` is the allowed relative distance of a pour's K-factor
+    /// from the median, e.g. `0.05` for 5 %.
+    pub fn new(min_pulses: u32, max_deviation: f32, required_pours: usize) -> Self {
+        Self {
+            pours: Vec::new(),
+            min_pulses,
+            max_deviation,
+            required_pours: required_pours.clamp(1, MAX_POURS),
+        }
+    }
+
+    pub fn pours(&self) -> &[Pour] {
+        &self.pours
+    }
+
+    pub fn required_pours(&self) -> usize {
+        self.required_pours
+    }
+
+    /// Adds a pour, returning the amount of pours collected so far.
+    pub fn add_pour(&mut self, pour: Pour) -> Result<usize, PourRejection> {
+        if pour.volume_ml == 0 {
+            return Err(PourRejection::NoVolume);
+        }
+        if pour.duration_us == 0 {
+            return Err(PourRejection::NoDuration);
+        }
+        if pour.pulses < self.min_pulses {
+            return Err(PourRejection::TooFewPulses);
+        }
+        self.pours.push(pour).map_err(|_| PourRejection::Full)?;
+        Ok(self.pours.len())
+    }
+
+    /// Drops all pours too far from the median K-factor and returns how many
+    /// were removed. Needs at least three pours to tell who is off.
+    pub fn reject_outliers(&mut self) -> usize {
+        if self.pours.len() < 3 {
+            return 0;
+        }
+
+        let median = self.median_pulses_per_litre();
+        let max_deviation = self.max_deviation;
+        let before = self.pours.len();
+        self.pours.retain(|pour| {
+            let deviation = (pour.pulses_per_litre() - median) / median;
+            deviation <= max_deviation && -deviation <= max_deviation
+        });
+        before - self.pours.len()
+    }
+
+    /// Averages the collected pours into a calibration point.
+    pub fn estimate(&self) -> Result<CalibrationPoint, EstimateError> {
+        if self.pours.len() < self.required_pours {
+            return Err(EstimateError::NotEnoughPours);
+        }
+
+        let count = self.pours.len() as f32;
+        let (k, f) = self.pours.iter().fold((0.0, 0.0), |(k, f), pour| {
+            (k + pour.pulses_per_litre(), f + pour.frequency_hz())
+        });
+        Ok(CalibrationPoint::new(f / count, k / count))
+    }
+
+    fn median_pulses_per_litre(&self) -> f32 {
+        let mut factors: Vec<f32, MAX_POURS> =
+            self.pours.iter().map(Pour::pulses_per_litre).collect();
+        factors.sort_unstable_by(|a, b| a.total_cmp(b));
+
+        let mid = factors.len() / 2;
+        if factors.len().is_multiple_of(2) {
+            (factors[mid - 1] + factors[mid]) / 2.0
+        } else {
+            factors[mid]
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pour(pulses: u32) -> Pour {
+        Pour {
+            pulses,
+            duration_us: 10_000_000,
+            volume_ml: 1_000,
+        }
+    }
+
+    fn run() -> CalibrationRun {
+        CalibrationRun::new(100, 0.05, 3)
+    }
+
+    #[test]
+    fn single_pour_k_factor() {
+        let pour = Pour {
+            pulses: 210,
+            duration_us: 5_000_000,
+            volume_ml: 500,
+        };
+        assert_eq!(pour.pulses_per_litre(), 420.0);
+        assert_eq!(pour.frequency_hz(), 42.0);
+    }
+
+    #[test]
+    fn rejects_short_or_empty_pours() {
+        let mut run = run();
+        assert_eq!(run.add_pour(pour(99)), Err(PourRejection::TooFewPulses));
+        assert_eq!(
+            run.add_pour(Pour {
+                volume_ml: 0,
+                ..pour(400)
+            }),
+            Err(PourRejection::NoVolume)
+        );
+        assert_eq!(
+            run.add_pour(Pour {
+                duration_us: 0,
+                ..pour(400)
+            }),
+            Err(PourRejection::NoDuration)
+        );
+        assert_eq!(run.add_pour(pour(100)), Ok(1));
+    }
+
+    #[test]
+    fn rejects_when_full() {
+        let mut run = CalibrationRun::new(1, 0.05, MAX_POURS);
+        for _ in 0..MAX_POURS {
+            run.add_pour(pour(400)).unwrap();
+        }
+        assert_eq!(run.add_pour(pour(400)), Err(PourRejection::Full));
+    }
+
+    #[test]
+    fn needs_required_pours() {
+        let mut run = run();
+        run.add_pour(pour(400)).unwrap();
+        run.add_pour(pour(400)).unwrap();
+        assert_eq!(run.estimate(), Err(EstimateError::NotEnoughPours));
+        run.add_pour(pour(400)).unwrap();
+        assert!(run.estimate().is_ok());
+    }
+
+    #[test]
+    fn averages_pours() {
+        let mut run = run();
+        for pulses in [400, 410, 420] {
+            run.add_pour(pour(pulses)).unwrap();
+        }
+        let point = run.estimate().unwrap();
+        assert_eq!(point.pulses_per_litre, 410.0);
+        assert_eq!(point.frequency_hz, 41.0);
+    }
+
+    #[test]
+    fn drops_outliers() {
+        let mut run = run();
+        for pulses in [400, 405, 395, 480] {
+            run.add_pour(pour(pulses)).unwrap();
+        }
+        assert_eq!(run.reject_outliers(), 1);
+        assert_eq!(run.pours().len(), 3);
+        assert_eq!(run.estimate().unwrap().pulses_per_litre, 400.0);
+    }
+
+    #[test]
+    fn outliers_can_leave_too_few_pours() {
+        let mut run = run();
+        for pulses in [300, 400, 500] {
+            run.add_pour(pour(pulses)).unwrap();
+        }
+        assert_eq!(run.reject_outliers(), 2);
+        assert_eq!(run.estimate(), Err(EstimateError::NotEnoughPours));
+    }
+
+    #[test]
+    fn two_pours_are_never_outliers() {
+        let mut run = CalibrationRun::new(100, 0.05, 2);
+        run.add_pour(pour(300)).unwrap();
+        run.add_pour(pour(500)).unwrap();
+        assert_eq!(run.reject_outliers(), 0);
+    }
+
+    #[test]
+    fn deviation_boundary_is_kept() {
+        let mut run = run();
+        for pulses in [380, 400, 420] {
+            run.add_pour(pour(pulses)).unwrap();
+        }
+        assert_eq!(run.reject_outliers(), 0);
+    }
+}
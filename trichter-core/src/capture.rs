@@ -0,0 +1,204 @@
+//! Raw pulse timestamps of a session for offline analysis.
+//!
+//! A [`PulseCapture`] keeps the intervals between consecutive raw pulses,
+//! before any glitch filtering. It is a ring buffer: once it is full, the
+//! oldest pulses are dropped and counted, so a long session still yields its
+//! most recent [`CAPTURE_PULSES`] pulses.
+//!
+//! For the upload, the intervals are encoded as unsigned LEB128 varints (one
+//! byte for intervals below 128 µs, three bytes for anything below two
+//! seconds) and the resulting bytes as standard base64 with padding.
+
+use core::fmt::{self, Write};
+
+use heapless::Deque;
+
+pub const CAPTURE_PULSES: usize = 1024;
+
+const BASE64: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+#[derive(Debug, Clone)]
+pub struct PulseCapture {
+    /// Timestamp of the oldest pulse still in the buffer.
+    first_us: Option<u64>,
+    last_us: u64,
+    /// Interval from each pulse to the next one, in microseconds.
+    deltas: Deque<u32, { CAPTURE_PULSES - 1 }>,
+    dropped: u32,
+}
+
+impl PulseCapture {
+    pub fn new() -> Self {
+        Self {
+            first_us: None,
+            last_us: 0,
+            deltas: Deque::new(),
+            dropped: 0,
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.first_us = None;
+        self.deltas.clear();
+        self.dropped = 0;
+    }
+
+    /// Records `count` pulses observed at `at_us`.
+    pub fn record(&mut self, at_us: u64, count: u32) {
+        for _ in 0..count {
+            let Some(first_us) = self.first_us else {
+                self.first_us = Some(at_us);
+                self.last_us = at_us;
+                continue;
+            };
+
+            if self.deltas.is_full() {
+                let oldest = self.deltas.pop_front().unwrap_or_default();
+                self.first_us = Some(first_us + oldest as u64);
+                self.dropped = self.dropped.saturating_add(1);
+            }
+            let delta = at_us.saturating_sub(self.last_us).min(u32::MAX as u64) as u32;
+            // cannot fail, there is room after dropping the oldest one
+            let _ = self.deltas.push_back(delta);
+            self.last_us = at_us;
+        }
+    }
+
+    pub fn first_us(&self) -> Option<u64> {
+        self.first_us
+    }
+
+    /// Number of pulses in the buffer.
+    pub fn pulses(&self) -> usize {
+        match self.first_us {
+            Some(_) => self.deltas.len() + 1,
+            None => 0,
+        }
+    }
+
+    /// Pulses that were dropped because the buffer was full.
+    pub fn dropped(&self) -> u32 {
+        self.dropped
+    }
+
+    pub fn deltas(&self) -> impl Iterator<Item = u32> + '_ {
+        self.deltas.iter().copied()
+    }
+
+    /// Writes the intervals as base64 encoded LEB128 varints.
+    pub fn encode(&self, out: &mut impl Write) -> fmt::Result {
+        let mut chunk = [0u8; 3];
+        let mut len = 0;
+        for delta in self.deltas() {
+            let mut varint = [0u8; 5];
+            for byte in leb128(delta, &mut varint) {
+                chunk[len] = *byte;
+                len += 1;
+                if len == chunk.len() {
+                    write_base64(&chunk, out)?;
+                    len = 0;
+                }
+            }
+        }
+        if len > 0 {
+            write_base64(&chunk[..len], out)?;
+        }
+        Ok(())
+    }
+}
+
+impl Default for PulseCapture {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn leb128(mut value: u32, buf: &mut [u8; 5]) -> &[u8] {
+    let mut len = 0;
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if value == 0 {
+            buf[len] = byte;
+            return &buf[..=len];
+        }
+        buf[len] = byte | 0x80;
+        len += 1;
+    }
+}
+
+/// Encodes up to three bytes, padding the output if there are fewer.
+pub(crate) fn write_base64(bytes: &[u8], out: &mut impl Write) -> fmt::Result {
+    let mut block = [0u8; 3];
+    block[..bytes.len()].copy_from_slice(bytes);
+    let bits = (block[0] as u32) << 16 | (block[1] as u32) << 8 | block[2] as u32;
+    for i in 0..4 {
+        if i > bytes.len() {
+            out.write_char('=')?;
+        } else {
+            let index = (bits >> (18 - 6 * i)) & 0x3f;
+            out.write_char(BASE64[index as usize] as char)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn empty_capture() {
+        let capture = PulseCapture::new();
+        assert_eq!(capture.pulses(), 0);
+        assert_eq!(capture.first_us(), None);
+        let mut encoded = String::new();
+        capture.encode(&mut encoded).unwrap();
+        assert_eq!(encoded, "");
+    }
+
+    #[test]
+    fn records_intervals() {
+        let mut capture = PulseCapture::new();
+        capture.record(1_000, 1);
+        capture.record(21_000, 1);
+        capture.record(41_000, 2);
+        assert_eq!(capture.first_us(), Some(1_000));
+        assert_eq!(capture.pulses(), 4);
+        assert_eq!(capture.deltas().collect::<Vec<_>>(), [20_000, 20_000, 0]);
+    }
+
+    #[test]
+    fn full_capture_drops_oldest_pulses() {
+        let mut capture = PulseCapture::new();
+        for i in 0..CAPTURE_PULSES as u64 + 10 {
+            capture.record(i * 1_000, 1);
+        }
+        assert_eq!(capture.pulses(), CAPTURE_PULSES);
+        assert_eq!(capture.dropped(), 10);
+        assert_eq!(capture.first_us(), Some(10_000));
+        assert!(capture.deltas().all(|delta| delta == 1_000));
+
+        capture.clear();
+        assert_eq!(capture.pulses(), 0);
+        assert_eq!(capture.dropped(), 0);
+    }
+
+    #[test]
+    fn encodes_varints_as_base64() {
+        let mut capture = PulseCapture::new();
+        for at in [0, 1, 301, 301] {
+            capture.record(at, 1);
+        }
+        // 0x01, 0xac 0x02, 0x00
+        let mut encoded = String::new();
+        capture.encode(&mut encoded).unwrap();
+        assert_eq!(encoded, "AawCAA==");
+
+        capture.record(301 + 20_000, 1);
+        // ..., 0xa0 0x9c 0x01
+        let mut encoded = String::new();
+        capture.encode(&mut encoded).unwrap();
+        assert_eq!(encoded, "AawCAKCcAQ==");
+    }
+}
@@ -0,0 +1,199 @@
+//! Lifecycle of a persistent connection to the backend.
+//!
+//! The [`Connection`] does not own a socket, the client reports what happened
+//! to its socket and asks before every request whether the connection can be
+//! reused. An idle connection is only reused within the idle timeout, which
+//! the server may shorten with its `Keep-Alive` header. The [`ConnectionStats`]
+//! are kept for diagnostics.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum ConnectionState {
+    /// No connection, the next request connects first.
+    Closed,
+    /// Connected and waiting for the next request.
+    Idle,
+    /// A request is in flight.
+    Busy,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct ConnectionStats {
+    pub state: ConnectionState,
+    pub connects: u32,
+    pub requests: u32,
+    /// Requests sent on a connection that already carried one before.
+    pub reused: u32,
+    pub failures: u32,
+    /// Requests on the current connection.
+    pub connection_requests: u32,
+    pub connected_at_us: Option<u64>,
+    pub last_used_us: Option<u64>,
+}
+
+impl ConnectionStats {
+    pub const fn new() -> Self {
+        Self {
+            state: ConnectionState::Closed,
+            connects: 0,
+            requests: 0,
+            reused: 0,
+            failures: 0,
+            connection_requests: 0,
+            connected_at_us: None,
+            last_used_us: None,
+        }
+    }
+}
+
+impl Default for ConnectionStats {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Servers close idle connections when their keep-alive timeout expires,
+/// reusing one right before that races with the close.
+const SERVER_TIMEOUT_MARGIN_US: u64 = 1_000_000;
+
+#[derive(Debug, Clone)]
+pub struct Connection {
+    idle_timeout_us: u64,
+    idle_until_us: u64,
+    stats: ConnectionStats,
+}
+
+impl Connection {
+    pub const fn new(idle_timeout_us: u64) -> Self {
+        Self {
+            idle_timeout_us,
+            idle_until_us: 0,
+            stats: ConnectionStats::new(),
+        }
+    }
+
+    pub fn state(&self) -> ConnectionState {
+        self.stats.state
+    }
+
+    pub fn stats(&self) -> ConnectionStats {
+        self.stats
+    }
+
+    /// Whether a request at `now_us` can go out on the current connection.
+    pub fn is_reusable(&self, now_us: u64) -> bool {
+        self.stats.state == ConnectionState::Idle && now_us < self.idle_until_us
+    }
+
+    pub fn connected(&mut self, now_us: u64) {
+        self.stats.state = ConnectionState::Idle;
+        self.stats.connects += 1;
+        self.stats.connection_requests = 0;
+        self.stats.connected_at_us = Some(now_us);
+        self.idle_until_us = now_us.saturating_add(self.idle_timeout_us);
+    }
+
+    /// Marks the connection busy, returns whether it was used before.
+    pub fn request_started(&mut self, now_us: u64) -> bool {
+        let reused = self.stats.connection_requests > 0;
+        self.stats.state = ConnectionState::Busy;
+        self.stats.requests += 1;
+        self.stats.connection_requests += 1;
+        if reused {
+            self.stats.reused += 1;
+        }
+        self.stats.last_used_us = Some(now_us);
+        reused
+    }
+
+    /// A complete response arrived, `keep_alive` and `server_timeout_secs`
+    /// as announced by the server.
+    pub fn response_received(
+        &mut self,
+        now_us: u64,
+        keep_alive: bool,
+        server_timeout_secs: Option<u64>,
+    ) {
+        self.stats.last_used_us = Some(now_us);
+        if !keep_alive {
+            self.stats.state = ConnectionState::Closed;
+            return;
+        }
+        let idle_us = server_timeout_secs.map_or(self.idle_timeout_us, |secs| {
+            secs.saturating_mul(1_000_000)
+                .saturating_sub(SERVER_TIMEOUT_MARGIN_US)
+                .min(self.idle_timeout_us)
+        });
+        self.stats.state = ConnectionState::Idle;
+        self.idle_until_us = now_us.saturating_add(idle_us);
+    }
+
+    /// The connection was closed on purpose.
+    pub fn closed(&mut self) {
+        self.stats.state = ConnectionState::Closed;
+    }
+
+    /// The connection broke or was given up.
+    pub fn failed(&mut self) {
+        self.stats.state = ConnectionState::Closed;
+        self.stats.failures += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn idle_connections_expire() {
+        let mut connection = Connection::new(4_000_000);
+        assert!(!connection.is_reusable(0));
+
+        connection.connected(1_000_000);
+        assert!(!connection.request_started(1_000_000));
+        assert!(!connection.is_reusable(1_100_000));
+        connection.response_received(1_100_000, true, None);
+        assert!(connection.is_reusable(5_000_000));
+        assert!(!connection.is_reusable(5_100_000));
+
+        assert!(connection.request_started(2_000_000));
+        let stats = connection.stats();
+        assert_eq!(stats.requests, 2);
+        assert_eq!(stats.reused, 1);
+        assert_eq!(stats.connects, 1);
+    }
+
+    #[test]
+    fn server_can_shorten_the_idle_timeout() {
+        let mut connection = Connection::new(60_000_000);
+        connection.connected(0);
+        connection.request_started(0);
+        connection.response_received(0, true, Some(5));
+        assert!(connection.is_reusable(3_900_000));
+        assert!(!connection.is_reusable(4_000_000));
+
+        // but not extend it
+        connection.request_started(0);
+        connection.response_received(0, true, Some(300));
+        assert!(!connection.is_reusable(60_000_000));
+    }
+
+    #[test]
+    fn closed_connections_are_not_reused() {
+        let mut connection = Connection::new(4_000_000);
+        connection.connected(0);
+        connection.request_started(0);
+        connection.response_received(0, false, None);
+        assert_eq!(connection.state(), ConnectionState::Closed);
+        assert!(!connection.is_reusable(1));
+
+        connection.connected(10);
+        assert_eq!(connection.stats().connection_requests, 0);
+        connection.request_started(10);
+        connection.failed();
+        assert!(!connection.is_reusable(11));
+        assert_eq!(connection.stats().failures, 1);
+        assert_eq!(connection.stats().connects, 2);
+    }
+}
@@ -0,0 +1,405 @@
+//! Credentials of a single device towards the backend.
+//!
+//! Every device is provisioned with its own ID and secret key, from a form
+//! like `device_id=trichter-0042&key=<64 hex digits>&token=<32 hex digits>`,
+//! where the token is the [provisioning token](FactorySecret::provisioning_token)
+//! of the device. Requests are signed with an HMAC-SHA256 over
+//!
+//! ```text
+//! timestamp "\n" nonce "\n" resource "\n" body
+//! ```
+//!
+//! with the timestamp in seconds since the Unix epoch and a random nonce.
+//! Both are sent along with the signature, so the backend can reject stale
+//! and replayed requests as well as forged ones. The backend signs the time
+//! it tells the device the same way, with the device's nonce and an empty
+//! body, see [`DeviceCredentials::verify`].
+//!
+//! An MQTT broker takes the device ID as user name and a password derived
+//! from the key, which the backend hands to the broker along with an ACL for
+//! the device's topics:
+//!
+//! ```text
+//! broker password = hex(hmac(key, "mqtt"))[..32]
+//! ```
+
+use core::fmt::{self, Write};
+
+use heapless::String;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+use crate::{
+    factory::{constant_time_eq, FactorySecret},
+    record::Persist,
+};
+
+pub const MAX_DEVICE_ID_LEN: usize = 32;
+pub const KEY_LEN: usize = 32;
+/// Length of a request signature.
+pub const DIGEST_LEN: usize = 32;
+/// Hex digits of the broker password.
+pub const BROKER_PASSWORD_LEN: usize = 32;
+
+const HEX_DIGITS: &[u8; 16] = b"0123456789abcdef";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum CredentialsError {
+    /// Empty, too long or with characters that do not belong in a header.
+    DeviceId,
+    /// Not exactly `2 * KEY_LEN` hex digits.
+    Key,
+    UnknownKey,
+    /// The form lacks the device ID or the key.
+    Missing,
+    /// The provisioning token is missing or not the one of this device.
+    Token,
+}
+
+/// A device ID and its secret key. The key never shows up in debug output.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct DeviceCredentials {
+    id: [u8; MAX_DEVICE_ID_LEN],
+    id_len: u8,
+    key: [u8; KEY_LEN],
+}
+
+impl DeviceCredentials {
+    pub fn new(device_id: &str, key: [u8; KEY_LEN]) -> Result<Self, CredentialsError> {
+        let valid = !device_id.is_empty()
+            && device_id.len() <= MAX_DEVICE_ID_LEN
+            && device_id
+                .bytes()
+                .all(|b| b.is_ascii_alphanumeric() || matches!(b, b'-' | b'_' | b'.'));
+        if !valid {
+            return Err(CredentialsError::DeviceId);
+        }
+
+        let mut id = [0; MAX_DEVICE_ID_LEN];
+        id[..device_id.len()].copy_from_slice(device_id.as_bytes());
+        Ok(Self {
+            id,
+            id_len: device_id.len() as u8,
+            key,
+        })
+    }
+
+    /// Parses a form encoded `device_id` and hex `key`, which have to come
+    /// with the provisioning `token` derived from `secret`.
+    pub fn parse(form: &str, secret: &FactorySecret) -> Result<Self, CredentialsError> {
+        let (mut device_id, mut key, mut token) = (None, None, None);
+        for pair in form.trim().split('&').filter(|pair| !pair.is_empty()) {
+            let (name, value) = pair.split_once('=').ok_or(CredentialsError::Missing)?;
+            match name.trim() {
+                "device_id" => device_id = Some(value.trim()),
+                "key" => key = Some(parse_key(value.trim())?),
+                "token" => token = Some(value),
+                _ => return Err(CredentialsError::UnknownKey),
+            }
+        }
+        match (device_id, key) {
+            (Some(device_id), Some(key)) => {
+                let credentials = Self::new(device_id, key)?;
+                if !token.is_some_and(|token| secret.authorizes_provisioning(token)) {
+                    return Err(CredentialsError::Token);
+                }
+                Ok(credentials)
+            }
+            _ => Err(CredentialsError::Missing),
+        }
+    }
+
+    pub fn device_id(&self) -> &str {
+        // only ever set from a valid ASCII string
+        core::str::from_utf8(&self.id[..self.id_len as usize]).unwrap_or_default()
+    }
+
+    /// The signature of a request, see the module documentation.
+    pub fn sign(
+        &self,
+        timestamp: u64,
+        nonce: &str,
+        resource: &str,
+        body: &[u8],
+    ) -> [u8; DIGEST_LEN] {
+        let mut timestamp_digits = [0u8; 20];
+        // cannot fail, HMAC takes keys of any length
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.key).unwrap();
+        mac.update(format_u64(timestamp, &mut timestamp_digits));
+        mac.update(b"\n");
+        mac.update(nonce.as_bytes());
+        mac.update(b"\n");
+        mac.update(resource.as_bytes());
+        mac.update(b"\n");
+        mac.update(body);
+        mac.finalize().into_bytes().into()
+    }
+
+    /// The password of the device at an MQTT broker, see the module
+    /// documentation.
+    pub fn broker_password(&self) -> String<BROKER_PASSWORD_LEN> {
+        // cannot fail, HMAC takes keys of any length
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.key).unwrap();
+        mac.update(b"mqtt");
+        let digest = mac.finalize().into_bytes();
+        let mut password = String::new();
+        for byte in &digest[..BROKER_PASSWORD_LEN / 2] {
+            let _ = write!(password, "{:02x}", byte);
+        }
+        password
+    }
+
+    /// Whether `signature`, in lowercase hex, is the signature of the device over the
+    /// other parts, i.e. whether it was made by someone who knows the key.
+    pub fn verify(
+        &self,
+        timestamp: u64,
+        nonce: &str,
+        resource: &str,
+        body: &[u8],
+        signature: &str,
+    ) -> bool {
+        let mut expected = [0u8; 2 * DIGEST_LEN];
+        for (digits, byte) in expected
+            .chunks_exact_mut(2)
+            .zip(self.sign(timestamp, nonce, resource, body))
+        {
+            digits[0] = HEX_DIGITS[(byte >> 4) as usize];
+            digits[1] = HEX_DIGITS[(byte & 0xf) as usize];
+        }
+        constant_time_eq(signature.trim().as_bytes(), &expected)
+    }
+}
+
+impl fmt::Debug for DeviceCredentials {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DeviceCredentials")
+            .field("device_id", &self.device_id())
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(feature = "defmt")]
+impl defmt::Format for DeviceCredentials {
+    fn format(&self, f: defmt::Formatter) {
+        defmt::write!(f, "DeviceCredentials {{ device_id: {} }}", self.device_id())
+    }
+}
+
+impl Persist for DeviceCredentials {
+    const SLOT: u8 = 2;
+    const MAX_LEN: usize = 1 + MAX_DEVICE_ID_LEN + KEY_LEN;
+
+    fn encode(&self, buf: &mut [u8]) -> Option<usize> {
+        let id = self.device_id().as_bytes();
+        let len = 1 + id.len() + KEY_LEN;
+        let buf = buf.get_mut(..len)?;
+        buf[0] = id.len() as u8;
+        buf[1..1 + id.len()].copy_from_slice(id);
+        buf[1 + id.len()..].copy_from_slice(&self.key);
+        Some(len)
+    }
+
+    fn decode(buf: &[u8]) -> Option<Self> {
+        let (&id_len, rest) = buf.split_first()?;
+        let (id, key) = rest.split_at_checked(id_len as usize)?;
+        let id = core::str::from_utf8(id).ok()?;
+        Self::new(id, key.try_into().ok()?).ok()
+    }
+}
+
+fn parse_key(hex: &str) -> Result<[u8; KEY_LEN], CredentialsError> {
+    if hex.len() != 2 * KEY_LEN {
+        return Err(CredentialsError::Key);
+    }
+    let digit = |d: u8| (d as char).to_digit(16).ok_or(CredentialsError::Key);
+    let mut key = [0; KEY_LEN];
+    for (byte, digits) in key.iter_mut().zip(hex.as_bytes().chunks_exact(2)) {
+        *byte = (digit(digits[0])? << 4 | digit(digits[1])?) as u8;
+    }
+    Ok(key)
+}
+
+/// Decimal digits of `value`, without allocating.
+fn format_u64(mut value: u64, buf: &mut [u8; 20]) -> &[u8] {
+    let mut start = buf.len();
+    loop {
+        start -= 1;
+        buf[start] = b'0' + (value % 10) as u8;
+        value /= 10;
+        if value == 0 {
+            return &buf[start..];
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY_HEX: &str = "000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f";
+    /// The provisioning token of `secret()`.
+    const TOKEN: &str = "e50dc107d59e8e838efea26a875ac45f";
+
+    fn secret() -> FactorySecret {
+        FactorySecret::new(core::array::from_fn(|i| i as u8)).unwrap()
+    }
+
+    fn key() -> [u8; KEY_LEN] {
+        core::array::from_fn(|i| i as u8)
+    }
+
+    #[test]
+    fn parses_provisioning_form() {
+        let credentials = DeviceCredentials::parse(
+            &std::format!("device_id=trichter-0042&key={KEY_HEX}&token={TOKEN}\r\n"),
+            &secret(),
+        )
+        .unwrap();
+        assert_eq!(credentials.device_id(), "trichter-0042");
+        assert_eq!(
+            credentials,
+            DeviceCredentials::new("trichter-0042", key()).unwrap()
+        );
+
+        let upper = KEY_HEX.to_uppercase();
+        let form = std::format!("token={TOKEN}&key={upper}&device_id=a");
+        assert!(DeviceCredentials::parse(&form, &secret()).is_ok());
+    }
+
+    #[test]
+    fn derives_the_broker_password() {
+        // python: hmac.new(bytes(range(32)), b"mqtt", hashlib.sha256).hexdigest()[:32]
+        let credentials = DeviceCredentials::new("a", key()).unwrap();
+        assert_eq!(
+            credentials.broker_password(),
+            "c582805f6b33410bfa2ac3b55af34356"
+        );
+        let other = DeviceCredentials::new("a", [1; KEY_LEN]).unwrap();
+        assert_ne!(other.broker_password(), credentials.broker_password());
+    }
+
+    #[test]
+    fn rejects_bad_credentials() {
+        let cases = [
+            (std::format!("key={KEY_HEX}"), CredentialsError::Missing),
+            (
+                std::string::String::from("device_id=a"),
+                CredentialsError::Missing,
+            ),
+            (
+                std::format!("device_id=&key={KEY_HEX}"),
+                CredentialsError::DeviceId,
+            ),
+            (
+                std::format!("device_id=a b&key={KEY_HEX}"),
+                CredentialsError::DeviceId,
+            ),
+            (
+                std::format!("device_id={}&key={KEY_HEX}", "x".repeat(33)),
+                CredentialsError::DeviceId,
+            ),
+            (
+                std::format!("device_id=a&key={}", &KEY_HEX[2..]),
+                CredentialsError::Key,
+            ),
+            (
+                std::format!("device_id=a&key={}zz", &KEY_HEX[2..]),
+                CredentialsError::Key,
+            ),
+            (
+                std::format!("device_id=a&key={}ä", &KEY_HEX[2..]),
+                CredentialsError::Key,
+            ),
+            (
+                std::format!("device_id=a&key=+f{}", &KEY_HEX[2..]),
+                CredentialsError::Key,
+            ),
+            (
+                std::format!("device_id=a&key={KEY_HEX}&secret=1"),
+                CredentialsError::UnknownKey,
+            ),
+            (
+                std::format!("device_id=a&key={KEY_HEX}"),
+                CredentialsError::Token,
+            ),
+            (
+                std::format!("device_id=a&key={KEY_HEX}&token={}", &TOKEN[1..]),
+                CredentialsError::Token,
+            ),
+            (
+                std::format!("device_id=a&key={KEY_HEX}&token={}", "0".repeat(32)),
+                CredentialsError::Token,
+            ),
+        ];
+        for (form, error) in cases {
+            assert_eq!(
+                DeviceCredentials::parse(&form, &secret()),
+                Err(error),
+                "{}",
+                form
+            );
+        }
+        // the token of another device does not do
+        let other = FactorySecret::new([1; crate::factory::SECRET_LEN]).unwrap();
+        let form = std::format!("device_id=a&key={KEY_HEX}&token={TOKEN}");
+        assert_eq!(
+            DeviceCredentials::parse(&form, &other),
+            Err(CredentialsError::Token)
+        );
+    }
+
+    #[test]
+    fn persists_roundtrip() {
+        let credentials = DeviceCredentials::new("trichter-0042", key()).unwrap();
+        let mut buf = [0; DeviceCredentials::MAX_LEN];
+        let len = credentials.encode(&mut buf).unwrap();
+        assert_eq!(DeviceCredentials::decode(&buf[..len]), Some(credentials));
+        assert_eq!(DeviceCredentials::decode(&buf[..len - 1]), None);
+        assert_eq!(DeviceCredentials::decode(&[]), None);
+    }
+
+    #[test]
+    fn signature_matches_reference() {
+        // python: hmac.new(bytes(range(32)), message, hashlib.sha256)
+        let credentials = DeviceCredentials::new("trichter-0042", key()).unwrap();
+        let signature = credentials.sign(
+            1_792_315_292,
+            "00112233445566778899aabbccddeeff",
+            "/api/v1/runs",
+            b"{\"volume_ml\": 500}",
+        );
+        let hex: std::string::String = signature
+            .iter()
+            .map(|b| std::format!("{:02x}", b))
+            .collect();
+        assert_eq!(
+            hex,
+            "68a38e042b768edc72473372a47f9df2a8ed44e5090c232867ef05e7e0c1cf2a"
+        );
+    }
+
+    #[test]
+    fn verifies_signatures_of_the_backend() {
+        // python: hmac.new(bytes(range(32)), message, hashlib.sha256)
+        let credentials = DeviceCredentials::new("trichter-0042", key()).unwrap();
+        let nonce = "00112233445566778899aabbccddeeff";
+        let signature = "ad4681f0adc62f80ba95d8f1dfe172decae48d961172194778fb4f9eef935ad0";
+        assert!(credentials.verify(1_760_000_000, nonce, "/api/v1/time", b"", signature));
+
+        assert!(!credentials.verify(1_760_000_001, nonce, "/api/v1/time", b"", signature));
+        assert!(!credentials.verify(1_760_000_000, "0", "/api/v1/time", b"", signature));
+        assert!(!credentials.verify(1_760_000_000, nonce, "/api/v1/time", b"", &signature[1..]));
+        let other = DeviceCredentials::new("trichter-0042", [7; KEY_LEN]).unwrap();
+        assert!(!other.verify(1_760_000_000, nonce, "/api/v1/time", b"", signature));
+    }
+
+    #[test]
+    fn debug_output_hides_the_key() {
+        let credentials = DeviceCredentials::new("trichter-0042", key()).unwrap();
+        let debug = std::format!("{:?}", credentials);
+        assert!(debug.contains("trichter-0042"));
+        assert!(!debug.contains("key"));
+    }
+}
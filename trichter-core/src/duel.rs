@@ -0,0 +1,211 @@
+//! Rules for a head-to-head race between two funnels.
+//!
+//! Both sides start at the same "go" timestamp. A pulse before "go" is a
+//! false start and disqualifies that side. The finish time of a side is the
+//! end of its session measured from "go", the faster side wins.
+
+use crate::session::DetectedSession;
+
+pub const SIDES: usize = 2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum SideResult {
+    /// Still racing, or not started yet.
+    Pending,
+    /// Pulsed before "go" at `at_us`.
+    FalseStart { at_us: u64 },
+    /// Finished `time_us` after "go".
+    Finished { time_us: u64 },
+    /// Did not finish before the duel timed out, or the session overran.
+    DidNotFinish,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct DuelOutcome {
+    /// `None` on a draw or if no side finished.
+    pub winner: Option<usize>,
+    /// Difference of the finish times, only if both sides finished.
+    pub margin_us: Option<u64>,
+    pub sides: [SideResult; SIDES],
+}
+
+pub struct Duel {
+    go_us: u64,
+    sides: [SideResult; SIDES],
+}
+
+impl Duel {
+    pub fn new(go_us: u64) -> Self {
+        Self {
+            go_us,
+            sides: [SideResult::Pending; SIDES],
+        }
+    }
+
+    pub fn go_us(&self) -> u64 {
+        self.go_us
+    }
+
+    pub fn side(&self, side: usize) -> SideResult {
+        self.sides[side]
+    }
+
+    pub fn is_pending(&self, side: usize) -> bool {
+        self.sides[side] == SideResult::Pending
+    }
+
+    /// Registers a pulse on `side`, returns true if it was a false start.
+    ///
+    /// Only the first false start is kept, pulses after "go" are left to the
+    /// session detector.
+    pub fn on_pulse(&mut self, side: usize, at_us: u64) -> bool {
+        if at_us >= self.go_us || !self.is_pending(side) {
+            return false;
+        }
+        self.sides[side] = SideResult::FalseStart { at_us };
+        true
+    }
+
+    /// Registers the finished session of `side`.
+    pub fn on_finish(&mut self, side: usize, session: &DetectedSession) {
+        if !self.is_pending(side) {
+            return;
+        }
+        let end_us = session.first_us + session.duration_us();
+        self.sides[side] = SideResult::Finished {
+            time_us: end_us.saturating_sub(self.go_us),
+        };
+    }
+
+    /// A side whose session overran did not finish.
+    pub fn on_overrun(&mut self, side: usize) {
+        if self.is_pending(side) {
+            self.sides[side] = SideResult::DidNotFinish;
+        }
+    }
+
+    /// Ends the duel, every side still racing did not finish.
+    pub fn on_timeout(&mut self) {
+        for side in self.sides.iter_mut() {
+            if *side == SideResult::Pending {
+                *side = SideResult::DidNotFinish;
+            }
+        }
+    }
+
+    /// The outcome once no side is pending anymore.
+    pub fn outcome(&self) -> Option<DuelOutcome> {
+        if self.sides.contains(&SideResult::Pending) {
+            return None;
+        }
+
+        let time = |side: usize| match self.sides[side] {
+            SideResult::Finished { time_us } => Some(time_us),
+            _ => None,
+        };
+        let (winner, margin_us) = match (time(0), time(1)) {
+            (Some(a), Some(b)) if a < b => (Some(0), Some(b - a)),
+            (Some(a), Some(b)) if b < a => (Some(1), Some(a - b)),
+            (Some(_), Some(_)) => (None, Some(0)),
+            (Some(_), None) => (Some(0), None),
+            (None, Some(_)) => (Some(1), None),
+            (None, None) => (None, None),
+        };
+
+        Some(DuelOutcome {
+            winner,
+            margin_us,
+            sides: self.sides,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GO: u64 = 10_000_000;
+
+    /// A session of 11 pulses 20 ms apart starting at `first_us`, which ends
+    /// 220 ms after its first pulse.
+    fn session(first_us: u64) -> DetectedSession {
+        DetectedSession {
+            pulses: 11,
+            first_us,
+            last_us: first_us + 200_000,
+        }
+    }
+
+    #[test]
+    fn faster_side_wins() {
+        let mut duel = Duel::new(GO);
+        duel.on_finish(1, &session(GO + 300_000));
+        assert_eq!(duel.outcome(), None);
+        duel.on_finish(0, &session(GO + 500_000));
+
+        let outcome = duel.outcome().unwrap();
+        assert_eq!(outcome.winner, Some(1));
+        assert_eq!(outcome.margin_us, Some(200_000));
+        assert_eq!(outcome.sides[1], SideResult::Finished { time_us: 520_000 });
+    }
+
+    #[test]
+    fn equal_times_are_a_draw() {
+        let mut duel = Duel::new(GO);
+        duel.on_finish(0, &session(GO));
+        duel.on_finish(1, &session(GO));
+        let outcome = duel.outcome().unwrap();
+        assert_eq!(outcome.winner, None);
+        assert_eq!(outcome.margin_us, Some(0));
+    }
+
+    #[test]
+    fn pulse_before_go_is_a_false_start() {
+        let mut duel = Duel::new(GO);
+        assert!(duel.on_pulse(0, GO - 1));
+        assert!(!duel.on_pulse(0, GO - 1));
+        assert!(!duel.on_pulse(1, GO));
+        assert_eq!(duel.side(0), SideResult::FalseStart { at_us: GO - 1 });
+        assert!(duel.is_pending(1));
+
+        // a disqualified side cannot finish anymore
+        duel.on_finish(0, &session(GO));
+        duel.on_finish(1, &session(GO + 2_000_000));
+        let outcome = duel.outcome().unwrap();
+        assert_eq!(outcome.winner, Some(1));
+        assert_eq!(outcome.margin_us, None);
+    }
+
+    #[test]
+    fn timeout_ends_the_duel() {
+        let mut duel = Duel::new(GO);
+        duel.on_finish(0, &session(GO));
+        duel.on_timeout();
+        let outcome = duel.outcome().unwrap();
+        assert_eq!(outcome.winner, Some(0));
+        assert_eq!(outcome.sides[1], SideResult::DidNotFinish);
+    }
+
+    #[test]
+    fn overrun_does_not_finish() {
+        let mut duel = Duel::new(GO);
+        duel.on_overrun(0);
+        duel.on_finish(0, &session(GO));
+        duel.on_finish(1, &session(GO + 5_000_000));
+        let outcome = duel.outcome().unwrap();
+        assert_eq!(outcome.sides[0], SideResult::DidNotFinish);
+        assert_eq!(outcome.winner, Some(1));
+    }
+
+    #[test]
+    fn no_winner_without_finish() {
+        let mut duel = Duel::new(GO);
+        duel.on_pulse(0, 0);
+        duel.on_pulse(1, 0);
+        let outcome = duel.outcome().unwrap();
+        assert_eq!(outcome.winner, None);
+        assert_eq!(outcome.margin_us, None);
+    }
+}
@@ -0,0 +1,187 @@
+//! The secret every device gets at the factory.
+//!
+//! Each device has its own random [`FactorySecret`], burned into the chip
+//! before it leaves the factory and kept by the backend together with the
+//! device's MAC address. The secrets that protect the device locally are
+//! derived from it, so a leaked one only opens a single device:
+//!
+//! ```text
+//! management password = hex(hmac(secret, "management"))[..32]
+//! provisioning token  = hex(hmac(secret, "provisioning"))[..32]
+//! revocation          = hex(hmac(secret, "revoked\n" device_id "\n" nonce))[..32]
+//! ```
+//!
+//! The management interface takes the password with the user [`USER`] in a
+//! basic auth header. The provisioning token comes from the backend along
+//! with the device's credentials, so knowing the management password is not
+//! enough to provision a device with credentials of one's own. The backend
+//! answers with the revocation when it no longer accepts the credentials of
+//! the device, anybody else's rejection is not taken for one.
+
+use core::fmt::{self, Write};
+
+use heapless::String;
+
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+use crate::capture::write_base64;
+
+pub const SECRET_LEN: usize = 16;
+pub const USER: &str = "trichter";
+
+/// Hex digits of a derived password.
+const PASSWORD_LEN: usize = 32;
+/// `USER ":" password` in base64, padded.
+const BASIC_LEN: usize = (USER.len() + 1 + PASSWORD_LEN).div_ceil(3) * 4;
+
+/// The secret of a single device. It never shows up in debug output.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct FactorySecret([u8; SECRET_LEN]);
+
+impl FactorySecret {
+    /// `None` for an unset secret, i.e. all bits clear or all set.
+    pub fn new(secret: [u8; SECRET_LEN]) -> Option<Self> {
+        let unset = secret.iter().all(|&b| b == 0) || secret.iter().all(|&b| b == 0xff);
+        (!unset).then_some(Self(secret))
+    }
+
+    /// The password of the management interface.
+    pub fn management_password(&self) -> String<PASSWORD_LEN> {
+        self.derive(&[b"management"])
+    }
+
+    /// What has to accompany the credentials when the device is provisioned.
+    pub fn provisioning_token(&self) -> String<PASSWORD_LEN> {
+        self.derive(&[b"provisioning"])
+    }
+
+    /// Whether `revocation` proves that the backend revoked the credentials
+    /// of `device_id`, in answer to a request with `nonce`.
+    pub fn verifies_revocation(&self, device_id: &str, nonce: &str, revocation: &str) -> bool {
+        let expected = self.derive(&[b"revoked\n", device_id.as_bytes(), b"\n", nonce.as_bytes()]);
+        constant_time_eq(revocation.trim().as_bytes(), expected.as_bytes())
+    }
+
+    /// Whether `token` is the provisioning token of the device.
+    pub fn authorizes_provisioning(&self, token: &str) -> bool {
+        constant_time_eq(
+            token.trim().as_bytes(),
+            self.provisioning_token().as_bytes(),
+        )
+    }
+
+    /// Whether the value of an `Authorization` header carries the
+    /// management credentials.
+    pub fn authorizes(&self, authorization: &str) -> bool {
+        let Some(credentials) = authorization.trim().strip_prefix("Basic ") else {
+            return false;
+        };
+        let mut plain: String<{ USER.len() + 1 + PASSWORD_LEN }> = String::new();
+        let _ = write!(plain, "{}:{}", USER, self.management_password());
+        let mut expected: String<BASIC_LEN> = String::new();
+        for chunk in plain.as_bytes().chunks(3) {
+            // cannot fail, the length is exact
+            let _ = write_base64(chunk, &mut expected);
+        }
+        constant_time_eq(credentials.trim().as_bytes(), expected.as_bytes())
+    }
+
+    /// The first `PASSWORD_LEN` hex digits of the HMAC over the `purpose`
+    /// parts.
+    fn derive(&self, purpose: &[&[u8]]) -> String<PASSWORD_LEN> {
+        // cannot fail, HMAC takes keys of any length
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.0).unwrap();
+        for part in purpose {
+            mac.update(part);
+        }
+        let digest = mac.finalize().into_bytes();
+        let mut password = String::new();
+        for byte in &digest[..PASSWORD_LEN / 2] {
+            let _ = write!(password, "{:02x}", byte);
+        }
+        password
+    }
+}
+
+impl fmt::Debug for FactorySecret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("FactorySecret(..)")
+    }
+}
+
+/// Compares without returning early, so the time taken does not tell how
+/// much of a guess was right.
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn secret() -> FactorySecret {
+        FactorySecret::new(core::array::from_fn(|i| i as u8)).unwrap()
+    }
+
+    #[test]
+    fn rejects_unset_secrets() {
+        assert_eq!(FactorySecret::new([0; SECRET_LEN]), None);
+        assert_eq!(FactorySecret::new([0xff; SECRET_LEN]), None);
+    }
+
+    #[test]
+    fn derives_the_management_password() {
+        // python: hmac.new(bytes(range(16)), b"management", hashlib.sha256).hexdigest()[:32]
+        assert_eq!(
+            secret().management_password(),
+            "32220c401f88ae47f2ae43980ccb7979"
+        );
+    }
+
+    #[test]
+    fn derives_the_provisioning_token() {
+        // python: hmac.new(bytes(range(16)), b"provisioning", hashlib.sha256).hexdigest()[:32]
+        let token = "e50dc107d59e8e838efea26a875ac45f";
+        assert_eq!(secret().provisioning_token(), token);
+        assert!(secret().authorizes_provisioning(token));
+        assert!(!secret().authorizes_provisioning(&token[1..]));
+        assert!(!secret().authorizes_provisioning(&secret().management_password()));
+        let other = FactorySecret::new([1; SECRET_LEN]).unwrap();
+        assert!(!other.authorizes_provisioning(token));
+    }
+
+    #[test]
+    fn verifies_revocations() {
+        // python: hmac.new(bytes(range(16)),
+        //     b"revoked\ntrichter-0042\n00112233445566778899aabbccddeeff",
+        //     hashlib.sha256).hexdigest()[:32]
+        let revocation = "108074a0ca6a852587e17061cf6ad85d";
+        let nonce = "00112233445566778899aabbccddeeff";
+        assert!(secret().verifies_revocation("trichter-0042", nonce, revocation));
+        assert!(!secret().verifies_revocation("trichter-0043", nonce, revocation));
+        assert!(!secret().verifies_revocation("trichter-0042", &nonce[1..], revocation));
+        assert!(!secret().verifies_revocation("trichter-0042", nonce, ""));
+        let other = FactorySecret::new([1; SECRET_LEN]).unwrap();
+        assert!(!other.verifies_revocation("trichter-0042", nonce, revocation));
+    }
+
+    #[test]
+    fn authorizes_basic_auth_with_the_password() {
+        // python: base64.b64encode(b"trichter:" + password)
+        let header = "Basic dHJpY2h0ZXI6MzIyMjBjNDAxZjg4YWU0N2YyYWU0Mzk4MGNjYjc5Nzk=";
+        assert!(secret().authorizes(header));
+        assert!(secret().authorizes(&std::format!(" {header} ")));
+
+        let other = FactorySecret::new([1; SECRET_LEN]).unwrap();
+        assert!(!other.authorizes(header));
+        assert!(!secret().authorizes(&header[..header.len() - 1]));
+        assert!(!secret().authorizes("Basic dHJpY2h0ZXI6c3VwZXItc2FmZS1wYXNzd29yZA=="));
+        assert!(!secret().authorizes(header.trim_start_matches("Basic ")));
+    }
+
+    #[test]
+    fn debug_output_hides_the_secret() {
+        assert_eq!(std::format!("{:?}", secret()), "FactorySecret(..)");
+    }
+}
@@ -0,0 +1,172 @@
+//! Glitch rejection for sensor pulses.
+//!
+//! The flow sensor can physically not produce pulses faster than a few
+//! hundred Hz, anything closer together than `min_interval_us` is noise picked
+//! up by the sensor line. Such pulses are dropped and counted.
+
+pub struct PulseFilter {
+    min_interval_us: u64,
+    /// When counting started, bounds the first batch.
+    reset_us: u64,
+    last_accepted_us: Option<u64>,
+    rejected: u32,
+}
+
+impl PulseFilter {
+    pub fn new(min_interval_us: u64) -> Self {
+        Self {
+            min_interval_us,
+            reset_us: 0,
+            last_accepted_us: None,
+            rejected: 0,
+        }
+    }
+
+    pub fn min_interval_us(&self) -> u64 {
+        self.min_interval_us
+    }
+
+    pub fn set_min_interval_us(&mut self, min_interval_us: u64) {
+        self.min_interval_us = min_interval_us;
+    }
+
+    /// Pulses rejected since the last [`Self::reset`].
+    pub fn rejected(&self) -> u32 {
+        self.rejected
+    }
+
+    /// Forgets all pulses, counting starts again at `at_us`.
+    pub fn reset(&mut self, at_us: u64) {
+        self.reset_us = at_us;
+        self.last_accepted_us = None;
+        self.rejected = 0;
+    }
+
+    /// Filters `count` pulses observed at `at_us` and returns how many of
+    /// them are plausible.
+    ///
+    /// Sources that count in hardware report several pulses at once without
+    /// individual timestamps. For those at most one pulse per
+    /// `min_interval_us` since the last accepted pulse is let through. The
+    /// first batch is measured against the last reset instead, and always
+    /// keeps at least one pulse.
+    pub fn accept(&mut self, at_us: u64, count: u32) -> u32 {
+        if count == 0 {
+            return 0;
+        }
+
+        let allowed = match self.last_accepted_us {
+            _ if self.min_interval_us == 0 => count,
+            None => {
+                let elapsed = at_us.saturating_sub(self.reset_us);
+                (elapsed / self.min_interval_us).clamp(1, count as u64) as u32
+            }
+            Some(last_us) => {
+                let elapsed = at_us.saturating_sub(last_us);
+                (elapsed / self.min_interval_us).min(count as u64) as u32
+            }
+        };
+
+        self.rejected += count - allowed;
+        if allowed > 0 {
+            self.last_accepted_us = Some(at_us);
+        }
+        allowed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Runs single pulses through the filter and returns the accepted ones.
+    fn filter(filter: &mut PulseFilter, pulses: &[u64]) -> Vec<u64> {
+        pulses
+            .iter()
+            .copied()
+            .filter(|at| filter.accept(*at, 1) == 1)
+            .collect()
+    }
+
+    #[test]
+    fn clean_signal_passes() {
+        let mut f = PulseFilter::new(2_000);
+        let pulses: Vec<u64> = (0..50).map(|i| i * 30_000).collect();
+        assert_eq!(filter(&mut f, &pulses), pulses);
+        assert_eq!(f.rejected(), 0);
+    }
+
+    #[test]
+    fn ringing_after_pulse_is_dropped() {
+        let mut f = PulseFilter::new(2_000);
+        let accepted = filter(
+            &mut f,
+            &[0, 200, 450, 1_999, 30_000, 30_100, 60_000, 61_500, 63_600],
+        );
+        assert_eq!(accepted, vec![0, 30_000, 60_000, 63_600]);
+        assert_eq!(f.rejected(), 5);
+    }
+
+    #[test]
+    fn interval_boundary_is_accepted() {
+        let mut f = PulseFilter::new(2_000);
+        assert_eq!(filter(&mut f, &[10_000, 12_000]), vec![10_000, 12_000]);
+    }
+
+    #[test]
+    fn rejected_pulses_do_not_extend_the_dead_time() {
+        let mut f = PulseFilter::new(2_000);
+        // a burst of spikes every 1.5 ms, measured from the last accepted one
+        let accepted = filter(&mut f, &[0, 1_500, 3_000, 4_500, 6_000]);
+        assert_eq!(accepted, vec![0, 3_000, 6_000]);
+    }
+
+    #[test]
+    fn zero_interval_disables_filter() {
+        let mut f = PulseFilter::new(0);
+        assert_eq!(filter(&mut f, &[0, 0, 1, 1]).len(), 4);
+        assert_eq!(f.accept(5, 10), 10);
+        assert_eq!(f.rejected(), 0);
+    }
+
+    #[test]
+    fn batches_are_limited_by_elapsed_time() {
+        let mut f = PulseFilter::new(2_000);
+        f.reset(0);
+        assert_eq!(f.accept(1_000, 3), 1);
+        assert_eq!(f.accept(5_000, 3), 2);
+        assert_eq!(f.accept(25_000, 3), 3);
+        assert_eq!(f.rejected(), 3);
+    }
+
+    #[test]
+    fn first_batch_is_limited_by_time_since_reset() {
+        let mut f = PulseFilter::new(2_000);
+        f.reset(100_000);
+        // a counter read 5 ms after arming holds up to 2 real pulses
+        assert_eq!(f.accept(105_000, 4), 2);
+        assert_eq!(f.rejected(), 2);
+
+        f.reset(200_000);
+        assert_eq!(f.accept(10_000_000, 40), 40);
+        assert_eq!(f.rejected(), 0);
+    }
+
+    #[test]
+    fn fully_rejected_batch_keeps_reference() {
+        let mut f = PulseFilter::new(2_000);
+        f.accept(0, 1);
+        assert_eq!(f.accept(1_000, 4), 0);
+        assert_eq!(f.accept(2_000, 1), 1);
+        assert_eq!(f.rejected(), 4);
+    }
+
+    #[test]
+    fn reset_clears_history() {
+        let mut f = PulseFilter::new(2_000);
+        filter(&mut f, &[0, 100]);
+        f.reset(150);
+        assert_eq!(f.rejected(), 0);
+        assert_eq!(f.accept(200, 1), 1);
+    }
+}
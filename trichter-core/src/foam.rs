@@ -0,0 +1,465 @@
+//! Foam and air bubble detection from the regularity of the pulses.
+//!
+//! Liquid spins the rotor of the sensor evenly, consecutive pulse intervals
+//! only drift slowly with the flow rate. Foam and air make it spin
+//! erratically, which inflates the pulse count. The [`FoamDetector`] splits a
+//! session into fixed windows and computes two metrics per window:
+//!
+//! - the variation, the standard deviation of the intervals relative to their
+//!   mean, which also rises with a changing flow rate
+//! - the irregularity, the mean difference between consecutive intervals
+//!   relative to the mean interval, which does not
+//!
+//! Windows above the irregularity limit are foam. Intervals longer than
+//! `gap_us` are pauses and left out. Windows with too few pulses to tell take
+//! the kind of the window before, liquid at the start. Consecutive windows of
+//! the same kind form the segments of the [`FoamAnalysis`].
+//!
+//! Pulses counted in hardware arrive in batches sharing one timestamp. The
+//! pulses of a batch share the time since the event before evenly, so only
+//! the rate per event is known. Windows made up of batches for the most part
+//! are not classified either, their intervals say nothing about the rotor.
+
+use heapless::Vec;
+
+pub const MAX_SEGMENTS: usize = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct FoamLimits {
+    pub window_us: u64,
+    /// Windows with fewer intervals are not classified.
+    pub min_intervals: u32,
+    /// Longer intervals are pauses, not irregularities.
+    pub gap_us: u64,
+    pub max_irregularity_percent: u32,
+}
+
+impl FoamLimits {
+    /// What the firmware runs with, the golden traces are checked against it.
+    pub const DEFAULT: Self = Self {
+        window_us: 250_000,
+        min_intervals: 8,
+        gap_us: 100_000,
+        max_irregularity_percent: 30,
+    };
+}
+
+/// How much a foam pulse counts towards the volume in the firmware, 0
+/// excludes foam.
+pub const FOAM_WEIGHT_PERCENT: u32 = 0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum FlowKind {
+    Liquid,
+    Foam,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Segment {
+    pub kind: FlowKind,
+    /// Start of the first and end of the last window of the segment, as
+    /// offsets from the first pulse of the session.
+    pub start_us: u64,
+    pub end_us: u64,
+    pub pulses: u32,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FoamAnalysis {
+    segments: Vec<Segment, MAX_SEGMENTS>,
+    truncated: bool,
+    pub liquid_pulses: u32,
+    pub foam_pulses: u32,
+    /// Mean distance of the classified windows from the limit, 0 if no
+    /// window had enough pulses.
+    pub confidence_percent: u32,
+    /// Pulse weighted means over the classified windows.
+    pub variation_percent: u32,
+    pub irregularity_percent: u32,
+}
+
+impl FoamAnalysis {
+    pub fn segments(&self) -> &[Segment] {
+        &self.segments
+    }
+
+    /// More than [`MAX_SEGMENTS`] segments, the pulses of the remaining ones
+    /// are still counted.
+    pub fn truncated(&self) -> bool {
+        self.truncated
+    }
+
+    pub fn is_foamy(&self) -> bool {
+        self.foam_pulses > 0
+    }
+
+    /// Pulses that count towards the volume, foam pulses only count with
+    /// `foam_weight_percent`.
+    pub fn counted_pulses(&self, foam_weight_percent: u32) -> u32 {
+        let foam = self.foam_pulses as u64 * foam_weight_percent.min(100) as u64 / 100;
+        self.liquid_pulses + foam as u32
+    }
+
+    /// Like [`Self::counted_pulses`] for the `pulses` from `start_us` to
+    /// `end_us`, e.g. a gulp. Their foam pulses are estimated from the foam
+    /// segments overlapping them.
+    pub fn counted_pulses_within(
+        &self,
+        start_us: u64,
+        end_us: u64,
+        pulses: u32,
+        foam_weight_percent: u32,
+    ) -> u32 {
+        // the last pulse is part of the span
+        let end_us = end_us + 1;
+        let foam: u64 = self
+            .segments
+            .iter()
+            .filter(|segment| segment.kind == FlowKind::Foam)
+            .map(|segment| {
+                let overlap_us = end_us
+                    .min(segment.end_us)
+                    .saturating_sub(start_us.max(segment.start_us));
+                let segment_us = (segment.end_us - segment.start_us).max(1);
+                segment.pulses as u64 * overlap_us / segment_us
+            })
+            .sum();
+        let foam = foam.min(pulses as u64);
+        let counted = pulses as u64 - foam + foam * foam_weight_percent.min(100) as u64 / 100;
+        counted as u32
+    }
+
+    fn push(&mut self, kind: FlowKind, start_us: u64, end_us: u64, pulses: u32) {
+        match kind {
+            FlowKind::Liquid => self.liquid_pulses += pulses,
+            FlowKind::Foam => self.foam_pulses += pulses,
+        }
+        if let Some(last) = self.segments.last_mut().filter(|last| last.kind == kind) {
+            last.end_us = end_us;
+            last.pulses += pulses;
+            return;
+        }
+        let segment = Segment {
+            kind,
+            start_us,
+            end_us,
+            pulses,
+        };
+        if self.segments.push(segment).is_err() {
+            self.truncated = true;
+        }
+    }
+}
+
+/// Interval statistics of the current window.
+#[derive(Debug, Clone, Copy, Default)]
+struct Window {
+    pulses: u32,
+    /// Pulses that arrived in batches of more than one.
+    batched: u32,
+    intervals: u32,
+    sum_us: u64,
+    sum_sq: u64,
+    diffs: u32,
+    diff_sum_us: u64,
+}
+
+pub struct FoamDetector {
+    limits: FoamLimits,
+    first_us: u64,
+    window_start_us: Option<u64>,
+    last_us: u64,
+    last_interval_us: Option<u64>,
+    window: Window,
+    analysis: FoamAnalysis,
+    classified_pulses: u64,
+    margin_sum: u64,
+    variation_sum: u64,
+    irregularity_sum: u64,
+}
+
+impl FoamDetector {
+    pub fn new(limits: FoamLimits) -> Self {
+        Self {
+            limits: FoamLimits {
+                window_us: limits.window_us.max(1),
+                ..limits
+            },
+            first_us: 0,
+            window_start_us: None,
+            last_us: 0,
+            last_interval_us: None,
+            window: Window::default(),
+            analysis: FoamAnalysis::default(),
+            classified_pulses: 0,
+            margin_sum: 0,
+            variation_sum: 0,
+            irregularity_sum: 0,
+        }
+    }
+
+    pub fn clear(&mut self) {
+        *self = Self::new(self.limits);
+    }
+
+    /// Records `count` pulses that arrived at `at_us`, the first ones start
+    /// the first window.
+    pub fn record(&mut self, at_us: u64, count: u32) {
+        if count == 0 {
+            return;
+        }
+        let batched = if count > 1 { count } else { 0 };
+        let Some(mut window_start_us) = self.window_start_us else {
+            self.first_us = at_us;
+            self.window_start_us = Some(at_us);
+            self.last_us = at_us;
+            self.window.pulses = count;
+            self.window.batched = batched;
+            return;
+        };
+
+        let window_us = self.limits.window_us;
+        if at_us >= window_start_us + window_us {
+            self.close_window(window_start_us);
+            window_start_us += (at_us - window_start_us) / window_us * window_us;
+            self.window_start_us = Some(window_start_us);
+        }
+
+        let elapsed_us = at_us.saturating_sub(self.last_us);
+        self.last_us = at_us;
+        self.window.pulses += count;
+        self.window.batched += batched;
+        if elapsed_us > self.limits.gap_us {
+            self.last_interval_us = None;
+            return;
+        }
+
+        // the intervals within a batch are all the same
+        let interval_us = elapsed_us / count as u64;
+        self.window.intervals += count;
+        self.window.sum_us += interval_us * count as u64;
+        self.window.sum_sq += interval_us * interval_us * count as u64;
+        self.window.diffs += count - 1;
+        if let Some(last_interval_us) = self.last_interval_us {
+            self.window.diffs += 1;
+            self.window.diff_sum_us += interval_us.abs_diff(last_interval_us);
+        }
+        self.last_interval_us = Some(interval_us);
+    }
+
+    /// Classifies the last window and hands out the analysis.
+    pub fn finish(&mut self) -> FoamAnalysis {
+        if let Some(window_start_us) = self.window_start_us {
+            self.close_window(window_start_us);
+        }
+        let mut analysis = core::mem::take(&mut self.analysis);
+        let mean = |sum: u64| sum.checked_div(self.classified_pulses).unwrap_or(0) as u32;
+        analysis.confidence_percent = mean(self.margin_sum);
+        analysis.variation_percent = mean(self.variation_sum);
+        analysis.irregularity_percent = mean(self.irregularity_sum);
+        self.clear();
+        analysis
+    }
+
+    fn close_window(&mut self, start_us: u64) {
+        let window = core::mem::take(&mut self.window);
+        if window.pulses == 0 {
+            return;
+        }
+        let start_us = start_us - self.first_us;
+        let end_us = start_us + self.limits.window_us;
+
+        let sparse = window.intervals < self.limits.min_intervals.max(2) || window.sum_us == 0;
+        if sparse || window.batched * 2 > window.pulses {
+            let kind = self
+                .analysis
+                .segments
+                .last()
+                .map_or(FlowKind::Liquid, |last| last.kind);
+            self.analysis.push(kind, start_us, end_us, window.pulses);
+            return;
+        }
+
+        let n = window.intervals as u64;
+        let mean_us = window.sum_us / n;
+        let variance = (n * window.sum_sq).saturating_sub(window.sum_us * window.sum_us) / (n * n);
+        let variation = (variance.isqrt() * 100 / mean_us.max(1)) as u32;
+        let irregularity = match window.diffs {
+            0 => 0,
+            diffs => (window.diff_sum_us * 100 / diffs as u64 / mean_us.max(1)) as u32,
+        };
+
+        let limit = self.limits.max_irregularity_percent.max(1);
+        let kind = if irregularity > limit {
+            FlowKind::Foam
+        } else {
+            FlowKind::Liquid
+        };
+        let margin = (irregularity.abs_diff(limit) as u64 * 100 / limit as u64).min(100);
+
+        let pulses = window.pulses as u64;
+        self.classified_pulses += pulses;
+        self.margin_sum += margin * pulses;
+        self.variation_sum += variation as u64 * pulses;
+        self.irregularity_sum += irregularity as u64 * pulses;
+        self.analysis.push(kind, start_us, end_us, window.pulses);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LIMITS: FoamLimits = FoamLimits {
+        window_us: 250_000,
+        min_intervals: 8,
+        gap_us: 100_000,
+        max_irregularity_percent: 30,
+    };
+
+    /// Deterministic intervals between `min_us` and `max_us`.
+    fn erratic(start_us: u64, count: usize, min_us: u64, max_us: u64) -> std::vec::Vec<u64> {
+        let mut seed: u32 = 0x2545_f491;
+        let mut at_us = start_us;
+        (0..count)
+            .map(|_| {
+                seed = seed.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+                at_us += min_us + (seed >> 8) as u64 % (max_us - min_us);
+                at_us
+            })
+            .collect()
+    }
+
+    fn steady(start_us: u64, count: u64, interval_us: u64) -> std::vec::Vec<u64> {
+        (0..count).map(|i| start_us + i * interval_us).collect()
+    }
+
+    fn analyse(pulses: &[u64]) -> FoamAnalysis {
+        let mut detector = FoamDetector::new(LIMITS);
+        for at in pulses {
+            detector.record(*at, 1);
+        }
+        detector.finish()
+    }
+
+    #[test]
+    fn steady_flow_is_liquid() {
+        let analysis = analyse(&steady(0, 200, 12_500));
+        assert!(!analysis.is_foamy());
+        assert_eq!(analysis.liquid_pulses, 200);
+        assert_eq!(analysis.segments().len(), 1);
+        assert_eq!(analysis.irregularity_percent, 0);
+        assert_eq!(analysis.confidence_percent, 100);
+    }
+
+    #[test]
+    fn changing_rate_and_pauses_are_liquid() {
+        // slowly speeding up, with a swallow pause in the middle
+        let mut pulses = std::vec::Vec::new();
+        let mut at_us = 0;
+        for i in 0..300 {
+            at_us += 20_000 - i * 30;
+            if i == 150 {
+                at_us += 300_000;
+            }
+            pulses.push(at_us);
+        }
+        let analysis = analyse(&pulses);
+        assert!(!analysis.is_foamy());
+        assert!(analysis.irregularity_percent < LIMITS.max_irregularity_percent / 2);
+    }
+
+    #[test]
+    fn erratic_pulses_are_foam() {
+        let mut pulses = steady(0, 160, 12_500);
+        pulses.extend(erratic(2_000_000, 120, 3_000, 30_000));
+        let analysis = analyse(&pulses);
+
+        assert!(analysis.is_foamy());
+        let segments = analysis.segments();
+        assert_eq!(segments.len(), 2);
+        assert_eq!(segments[0].kind, FlowKind::Liquid);
+        assert_eq!(segments[1].kind, FlowKind::Foam);
+        assert_eq!(segments[1].start_us, 2_000_000);
+        assert_eq!(analysis.liquid_pulses, 160);
+        assert_eq!(analysis.foam_pulses, 120);
+        assert!(analysis.confidence_percent > 50);
+    }
+
+    #[test]
+    fn batched_steady_flow_is_liquid() {
+        // 80 Hz read out every 50 ms, as a busy executor would
+        let mut detector = FoamDetector::new(LIMITS);
+        let pulses = steady(0, 400, 12_500);
+        for chunk in pulses.chunk_by(|a, b| a / 50_000 == b / 50_000) {
+            let at_us = (chunk[0] / 50_000 + 1) * 50_000;
+            detector.record(at_us, chunk.len() as u32);
+        }
+        let analysis = detector.finish();
+        assert!(!analysis.is_foamy());
+        assert_eq!(analysis.liquid_pulses, 400);
+    }
+
+    #[test]
+    fn occasional_batches_spread_over_their_interval() {
+        // every tenth event carries two pulses after twice the interval
+        let mut detector = FoamDetector::new(LIMITS);
+        let mut at_us = 0;
+        for i in 0..200 {
+            let count = if i % 10 == 9 { 2 } else { 1 };
+            at_us += 12_500 * count as u64;
+            detector.record(at_us, count);
+        }
+        let analysis = detector.finish();
+        assert!(!analysis.is_foamy());
+        assert_eq!(analysis.liquid_pulses, 220);
+        assert_eq!(analysis.irregularity_percent, 0);
+        assert_eq!(analysis.confidence_percent, 100);
+    }
+
+    #[test]
+    fn foam_pulses_are_discounted() {
+        let analysis = FoamAnalysis {
+            liquid_pulses: 100,
+            foam_pulses: 50,
+            ..Default::default()
+        };
+        assert_eq!(analysis.counted_pulses(0), 100);
+        assert_eq!(analysis.counted_pulses(50), 125);
+        assert_eq!(analysis.counted_pulses(200), 150);
+    }
+
+    #[test]
+    fn foam_pulses_of_a_span_are_discounted() {
+        let mut analysis = FoamAnalysis::default();
+        analysis.push(FlowKind::Liquid, 0, 1_000_000, 80);
+        analysis.push(FlowKind::Foam, 1_000_000, 2_000_000, 100);
+        analysis.push(FlowKind::Liquid, 2_000_000, 3_000_000, 80);
+
+        assert_eq!(analysis.counted_pulses_within(0, 999_999, 80, 0), 80);
+        assert_eq!(
+            analysis.counted_pulses_within(1_500_000, 2_499_999, 90, 0),
+            40
+        );
+        assert_eq!(
+            analysis.counted_pulses_within(1_500_000, 2_499_999, 90, 50),
+            65
+        );
+        assert_eq!(analysis.counted_pulses_within(0, 2_999_999, 260, 0), 160);
+        // never more foam than pulses
+        assert_eq!(
+            analysis.counted_pulses_within(1_000_000, 1_999_999, 50, 0),
+            0
+        );
+    }
+
+    #[test]
+    fn sparse_windows_are_not_classified() {
+        let analysis = analyse(&steady(0, 5, 40_000));
+        assert_eq!(analysis.liquid_pulses, 5);
+        assert_eq!(analysis.confidence_percent, 0);
+    }
+}
@@ -0,0 +1,200 @@
+//! Gulps within a session.
+//!
+//! Pauses shorter than the idle timeout do not end a session, but a pause of
+//! at least `min_pause_us` (taking a breath) splits it into gulps. The
+//! minimum pause has to stay below the idle timeout, see
+//! [`Gulps::for_idle_timeout`]. Like the
+//! [`FlowProfile`](crate::profile::FlowProfile), the gulps are bounded: past
+//! [`MAX_GULPS`] they are still counted, but not listed.
+
+use heapless::Vec;
+
+use crate::session::flow_duration_us;
+
+pub const MAX_GULPS: usize = 16;
+pub const DEFAULT_MIN_PAUSE_US: u64 = 200_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Gulp {
+    /// Offsets of the first and last pulse from the first pulse of the session.
+    pub start_us: u64,
+    pub end_us: u64,
+    pub pulses: u32,
+}
+
+impl Gulp {
+    /// Span plus one mean pulse interval like the duration of the session,
+    /// 0 for a single pulse.
+    pub fn duration_us(&self) -> u64 {
+        flow_duration_us(self.end_us - self.start_us, self.pulses)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Gulps {
+    min_pause_us: u64,
+    first_us: Option<u64>,
+    last_us: u64,
+    gulps: Vec<Gulp, MAX_GULPS>,
+    count: u32,
+    longest_pause_us: u64,
+    truncated: bool,
+}
+
+impl Gulps {
+    pub fn new(min_pause_us: u64) -> Self {
+        Self {
+            min_pause_us,
+            first_us: None,
+            last_us: 0,
+            gulps: Vec::new(),
+            count: 0,
+            longest_pause_us: 0,
+            truncated: false,
+        }
+    }
+
+    /// Gulps of sessions that end after `idle_timeout_us` without a pulse.
+    /// Only shorter pauses can split gulps, so the minimum pause is at most
+    /// half of it.
+    pub fn for_idle_timeout(idle_timeout_us: u64) -> Self {
+        Self::new(DEFAULT_MIN_PAUSE_US.min(idle_timeout_us / 2))
+    }
+
+    pub fn clear(&mut self) {
+        *self = Self::new(self.min_pause_us);
+    }
+
+    /// Records a pulse, a pause of at least `min_pause_us` before it starts
+    /// a new gulp.
+    pub fn record(&mut self, at_us: u64) {
+        let first_us = *self.first_us.get_or_insert(at_us);
+        let offset_us = at_us.saturating_sub(first_us);
+        let pause_us = at_us.saturating_sub(self.last_us);
+        self.last_us = at_us;
+
+        if self.count > 0 && pause_us < self.min_pause_us {
+            if let Some(gulp) = self.gulps.last_mut().filter(|_| !self.truncated) {
+                gulp.end_us = offset_us;
+                gulp.pulses += 1;
+            }
+            return;
+        }
+
+        if self.count > 0 {
+            self.longest_pause_us = self.longest_pause_us.max(pause_us);
+        }
+        self.count += 1;
+        let gulp = Gulp {
+            start_us: offset_us,
+            end_us: offset_us,
+            pulses: 1,
+        };
+        if self.gulps.push(gulp).is_err() {
+            self.truncated = true;
+        }
+    }
+
+    /// The first [`MAX_GULPS`] gulps.
+    pub fn gulps(&self) -> &[Gulp] {
+        &self.gulps
+    }
+
+    /// Number of gulps, including the ones that did not fit.
+    pub fn count(&self) -> u32 {
+        self.count
+    }
+
+    /// Longest pause between two gulps, 0 for a single one.
+    pub fn longest_pause_us(&self) -> u64 {
+        self.longest_pause_us
+    }
+
+    pub fn truncated(&self) -> bool {
+        self.truncated
+    }
+}
+
+impl Default for Gulps {
+    fn default() -> Self {
+        Self::new(DEFAULT_MIN_PAUSE_US)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn gulps(pulses: &[u64]) -> Gulps {
+        let mut gulps = Gulps::new(200_000);
+        for at in pulses {
+            gulps.record(*at);
+        }
+        gulps
+    }
+
+    #[test]
+    fn no_pulses_no_gulps() {
+        let gulps = gulps(&[]);
+        assert_eq!(gulps.count(), 0);
+        assert!(gulps.gulps().is_empty());
+    }
+
+    #[test]
+    fn single_chug() {
+        let pulses: std::vec::Vec<u64> = (0..100).map(|i| 1_000_000 + i * 20_000).collect();
+        let gulps = gulps(&pulses);
+        assert_eq!(gulps.count(), 1);
+        assert_eq!(gulps.longest_pause_us(), 0);
+        assert_eq!(
+            gulps.gulps(),
+            [Gulp {
+                start_us: 0,
+                end_us: 1_980_000,
+                pulses: 100
+            }]
+        );
+    }
+
+    #[test]
+    fn pauses_split_gulps() {
+        // 150 ms is still the same gulp, 300 ms and 400 ms are breaths
+        let gulps = gulps(&[0, 20_000, 170_000, 470_000, 490_000, 890_000]);
+        assert_eq!(gulps.count(), 3);
+        assert_eq!(gulps.longest_pause_us(), 400_000);
+        let pulses: std::vec::Vec<u32> = gulps.gulps().iter().map(|g| g.pulses).collect();
+        assert_eq!(pulses, [3, 2, 1]);
+        assert_eq!(gulps.gulps()[1].start_us, 470_000);
+        // two pulses enclose one interval, the last one flows for another
+        assert_eq!(gulps.gulps()[1].duration_us(), 40_000);
+        assert_eq!(gulps.gulps()[2].duration_us(), 0);
+    }
+
+    #[test]
+    fn short_idle_timeouts_shorten_the_minimum_pause() {
+        let mut gulps = Gulps::for_idle_timeout(100_000);
+        for at in [0, 20_000, 80_000, 100_000] {
+            gulps.record(at);
+        }
+        assert_eq!(gulps.count(), 2);
+        assert_eq!(gulps.longest_pause_us(), 60_000);
+        assert_eq!(
+            Gulps::for_idle_timeout(2_000_000),
+            Gulps::new(DEFAULT_MIN_PAUSE_US)
+        );
+    }
+
+    #[test]
+    fn gulps_past_the_limit_are_counted() {
+        let pulses: std::vec::Vec<u64> = (0..MAX_GULPS as u64 + 4).map(|i| i * 500_000).collect();
+        let mut gulps = gulps(&pulses);
+        assert_eq!(gulps.count(), MAX_GULPS as u32 + 4);
+        assert_eq!(gulps.gulps().len(), MAX_GULPS);
+        assert!(gulps.truncated());
+
+        gulps.clear();
+        assert_eq!(gulps.count(), 0);
+        assert!(!gulps.truncated());
+    }
+}
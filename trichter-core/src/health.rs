@@ -0,0 +1,240 @@
+//! Health diagnostics of a sensor line.
+//!
+//! The line is pulled up, so an unplugged sensor looks exactly like an idle
+//! one. The [`HealthMonitor`] therefore combines periodic samples of the line
+//! level with edge statistics and reports three kinds of problems:
+//!
+//! - the line is stuck low without producing pulses (shorted or broken sensor)
+//! - no pulses at all for a long time while an event is running (unplugged)
+//! - more pulses per second than the sensor can physically produce (noise)
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct HealthLimits {
+    /// The line has to be low for this long without a pulse to be stuck.
+    pub stuck_low_us: u64,
+    /// Longest plausible silence during an active event.
+    pub silence_us: u64,
+    /// Pulses are counted in windows of this length...
+    pub window_us: u64,
+    /// ...and must not exceed this frequency.
+    pub max_frequency_hz: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct HealthStatus {
+    pub stuck_low: bool,
+    pub no_pulses: bool,
+    pub implausible_frequency: bool,
+    /// Pulses seen since the monitor was created, before glitch filtering.
+    pub pulses: u32,
+    pub last_pulse_us: Option<u64>,
+    /// Highest frequency of a completed window.
+    pub peak_frequency_hz: u32,
+}
+
+impl HealthStatus {
+    pub fn is_healthy(&self) -> bool {
+        !(self.stuck_low || self.no_pulses || self.implausible_frequency)
+    }
+}
+
+pub struct HealthMonitor {
+    limits: HealthLimits,
+    event_active: bool,
+    /// Start of the current silence, the last pulse or event start.
+    quiet_since_us: u64,
+    low_since_us: Option<u64>,
+    window_start_us: u64,
+    window_pulses: u32,
+    implausible: bool,
+    pulses: u32,
+    last_pulse_us: Option<u64>,
+    peak_frequency_hz: u32,
+}
+
+impl HealthMonitor {
+    /// A monitor for an event that is active from `now_us` on.
+    pub fn new(limits: HealthLimits, now_us: u64) -> Self {
+        Self {
+            limits,
+            event_active: true,
+            quiet_since_us: now_us,
+            low_since_us: None,
+            window_start_us: now_us,
+            window_pulses: 0,
+            implausible: false,
+            pulses: 0,
+            last_pulse_us: None,
+            peak_frequency_hz: 0,
+        }
+    }
+
+    /// Silence is only a problem while an event is running. Activating the
+    /// event restarts the silence timer.
+    pub fn set_event_active(&mut self, active: bool, now_us: u64) {
+        if active && !self.event_active {
+            self.quiet_since_us = now_us;
+        }
+        self.event_active = active;
+    }
+
+    pub fn event_active(&self) -> bool {
+        self.event_active
+    }
+
+    /// Records the line level sampled at `at_us`.
+    pub fn on_sample(&mut self, at_us: u64, low: bool) {
+        self.close_windows(at_us);
+        if !low {
+            self.low_since_us = None;
+        } else if self.low_since_us.is_none() {
+            self.low_since_us = Some(at_us);
+        }
+    }
+
+    /// Records `count` unfiltered pulses observed at `at_us`.
+    pub fn on_pulses(&mut self, at_us: u64, count: u32) {
+        if count == 0 {
+            return;
+        }
+        self.close_windows(at_us);
+        self.window_pulses = self.window_pulses.saturating_add(count);
+        self.pulses = self.pulses.saturating_add(count);
+        self.last_pulse_us = Some(at_us);
+        self.quiet_since_us = at_us;
+        // pulses prove the line is not stuck
+        self.low_since_us = None;
+    }
+
+    pub fn status(&self, now_us: u64) -> HealthStatus {
+        HealthStatus {
+            stuck_low: self
+                .low_since_us
+                .is_some_and(|since| now_us.saturating_sub(since) >= self.limits.stuck_low_us),
+            no_pulses: self.event_active
+                && now_us.saturating_sub(self.quiet_since_us) >= self.limits.silence_us,
+            implausible_frequency: self.implausible,
+            pulses: self.pulses,
+            last_pulse_us: self.last_pulse_us,
+            peak_frequency_hz: self.peak_frequency_hz,
+        }
+    }
+
+    /// Evaluates all windows that ended before `now_us`. The flag is cleared
+    /// again by the first plausible window.
+    fn close_windows(&mut self, now_us: u64) {
+        if self.limits.window_us == 0 {
+            return;
+        }
+        let elapsed = now_us.saturating_sub(self.window_start_us);
+        if elapsed < self.limits.window_us {
+            return;
+        }
+
+        let frequency_hz =
+            (self.window_pulses as u64 * 1_000_000 / self.limits.window_us).min(u32::MAX as u64);
+        let frequency_hz = frequency_hz as u32;
+        self.peak_frequency_hz = self.peak_frequency_hz.max(frequency_hz);
+        self.implausible = frequency_hz > self.limits.max_frequency_hz;
+
+        let windows = elapsed / self.limits.window_us;
+        if windows > 1 {
+            // the windows in between were empty
+            self.implausible = false;
+        }
+        self.window_start_us += windows * self.limits.window_us;
+        self.window_pulses = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECOND: u64 = 1_000_000;
+    const HOUR: u64 = 3_600 * SECOND;
+
+    const LIMITS: HealthLimits = HealthLimits {
+        stuck_low_us: 10 * SECOND,
+        silence_us: 2 * HOUR,
+        window_us: SECOND,
+        max_frequency_hz: 500,
+    };
+
+    #[test]
+    fn fresh_monitor_is_healthy() {
+        let monitor = HealthMonitor::new(LIMITS, 0);
+        assert!(monitor.status(SECOND).is_healthy());
+    }
+
+    #[test]
+    fn line_stuck_low_is_detected() {
+        let mut monitor = HealthMonitor::new(LIMITS, 0);
+        for s in 0..=10 {
+            monitor.on_sample(s * SECOND, true);
+        }
+        assert!(monitor.status(10 * SECOND).stuck_low);
+
+        monitor.on_sample(11 * SECOND, false);
+        assert!(!monitor.status(11 * SECOND).stuck_low);
+    }
+
+    #[test]
+    fn pulses_clear_stuck_low() {
+        let mut monitor = HealthMonitor::new(LIMITS, 0);
+        monitor.on_sample(0, true);
+        monitor.on_pulses(5 * SECOND, 3);
+        monitor.on_sample(6 * SECOND, true);
+        assert!(!monitor.status(12 * SECOND).stuck_low);
+        assert!(monitor.status(16 * SECOND).stuck_low);
+    }
+
+    #[test]
+    fn silence_during_event_is_detected() {
+        let mut monitor = HealthMonitor::new(LIMITS, 0);
+        monitor.on_pulses(HOUR, 10);
+        assert!(!monitor.status(3 * HOUR - 1).no_pulses);
+        assert!(monitor.status(3 * HOUR).no_pulses);
+        assert_eq!(monitor.status(3 * HOUR).last_pulse_us, Some(HOUR));
+    }
+
+    #[test]
+    fn silence_outside_of_event_is_fine() {
+        let mut monitor = HealthMonitor::new(LIMITS, 0);
+        monitor.set_event_active(false, 0);
+        assert!(!monitor.status(10 * HOUR).no_pulses);
+
+        // the silence timer starts with the event
+        monitor.set_event_active(true, 10 * HOUR);
+        assert!(!monitor.status(11 * HOUR).no_pulses);
+        assert!(monitor.status(12 * HOUR).no_pulses);
+    }
+
+    #[test]
+    fn implausible_frequency_is_flagged_until_clean_window() {
+        let mut monitor = HealthMonitor::new(LIMITS, 0);
+        monitor.on_pulses(SECOND / 2, 800);
+        monitor.on_sample(SECOND, false);
+        let status = monitor.status(SECOND);
+        assert!(status.implausible_frequency);
+        assert_eq!(status.peak_frequency_hz, 800);
+
+        monitor.on_pulses(SECOND + 1, 100);
+        monitor.on_sample(2 * SECOND, false);
+        let status = monitor.status(2 * SECOND);
+        assert!(!status.implausible_frequency);
+        assert_eq!(status.peak_frequency_hz, 800);
+        assert_eq!(status.pulses, 900);
+    }
+
+    #[test]
+    fn empty_windows_clear_the_flag() {
+        let mut monitor = HealthMonitor::new(LIMITS, 0);
+        monitor.on_pulses(0, 600);
+        monitor.on_sample(5 * SECOND, false);
+        assert!(!monitor.status(5 * SECOND).implausible_frequency);
+        assert_eq!(monitor.status(5 * SECOND).peak_frequency_hz, 600);
+    }
+}
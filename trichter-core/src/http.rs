@@ -0,0 +1,577 @@
+//! A small HTTP/1.1 response parser for the backend clients.
+//!
+//! The client reads into a fixed buffer until [`response_len`] reports a
+//! complete response, then [`parse_response`] splits it into status line,
+//! headers and body. The body is delimited by `Content-Length`, chunked
+//! transfer encoding or the end of the connection. Chunked bodies are decoded
+//! in place, so the body is always a single slice of the buffer.
+//!
+//! Whether the connection can be reused for the next request follows from
+//! the version, the `Connection` header and the framing, see
+//! [`HttpResponse::keep_alive`].
+//!
+//! Neither function panics on malformed input, which the mutation test at the
+//! bottom checks.
+
+use core::str;
+
+use heapless::Vec;
+
+pub const MAX_HEADERS: usize = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum HttpError {
+    StatusLine,
+    Header,
+    TooManyHeaders,
+    ContentLength,
+    Chunk,
+    /// The response ended before it was complete.
+    Incomplete,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Header<'a> {
+    pub name: &'a str,
+    pub value: &'a str,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct HttpResponse<'a> {
+    pub status: u16,
+    pub reason: &'a str,
+    headers: Vec<Header<'a>, MAX_HEADERS>,
+    pub body: &'a [u8],
+    keep_alive: bool,
+}
+
+impl<'a> HttpResponse<'a> {
+    pub fn headers(&self) -> &[Header<'a>] {
+        &self.headers
+    }
+
+    /// Value of the first header called `name`, ignoring case.
+    pub fn header(&self, name: &str) -> Option<&'a str> {
+        header(&self.headers, name)
+    }
+
+    pub fn is_success(&self) -> bool {
+        (200..300).contains(&self.status)
+    }
+
+    /// Whether the server keeps the connection open after this response.
+    ///
+    /// HTTP/1.1 connections persist unless the server sends
+    /// `Connection: close`, HTTP/1.0 ones only with `Connection: keep-alive`.
+    /// A body that ends with the connection always closes it.
+    pub fn keep_alive(&self) -> bool {
+        self.keep_alive
+    }
+
+    /// The `timeout` parameter of the `Keep-Alive` header, how long the
+    /// server keeps an idle connection open.
+    pub fn keep_alive_timeout_secs(&self) -> Option<u64> {
+        self.header("keep-alive")?
+            .split(',')
+            .filter_map(|param| param.split_once('='))
+            .find(|(name, _)| name.trim().eq_ignore_ascii_case("timeout"))
+            .and_then(|(_, value)| value.trim().parse().ok())
+    }
+
+    /// The `Date` header as seconds since the Unix epoch, the server's
+    /// clock at the time of the response.
+    pub fn date_unix_secs(&self) -> Option<u64> {
+        parse_date(self.header("date")?)
+    }
+
+    pub fn body_str(&self) -> Option<&'a str> {
+        str::from_utf8(self.body).ok()
+    }
+}
+
+/// Parses an IMF-fixdate (`Sun, 06 Nov 1994 08:49:37 GMT`), the only date
+/// format servers send today.
+fn parse_date(value: &str) -> Option<u64> {
+    const MONTHS: [&str; 12] = [
+        "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+    ];
+
+    let (_weekday, date) = value.trim().split_once(", ")?;
+    let mut parts = date.split(' ');
+    let day: u64 = parts.next()?.parse().ok()?;
+    let month = parts.next()?;
+    let month = MONTHS.iter().position(|name| *name == month)? as u64 + 1;
+    let year: u64 = parts.next()?.parse().ok()?;
+    let mut time = parts.next()?.split(':');
+    let (hour, minute, second): (u64, u64, u64) = (
+        time.next()?.parse().ok()?,
+        time.next()?.parse().ok()?,
+        time.next()?.parse().ok()?,
+    );
+    if parts.next()? != "GMT" || parts.next().is_some() || time.next().is_some() {
+        return None;
+    }
+    if !(1..=31).contains(&day)
+        || !(1970..=9999).contains(&year)
+        || hour > 23
+        || minute > 59
+        || second > 60
+    {
+        return None;
+    }
+
+    // days since the epoch of the civil date, shifted to start the year in
+    // March so the leap day comes last
+    let (y, m) = if month <= 2 {
+        (year - 1, month + 9)
+    } else {
+        (year, month - 3)
+    };
+    let era = y / 400;
+    let year_of_era = y % 400;
+    let day_of_year = (153 * m + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    let days = (era * 146_097 + day_of_era).checked_sub(719_468)?;
+
+    Some(days * 86_400 + hour * 3_600 + minute * 60 + second)
+}
+
+/// How the end of the body is found.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Framing {
+    Empty,
+    Length(usize),
+    Chunked,
+    /// The body ends with the connection.
+    UntilClose,
+}
+
+struct Head<'a> {
+    status: u16,
+    reason: &'a str,
+    headers: Vec<Header<'a>, MAX_HEADERS>,
+    framing: Framing,
+    keep_alive: bool,
+}
+
+/// Length of the complete response at the start of `buf`, `None` if more
+/// bytes are needed. A response without a length is never complete before
+/// the connection ends.
+pub fn response_len(buf: &[u8]) -> Result<Option<usize>, HttpError> {
+    let Some(head_len) = head_len(buf) else {
+        return Ok(None);
+    };
+    let head = parse_head(&buf[..head_len])?;
+    let body = &buf[head_len..];
+    let body_len = match head.framing {
+        Framing::Empty => Some(0),
+        Framing::Length(len) => Some(len).filter(|len| body.len() >= *len),
+        Framing::Chunked => chunked_len(body)?,
+        Framing::UntilClose => None,
+    };
+    Ok(body_len.map(|len| head_len + len))
+}
+
+/// Parses a response that [`response_len`] reported complete, or everything
+/// received until the connection ended.
+pub fn parse_response(buf: &mut [u8]) -> Result<HttpResponse<'_>, HttpError> {
+    let head_len = head_len(buf).ok_or(HttpError::Incomplete)?;
+    let (head, body) = buf.split_at_mut(head_len);
+    let head = parse_head(head)?;
+    let body: &[u8] = match head.framing {
+        Framing::Empty => &[],
+        Framing::Length(len) => body.get(..len).ok_or(HttpError::Incomplete)?,
+        Framing::Chunked => {
+            let len = decode_chunks(body)?;
+            &body[..len]
+        }
+        Framing::UntilClose => body,
+    };
+    Ok(HttpResponse {
+        status: head.status,
+        reason: head.reason,
+        headers: head.headers,
+        body,
+        keep_alive: head.keep_alive,
+    })
+}
+
+/// Length of the status line and headers including the empty line.
+fn head_len(buf: &[u8]) -> Option<usize> {
+    find(buf, b"\r\n\r\n").map(|end| end + 4)
+}
+
+fn parse_head(buf: &[u8]) -> Result<Head<'_>, HttpError> {
+    let head = str::from_utf8(buf).map_err(|_| HttpError::Header)?;
+    let mut lines = head.trim_end_matches("\r\n").split("\r\n");
+
+    let status_line = lines.next().unwrap_or_default();
+    let (version, rest) = status_line.split_once(' ').ok_or(HttpError::StatusLine)?;
+    let (code, reason) = rest.split_once(' ').unwrap_or((rest, ""));
+    if !matches!(version, "HTTP/1.1" | "HTTP/1.0")
+        || code.len() != 3
+        || !code.bytes().all(|b| b.is_ascii_digit())
+    {
+        return Err(HttpError::StatusLine);
+    }
+    let status = code.parse().map_err(|_| HttpError::StatusLine)?;
+
+    let mut headers = Vec::new();
+    for line in lines {
+        let (name, value) = line.split_once(':').ok_or(HttpError::Header)?;
+        if name.is_empty() || name.bytes().any(|b| b.is_ascii_whitespace()) {
+            return Err(HttpError::Header);
+        }
+        let value = value.trim();
+        headers
+            .push(Header { name, value })
+            .map_err(|_| HttpError::TooManyHeaders)?;
+    }
+
+    let framing = if matches!(status, 100..=199 | 204 | 304) {
+        Framing::Empty
+    } else if let Some(encoding) = header(&headers, "transfer-encoding") {
+        let last = encoding.rsplit(',').next().unwrap_or_default().trim();
+        if last.eq_ignore_ascii_case("chunked") {
+            Framing::Chunked
+        } else {
+            Framing::UntilClose
+        }
+    } else {
+        let mut length = None;
+        for header in headers
+            .iter()
+            .filter(|header| header.name.eq_ignore_ascii_case("content-length"))
+        {
+            let value = header.value;
+            if value.is_empty() || !value.bytes().all(|b| b.is_ascii_digit()) {
+                return Err(HttpError::ContentLength);
+            }
+            let value = value.parse().map_err(|_| HttpError::ContentLength)?;
+            if length.is_some_and(|length| length != value) {
+                return Err(HttpError::ContentLength);
+            }
+            length = Some(value);
+        }
+        length.map_or(Framing::UntilClose, Framing::Length)
+    };
+
+    let connection = header(&headers, "connection").unwrap_or_default();
+    let has_option = |option: &str| {
+        connection
+            .split(',')
+            .any(|token| token.trim().eq_ignore_ascii_case(option))
+    };
+    let keep_alive = framing != Framing::UntilClose
+        && !has_option("close")
+        && (version == "HTTP/1.1" || has_option("keep-alive"));
+
+    Ok(Head {
+        status,
+        reason,
+        headers,
+        framing,
+        keep_alive,
+    })
+}
+
+fn header<'a>(headers: &[Header<'a>], name: &str) -> Option<&'a str> {
+    headers
+        .iter()
+        .find(|header| header.name.eq_ignore_ascii_case(name))
+        .map(|header| header.value)
+}
+
+/// Start of the data and size of the chunk at `pos`, once its size line is
+/// complete.
+fn chunk_header(buf: &[u8], pos: usize) -> Result<Option<(usize, usize)>, HttpError> {
+    let Some(len) = find(&buf[pos..], b"\r\n") else {
+        return Ok(None);
+    };
+    let line = str::from_utf8(&buf[pos..pos + len]).map_err(|_| HttpError::Chunk)?;
+    // chunk extensions are ignored
+    let size = line.split(';').next().unwrap_or_default().trim();
+    if size.is_empty() || !size.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return Err(HttpError::Chunk);
+    }
+    let size = usize::from_str_radix(size, 16).map_err(|_| HttpError::Chunk)?;
+    Ok(Some((pos + len + 2, size)))
+}
+
+/// End of the chunk data at `start` including its line break.
+fn chunk_end(buf: &[u8], start: usize, size: usize) -> Result<Option<usize>, HttpError> {
+    let end = start
+        .checked_add(size)
+        .and_then(|end| end.checked_add(2))
+        .ok_or(HttpError::Chunk)?;
+    if buf.len() < end {
+        return Ok(None);
+    }
+    if &buf[end - 2..end] != b"\r\n" {
+        return Err(HttpError::Chunk);
+    }
+    Ok(Some(end))
+}
+
+/// Length of a complete chunked body including the trailers.
+fn chunked_len(buf: &[u8]) -> Result<Option<usize>, HttpError> {
+    let mut pos = 0;
+    loop {
+        let Some((start, size)) = chunk_header(buf, pos)? else {
+            return Ok(None);
+        };
+        if size == 0 {
+            return Ok(trailers_end(buf, start));
+        }
+        let Some(end) = chunk_end(buf, start, size)? else {
+            return Ok(None);
+        };
+        pos = end;
+    }
+}
+
+/// Trailers are skipped up to the empty line that ends them.
+fn trailers_end(buf: &[u8], mut pos: usize) -> Option<usize> {
+    loop {
+        let len = find(&buf[pos..], b"\r\n")?;
+        pos += len + 2;
+        if len == 0 {
+            return Some(pos);
+        }
+    }
+}
+
+/// Moves the data of all chunks to the start of `buf`, returns its length.
+fn decode_chunks(buf: &mut [u8]) -> Result<usize, HttpError> {
+    let mut pos = 0;
+    let mut len = 0;
+    loop {
+        let (start, size) = chunk_header(buf, pos)?.ok_or(HttpError::Incomplete)?;
+        if size == 0 {
+            return Ok(len);
+        }
+        pos = chunk_end(buf, start, size)?.ok_or(HttpError::Incomplete)?;
+        buf.copy_within(start..start + size, len);
+        len += size;
+    }
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CREATED: &[u8] = b"HTTP/1.1 201 Created\r\n\
+        Content-Type: application/json\r\n\
+        content-length: 11\r\n\
+        \r\n\
+        {\"id\": 42}\n";
+
+    const CHUNKED: &[u8] = b"HTTP/1.1 200 OK\r\n\
+        Transfer-Encoding: chunked\r\n\
+        \r\n\
+        4;ext=1\r\n\
+        {\"ok\r\n\
+        6\r\n\
+        \": tru\r\n\
+        2\r\n\
+        e}\r\n\
+        0\r\n\
+        X-Trailer: yes\r\n\
+        \r\n";
+
+    fn parse(response: &[u8]) -> Result<(u16, std::vec::Vec<u8>), HttpError> {
+        let mut buf = response.to_vec();
+        let response = parse_response(&mut buf)?;
+        Ok((response.status, response.body.to_vec()))
+    }
+
+    #[test]
+    fn parses_content_length_response() {
+        let mut buf = CREATED.to_vec();
+        let response = parse_response(&mut buf).unwrap();
+        assert_eq!(response.status, 201);
+        assert_eq!(response.reason, "Created");
+        assert!(response.is_success());
+        assert_eq!(response.headers().len(), 2);
+        assert_eq!(response.header("Content-Length"), Some("11"));
+        assert_eq!(response.header("x-missing"), None);
+        assert_eq!(response.body_str(), Some("{\"id\": 42}\n"));
+    }
+
+    #[test]
+    fn prefixes_are_incomplete() {
+        for len in 0..CREATED.len() {
+            assert_eq!(response_len(&CREATED[..len]), Ok(None), "prefix {len}");
+        }
+        assert_eq!(response_len(CREATED), Ok(Some(CREATED.len())));
+
+        // bytes after the response are not part of it
+        let mut pipelined = CREATED.to_vec();
+        pipelined.extend_from_slice(b"HTTP/1.1 200 OK\r\n");
+        assert_eq!(response_len(&pipelined), Ok(Some(CREATED.len())));
+    }
+
+    #[test]
+    fn decodes_chunked_body() {
+        for len in 0..CHUNKED.len() {
+            assert_eq!(response_len(&CHUNKED[..len]), Ok(None), "prefix {len}");
+        }
+        assert_eq!(response_len(CHUNKED), Ok(Some(CHUNKED.len())));
+        assert_eq!(parse(CHUNKED), Ok((200, b"{\"ok\": true}".to_vec())));
+    }
+
+    #[test]
+    fn body_without_length_ends_with_connection() {
+        let response = b"HTTP/1.0 200 OK\r\nServer: test\r\n\r\nhello";
+        assert_eq!(response_len(response), Ok(None));
+        assert_eq!(parse(response), Ok((200, b"hello".to_vec())));
+    }
+
+    #[test]
+    fn some_responses_have_no_body() {
+        let response = b"HTTP/1.1 204 No Content\r\nContent-Length: 5\r\n\r\n";
+        assert_eq!(response_len(response), Ok(Some(response.len())));
+        assert_eq!(parse(response), Ok((204, vec![])));
+    }
+
+    #[test]
+    fn connection_persistence() {
+        let keep_alive =
+            |response: &[u8]| parse_response(&mut response.to_vec()).unwrap().keep_alive();
+
+        assert!(keep_alive(CREATED));
+        assert!(keep_alive(CHUNKED));
+        assert!(!keep_alive(
+            b"HTTP/1.1 200 OK\r\nConnection: Close\r\nContent-Length: 0\r\n\r\n"
+        ));
+        assert!(!keep_alive(b"HTTP/1.1 200 OK\r\n\r\nuntil close"));
+        assert!(!keep_alive(b"HTTP/1.0 200 OK\r\nContent-Length: 0\r\n\r\n"));
+        assert!(keep_alive(
+            b"HTTP/1.0 200 OK\r\nConnection: keep-alive\r\nContent-Length: 0\r\n\r\n"
+        ));
+    }
+
+    #[test]
+    fn keep_alive_timeout() {
+        let mut response = b"HTTP/1.1 204 No Content\r\n\
+            Keep-Alive: max=100, timeout=5\r\n\
+            \r\n"
+            .to_vec();
+        let response = parse_response(&mut response).unwrap();
+        assert_eq!(response.keep_alive_timeout_secs(), Some(5));
+
+        let mut response = CREATED.to_vec();
+        let response = parse_response(&mut response).unwrap();
+        assert_eq!(response.keep_alive_timeout_secs(), None);
+    }
+
+    #[test]
+    fn date_header() {
+        let mut response = b"HTTP/1.1 204 No Content\r\n\
+            Date: Sun, 06 Nov 1994 08:49:37 GMT\r\n\
+            \r\n"
+            .to_vec();
+        let response = parse_response(&mut response).unwrap();
+        assert_eq!(response.date_unix_secs(), Some(784_111_777));
+
+        assert_eq!(parse_date("Thu, 01 Jan 1970 00:00:00 GMT"), Some(0));
+        assert_eq!(
+            parse_date("Thu, 29 Feb 2024 12:00:00 GMT"),
+            Some(1_709_208_000)
+        );
+        assert_eq!(
+            parse_date("Sun, 18 Oct 2026 09:21:32 GMT"),
+            Some(1_792_315_292)
+        );
+        assert_eq!(parse_date("Sun, 06 Nov 1994 08:49:37 CET"), None);
+        assert_eq!(parse_date("Sunday, 06-Nov-94 08:49:37 GMT"), None);
+        assert_eq!(parse_date("Sun, 06 Foo 1994 08:49:37 GMT"), None);
+        assert_eq!(parse_date("Sun, 06 Nov 1994 24:49:37 GMT"), None);
+        assert_eq!(parse_date("Sun, 06 Nov 99999999999999 08:49:37 GMT"), None);
+    }
+
+    #[test]
+    fn truncated_body_is_incomplete() {
+        assert_eq!(
+            parse(&CREATED[..CREATED.len() - 1]),
+            Err(HttpError::Incomplete)
+        );
+        assert_eq!(parse(&CHUNKED[..40]), Err(HttpError::Incomplete));
+        assert_eq!(parse(b"HTTP/1.1 200 OK\r\n"), Err(HttpError::Incomplete));
+    }
+
+    #[test]
+    fn rejects_malformed_responses() {
+        let cases: [(&[u8], HttpError); 7] = [
+            (b"HTTP/2 200 OK\r\n\r\n", HttpError::StatusLine),
+            (b"HTTP/1.1 20 OK\r\n\r\n", HttpError::StatusLine),
+            (b"HTTP/1.1 200 OK\r\nbroken\r\n\r\n", HttpError::Header),
+            (b"HTTP/1.1 200 OK\r\nBad Name: x\r\n\r\n", HttpError::Header),
+            (
+                b"HTTP/1.1 200 OK\r\nContent-Length: -1\r\n\r\n",
+                HttpError::ContentLength,
+            ),
+            (
+                b"HTTP/1.1 200 OK\r\nContent-Length: 1\r\nContent-Length: 2\r\n\r\n",
+                HttpError::ContentLength,
+            ),
+            (
+                b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\nzz\r\n",
+                HttpError::Chunk,
+            ),
+        ];
+        for (response, error) in cases {
+            assert_eq!(response_len(response), Err(error));
+        }
+
+        let mut many = std::vec::Vec::from(&b"HTTP/1.1 200 OK\r\n"[..]);
+        for i in 0..=MAX_HEADERS {
+            many.extend_from_slice(format!("X-{i}: {i}\r\n").as_bytes());
+        }
+        many.extend_from_slice(b"\r\n");
+        assert_eq!(response_len(&many), Err(HttpError::TooManyHeaders));
+    }
+
+    #[test]
+    fn mutated_responses_do_not_panic() {
+        let mut seed: u32 = 0x1234_5678;
+        let mut random = move |limit: usize| {
+            seed = seed.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+            (seed >> 8) as usize % limit.max(1)
+        };
+        const TOKENS: &[&[u8]] = &[b"\r\n", b":", b";", b"0", b"f", b" ", b"\xff", b"chunked"];
+
+        for sample in [CREATED, CHUNKED] {
+            for _ in 0..5_000 {
+                let mut buf = sample.to_vec();
+                for _ in 0..1 + random(4) {
+                    let at = random(buf.len());
+                    match random(3) {
+                        0 => buf[at] = random(256) as u8,
+                        1 => {
+                            buf.remove(at);
+                        }
+                        _ => {
+                            let token = TOKENS[random(TOKENS.len())];
+                            buf.splice(at..at, token.iter().copied());
+                        }
+                    }
+                }
+                buf.truncate(random(buf.len() + 1) + 1);
+
+                if let Ok(Some(len)) = response_len(&buf) {
+                    assert!(len <= buf.len());
+                    let _ = parse_response(&mut buf[..len]);
+                }
+                let _ = parse_response(&mut buf);
+            }
+        }
+    }
+}
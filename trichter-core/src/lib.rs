@@ -0,0 +1,50 @@
+#![cfg_attr(not(test), no_std)]
+
+//! Hardware independent logic of the trichter firmware.
+//!
+//! Everything in here works on plain microsecond timestamps so it can be driven
+//! by the embassy based drivers on the device and by unit tests on the host
+//! (`cargo test` inside this directory).
+//!
+//! Large state that is only needed sometimes, like the pulse capture, lives
+//! on the heap, so the crate needs a global allocator.
+
+extern crate alloc;
+
+pub mod backoff;
+pub mod calibration;
+pub mod calibration_run;
+pub mod capture;
+pub mod connection;
+pub mod credentials;
+pub mod duel;
+pub mod endpoint;
+pub mod factory;
+pub mod filter;
+pub mod foam;
+pub mod gulps;
+pub mod health;
+pub mod http;
+pub mod meter;
+pub mod mqtt;
+pub mod overrun;
+pub mod profile;
+pub mod queue;
+pub mod record;
+pub mod replay;
+pub mod session;
+pub mod settings;
+pub mod source;
+pub mod x509;
+
+/// Polls a future to completion, for futures that never actually wait like
+/// the ones of a [`replay::ReplaySource`].
+pub fn block_on<F: core::future::Future>(future: F) -> F::Output {
+    let mut future = core::pin::pin!(future);
+    let mut cx = core::task::Context::from_waker(core::task::Waker::noop());
+    loop {
+        if let core::task::Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+            return output;
+        }
+    }
+}
@@ -0,0 +1,461 @@
+//! The per funnel measurement pipeline.
+//!
+//! A [`SessionMeter`] chains the glitch filter, the session detector, the
+//! overrun guard and the analyses of the session: flow profile, foam and
+//! gulps. It is fed with [`PulseEvent`]s from
+//! any [`PulseSource`] and hands out a [`MeasuredSession`] once a session
+//! finished or was cut off. [`measure_session`] drives it from a source until
+//! then, which is how recorded traces are replayed on the host.
+//!
+//! If enabled, the meter also keeps a [`PulseCapture`] of the raw pulses from
+//! the moment the channel left idle. It stays available after the session
+//! finished until the next one starts.
+//!
+//! A session that was cut off leaves the liquid still flowing, so after an
+//! overrun the meter ignores pulses until none arrived for the idle timeout.
+//! Otherwise the same pour would overrun again and again.
+
+use alloc::boxed::Box;
+use core::mem;
+
+use crate::{
+    capture::PulseCapture,
+    filter::PulseFilter,
+    foam::{FoamAnalysis, FoamDetector, FoamLimits},
+    gulps::Gulps,
+    overrun::{OverrunGuard, SteadyFlow},
+    profile::FlowProfile,
+    session::{
+        DetectedSession, FalseStart, SessionDetector, SessionOutcome, SessionState, StartupWindow,
+        Transition,
+    },
+    source::{PulseEvent, PulseSource},
+};
+
+/// Parameters that can change between sessions.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct SessionLimits {
+    pub startup_window: StartupWindow,
+    pub idle_timeout_us: u64,
+    pub max_duration_us: u64,
+    /// Pulses closer together are rejected as glitches, 0 disables the filter.
+    pub min_pulse_interval_us: u64,
+}
+
+/// Progress of the current session, e.g. to drive indicator lights.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum MeterEvent {
+    StartingUp,
+    Running,
+    FalseStart(FalseStart),
+    /// The flow that overran the last session stopped, the meter waits for
+    /// the next one.
+    FlowStopped,
+}
+
+pub struct MeasuredSession {
+    pub session: DetectedSession,
+    pub outcome: SessionOutcome,
+    pub profile: FlowProfile,
+    pub foam: FoamAnalysis,
+    pub gulps: Gulps,
+    /// Pulses rejected as noise while waiting for and during the session.
+    pub glitches: u32,
+}
+
+pub struct SessionMeter {
+    filter: PulseFilter,
+    detector: SessionDetector,
+    guard: OverrunGuard,
+    steady_flow: SteadyFlow,
+    profile: FlowProfile,
+    foam: FoamDetector,
+    /// Pulses of the current event for the foam detector, which wants them
+    /// as one batch.
+    foam_batch: u32,
+    gulps: Gulps,
+    /// Boxed, the capture is large and mostly disabled.
+    capture: Option<Box<PulseCapture>>,
+    idle_timeout_us: u64,
+    /// Last pulse after an overrun, until the flow stopped.
+    draining: Option<u64>,
+}
+
+impl SessionMeter {
+    pub fn new(limits: SessionLimits, steady_flow: SteadyFlow, foam: FoamLimits) -> Self {
+        Self {
+            filter: PulseFilter::new(limits.min_pulse_interval_us),
+            detector: SessionDetector::new(limits.startup_window, limits.idle_timeout_us),
+            guard: OverrunGuard::new(limits.max_duration_us, steady_flow),
+            steady_flow,
+            profile: FlowProfile::default(),
+            foam: FoamDetector::new(foam),
+            foam_batch: 0,
+            gulps: Gulps::for_idle_timeout(limits.idle_timeout_us),
+            capture: None,
+            idle_timeout_us: limits.idle_timeout_us,
+            draining: None,
+        }
+    }
+
+    pub fn state(&self) -> SessionState {
+        self.detector.state()
+    }
+
+    /// Whether the meter waits for the flow of an overrun session to stop.
+    pub fn is_draining(&self) -> bool {
+        self.draining.is_some()
+    }
+
+    /// The glitch filter, for callers that count pulses outside of sessions.
+    pub fn filter_mut(&mut self) -> &mut PulseFilter {
+        &mut self.filter
+    }
+
+    /// Starts or stops capturing raw pulses, a disabled capture is discarded.
+    pub fn set_capture(&mut self, enabled: bool) {
+        match (enabled, &self.capture) {
+            (true, None) => self.capture = Some(Box::new(PulseCapture::new())),
+            (false, Some(_)) => self.capture = None,
+            _ => {}
+        }
+    }
+
+    /// Raw pulses of the current or last session, if capturing is enabled.
+    pub fn capture(&self) -> Option<&PulseCapture> {
+        self.capture.as_deref()
+    }
+
+    /// Point in time at which a [`PulseEvent::Deadline`] is expected.
+    pub fn deadline(&self) -> Option<u64> {
+        match self.draining {
+            Some(last_us) => Some(last_us + self.idle_timeout_us),
+            None => self.detector.deadline(),
+        }
+    }
+
+    /// Drops any session in progress and applies new limits, pulses are
+    /// counted from `now_us` on, even while draining.
+    pub fn restart(&mut self, limits: SessionLimits, now_us: u64) {
+        self.idle_timeout_us = limits.idle_timeout_us;
+        self.draining = None;
+        self.detector = SessionDetector::new(limits.startup_window, limits.idle_timeout_us);
+        self.guard = OverrunGuard::new(limits.max_duration_us, self.steady_flow);
+        self.profile.clear();
+        self.foam.clear();
+        self.gulps = Gulps::for_idle_timeout(limits.idle_timeout_us);
+        self.filter
+            .set_min_interval_us(limits.min_pulse_interval_us);
+        self.filter.reset(now_us);
+        if let Some(capture) = self.capture.as_mut() {
+            capture.clear();
+        }
+    }
+
+    /// Processes an event of the pulse source, returning the session once it
+    /// finished.
+    pub fn on_event(
+        &mut self,
+        event: PulseEvent,
+        observer: &mut impl FnMut(MeterEvent),
+    ) -> Option<MeasuredSession> {
+        let (now_us, pulses) = match event {
+            PulseEvent::Pulses { at_us, count } => (at_us, count),
+            PulseEvent::Deadline { at_us } => (at_us, 0),
+            PulseEvent::End => return None,
+        };
+
+        if let Some(last_us) = self.draining {
+            if pulses > 0 {
+                self.draining = Some(now_us);
+            } else if now_us >= last_us + self.idle_timeout_us {
+                self.draining = None;
+                self.guard.reset();
+                observer(MeterEvent::FlowStopped);
+            }
+            return None;
+        }
+
+        if let Some(capture) = self.capture.as_mut() {
+            if pulses > 0 && self.detector.state() == SessionState::Idle {
+                capture.clear();
+            }
+            capture.record(now_us, pulses);
+        }
+
+        let pulses = self.filter.accept(now_us, pulses);
+        let deadline_passed = self
+            .detector
+            .deadline()
+            .is_some_and(|deadline| now_us >= deadline);
+
+        let finished = if pulses > 0 {
+            (0..pulses).find_map(|_| self.advance(now_us, true, observer))
+        } else if deadline_passed {
+            self.advance(now_us, false, observer)
+        } else {
+            // everything was rejected as a glitch
+            None
+        };
+        let foam_batch = mem::take(&mut self.foam_batch);
+        self.foam.record(now_us, foam_batch);
+
+        finished.map(|(session, outcome)| {
+            self.detector.reset();
+            if let SessionOutcome::Overrun(_) = outcome {
+                self.draining = Some(now_us);
+            }
+            MeasuredSession {
+                session,
+                outcome,
+                profile: mem::take(&mut self.profile),
+                foam: self.foam.finish(),
+                gulps: mem::take(&mut self.gulps),
+                glitches: self.filter.rejected(),
+            }
+        })
+    }
+
+    /// Feeds a single pulse (or a timer tick if `pulse` is false) at `now_us`
+    /// into the detector, profile and overrun guard, returning the session
+    /// once it finished or was cut off.
+    fn advance(
+        &mut self,
+        now_us: u64,
+        pulse: bool,
+        observer: &mut impl FnMut(MeterEvent),
+    ) -> Option<(DetectedSession, SessionOutcome)> {
+        let transition = if pulse {
+            self.detector.on_pulse(now_us)
+        } else {
+            self.detector.on_tick(now_us)
+        };
+
+        match transition {
+            Some(Transition::StartingUp) => observer(MeterEvent::StartingUp),
+            Some(Transition::Running) => observer(MeterEvent::Running),
+            Some(Transition::Aborted(false_start)) => {
+                observer(MeterEvent::FalseStart(false_start));
+                self.detector.reset();
+                self.guard.reset();
+                self.profile.clear();
+                self.foam.clear();
+                self.foam_batch = 0;
+                self.gulps.clear();
+                // the pulse that revealed the expired window opens the next one
+                if pulse {
+                    self.detector.on_pulse(now_us);
+                    observer(MeterEvent::StartingUp);
+                }
+            }
+            Some(Transition::Finished(session)) => {
+                return Some((session, SessionOutcome::Completed))
+            }
+            None => {}
+        }
+
+        if pulse
+            && matches!(
+                self.detector.state(),
+                SessionState::StartingUp | SessionState::Running
+            )
+        {
+            self.profile.record(now_us);
+            self.foam_batch += 1;
+            self.gulps.record(now_us);
+            if let Some(reason) = self.guard.on_pulse(now_us) {
+                if let Some(session) = self.detector.cut_off() {
+                    return Some((session, SessionOutcome::Overrun(reason)));
+                }
+            }
+        }
+        None
+    }
+}
+
+/// Feeds `meter` from `source` until a session finished, `None` if the source
+/// ended before.
+pub async fn measure_session<S: PulseSource>(
+    source: &mut S,
+    meter: &mut SessionMeter,
+    mut observer: impl FnMut(MeterEvent),
+) -> Option<MeasuredSession> {
+    loop {
+        let event = source.wait(meter.deadline()).await;
+        if event == PulseEvent::End {
+            return None;
+        }
+        if let Some(measured) = meter.on_event(event, &mut observer) {
+            return Some(measured);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{block_on, overrun::OverrunReason, replay::ReplaySource};
+
+    const LIMITS: SessionLimits = SessionLimits {
+        startup_window: StartupWindow {
+            pulses: 5,
+            length_us: 200_000,
+        },
+        idle_timeout_us: 500_000,
+        max_duration_us: 60_000_000,
+        min_pulse_interval_us: 2_000,
+    };
+
+    const STEADY_FLOW: SteadyFlow = SteadyFlow {
+        window_us: 1_000_000,
+        windows: 15,
+        min_pulses: 20,
+        tolerance_percent: 3,
+    };
+
+    const FOAM: FoamLimits = FoamLimits {
+        window_us: 250_000,
+        min_intervals: 8,
+        gap_us: 100_000,
+        max_irregularity_percent: 30,
+    };
+
+    fn meter() -> SessionMeter {
+        SessionMeter::new(LIMITS, STEADY_FLOW, FOAM)
+    }
+
+    fn pulses(start_us: u64, interval_us: u64, count: u64) -> Vec<u64> {
+        (0..count).map(|i| start_us + i * interval_us).collect()
+    }
+
+    #[test]
+    fn measures_a_session() {
+        let trace = pulses(0, 20_000, 100);
+        let mut source = ReplaySource::new(&trace, 1_000_000);
+        let mut events = Vec::new();
+        let measured = block_on(measure_session(&mut source, &mut meter(), |e| {
+            events.push(e)
+        }))
+        .unwrap();
+
+        assert_eq!(events, vec![MeterEvent::StartingUp, MeterEvent::Running]);
+        assert_eq!(measured.outcome, SessionOutcome::Completed);
+        assert_eq!(measured.session.pulses, 100);
+        assert_eq!(measured.session.duration_us(), 2_000_000);
+        assert_eq!(measured.profile.buckets().iter().sum::<u16>(), 100);
+        assert_eq!(measured.foam.liquid_pulses, 100);
+        assert_eq!(measured.gulps.count(), 1);
+        // the idle timeout passed after the last pulse
+        assert_eq!(source.now_us(), 1_000_000 + 1_980_000 + 500_000);
+    }
+
+    #[test]
+    fn reports_false_starts_and_keeps_measuring() {
+        let mut trace = pulses(0, 100_000, 2);
+        trace.extend(pulses(1_000_000, 20_000, 50));
+        let mut source = ReplaySource::new(&trace, 0);
+        let mut events = Vec::new();
+        let measured = block_on(measure_session(&mut source, &mut meter(), |e| {
+            events.push(e)
+        }))
+        .unwrap();
+
+        assert!(matches!(
+            events[1],
+            MeterEvent::FalseStart(FalseStart { pulses: 2, .. })
+        ));
+        assert_eq!(measured.session.pulses, 50);
+        assert_eq!(measured.session.first_us, 1_000_000);
+    }
+
+    #[test]
+    fn glitches_are_filtered_and_counted() {
+        let mut trace = pulses(0, 20_000, 50);
+        trace.extend(pulses(500_100, 20_000, 10));
+        trace.sort_unstable();
+        let mut source = ReplaySource::new(&trace, 0);
+        let measured = block_on(measure_session(&mut source, &mut meter(), |_| {})).unwrap();
+        assert_eq!(measured.session.pulses, 50);
+        assert_eq!(measured.glitches, 10);
+    }
+
+    #[test]
+    fn captures_raw_pulses_of_the_session() {
+        let mut trace = pulses(0, 100_000, 2);
+        trace.extend(pulses(1_000_000, 20_000, 50));
+        trace.push(1_000_100);
+        trace.sort_unstable();
+        let mut source = ReplaySource::new(&trace, 0);
+        let mut meter = meter();
+        meter.set_capture(true);
+        block_on(measure_session(&mut source, &mut meter, |_| {})).unwrap();
+
+        // the false start is gone, the glitch is kept
+        let capture = meter.capture().unwrap();
+        assert_eq!(capture.first_us(), Some(1_000_000));
+        assert_eq!(capture.pulses(), 51);
+        assert_eq!(capture.deltas().take(2).collect::<Vec<_>>(), [100, 19_900]);
+
+        meter.set_capture(false);
+        assert!(meter.capture().is_none());
+    }
+
+    #[test]
+    fn steady_flow_is_cut_off() {
+        let trace = pulses(0, 25_000, 4_000);
+        let mut source = ReplaySource::new(&trace, 0);
+        let measured = block_on(measure_session(&mut source, &mut meter(), |_| {})).unwrap();
+        assert_eq!(
+            measured.outcome,
+            SessionOutcome::Overrun(OverrunReason::ContinuousFlow)
+        );
+        assert!(source.remaining() > 0);
+    }
+
+    #[test]
+    fn flow_after_an_overrun_is_ignored_until_it_stops() {
+        let mut trace = pulses(0, 25_000, 4_000);
+        trace.extend(pulses(200_000_000, 20_000, 100));
+        let mut source = ReplaySource::new(&trace, 0);
+        let mut meter = meter();
+        let overrun = block_on(measure_session(&mut source, &mut meter, |_| {})).unwrap();
+        assert!(matches!(overrun.outcome, SessionOutcome::Overrun(_)));
+        assert!(meter.is_draining());
+
+        let mut events = Vec::new();
+        let measured =
+            block_on(measure_session(&mut source, &mut meter, |e| events.push(e))).unwrap();
+        assert_eq!(
+            events,
+            vec![
+                MeterEvent::FlowStopped,
+                MeterEvent::StartingUp,
+                MeterEvent::Running
+            ]
+        );
+        assert_eq!(measured.outcome, SessionOutcome::Completed);
+        assert_eq!(measured.session.first_us, 200_000_000);
+        assert_eq!(measured.session.pulses, 100);
+        assert!(!meter.is_draining());
+    }
+
+    #[test]
+    fn restart_stops_draining() {
+        let trace = pulses(0, 25_000, 4_000);
+        let mut source = ReplaySource::new(&trace, 0);
+        let mut meter = meter();
+        block_on(measure_session(&mut source, &mut meter, |_| {})).unwrap();
+        assert!(meter.is_draining());
+        meter.restart(LIMITS, source.now_us());
+        assert!(!meter.is_draining());
+        assert_eq!(meter.deadline(), None);
+    }
+
+    #[test]
+    fn empty_trace_ends_without_session() {
+        let mut source = ReplaySource::new(&[], 0);
+        assert!(block_on(measure_session(&mut source, &mut meter(), |_| {})).is_none());
+    }
+}
@@ -0,0 +1,900 @@
+//! MQTT packets for publishing to a broker instead of the backend.
+//!
+//! Only what a device that publishes and follows a command topic needs is
+//! supported: CONNECT with a will, PUBLISH with QoS 0 and 1, SUBSCRIBE to a
+//! single filter, PINGREQ and DISCONNECT on the way out, their answers and
+//! PUBLISH on the way in. QoS 2 is not supported. Both MQTT 3.1.1 and 5 are
+//! spoken, for 5 all properties are left empty and skipped when received.
+//!
+//! Packets are encoded into and decoded from buffers of the caller, the
+//! client reads until [`packet_len`] reports a complete packet and hands it
+//! to [`decode`]. [`KeepAlive`] tells it when to ping the broker. Neither
+//! [`packet_len`] nor [`decode`] panics on malformed input.
+
+use core::str;
+
+/// Bytes a QoS 1 PUBLISH needs on top of its topic and payload.
+pub const PUBLISH_OVERHEAD: usize = MAX_FIXED_HEADER_LEN + 2 + 2 + 1;
+
+/// Packet type and up to four bytes of remaining length.
+const MAX_FIXED_HEADER_LEN: usize = 5;
+const MAX_REMAINING_LEN: usize = 268_435_455;
+
+const CONNECT: u8 = 1;
+const CONNACK: u8 = 2;
+const PUBLISH: u8 = 3;
+const PUBACK: u8 = 4;
+const SUBSCRIBE: u8 = 8;
+const SUBACK: u8 = 9;
+const PINGREQ: u8 = 12;
+const PINGRESP: u8 = 13;
+const DISCONNECT: u8 = 14;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Version {
+    V311,
+    V5,
+}
+
+impl Version {
+    fn level(self) -> u8 {
+        match self {
+            Self::V311 => 4,
+            Self::V5 => 5,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum QoS {
+    AtMostOnce = 0,
+    AtLeastOnce = 1,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum MqttError {
+    /// The packet does not fit into the buffer.
+    TooLarge,
+    Malformed,
+    /// A packet a client never receives, or one for QoS 2.
+    Unexpected,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Message<'a> {
+    pub topic: &'a str,
+    pub payload: &'a [u8],
+    pub qos: QoS,
+    /// The broker keeps the message and hands it to every new subscriber.
+    pub retain: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Connect<'a> {
+    pub client_id: &'a str,
+    /// 0 disables the keep alive.
+    pub keep_alive_secs: u16,
+    /// Starts without the subscriptions and messages of an earlier session.
+    pub clean_start: bool,
+    /// Published by the broker when the connection is lost without a
+    /// DISCONNECT.
+    pub will: Option<Message<'a>>,
+    pub username: Option<&'a str>,
+    /// MQTT 3.1.1 only allows a password together with a username.
+    pub password: Option<&'a [u8]>,
+}
+
+/// A packet from the broker. Reason codes of 0x80 and above are failures,
+/// for CONNACK anything but 0 is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Packet<'a> {
+    ConnAck {
+        session_present: bool,
+        code: u8,
+    },
+    Publish {
+        message: Message<'a>,
+        /// Set for QoS 1, the message has to be acknowledged with it.
+        packet_id: Option<u16>,
+        dup: bool,
+    },
+    PubAck {
+        packet_id: u16,
+        code: u8,
+    },
+    /// The code of the first filter, the client subscribes one at a time.
+    SubAck {
+        packet_id: u16,
+        code: u8,
+    },
+    PingResp,
+    /// Only MQTT 5 brokers announce that they close the connection.
+    Disconnect {
+        code: u8,
+    },
+}
+
+/// Encodes a CONNECT into `buf`, returning its length.
+pub fn encode_connect(
+    version: Version,
+    connect: &Connect,
+    buf: &mut [u8],
+) -> Result<usize, MqttError> {
+    let mut flags = 0;
+    if connect.clean_start {
+        flags |= 0x02;
+    }
+    if let Some(will) = &connect.will {
+        flags |= 0x04 | (will.qos as u8) << 3;
+        if will.retain {
+            flags |= 0x20;
+        }
+    }
+    if connect.password.is_some() {
+        flags |= 0x40;
+    }
+    if connect.username.is_some() {
+        flags |= 0x80;
+    }
+
+    encode(buf, CONNECT << 4, |w| {
+        w.str("MQTT");
+        w.u8(version.level());
+        w.u8(flags);
+        w.u16(connect.keep_alive_secs);
+        w.properties(version);
+        w.str(connect.client_id);
+        if let Some(will) = &connect.will {
+            w.properties(version);
+            w.str(will.topic);
+            w.bytes(will.payload);
+        }
+        if let Some(username) = connect.username {
+            w.str(username);
+        }
+        if let Some(password) = connect.password {
+            w.bytes(password);
+        }
+    })
+}
+
+/// Encodes a PUBLISH into `buf`, `packet_id` is required for QoS 1 and
+/// ignored for QoS 0.
+pub fn encode_publish(
+    version: Version,
+    message: &Message,
+    packet_id: u16,
+    dup: bool,
+    buf: &mut [u8],
+) -> Result<usize, MqttError> {
+    let mut header = PUBLISH << 4 | (message.qos as u8) << 1;
+    if dup {
+        header |= 0x08;
+    }
+    if message.retain {
+        header |= 0x01;
+    }
+
+    encode(buf, header, |w| {
+        w.str(message.topic);
+        if message.qos == QoS::AtLeastOnce {
+            w.u16(packet_id);
+        }
+        w.properties(version);
+        w.raw(message.payload);
+    })
+}
+
+pub fn encode_puback(packet_id: u16, buf: &mut [u8]) -> Result<usize, MqttError> {
+    // MQTT 5 allows leaving out the reason code if it is success
+    encode(buf, PUBACK << 4, |w| w.u16(packet_id))
+}
+
+/// Encodes a SUBSCRIBE to the single `filter`.
+pub fn encode_subscribe(
+    version: Version,
+    packet_id: u16,
+    filter: &str,
+    qos: QoS,
+    buf: &mut [u8],
+) -> Result<usize, MqttError> {
+    encode(buf, SUBSCRIBE << 4 | 0x02, |w| {
+        w.u16(packet_id);
+        w.properties(version);
+        w.str(filter);
+        w.u8(qos as u8);
+    })
+}
+
+pub fn encode_pingreq(buf: &mut [u8]) -> Result<usize, MqttError> {
+    encode(buf, PINGREQ << 4, |_| {})
+}
+
+/// Encodes a DISCONNECT, the broker discards the will.
+pub fn encode_disconnect(buf: &mut [u8]) -> Result<usize, MqttError> {
+    // without a reason code MQTT 5 means a normal disconnect as well
+    encode(buf, DISCONNECT << 4, |_| {})
+}
+
+/// Length of the packet at the start of `buf`, `None` while it is
+/// incomplete.
+pub fn packet_len(buf: &[u8]) -> Result<Option<usize>, MqttError> {
+    Ok(fixed_header(buf)?.map(|(header_len, remaining_len)| header_len + remaining_len))
+}
+
+/// Decodes a single packet, `packet` is as long as [`packet_len`] said.
+pub fn decode(version: Version, packet: &[u8]) -> Result<Packet<'_>, MqttError> {
+    let header_len = match fixed_header(packet)? {
+        Some((header_len, remaining_len)) if header_len + remaining_len == packet.len() => {
+            header_len
+        }
+        _ => return Err(MqttError::Malformed),
+    };
+    let flags = packet[0] & 0x0f;
+    let mut r = Reader(&packet[header_len..]);
+
+    let decoded = match packet[0] >> 4 {
+        CONNACK => {
+            let session_present = r.u8()? & 0x01 != 0;
+            let code = r.u8()?;
+            if version == Version::V5 {
+                r.skip_properties()?;
+            }
+            Packet::ConnAck {
+                session_present,
+                code,
+            }
+        }
+        PUBLISH => {
+            let qos = match (flags >> 1) & 0x03 {
+                0 => QoS::AtMostOnce,
+                1 => QoS::AtLeastOnce,
+                _ => return Err(MqttError::Unexpected),
+            };
+            let topic = r.str()?;
+            let packet_id = match qos {
+                QoS::AtMostOnce => None,
+                QoS::AtLeastOnce => Some(r.u16()?),
+            };
+            if version == Version::V5 {
+                r.skip_properties()?;
+            }
+            let payload = r.rest();
+            Packet::Publish {
+                message: Message {
+                    topic,
+                    payload,
+                    qos,
+                    retain: flags & 0x01 != 0,
+                },
+                packet_id,
+                dup: flags & 0x08 != 0,
+            }
+        }
+        PUBACK => {
+            let packet_id = r.u16()?;
+            // the reason code may be left out, which means success
+            let code = if r.is_empty() { 0 } else { r.u8()? };
+            if version == Version::V5 && !r.is_empty() {
+                r.skip_properties()?;
+            }
+            Packet::PubAck { packet_id, code }
+        }
+        SUBACK => {
+            let packet_id = r.u16()?;
+            if version == Version::V5 {
+                r.skip_properties()?;
+            }
+            let code = r.u8()?;
+            r.rest();
+            Packet::SubAck { packet_id, code }
+        }
+        PINGRESP => Packet::PingResp,
+        DISCONNECT => {
+            let code = if r.is_empty() { 0 } else { r.u8()? };
+            if !r.is_empty() {
+                r.skip_properties()?;
+            }
+            Packet::Disconnect { code }
+        }
+        _ => return Err(MqttError::Unexpected),
+    };
+    if r.is_empty() {
+        Ok(decoded)
+    } else {
+        Err(MqttError::Malformed)
+    }
+}
+
+/// When to ping the broker.
+///
+/// The client has to send something at least once per keep alive interval,
+/// or the broker drops it. If the broker does not send anything within an
+/// interval after a ping either, the connection is dead.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct KeepAlive {
+    interval_us: u64,
+    last_sent_us: u64,
+    ping_sent_us: Option<u64>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum KeepAliveAction {
+    /// Nothing to do before this time.
+    Wait(u64),
+    Ping,
+    /// The broker did not answer the ping.
+    Dead,
+}
+
+impl KeepAlive {
+    /// A keep alive for a connection established at `now_us`, an interval of
+    /// 0 never pings.
+    pub const fn new(interval_secs: u16, now_us: u64) -> Self {
+        Self {
+            interval_us: interval_secs as u64 * 1_000_000,
+            last_sent_us: now_us,
+            ping_sent_us: None,
+        }
+    }
+
+    /// A packet was sent at `now_us`.
+    pub fn sent(&mut self, now_us: u64) {
+        self.last_sent_us = now_us;
+    }
+
+    pub fn pinged(&mut self, now_us: u64) {
+        self.last_sent_us = now_us;
+        self.ping_sent_us = Some(now_us);
+    }
+
+    /// A packet arrived, which shows the broker is still there.
+    pub fn received(&mut self) {
+        self.ping_sent_us = None;
+    }
+
+    pub fn poll(&self, now_us: u64) -> KeepAliveAction {
+        if self.interval_us == 0 {
+            return KeepAliveAction::Wait(u64::MAX);
+        }
+        let deadline_us = self
+            .ping_sent_us
+            .unwrap_or(self.last_sent_us)
+            .saturating_add(self.interval_us);
+        if now_us < deadline_us {
+            KeepAliveAction::Wait(deadline_us)
+        } else if self.ping_sent_us.is_some() {
+            KeepAliveAction::Dead
+        } else {
+            KeepAliveAction::Ping
+        }
+    }
+}
+
+/// What can be sent to the command topic of a device.
+///
+/// The payload is a name, followed by a form like the management interface
+/// takes for some: `event active=true`, `capture enabled=false` or `status`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Command {
+    Event {
+        active: bool,
+    },
+    Capture {
+        enabled: bool,
+    },
+    /// Publish the status right away.
+    Status,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum CommandError {
+    UnknownCommand,
+    UnknownKey,
+    InvalidValue,
+}
+
+impl Command {
+    pub fn parse(payload: &[u8]) -> Result<Self, CommandError> {
+        let payload = str::from_utf8(payload)
+            .map_err(|_| CommandError::UnknownCommand)?
+            .trim();
+        let (name, form) = payload.split_once(' ').unwrap_or((payload, ""));
+        match name {
+            "event" => Ok(Self::Event {
+                active: flag(form, "active")?,
+            }),
+            "capture" => Ok(Self::Capture {
+                enabled: flag(form, "enabled")?,
+            }),
+            "status" if form.trim().is_empty() => Ok(Self::Status),
+            "status" => Err(CommandError::UnknownKey),
+            _ => Err(CommandError::UnknownCommand),
+        }
+    }
+}
+
+fn flag(form: &str, key: &str) -> Result<bool, CommandError> {
+    let (name, value) = form
+        .trim()
+        .split_once('=')
+        .ok_or(CommandError::InvalidValue)?;
+    if name.trim() != key {
+        return Err(CommandError::UnknownKey);
+    }
+    match value.trim() {
+        "true" | "1" => Ok(true),
+        "false" | "0" => Ok(false),
+        _ => Err(CommandError::InvalidValue),
+    }
+}
+
+/// Length of the fixed header and the remaining length it announces.
+fn fixed_header(buf: &[u8]) -> Result<Option<(usize, usize)>, MqttError> {
+    let Some(rest) = buf.get(1..) else {
+        return Ok(None);
+    };
+    let mut remaining_len = 0;
+    for (i, byte) in rest.iter().enumerate().take(4) {
+        remaining_len |= ((byte & 0x7f) as usize) << (7 * i);
+        if byte & 0x80 == 0 {
+            return Ok(Some((1 + i + 1, remaining_len)));
+        }
+    }
+    if rest.len() < 4 {
+        Ok(None)
+    } else {
+        Err(MqttError::Malformed)
+    }
+}
+
+/// Writes the body of a packet behind room for the fixed header, which is
+/// filled in and moved up once the length is known.
+fn encode(buf: &mut [u8], header: u8, body: impl FnOnce(&mut Writer)) -> Result<usize, MqttError> {
+    let mut w = Writer {
+        buf: buf
+            .get_mut(MAX_FIXED_HEADER_LEN..)
+            .ok_or(MqttError::TooLarge)?,
+        len: 0,
+        overflow: false,
+    };
+    body(&mut w);
+    if w.overflow {
+        return Err(MqttError::TooLarge);
+    }
+    let body_len = w.len;
+    if body_len > MAX_REMAINING_LEN {
+        return Err(MqttError::TooLarge);
+    }
+
+    let mut fixed = [header, 0, 0, 0, 0];
+    let mut fixed_len = 1;
+    let mut remaining = body_len;
+    loop {
+        let mut byte = (remaining & 0x7f) as u8;
+        remaining >>= 7;
+        if remaining > 0 {
+            byte |= 0x80;
+        }
+        fixed[fixed_len] = byte;
+        fixed_len += 1;
+        if remaining == 0 {
+            break;
+        }
+    }
+    buf.copy_within(
+        MAX_FIXED_HEADER_LEN..MAX_FIXED_HEADER_LEN + body_len,
+        fixed_len,
+    );
+    buf[..fixed_len].copy_from_slice(&fixed[..fixed_len]);
+    Ok(fixed_len + body_len)
+}
+
+/// Appends to a buffer, remembering instead of failing when it runs full.
+struct Writer<'b> {
+    buf: &'b mut [u8],
+    len: usize,
+    overflow: bool,
+}
+
+impl Writer<'_> {
+    fn raw(&mut self, bytes: &[u8]) {
+        match self.buf.get_mut(self.len..self.len + bytes.len()) {
+            Some(dest) => {
+                dest.copy_from_slice(bytes);
+                self.len += bytes.len();
+            }
+            None => self.overflow = true,
+        }
+    }
+
+    fn u8(&mut self, value: u8) {
+        self.raw(&[value]);
+    }
+
+    fn u16(&mut self, value: u16) {
+        self.raw(&value.to_be_bytes());
+    }
+
+    /// Binary data, prefixed with its length.
+    fn bytes(&mut self, bytes: &[u8]) {
+        match u16::try_from(bytes.len()) {
+            Ok(len) => {
+                self.u16(len);
+                self.raw(bytes);
+            }
+            Err(_) => self.overflow = true,
+        }
+    }
+
+    fn str(&mut self, value: &str) {
+        self.bytes(value.as_bytes());
+    }
+
+    /// Empty properties, which only MQTT 5 has.
+    fn properties(&mut self, version: Version) {
+        if version == Version::V5 {
+            self.u8(0);
+        }
+    }
+}
+
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8], MqttError> {
+        if self.0.len() < len {
+            return Err(MqttError::Malformed);
+        }
+        let (taken, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Ok(taken)
+    }
+
+    fn rest(&mut self) -> &'a [u8] {
+        core::mem::take(&mut self.0)
+    }
+
+    fn u8(&mut self) -> Result<u8, MqttError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, MqttError> {
+        let bytes = self.take(2)?;
+        Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn str(&mut self) -> Result<&'a str, MqttError> {
+        let len = self.u16()? as usize;
+        str::from_utf8(self.take(len)?).map_err(|_| MqttError::Malformed)
+    }
+
+    fn varint(&mut self) -> Result<usize, MqttError> {
+        let mut value = 0;
+        for i in 0..4 {
+            let byte = self.u8()?;
+            value |= ((byte & 0x7f) as usize) << (7 * i);
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        Err(MqttError::Malformed)
+    }
+
+    fn skip_properties(&mut self) -> Result<(), MqttError> {
+        let len = self.varint()?;
+        self.take(len).map(|_| ())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encodes_connect_with_will_and_credentials() {
+        let connect = Connect {
+            client_id: "dev",
+            keep_alive_secs: 60,
+            clean_start: true,
+            will: Some(Message {
+                topic: "t",
+                payload: b"off",
+                qos: QoS::AtLeastOnce,
+                retain: true,
+            }),
+            username: Some("u"),
+            password: Some(b"p"),
+        };
+        let mut buf = [0; 64];
+
+        let len = encode_connect(Version::V311, &connect, &mut buf).unwrap();
+        assert_eq!(
+            &buf[..len],
+            b"\x10\x1d\x00\x04MQTT\x04\xee\x00\x3c\
+              \x00\x03dev\x00\x01t\x00\x03off\x00\x01u\x00\x01p"
+        );
+
+        // properties after the keep alive and before the will topic
+        let len = encode_connect(Version::V5, &connect, &mut buf).unwrap();
+        assert_eq!(
+            &buf[..len],
+            b"\x10\x1f\x00\x04MQTT\x05\xee\x00\x3c\x00\
+              \x00\x03dev\x00\x00\x01t\x00\x03off\x00\x01u\x00\x01p"
+        );
+    }
+
+    #[test]
+    fn encodes_publish_and_subscribe() {
+        let message = Message {
+            topic: "a/b",
+            payload: b"{}",
+            qos: QoS::AtLeastOnce,
+            retain: true,
+        };
+        let mut buf = [0; 32];
+
+        let len = encode_publish(Version::V311, &message, 7, false, &mut buf).unwrap();
+        assert_eq!(&buf[..len], b"\x33\x09\x00\x03a/b\x00\x07{}");
+        let len = encode_publish(Version::V5, &message, 7, true, &mut buf).unwrap();
+        assert_eq!(&buf[..len], b"\x3b\x0a\x00\x03a/b\x00\x07\x00{}");
+        let qos0 = Message {
+            qos: QoS::AtMostOnce,
+            retain: false,
+            ..message
+        };
+        let len = encode_publish(Version::V311, &qos0, 7, false, &mut buf).unwrap();
+        assert_eq!(&buf[..len], b"\x30\x07\x00\x03a/b{}");
+
+        let len = encode_subscribe(Version::V5, 1, "c/#", QoS::AtLeastOnce, &mut buf).unwrap();
+        assert_eq!(&buf[..len], b"\x82\x09\x00\x01\x00\x00\x03c/#\x01");
+        assert_eq!(encode_puback(0x1234, &mut buf), Ok(4));
+        assert_eq!(&buf[..4], b"\x40\x02\x12\x34");
+        assert_eq!(encode_pingreq(&mut buf), Ok(2));
+        assert_eq!(&buf[..2], b"\xc0\x00");
+        assert_eq!(encode_disconnect(&mut buf), Ok(2));
+        assert_eq!(&buf[..2], b"\xe0\x00");
+    }
+
+    #[test]
+    fn publish_overhead_is_enough() {
+        let payload = [b'x'; 300];
+        let message = Message {
+            topic: "trichter/dev/runs",
+            payload: &payload,
+            qos: QoS::AtLeastOnce,
+            retain: true,
+        };
+        let mut buf = [0; PUBLISH_OVERHEAD + 17 + 300];
+        let len = encode_publish(Version::V5, &message, 1, false, &mut buf).unwrap();
+        assert_eq!(len, buf.len() - 2);
+        assert_eq!(&buf[..3], b"\x33\xc2\x02");
+        assert_eq!(
+            encode_publish(Version::V5, &message, 1, false, &mut buf[..len]),
+            Err(MqttError::TooLarge)
+        );
+    }
+
+    #[test]
+    fn finds_packet_boundaries() {
+        assert_eq!(packet_len(b""), Ok(None));
+        assert_eq!(packet_len(b"\xd0"), Ok(None));
+        assert_eq!(packet_len(b"\xd0\x00"), Ok(Some(2)));
+        assert_eq!(packet_len(b"\x30\xb7\x02"), Ok(Some(3 + 311)));
+        assert_eq!(packet_len(b"\x30\x80\x80"), Ok(None));
+        assert_eq!(
+            packet_len(b"\x30\xff\xff\xff\x7f"),
+            Ok(Some(5 + MAX_REMAINING_LEN))
+        );
+        assert_eq!(
+            packet_len(b"\x30\xff\xff\xff\xff"),
+            Err(MqttError::Malformed)
+        );
+    }
+
+    #[test]
+    fn decodes_broker_packets() {
+        assert_eq!(
+            decode(Version::V311, b"\x20\x02\x01\x00"),
+            Ok(Packet::ConnAck {
+                session_present: true,
+                code: 0
+            })
+        );
+        assert_eq!(
+            decode(Version::V5, b"\x20\x06\x00\x87\x03\x21\x00\x10"),
+            Ok(Packet::ConnAck {
+                session_present: false,
+                code: 0x87
+            })
+        );
+        assert_eq!(
+            decode(Version::V311, b"\x40\x02\x00\x07"),
+            Ok(Packet::PubAck {
+                packet_id: 7,
+                code: 0
+            })
+        );
+        assert_eq!(
+            decode(Version::V5, b"\x40\x04\x00\x07\x10\x00"),
+            Ok(Packet::PubAck {
+                packet_id: 7,
+                code: 0x10
+            })
+        );
+        assert_eq!(
+            decode(Version::V5, b"\x90\x04\x00\x01\x00\x80"),
+            Ok(Packet::SubAck {
+                packet_id: 1,
+                code: 0x80
+            })
+        );
+        assert_eq!(decode(Version::V311, b"\xd0\x00"), Ok(Packet::PingResp));
+        assert_eq!(
+            decode(Version::V5, b"\xe0\x01\x8e"),
+            Ok(Packet::Disconnect { code: 0x8e })
+        );
+        assert_eq!(
+            decode(Version::V5, b"\x33\x0f\x00\x03a/b\x00\x05\x02\x01\x01event"),
+            Ok(Packet::Publish {
+                message: Message {
+                    topic: "a/b",
+                    payload: b"event",
+                    qos: QoS::AtLeastOnce,
+                    retain: true,
+                },
+                packet_id: Some(5),
+                dup: false,
+            })
+        );
+        assert_eq!(
+            decode(Version::V311, b"\x38\x05\x00\x01ost"),
+            Ok(Packet::Publish {
+                message: Message {
+                    topic: "o",
+                    payload: b"st",
+                    qos: QoS::AtMostOnce,
+                    retain: false,
+                },
+                packet_id: None,
+                dup: true,
+            })
+        );
+    }
+
+    #[test]
+    fn rejects_what_a_client_does_not_expect() {
+        // QoS 2
+        assert_eq!(
+            decode(Version::V311, b"\x34\x05\x00\x01a\x00\x01"),
+            Err(MqttError::Unexpected)
+        );
+        assert_eq!(
+            decode(Version::V311, b"\x50\x02\x00\x01"),
+            Err(MqttError::Unexpected)
+        );
+        assert_eq!(
+            decode(Version::V311, b"\x10\x00"),
+            Err(MqttError::Unexpected)
+        );
+        // trailing bytes
+        assert_eq!(
+            decode(Version::V311, b"\xd0\x01\x00"),
+            Err(MqttError::Malformed)
+        );
+        assert_eq!(
+            decode(Version::V311, b"\x20\x03\x00\x00\x00"),
+            Err(MqttError::Malformed)
+        );
+        // not the length announced
+        assert_eq!(
+            decode(Version::V311, b"\x40\x02\x00"),
+            Err(MqttError::Malformed)
+        );
+        assert_eq!(
+            decode(Version::V311, b"\x40\x02\x00\x01\x00"),
+            Err(MqttError::Malformed)
+        );
+        // topic is not UTF-8
+        assert_eq!(
+            decode(Version::V311, b"\x30\x03\x00\x01\xff"),
+            Err(MqttError::Malformed)
+        );
+    }
+
+    #[test]
+    fn decoding_never_panics() {
+        let packets: [&[u8]; 4] = [
+            b"\x20\x06\x00\x87\x03\x21\x00\x10",
+            b"\x33\x0f\x00\x03a/b\x00\x05\x02\x01\x01event",
+            b"\x40\x04\x00\x07\x10\x00",
+            b"\x90\x04\x00\x01\x00\x80",
+        ];
+        for packet in packets {
+            for version in [Version::V311, Version::V5] {
+                for len in 0..=packet.len() {
+                    let _ = packet_len(&packet[..len]);
+                    let _ = decode(version, &packet[..len]);
+                }
+                for i in 0..packet.len() {
+                    for value in [0x00, 0x01, 0x7f, 0x80, 0xff] {
+                        let mut mutated = std::vec::Vec::from(packet);
+                        mutated[i] = value;
+                        let _ = packet_len(&mutated);
+                        let _ = decode(version, &mutated);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn pings_when_idle_and_gives_up_without_answer() {
+        let mut keep_alive = KeepAlive::new(10, 0);
+        assert_eq!(keep_alive.poll(0), KeepAliveAction::Wait(10_000_000));
+        keep_alive.sent(4_000_000);
+        assert_eq!(
+            keep_alive.poll(10_000_000),
+            KeepAliveAction::Wait(14_000_000)
+        );
+        assert_eq!(keep_alive.poll(14_000_000), KeepAliveAction::Ping);
+
+        keep_alive.pinged(14_000_000);
+        // publishing does not answer the ping
+        keep_alive.sent(15_000_000);
+        assert_eq!(
+            keep_alive.poll(20_000_000),
+            KeepAliveAction::Wait(24_000_000)
+        );
+        assert_eq!(keep_alive.poll(24_000_000), KeepAliveAction::Dead);
+
+        keep_alive.received();
+        assert_eq!(
+            keep_alive.poll(24_000_000),
+            KeepAliveAction::Wait(25_000_000)
+        );
+        assert_eq!(
+            KeepAlive::new(0, 0).poll(u64::MAX - 1),
+            KeepAliveAction::Wait(u64::MAX)
+        );
+    }
+
+    #[test]
+    fn parses_commands() {
+        assert_eq!(
+            Command::parse(b"event active=true"),
+            Ok(Command::Event { active: true })
+        );
+        assert_eq!(
+            Command::parse(b" capture enabled=0\n"),
+            Ok(Command::Capture { enabled: false })
+        );
+        assert_eq!(Command::parse(b"status"), Ok(Command::Status));
+        assert_eq!(
+            Command::parse(b"event enabled=true"),
+            Err(CommandError::UnknownKey)
+        );
+        assert_eq!(
+            Command::parse(b"event active=yes"),
+            Err(CommandError::InvalidValue)
+        );
+        assert_eq!(Command::parse(b"event"), Err(CommandError::InvalidValue));
+        assert_eq!(Command::parse(b"status now"), Err(CommandError::UnknownKey));
+        assert_eq!(Command::parse(b"reboot"), Err(CommandError::UnknownCommand));
+        assert_eq!(Command::parse(b"\xff"), Err(CommandError::UnknownCommand));
+    }
+}
@@ -0,0 +1,211 @@
+//! Guards against sessions that never end.
+//!
+//! A tap left running into the funnel or an oscillating sensor line produce
+//! pulses without the pauses the idle timeout waits for. The [`OverrunGuard`]
+//! cuts such sessions off once they exceed a maximum duration, or earlier if
+//! the flow is suspiciously steady: people drinking never keep the exact same
+//! rate for long, a tap does.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum OverrunReason {
+    MaxDuration,
+    ContinuousFlow,
+}
+
+/// Parameters of the continuous flow detection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct SteadyFlow {
+    /// Pulses are counted in windows of this length.
+    pub window_us: u64,
+    /// Consecutive steady windows that count as continuous flow, 0 disables
+    /// the detection.
+    pub windows: u32,
+    /// Windows with fewer pulses are never steady.
+    pub min_pulses: u32,
+    /// Allowed deviation of a window from the first window of a steady run.
+    pub tolerance_percent: u32,
+}
+
+impl SteadyFlow {
+    /// What the firmware runs with, the golden traces are checked against it.
+    pub const DEFAULT: Self = Self {
+        window_us: 1_000_000,
+        windows: 15,
+        min_pulses: 20,
+        tolerance_percent: 3,
+    };
+}
+
+pub struct OverrunGuard {
+    max_duration_us: u64,
+    steady: SteadyFlow,
+    first_us: Option<u64>,
+    window_start_us: u64,
+    window_pulses: u32,
+    reference: u32,
+    steady_windows: u32,
+}
+
+impl OverrunGuard {
+    pub fn new(max_duration_us: u64, steady: SteadyFlow) -> Self {
+        Self {
+            max_duration_us,
+            steady,
+            first_us: None,
+            window_start_us: 0,
+            window_pulses: 0,
+            reference: 0,
+            steady_windows: 0,
+        }
+    }
+
+    pub fn reset(&mut self) {
+        *self = Self::new(self.max_duration_us, self.steady);
+    }
+
+    /// Feeds a pulse of the session, the first one starts the clock.
+    pub fn on_pulse(&mut self, at_us: u64) -> Option<OverrunReason> {
+        let Some(first_us) = self.first_us else {
+            self.first_us = Some(at_us);
+            self.window_start_us = at_us;
+            self.window_pulses = 1;
+            return None;
+        };
+
+        if at_us.saturating_sub(first_us) >= self.max_duration_us {
+            return Some(OverrunReason::MaxDuration);
+        }
+        if self.steady.windows == 0 {
+            return None;
+        }
+
+        let elapsed = at_us.saturating_sub(self.window_start_us);
+        if elapsed >= self.steady.window_us {
+            self.close_window();
+            let windows = elapsed / self.steady.window_us;
+            if windows > 1 {
+                // the windows in between were empty
+                self.steady_windows = 0;
+            }
+            self.window_start_us += windows * self.steady.window_us;
+            self.window_pulses = 0;
+
+            if self.steady_windows >= self.steady.windows {
+                return Some(OverrunReason::ContinuousFlow);
+            }
+        }
+        self.window_pulses += 1;
+        None
+    }
+
+    fn close_window(&mut self) {
+        let pulses = self.window_pulses;
+        if pulses < self.steady.min_pulses {
+            self.steady_windows = 0;
+        } else if self.steady_windows > 0
+            && pulses.abs_diff(self.reference) * 100
+                <= self.reference * self.steady.tolerance_percent
+        {
+            self.steady_windows += 1;
+        } else {
+            self.reference = pulses;
+            self.steady_windows = 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const STEADY: SteadyFlow = SteadyFlow {
+        window_us: 1_000_000,
+        windows: 5,
+        min_pulses: 10,
+        tolerance_percent: 5,
+    };
+
+    fn guard() -> OverrunGuard {
+        OverrunGuard::new(60_000_000, STEADY)
+    }
+
+    /// Feeds `count` pulses `interval_us` apart from `start_us` and returns
+    /// the first overrun with the pulse that triggered it.
+    fn feed(
+        guard: &mut OverrunGuard,
+        start_us: u64,
+        interval_us: u64,
+        count: u64,
+    ) -> Option<(u64, OverrunReason)> {
+        (0..count)
+            .map(|i| start_us + i * interval_us)
+            .find_map(|at| guard.on_pulse(at).map(|reason| (at, reason)))
+    }
+
+    #[test]
+    fn steady_flow_is_continuous() {
+        let mut g = guard();
+        // 40 Hz, every window holds 40 pulses
+        assert_eq!(
+            feed(&mut g, 0, 25_000, 1_000),
+            Some((5_000_000, OverrunReason::ContinuousFlow))
+        );
+    }
+
+    #[test]
+    fn varying_flow_is_not_continuous() {
+        let mut g = guard();
+        let mut at = 0;
+        for second in 0..50u64 {
+            // alternates between 40 and 60 pulses per second
+            let pulses = if second % 2 == 0 { 40 } else { 60 };
+            let interval = 1_000_000 / pulses;
+            assert_eq!(feed(&mut g, at, interval, pulses), None);
+            at += 1_000_000;
+        }
+    }
+
+    #[test]
+    fn slow_drift_is_still_steady() {
+        let mut g = guard();
+        let mut at = 0;
+        let mut result = None;
+        for pulses in [40, 41, 42, 41, 40, 40, 40] {
+            result = result.or(feed(&mut g, at, 1_000_000 / pulses, pulses));
+            at += 1_000_000;
+        }
+        assert_eq!(result, Some((5_000_000, OverrunReason::ContinuousFlow)));
+    }
+
+    #[test]
+    fn gaps_and_trickles_break_the_run() {
+        let mut g = guard();
+        assert_eq!(feed(&mut g, 0, 25_000, 160), None);
+        // a two second pause, then a trickle below the minimum
+        assert_eq!(feed(&mut g, 6_000_000, 25_000, 120), None);
+        assert_eq!(feed(&mut g, 9_000_000, 200_000, 10), None);
+        assert_eq!(
+            feed(&mut g, 11_000_000, 25_000, 1_000),
+            Some((16_000_000, OverrunReason::ContinuousFlow))
+        );
+    }
+
+    #[test]
+    fn max_duration_cuts_off() {
+        let mut g = OverrunGuard::new(
+            10_000_000,
+            SteadyFlow {
+                windows: 0,
+                ..STEADY
+            },
+        );
+        assert_eq!(
+            feed(&mut g, 1_000, 25_000, 1_000),
+            Some((10_001_000, OverrunReason::MaxDuration))
+        );
+        g.reset();
+        assert_eq!(feed(&mut g, 20_000_000, 25_000, 10), None);
+    }
+}
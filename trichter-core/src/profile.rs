@@ -0,0 +1,145 @@
+//! Time-bucketed flow profile of a session.
+//!
+//! Pulses are counted per fixed-size bucket relative to the first pulse, so a
+//! session can be plotted as slow start, peak and tail-off. The profile is
+//! bounded, pulses past the last bucket are dropped and mark it as truncated.
+
+use heapless::Vec;
+
+pub const PROFILE_BUCKETS: usize = 300;
+pub const DEFAULT_BUCKET_US: u64 = 100_000;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct FlowProfile {
+    bucket_us: u64,
+    start_us: Option<u64>,
+    buckets: Vec<u16, PROFILE_BUCKETS>,
+    truncated: bool,
+}
+
+impl FlowProfile {
+    pub fn new(bucket_us: u64) -> Self {
+        Self {
+            bucket_us: bucket_us.max(1),
+            start_us: None,
+            buckets: Vec::new(),
+            truncated: false,
+        }
+    }
+
+    pub fn clear(&mut self) {
+        *self = Self::new(self.bucket_us);
+    }
+
+    /// Counts a pulse, the first one starts the profile.
+    pub fn record(&mut self, at_us: u64) {
+        let start_us = *self.start_us.get_or_insert(at_us);
+        let index = (at_us.saturating_sub(start_us) / self.bucket_us) as usize;
+        if index >= PROFILE_BUCKETS {
+            self.truncated = true;
+            return;
+        }
+        while self.buckets.len() <= index {
+            // cannot fail, index is below the capacity
+            let _ = self.buckets.push(0);
+        }
+        self.buckets[index] = self.buckets[index].saturating_add(1);
+    }
+
+    pub fn bucket_us(&self) -> u64 {
+        self.bucket_us
+    }
+
+    pub fn buckets(&self) -> &[u16] {
+        &self.buckets
+    }
+
+    pub fn truncated(&self) -> bool {
+        self.truncated
+    }
+
+    /// Index and pulse count of the busiest bucket, the earliest one on ties.
+    pub fn peak(&self) -> Option<(usize, u16)> {
+        self.buckets
+            .iter()
+            .copied()
+            .enumerate()
+            .fold(None, |peak, (index, count)| match peak {
+                Some((_, max)) if max >= count => peak,
+                _ => Some((index, count)),
+            })
+    }
+
+    /// Offset of the start of the peak bucket from the first pulse.
+    pub fn time_to_peak_us(&self) -> u64 {
+        self.peak()
+            .map_or(0, |(index, _)| index as u64 * self.bucket_us)
+    }
+}
+
+impl Default for FlowProfile {
+    fn default() -> Self {
+        Self::new(DEFAULT_BUCKET_US)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn profile(pulses: &[u64]) -> FlowProfile {
+        let mut profile = FlowProfile::new(100_000);
+        for at in pulses {
+            profile.record(*at);
+        }
+        profile
+    }
+
+    #[test]
+    fn empty_profile() {
+        let profile = FlowProfile::default();
+        assert!(profile.buckets().is_empty());
+        assert_eq!(profile.peak(), None);
+        assert_eq!(profile.time_to_peak_us(), 0);
+    }
+
+    #[test]
+    fn buckets_are_relative_to_first_pulse() {
+        let profile = profile(&[1_000_000, 1_050_000, 1_099_999, 1_100_000, 1_350_000]);
+        assert_eq!(profile.buckets(), &[3, 1, 0, 1]);
+    }
+
+    #[test]
+    fn peak_and_time_to_peak() {
+        let profile = profile(&[0, 100_000, 110_000, 200_000, 210_000, 220_000, 300_000]);
+        assert_eq!(profile.peak(), Some((2, 3)));
+        assert_eq!(profile.time_to_peak_us(), 200_000);
+    }
+
+    #[test]
+    fn earliest_peak_wins_ties() {
+        let profile = profile(&[0, 10_000, 200_000, 210_000]);
+        assert_eq!(profile.peak(), Some((0, 2)));
+    }
+
+    #[test]
+    fn truncates_long_sessions() {
+        let end = PROFILE_BUCKETS as u64 * 100_000;
+        let profile = profile(&[0, end - 1, end, end + 500_000]);
+        assert!(profile.truncated());
+        assert_eq!(profile.buckets().len(), PROFILE_BUCKETS);
+        assert_eq!(profile.buckets()[PROFILE_BUCKETS - 1], 1);
+    }
+
+    #[test]
+    fn clear_keeps_bucket_size() {
+        let mut profile = FlowProfile::new(50_000);
+        profile.record(0);
+        profile.record(500_000);
+        profile.clear();
+        assert!(profile.buckets().is_empty());
+        assert_eq!(profile.bucket_us(), 50_000);
+        profile.record(10_000_000);
+        assert_eq!(profile.buckets(), &[1]);
+    }
+}
@@ -0,0 +1,792 @@
+//! Persistent FIFO of opaque entries in a region of NOR flash.
+//!
+//! The region is split into blocks of one or more erase sectors that are
+//! written as a circular log: entries are appended to the newest block, once
+//! it is full the next block is erased and continues the log. Every block is
+//! erased once per pass through the region, which spreads the wear evenly.
+//! When the log catches up with the oldest pending entry the queue is full
+//! and the entries of the oldest block are dropped.
+//!
+//! A block starts with a [`record`](crate::record) holding its sequence
+//! number, the newest block has the highest one. Entries follow as
+//!
+//! ```text
+//! magic | state | len (u16) | crc32 | payload | padding to 4 bytes
+//! ```
+//!
+//! The state only ever clears bits, so entries are updated in place:
+//! `WRITING` until the payload is complete, then `PENDING` until
+//! [`FlashQueue::pop`] marks them `DONE`. Entries that can never be delivered
+//! are marked `PARKED` by [`FlashQueue::park`] instead, they stay in flash
+//! for inspection until their block is reused, but are no longer handed out.
+//! A power loss while writing leaves an entry in `WRITING`, which is skipped.
+//! Payloads are checked against their CRC when read, corrupt ones are
+//! dropped.
+//!
+//! Since the oldest entries can be dropped at any push, callers that work on
+//! an entry across pushes refer to it by its [`EntryId`] and remove it with
+//! [`FlashQueue::pop_entry`] or [`FlashQueue::park_entry`].
+//!
+//! All flash accesses are aligned to and a multiple of 4 bytes.
+
+use crate::record::{self, RECORD_OVERHEAD};
+
+/// NOR flash holding the queue, offsets are relative to the start of the
+/// region.
+pub trait Flash {
+    type Error;
+
+    /// Erase granularity, blocks must be a multiple of it.
+    const ERASE_SIZE: u32;
+
+    fn capacity(&self) -> u32;
+
+    fn read(&mut self, offset: u32, buf: &mut [u8]) -> Result<(), Self::Error>;
+
+    /// Programs `data`, which can only clear bits of what is stored.
+    fn write(&mut self, offset: u32, data: &[u8]) -> Result<(), Self::Error>;
+
+    /// Sets `from..to` back to `0xFF`.
+    fn erase(&mut self, from: u32, to: u32) -> Result<(), Self::Error>;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum QueueError<E> {
+    Flash(E),
+    /// Fewer than two blocks, or blocks not a multiple of the erase size.
+    Geometry,
+    /// The payload does not fit into a block.
+    TooLarge,
+    /// The buffer is smaller than the padded payload.
+    BufferTooSmall,
+}
+
+/// Slot of the block header record, records elsewhere use small slots.
+const BLOCK_SLOT: u8 = 0xB0;
+const BLOCK_HEADER_LEN: u32 = 16;
+
+const ENTRY_MAGIC: u8 = 0xA5;
+const ENTRY_HEADER_LEN: u32 = 8;
+const WRITING: u8 = 0xFF;
+const PENDING: u8 = 0xAA;
+const PARKED: u8 = 0x22;
+const DONE: u8 = 0x00;
+
+const fn padded(len: u32) -> u32 {
+    (len + 3) & !3
+}
+
+/// An entry of an open queue. Positions in flash are reused once the log
+/// wraps around, the sequence numbers of the blocks are not.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct EntryId {
+    block_seq: u32,
+    offset: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Position {
+    block: u32,
+    offset: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct EntryHeader {
+    state: u8,
+    len: u32,
+    crc: u32,
+}
+
+impl EntryHeader {
+    fn size(&self) -> u32 {
+        ENTRY_HEADER_LEN + padded(self.len)
+    }
+
+    fn encode(&self, state: u8) -> [u8; ENTRY_HEADER_LEN as usize] {
+        let len = (self.len as u16).to_le_bytes();
+        let crc = self.crc.to_le_bytes();
+        [
+            ENTRY_MAGIC,
+            state,
+            len[0],
+            len[1],
+            crc[0],
+            crc[1],
+            crc[2],
+            crc[3],
+        ]
+    }
+}
+
+enum Slot {
+    /// Erased, the block continues here.
+    Free,
+    /// Unreadable or past the end, the rest of the block is unusable.
+    End,
+    Entry(EntryHeader),
+}
+
+pub struct FlashQueue<F: Flash> {
+    flash: F,
+    block_size: u32,
+    blocks: u32,
+    head: Position,
+    head_seq: u32,
+    /// At or before the oldest pending entry.
+    tail: Position,
+    len: u32,
+    dropped: u32,
+    parked: u32,
+}
+
+impl<F: Flash> FlashQueue<F> {
+    /// Recovers the queue from `flash`, which may be erased, hold a queue or
+    /// anything else.
+    pub fn open(flash: F, block_size: u32) -> Result<Self, QueueError<F::Error>> {
+        let blocks = flash.capacity() / block_size.max(1);
+        if blocks < 2 || !block_size.is_multiple_of(F::ERASE_SIZE) || !block_size.is_multiple_of(4)
+        {
+            return Err(QueueError::Geometry);
+        }
+
+        let mut queue = Self {
+            flash,
+            block_size,
+            blocks,
+            // the first push moves on to block 0 with sequence 0
+            head: Position {
+                block: blocks - 1,
+                offset: block_size,
+            },
+            head_seq: u32::MAX,
+            tail: Position {
+                block: 0,
+                offset: BLOCK_HEADER_LEN,
+            },
+            len: 0,
+            dropped: 0,
+            parked: 0,
+        };
+
+        let mut newest = None;
+        for block in 0..blocks {
+            if let Some(seq) = queue.block_seq(block)? {
+                if newest.is_none_or(|(_, newest_seq)| seq > newest_seq) {
+                    newest = Some((block, seq));
+                }
+            }
+        }
+        let Some((head_block, head_seq)) = newest else {
+            return Ok(queue);
+        };
+        queue.head_seq = head_seq;
+
+        // oldest to newest block
+        let mut tail = None;
+        for i in 1..=blocks {
+            let block = (head_block + i) % blocks;
+            if queue.block_seq(block)?.is_none() {
+                continue;
+            }
+            let mut pos = Position {
+                block,
+                offset: BLOCK_HEADER_LEN,
+            };
+            let end = loop {
+                match queue.slot(pos)? {
+                    Slot::Entry(header) => {
+                        match header.state {
+                            PENDING => {
+                                queue.len += 1;
+                                tail.get_or_insert(pos);
+                            }
+                            PARKED => queue.parked += 1,
+                            _ => {}
+                        }
+                        pos.offset += header.size();
+                    }
+                    Slot::Free => break pos.offset,
+                    Slot::End => break block_size,
+                }
+            };
+            if block == head_block {
+                queue.head = Position { block, offset: end };
+            }
+        }
+        queue.tail = tail.unwrap_or(queue.head);
+
+        Ok(queue)
+    }
+
+    /// Pending entries.
+    pub fn len(&self) -> u32 {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Entries lost since opening, overwritten because the queue was full or
+    /// corrupt.
+    pub fn dropped(&self) -> u32 {
+        self.dropped
+    }
+
+    /// Entries in flash that were parked, including the ones from before
+    /// opening.
+    pub fn parked(&self) -> u32 {
+        self.parked
+    }
+
+    /// Largest payload that fits into a block.
+    pub fn max_payload_len(&self) -> usize {
+        (self.block_size - BLOCK_HEADER_LEN - ENTRY_HEADER_LEN).min(u16::MAX as u32) as usize
+    }
+
+    pub fn into_inner(self) -> F {
+        self.flash
+    }
+
+    /// Appends `payload`, dropping the oldest block if the queue is full.
+    pub fn push(&mut self, payload: &[u8]) -> Result<(), QueueError<F::Error>> {
+        if payload.len() > self.max_payload_len() {
+            return Err(QueueError::TooLarge);
+        }
+        let header = EntryHeader {
+            state: PENDING,
+            len: payload.len() as u32,
+            crc: record::crc32(payload),
+        };
+        if self.head.offset + header.size() > self.block_size {
+            self.next_block()?;
+        }
+
+        let addr = self.address(self.head);
+        self.write(addr, &header.encode(WRITING))?;
+        let aligned = payload.len() & !3;
+        self.write(addr + ENTRY_HEADER_LEN, &payload[..aligned])?;
+        if aligned < payload.len() {
+            let mut last = [0xFF; 4];
+            last[..payload.len() - aligned].copy_from_slice(&payload[aligned..]);
+            self.write(addr + ENTRY_HEADER_LEN + aligned as u32, &last)?;
+        }
+        self.write(addr, &header.encode(PENDING)[..4])?;
+
+        if self.len == 0 {
+            self.tail = self.head;
+        }
+        self.len += 1;
+        self.head.offset += header.size();
+        Ok(())
+    }
+
+    /// Reads the oldest entry into `buf` without removing it, returns its
+    /// length. `buf` needs room for the payload padded to 4 bytes.
+    pub fn peek(&mut self, buf: &mut [u8]) -> Result<Option<usize>, QueueError<F::Error>> {
+        while let Some(header) = self.front()? {
+            let padded_len = padded(header.len) as usize;
+            if buf.len() < padded_len {
+                return Err(QueueError::BufferTooSmall);
+            }
+            let addr = self.address(self.tail) + ENTRY_HEADER_LEN;
+            self.flash
+                .read(addr, &mut buf[..padded_len])
+                .map_err(QueueError::Flash)?;
+
+            let len = header.len as usize;
+            if record::crc32(&buf[..len]) == header.crc {
+                return Ok(Some(len));
+            }
+            self.remove(header, DONE)?;
+            self.dropped += 1;
+        }
+        Ok(None)
+    }
+
+    /// Removes the oldest entry, if any.
+    pub fn pop(&mut self) -> Result<(), QueueError<F::Error>> {
+        if let Some(header) = self.front()? {
+            self.remove(header, DONE)?;
+        }
+        Ok(())
+    }
+
+    /// Removes the oldest entry, if any, but keeps it in flash.
+    pub fn park(&mut self) -> Result<(), QueueError<F::Error>> {
+        if let Some(header) = self.front()? {
+            self.remove(header, PARKED)?;
+            self.parked += 1;
+        }
+        Ok(())
+    }
+
+    /// The oldest entry, the one [`peek`](Self::peek) reads.
+    pub fn front_id(&mut self) -> Result<Option<EntryId>, QueueError<F::Error>> {
+        Ok(self.front()?.map(|_| self.id(self.tail)))
+    }
+
+    /// Removes the oldest entry if it is `id`, returns whether it was.
+    pub fn pop_entry(&mut self, id: EntryId) -> Result<bool, QueueError<F::Error>> {
+        match self.front()? {
+            Some(header) if self.id(self.tail) == id => {
+                self.remove(header, DONE)?;
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    /// Parks the oldest entry if it is `id`, returns whether it was.
+    pub fn park_entry(&mut self, id: EntryId) -> Result<bool, QueueError<F::Error>> {
+        match self.front()? {
+            Some(header) if self.id(self.tail) == id => {
+                self.remove(header, PARKED)?;
+                self.parked += 1;
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    /// Blocks are used in order, so the one `n` behind the head has the
+    /// sequence number `n` below it.
+    fn id(&self, pos: Position) -> EntryId {
+        let behind = (self.head.block + self.blocks - pos.block) % self.blocks;
+        EntryId {
+            block_seq: self.head_seq.wrapping_sub(behind),
+            offset: pos.offset,
+        }
+    }
+
+    /// Moves the tail to the oldest pending entry.
+    fn front(&mut self) -> Result<Option<EntryHeader>, QueueError<F::Error>> {
+        while self.len > 0 {
+            match self.slot(self.tail)? {
+                Slot::Entry(header) if header.state == PENDING => return Ok(Some(header)),
+                Slot::Entry(header) => self.tail.offset += header.size(),
+                Slot::Free | Slot::End if self.tail.block == self.head.block => {
+                    // counted more entries than there are, cannot happen
+                    // unless the flash changed underneath
+                    self.len = 0;
+                }
+                Slot::Free | Slot::End => {
+                    self.tail = Position {
+                        block: (self.tail.block + 1) % self.blocks,
+                        offset: BLOCK_HEADER_LEN,
+                    };
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    /// Marks the entry at the tail as done or parked.
+    fn remove(&mut self, header: EntryHeader, state: u8) -> Result<(), QueueError<F::Error>> {
+        let addr = self.address(self.tail);
+        self.write(addr, &header.encode(state)[..4])?;
+        self.tail.offset += header.size();
+        self.len -= 1;
+        Ok(())
+    }
+
+    /// Erases the block after the head and continues the log there.
+    fn next_block(&mut self) -> Result<(), QueueError<F::Error>> {
+        let block = (self.head.block + 1) % self.blocks;
+        // only the entries of blocks in the log were counted
+        if self.block_seq(block)?.is_some() {
+            let mut pos = Position {
+                block,
+                offset: BLOCK_HEADER_LEN,
+            };
+            while let Slot::Entry(header) = self.slot(pos)? {
+                match header.state {
+                    PENDING => {
+                        self.len -= 1;
+                        self.dropped += 1;
+                    }
+                    PARKED => self.parked -= 1,
+                    _ => {}
+                }
+                pos.offset += header.size();
+            }
+        }
+        if self.tail.block == block {
+            self.tail = Position {
+                block: (block + 1) % self.blocks,
+                offset: BLOCK_HEADER_LEN,
+            };
+        }
+
+        let start = block * self.block_size;
+        self.flash
+            .erase(start, start + self.block_size)
+            .map_err(QueueError::Flash)?;
+        let seq = self.head_seq.wrapping_add(1);
+        let mut header = [0xFF; BLOCK_HEADER_LEN as usize];
+        // cannot fail, the header has room for the record
+        let _ = record::encode(BLOCK_SLOT, &seq.to_le_bytes(), &mut header);
+        self.write(start, &header)?;
+
+        self.head = Position {
+            block,
+            offset: BLOCK_HEADER_LEN,
+        };
+        self.head_seq = seq;
+        if self.len == 0 {
+            self.tail = self.head;
+        }
+        Ok(())
+    }
+
+    fn block_seq(&mut self, block: u32) -> Result<Option<u32>, QueueError<F::Error>> {
+        let mut header = [0; BLOCK_HEADER_LEN as usize];
+        self.flash
+            .read(block * self.block_size, &mut header)
+            .map_err(QueueError::Flash)?;
+        Ok(record::decode(BLOCK_SLOT, &header)
+            .and_then(|payload| payload.try_into().ok())
+            .map(u32::from_le_bytes))
+    }
+
+    fn slot(&mut self, pos: Position) -> Result<Slot, QueueError<F::Error>> {
+        if pos.offset + ENTRY_HEADER_LEN > self.block_size {
+            return Ok(Slot::End);
+        }
+        let mut raw = [0; ENTRY_HEADER_LEN as usize];
+        self.flash
+            .read(self.address(pos), &mut raw)
+            .map_err(QueueError::Flash)?;
+        if raw == [0xFF; ENTRY_HEADER_LEN as usize] {
+            return Ok(Slot::Free);
+        }
+        let header = EntryHeader {
+            state: raw[1],
+            len: u16::from_le_bytes([raw[2], raw[3]]) as u32,
+            crc: u32::from_le_bytes([raw[4], raw[5], raw[6], raw[7]]),
+        };
+        if raw[0] != ENTRY_MAGIC || pos.offset + header.size() > self.block_size {
+            return Ok(Slot::End);
+        }
+        Ok(Slot::Entry(header))
+    }
+
+    fn address(&self, pos: Position) -> u32 {
+        pos.block * self.block_size + pos.offset
+    }
+
+    fn write(&mut self, addr: u32, data: &[u8]) -> Result<(), QueueError<F::Error>> {
+        if data.is_empty() {
+            return Ok(());
+        }
+        self.flash.write(addr, data).map_err(QueueError::Flash)
+    }
+}
+
+const _: () = assert!(BLOCK_HEADER_LEN as usize >= RECORD_OVERHEAD + 4);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECTOR: u32 = 256;
+
+    /// NOR flash in RAM that can lose power after a number of writes.
+    struct RamFlash {
+        data: std::vec::Vec<u8>,
+        erases: std::vec::Vec<u32>,
+        writes_left: Option<u32>,
+    }
+
+    #[derive(Debug, PartialEq)]
+    struct PowerLoss;
+
+    impl RamFlash {
+        fn new(sectors: u32) -> Self {
+            Self {
+                data: vec![0xFF; (sectors * SECTOR) as usize],
+                erases: vec![0; sectors as usize],
+                writes_left: None,
+            }
+        }
+    }
+
+    impl Flash for RamFlash {
+        type Error = PowerLoss;
+        const ERASE_SIZE: u32 = SECTOR;
+
+        fn capacity(&self) -> u32 {
+            self.data.len() as u32
+        }
+
+        fn read(&mut self, offset: u32, buf: &mut [u8]) -> Result<(), PowerLoss> {
+            assert!(
+                offset.is_multiple_of(4) && buf.len().is_multiple_of(4),
+                "unaligned read"
+            );
+            let offset = offset as usize;
+            buf.copy_from_slice(&self.data[offset..offset + buf.len()]);
+            Ok(())
+        }
+
+        fn write(&mut self, offset: u32, data: &[u8]) -> Result<(), PowerLoss> {
+            assert!(
+                offset.is_multiple_of(4) && data.len().is_multiple_of(4),
+                "unaligned write"
+            );
+            let mut data = data;
+            if let Some(left) = self.writes_left.as_mut() {
+                if *left == 0 {
+                    // half of the write made it
+                    data = &data[..data.len() / 2];
+                }
+            }
+            for (i, byte) in data.iter().enumerate() {
+                self.data[offset as usize + i] &= byte;
+            }
+            match self.writes_left.as_mut() {
+                Some(0) => Err(PowerLoss),
+                Some(left) => {
+                    *left -= 1;
+                    Ok(())
+                }
+                None => Ok(()),
+            }
+        }
+
+        fn erase(&mut self, from: u32, to: u32) -> Result<(), PowerLoss> {
+            assert!(
+                from.is_multiple_of(SECTOR) && to.is_multiple_of(SECTOR),
+                "unaligned erase"
+            );
+            self.data[from as usize..to as usize].fill(0xFF);
+            for sector in from / SECTOR..to / SECTOR {
+                self.erases[sector as usize] += 1;
+            }
+            Ok(())
+        }
+    }
+
+    fn open(flash: RamFlash) -> FlashQueue<RamFlash> {
+        FlashQueue::open(flash, SECTOR).unwrap()
+    }
+
+    fn pop(queue: &mut FlashQueue<RamFlash>) -> Option<std::vec::Vec<u8>> {
+        let mut buf = [0; SECTOR as usize];
+        let len = queue.peek(&mut buf).unwrap()?;
+        queue.pop().unwrap();
+        Some(buf[..len].to_vec())
+    }
+
+    fn entry(i: u32) -> std::vec::Vec<u8> {
+        std::format!("entry {}", i).into_bytes()
+    }
+
+    #[test]
+    fn entries_come_out_in_order() {
+        let mut queue = open(RamFlash::new(4));
+        assert_eq!(pop(&mut queue), None);
+
+        for i in 0..20 {
+            queue.push(&entry(i)).unwrap();
+        }
+        assert_eq!(queue.len(), 20);
+        for i in 0..20 {
+            assert_eq!(pop(&mut queue), Some(entry(i)));
+        }
+        assert!(queue.is_empty());
+        assert_eq!(pop(&mut queue), None);
+    }
+
+    #[test]
+    fn peek_does_not_remove() {
+        let mut queue = open(RamFlash::new(4));
+        queue.push(b"abc").unwrap();
+        let mut buf = [0; 8];
+        assert_eq!(queue.peek(&mut buf), Ok(Some(3)));
+        assert_eq!(queue.peek(&mut buf), Ok(Some(3)));
+        assert_eq!(&buf[..3], b"abc");
+        assert_eq!(queue.peek(&mut [0; 2]), Err(QueueError::BufferTooSmall));
+    }
+
+    #[test]
+    fn survives_reopening() {
+        let mut queue = open(RamFlash::new(4));
+        for i in 0..30 {
+            queue.push(&entry(i)).unwrap();
+        }
+        for i in 0..12 {
+            assert_eq!(pop(&mut queue), Some(entry(i)));
+        }
+
+        let mut queue = open(queue.into_inner());
+        assert_eq!(queue.len(), 18);
+        queue.push(&entry(30)).unwrap();
+        for i in 12..31 {
+            assert_eq!(pop(&mut queue), Some(entry(i)));
+        }
+
+        let queue = open(queue.into_inner());
+        assert!(queue.is_empty());
+    }
+
+    #[test]
+    fn parked_entries_are_skipped_but_kept() {
+        let mut queue = open(RamFlash::new(4));
+        for i in 0..3 {
+            queue.push(&entry(i)).unwrap();
+        }
+        queue.park().unwrap();
+        assert_eq!(queue.len(), 2);
+        assert_eq!(queue.parked(), 1);
+        assert_eq!(pop(&mut queue), Some(entry(1)));
+
+        let mut queue = open(queue.into_inner());
+        assert_eq!(queue.parked(), 1);
+        assert_eq!(pop(&mut queue), Some(entry(2)));
+        assert_eq!(pop(&mut queue), None);
+
+        // until their block is reused
+        for i in 3..100 {
+            queue.push(&entry(i)).unwrap();
+        }
+        assert_eq!(queue.parked(), 0);
+    }
+
+    #[test]
+    fn full_queue_drops_the_oldest_block() {
+        let mut queue = open(RamFlash::new(4));
+        for i in 0..100 {
+            queue.push(&entry(i)).unwrap();
+        }
+        assert!(queue.dropped() > 0);
+        assert_eq!(queue.len() + queue.dropped(), 100);
+
+        let first = 100 - queue.len();
+        for i in first..100 {
+            assert_eq!(pop(&mut queue), Some(entry(i)));
+        }
+        assert_eq!(pop(&mut queue), None);
+    }
+
+    #[test]
+    fn wear_is_spread_over_all_blocks() {
+        let mut queue = open(RamFlash::new(8));
+        for i in 0..1_000 {
+            queue.push(&entry(i)).unwrap();
+            if !i.is_multiple_of(3) {
+                assert!(pop(&mut queue).is_some());
+            }
+        }
+        let erases = &queue.into_inner().erases;
+        let min = erases.iter().min().unwrap();
+        let max = erases.iter().max().unwrap();
+        assert!(*min > 0);
+        assert!(max - min <= 1, "{:?}", erases);
+    }
+
+    #[test]
+    fn entries_are_removed_by_identity() {
+        let mut queue = open(RamFlash::new(4));
+        queue.push(&entry(0)).unwrap();
+        let first = queue.front_id().unwrap().unwrap();
+
+        // fill the queue until the block of the first entry is dropped and
+        // its position is taken by a newer entry
+        let mut i = 1;
+        while queue.dropped() == 0 {
+            queue.push(&entry(i)).unwrap();
+            i += 1;
+        }
+        let front = queue.front_id().unwrap().unwrap();
+        assert_ne!(front, first);
+        assert_eq!(queue.pop_entry(first), Ok(false));
+        assert_eq!(queue.park_entry(first), Ok(false));
+        let len = queue.len();
+
+        assert_eq!(queue.pop_entry(front), Ok(true));
+        assert_eq!(queue.len(), len - 1);
+        let next = queue.front_id().unwrap().unwrap();
+        assert_eq!(queue.park_entry(next), Ok(true));
+        assert_eq!(queue.parked(), 1);
+        assert_ne!(queue.front_id().unwrap(), Some(next));
+    }
+
+    #[test]
+    fn ids_survive_reopening() {
+        let mut queue = open(RamFlash::new(4));
+        for i in 0..3 {
+            queue.push(&entry(i)).unwrap();
+        }
+        let id = queue.front_id().unwrap();
+        let mut queue = open(queue.into_inner());
+        assert_eq!(queue.front_id().unwrap(), id);
+    }
+
+    #[test]
+    fn interrupted_push_is_skipped() {
+        // header, payload and tail, a power loss during the commit keeps
+        // the complete entry or not
+        for writes in 0..3 {
+            let mut queue = open(RamFlash::new(4));
+            queue.push(b"first").unwrap();
+
+            let mut flash = queue.into_inner();
+            flash.writes_left = Some(writes);
+            let mut queue = open(flash);
+            assert_eq!(queue.push(b"lost entry"), Err(QueueError::Flash(PowerLoss)));
+
+            let mut flash = queue.into_inner();
+            flash.writes_left = None;
+            let mut queue = open(flash);
+            assert_eq!(queue.len(), 1, "after {} writes", writes);
+            queue.push(b"second").unwrap();
+            assert_eq!(pop(&mut queue).as_deref(), Some(&b"first"[..]));
+            assert_eq!(pop(&mut queue).as_deref(), Some(&b"second"[..]));
+        }
+    }
+
+    #[test]
+    fn corrupt_entries_are_dropped() {
+        let mut queue = open(RamFlash::new(4));
+        queue.push(b"flipped").unwrap();
+        queue.push(b"intact").unwrap();
+
+        let mut flash = queue.into_inner();
+        // first payload byte after the block and entry headers
+        flash.data[(BLOCK_HEADER_LEN + ENTRY_HEADER_LEN) as usize] ^= 0x01;
+        let mut queue = open(flash);
+        assert_eq!(pop(&mut queue).as_deref(), Some(&b"intact"[..]));
+        assert_eq!(queue.dropped(), 1);
+    }
+
+    #[test]
+    fn garbage_is_not_an_entry() {
+        let mut flash = RamFlash::new(4);
+        flash.data.fill(0x5A);
+        let mut queue = open(flash);
+        assert!(queue.is_empty());
+        queue.push(b"hello").unwrap();
+        assert_eq!(pop(&mut queue).as_deref(), Some(&b"hello"[..]));
+    }
+
+    #[test]
+    fn rejects_oversized_payloads_and_geometry() {
+        let mut queue = open(RamFlash::new(4));
+        let max = queue.max_payload_len();
+        assert_eq!(queue.push(&[0; 256]), Err(QueueError::TooLarge));
+        queue.push(&std::vec![7; max]).unwrap();
+        assert_eq!(pop(&mut queue), Some(std::vec![7; max]));
+
+        assert!(matches!(
+            FlashQueue::open(RamFlash::new(1), SECTOR),
+            Err(QueueError::Geometry)
+        ));
+        assert!(matches!(
+            FlashQueue::open(RamFlash::new(4), SECTOR + 4),
+            Err(QueueError::Geometry)
+        ));
+    }
+}
@@ -0,0 +1,133 @@
+//! Framing for small records kept in persistent storage.
+//!
+//! A record is laid out as `magic | slot | len | payload | crc32`, with all
+//! integers in little endian. Erased flash (all `0xFF`) never decodes, so an
+//! empty slot simply reads as "no record".
+
+const MAGIC: u16 = 0x7472;
+const HEADER_LEN: usize = 5;
+const CRC_LEN: usize = 4;
+
+/// Bytes a record needs on top of its payload.
+pub const RECORD_OVERHEAD: usize = HEADER_LEN + CRC_LEN;
+
+/// A value that can be stored in a slot of the settings partition.
+pub trait Persist: Sized {
+    /// Slot the record lives in, every type needs its own.
+    const SLOT: u8;
+    /// Upper bound for the encoded payload.
+    const MAX_LEN: usize;
+
+    /// Writes the payload into `buf`, returning the amount of bytes used.
+    fn encode(&self, buf: &mut [u8]) -> Option<usize>;
+
+    /// Parses a payload, `None` if it is malformed or fails validation.
+    fn decode(buf: &[u8]) -> Option<Self>;
+}
+
+/// Frames `payload` for `slot` into `out`, returning the record length.
+pub fn encode(slot: u8, payload: &[u8], out: &mut [u8]) -> Option<usize> {
+    let len = u16::try_from(payload.len()).ok()?;
+    let total = RECORD_OVERHEAD + payload.len();
+    if out.len() < total {
+        return None;
+    }
+
+    out[0..2].copy_from_slice(&MAGIC.to_le_bytes());
+    out[2] = slot;
+    out[3..5].copy_from_slice(&len.to_le_bytes());
+    out[HEADER_LEN..HEADER_LEN + payload.len()].copy_from_slice(payload);
+
+    let crc = crc32(&out[2..HEADER_LEN + payload.len()]);
+    out[HEADER_LEN + payload.len()..total].copy_from_slice(&crc.to_le_bytes());
+
+    Some(total)
+}
+
+/// Returns the payload of a record for `slot`, `None` if `raw` does not
+/// start with a valid one.
+pub fn decode(slot: u8, raw: &[u8]) -> Option<&[u8]> {
+    if raw.len() < RECORD_OVERHEAD {
+        return None;
+    }
+    if u16::from_le_bytes([raw[0], raw[1]]) != MAGIC || raw[2] != slot {
+        return None;
+    }
+
+    let len = u16::from_le_bytes([raw[3], raw[4]]) as usize;
+    let end = HEADER_LEN + len;
+    if raw.len() < end + CRC_LEN {
+        return None;
+    }
+
+    let crc = u32::from_le_bytes([raw[end], raw[end + 1], raw[end + 2], raw[end + 3]]);
+    if crc32(&raw[2..end]) != crc {
+        return None;
+    }
+
+    Some(&raw[HEADER_LEN..end])
+}
+
+/// CRC-32 (IEEE 802.3), bitwise since records are tiny.
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for byte in data {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xEDB8_8320 & mask);
+        }
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn crc32_check_value() {
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+    }
+
+    #[test]
+    fn roundtrip() {
+        let mut buf = [0xFF; 32];
+        let len = encode(3, b"hello", &mut buf).unwrap();
+        assert_eq!(len, 5 + RECORD_OVERHEAD);
+        assert_eq!(decode(3, &buf), Some(&b"hello"[..]));
+    }
+
+    #[test]
+    fn erased_flash_is_empty() {
+        assert_eq!(decode(0, &[0xFF; 64]), None);
+    }
+
+    #[test]
+    fn wrong_slot_is_rejected() {
+        let mut buf = [0; 32];
+        encode(1, b"abc", &mut buf).unwrap();
+        assert_eq!(decode(2, &buf), None);
+    }
+
+    #[test]
+    fn corruption_is_detected() {
+        let mut buf = [0; 32];
+        encode(1, b"abc", &mut buf).unwrap();
+        buf[HEADER_LEN + 1] ^= 0x01;
+        assert_eq!(decode(1, &buf), None);
+    }
+
+    #[test]
+    fn truncated_record_is_rejected() {
+        let mut buf = [0; 32];
+        let len = encode(1, b"abc", &mut buf).unwrap();
+        assert_eq!(decode(1, &buf[..len - 1]), None);
+    }
+
+    #[test]
+    fn too_small_output() {
+        let mut buf = [0; RECORD_OVERHEAD + 2];
+        assert_eq!(encode(1, b"abc", &mut buf), None);
+    }
+}
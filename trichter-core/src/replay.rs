@@ -0,0 +1,168 @@
+//! Playback of recorded pulse traces.
+//!
+//! A trace is plain text with one pulse per line, given as microsecond offset
+//! from the start of the recording. Empty lines and everything after a `#`
+//! are ignored:
+//!
+//! ```text
+//! # 0.5 L poured at event X
+//! 0
+//! 12480
+//! 24911
+//! ```
+
+use crate::source::{PulseEvent, PulseSource};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum TraceError {
+    /// The line is not a number.
+    InvalidOffset { line: usize },
+    /// Offsets have to be ascending.
+    NotAscending { line: usize },
+}
+
+/// Parses a trace, yielding the offsets in microseconds.
+pub fn parse_trace(text: &str) -> impl Iterator<Item = Result<u64, TraceError>> + '_ {
+    let mut previous = 0;
+    text.lines()
+        .enumerate()
+        .filter_map(|(index, line)| {
+            let line_content = line.split('#').next().unwrap_or_default().trim();
+            (!line_content.is_empty()).then_some((index + 1, line_content))
+        })
+        .map(move |(line, offset)| {
+            let offset: u64 = offset
+                .parse()
+                .map_err(|_| TraceError::InvalidOffset { line })?;
+            if offset < previous {
+                return Err(TraceError::NotAscending { line });
+            }
+            previous = offset;
+            Ok(offset)
+        })
+}
+
+/// Plays back a trace in virtual time: waiting never blocks, the clock jumps
+/// straight to the next pulse or deadline.
+pub struct ReplaySource<'a> {
+    trace: &'a [u64],
+    start_us: u64,
+    next: usize,
+    now_us: u64,
+}
+
+impl<'a> ReplaySource<'a> {
+    /// Replays `trace` with its first offset mapped to `start_us`.
+    pub fn new(trace: &'a [u64], start_us: u64) -> Self {
+        Self {
+            trace,
+            start_us,
+            next: 0,
+            now_us: start_us,
+        }
+    }
+
+    pub fn now_us(&self) -> u64 {
+        self.now_us
+    }
+
+    /// Pulses that were not played back yet.
+    pub fn remaining(&self) -> usize {
+        self.trace.len() - self.next
+    }
+}
+
+impl PulseSource for ReplaySource<'_> {
+    async fn wait(&mut self, deadline_us: Option<u64>) -> PulseEvent {
+        let next_us = self
+            .trace
+            .get(self.next)
+            .map(|offset| self.start_us + offset);
+        if let Some(at_us) = next_us.filter(|at_us| deadline_us.is_none_or(|d| *at_us < d)) {
+            // pulses with the same timestamp are reported as one batch
+            let count = self.trace[self.next..]
+                .iter()
+                .take_while(|offset| self.start_us + **offset == at_us)
+                .count();
+            self.next += count;
+            self.now_us = self.now_us.max(at_us);
+            return PulseEvent::Pulses {
+                at_us,
+                count: count as u32,
+            };
+        }
+
+        match deadline_us {
+            Some(deadline_us) => {
+                self.now_us = self.now_us.max(deadline_us);
+                PulseEvent::Deadline { at_us: self.now_us }
+            }
+            None => PulseEvent::End,
+        }
+    }
+
+    fn clear(&mut self) {
+        // nothing can arrive unnoticed in virtual time
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block_on;
+
+    #[test]
+    fn parses_offsets_and_skips_comments() {
+        let trace: Result<Vec<_>, _> =
+            parse_trace("# header\n0\n\n 100 # first\n250\r\n").collect();
+        assert_eq!(trace, Ok(vec![0, 100, 250]));
+    }
+
+    #[test]
+    fn rejects_malformed_traces() {
+        let trace: Result<Vec<_>, _> = parse_trace("0\nabc\n").collect();
+        assert_eq!(trace, Err(TraceError::InvalidOffset { line: 2 }));
+        let trace: Result<Vec<_>, _> = parse_trace("# x\n100\n50\n").collect();
+        assert_eq!(trace, Err(TraceError::NotAscending { line: 3 }));
+    }
+
+    #[test]
+    fn plays_back_in_virtual_time() {
+        let trace = [0, 100, 100, 500];
+        let mut source = ReplaySource::new(&trace, 1_000);
+
+        assert_eq!(
+            block_on(source.wait(None)),
+            PulseEvent::Pulses {
+                at_us: 1_000,
+                count: 1
+            }
+        );
+        assert_eq!(
+            block_on(source.wait(Some(1_300))),
+            PulseEvent::Pulses {
+                at_us: 1_100,
+                count: 2
+            }
+        );
+        assert_eq!(
+            block_on(source.wait(Some(1_300))),
+            PulseEvent::Deadline { at_us: 1_300 }
+        );
+        assert_eq!(source.remaining(), 1);
+        assert_eq!(
+            block_on(source.wait(Some(2_000))),
+            PulseEvent::Pulses {
+                at_us: 1_500,
+                count: 1
+            }
+        );
+        assert_eq!(
+            block_on(source.wait(Some(2_000))),
+            PulseEvent::Deadline { at_us: 2_000 }
+        );
+        assert_eq!(block_on(source.wait(None)), PulseEvent::End);
+        assert_eq!(source.now_us(), 2_000);
+    }
+}
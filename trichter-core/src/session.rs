@@ -0,0 +1,450 @@
+//! Detection of drinking sessions from a stream of sensor pulses.
+//!
+//! The [`SessionDetector`] is fed with pulse timestamps and timer ticks and
+//! walks through `Idle → StartingUp → Running → Finished/Aborted`. It does not
+//! wait for anything itself, the caller asks for the next [`deadline`] and
+//! calls [`on_tick`] once it has passed.
+//!
+//! [`deadline`]: SessionDetector::deadline
+//! [`on_tick`]: SessionDetector::on_tick
+
+pub use crate::overrun::OverrunReason;
+
+/// Amount of pulses that have to arrive within `length_us` after the first
+/// pulse for a session to be considered started.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct StartupWindow {
+    pub pulses: u32,
+    pub length_us: u64,
+}
+
+impl StartupWindow {
+    pub fn new(pulses: u32, window_ms: u64) -> Self {
+        Self {
+            pulses,
+            length_us: window_ms * 1_000,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum SessionState {
+    /// Waiting for the first pulse.
+    Idle,
+    /// The startup window is open.
+    StartingUp,
+    /// The startup window was fulfilled, waiting for the idle timeout.
+    Running,
+    /// The idle timeout elapsed, the session is complete.
+    Finished,
+    /// The startup window elapsed without enough pulses.
+    Aborted,
+}
+
+/// Raw pulse statistics of a finished session.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct DetectedSession {
+    pub pulses: u32,
+    pub first_us: u64,
+    pub last_us: u64,
+}
+
+impl DetectedSession {
+    /// Time between the first and the last pulse.
+    pub fn span_us(&self) -> u64 {
+        self.last_us - self.first_us
+    }
+
+    /// Span plus one mean pulse interval.
+    ///
+    /// `pulses` timestamps only enclose `pulses - 1` intervals, this accounts
+    /// the flow of the last pulse as well so `pulses / duration` is the mean
+    /// pulse frequency.
+    pub fn duration_us(&self) -> u64 {
+        flow_duration_us(self.span_us(), self.pulses)
+    }
+}
+
+/// `span_us` between the first and the last of `pulses` plus one mean pulse
+/// interval, 0 for a single pulse. See [`DetectedSession::duration_us`].
+pub fn flow_duration_us(span_us: u64, pulses: u32) -> u64 {
+    if pulses <= 1 {
+        return span_us;
+    }
+    span_us + span_us / (pulses as u64 - 1)
+}
+
+/// A startup window that was opened but not fulfilled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct FalseStart {
+    /// Timestamp of the pulse that opened the window.
+    pub at_us: u64,
+    pub pulses: u32,
+    pub window_us: u64,
+}
+
+/// Noteworthy things that happen around sessions, reported as telemetry.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum SessionEvent {
+    FalseStart(FalseStart),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Transition {
+    StartingUp,
+    Running,
+    Finished(DetectedSession),
+    Aborted(FalseStart),
+}
+
+/// How a session ended.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum SessionOutcome {
+    /// The flow stopped for longer than the idle timeout.
+    Completed,
+    /// The session was cut off, its result is not a valid run.
+    Overrun(OverrunReason),
+}
+
+pub struct SessionDetector {
+    window: StartupWindow,
+    idle_timeout_us: u64,
+    state: SessionState,
+    pulses: u32,
+    first_us: u64,
+    last_us: u64,
+}
+
+impl SessionDetector {
+    pub fn new(window: StartupWindow, idle_timeout_us: u64) -> Self {
+        Self {
+            window,
+            idle_timeout_us,
+            state: SessionState::Idle,
+            pulses: 0,
+            first_us: 0,
+            last_us: 0,
+        }
+    }
+
+    pub fn state(&self) -> SessionState {
+        self.state
+    }
+
+    pub fn pulses(&self) -> u32 {
+        self.pulses
+    }
+
+    /// Point in time at which [`Self::on_tick`] has to be called next.
+    ///
+    /// `None` means the detector only advances on pulses (or not at all once
+    /// it reached a final state).
+    pub fn deadline(&self) -> Option<u64> {
+        match self.state {
+            SessionState::StartingUp => Some(self.first_us.saturating_add(self.window.length_us)),
+            SessionState::Running => Some(self.last_us.saturating_add(self.idle_timeout_us)),
+            _ => None,
+        }
+    }
+
+    /// Returns to `Idle`, required after `Finished` or `Aborted`.
+    pub fn reset(&mut self) {
+        self.state = SessionState::Idle;
+        self.pulses = 0;
+        self.first_us = 0;
+        self.last_us = 0;
+    }
+
+    /// Ends a running session right away, e.g. because it overran. Returns
+    /// the session so far, the detector is `Finished` afterwards.
+    pub fn cut_off(&mut self) -> Option<DetectedSession> {
+        if self.state != SessionState::Running {
+            return None;
+        }
+        self.state = SessionState::Finished;
+        Some(DetectedSession {
+            pulses: self.pulses,
+            first_us: self.first_us,
+            last_us: self.last_us,
+        })
+    }
+
+    /// Feeds a pulse that arrived at `at_us`.
+    ///
+    /// A pulse at or after the current deadline first resolves the deadline.
+    /// If that ends the session (`Finished` or `Aborted`) the pulse is not
+    /// consumed and has to be fed again after [`Self::reset`].
+    pub fn on_pulse(&mut self, at_us: u64) -> Option<Transition> {
+        match self.state {
+            SessionState::Idle => {
+                self.state = SessionState::StartingUp;
+                self.pulses = 1;
+                self.first_us = at_us;
+                self.last_us = at_us;
+                Some(Transition::StartingUp)
+            }
+            SessionState::StartingUp | SessionState::Running => {
+                let mut transition = None;
+                if self.deadline().is_some_and(|deadline| at_us >= deadline) {
+                    transition = self.on_tick(at_us);
+                    if self.state != SessionState::Running {
+                        return transition;
+                    }
+                }
+                self.pulses += 1;
+                self.last_us = at_us;
+                transition
+            }
+            SessionState::Finished | SessionState::Aborted => None,
+        }
+    }
+
+    /// Advances the timers to `now_us`.
+    pub fn on_tick(&mut self, now_us: u64) -> Option<Transition> {
+        let deadline = self.deadline()?;
+        if now_us < deadline {
+            return None;
+        }
+
+        match self.state {
+            SessionState::StartingUp if self.pulses >= self.window.pulses => {
+                self.state = SessionState::Running;
+                Some(Transition::Running)
+            }
+            SessionState::StartingUp => {
+                self.state = SessionState::Aborted;
+                Some(Transition::Aborted(FalseStart {
+                    at_us: self.first_us,
+                    pulses: self.pulses,
+                    window_us: self.window.length_us,
+                }))
+            }
+            SessionState::Running => {
+                self.state = SessionState::Finished;
+                Some(Transition::Finished(DetectedSession {
+                    pulses: self.pulses,
+                    first_us: self.first_us,
+                    last_us: self.last_us,
+                }))
+            }
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const IDLE_TIMEOUT_US: u64 = 500_000;
+
+    fn detector(pulses: u32, window_ms: u64) -> SessionDetector {
+        SessionDetector::new(StartupWindow::new(pulses, window_ms), IDLE_TIMEOUT_US)
+    }
+
+    /// Feeds `count` pulses spaced `interval_us` apart, starting at `start_us`.
+    fn feed(detector: &mut SessionDetector, start_us: u64, interval_us: u64, count: u32) {
+        for i in 0..count as u64 {
+            detector.on_pulse(start_us + i * interval_us);
+        }
+    }
+
+    #[test]
+    fn idle_has_no_deadline() {
+        let mut d = detector(5, 200);
+        assert_eq!(d.state(), SessionState::Idle);
+        assert_eq!(d.deadline(), None);
+        assert_eq!(d.on_tick(u64::MAX), None);
+    }
+
+    #[test]
+    fn first_pulse_opens_window() {
+        let mut d = detector(5, 200);
+        assert_eq!(d.on_pulse(1_000), Some(Transition::StartingUp));
+        assert_eq!(d.state(), SessionState::StartingUp);
+        assert_eq!(d.deadline(), Some(201_000));
+    }
+
+    #[test]
+    fn window_is_only_resolved_at_deadline() {
+        let mut d = detector(5, 200);
+        feed(&mut d, 0, 10_000, 10);
+        assert_eq!(d.on_tick(199_999), None);
+        assert_eq!(d.state(), SessionState::StartingUp);
+        assert_eq!(d.on_tick(200_000), Some(Transition::Running));
+    }
+
+    #[test]
+    fn exactly_required_pulses_start_session() {
+        let mut d = detector(5, 200);
+        feed(&mut d, 0, 40_000, 5);
+        assert_eq!(d.pulses(), 5);
+        assert_eq!(d.on_tick(200_000), Some(Transition::Running));
+        assert_eq!(d.state(), SessionState::Running);
+    }
+
+    #[test]
+    fn one_pulse_short_aborts() {
+        let mut d = detector(5, 200);
+        feed(&mut d, 0, 40_000, 4);
+        assert_eq!(
+            d.on_tick(200_000),
+            Some(Transition::Aborted(FalseStart {
+                at_us: 0,
+                pulses: 4,
+                window_us: 200_000
+            }))
+        );
+        assert_eq!(d.state(), SessionState::Aborted);
+        assert_eq!(d.deadline(), None);
+    }
+
+    #[test]
+    fn pulse_just_before_deadline_counts() {
+        let mut d = detector(2, 200);
+        d.on_pulse(0);
+        assert_eq!(d.on_pulse(199_999), None);
+        assert_eq!(d.on_tick(200_000), Some(Transition::Running));
+    }
+
+    #[test]
+    fn pulse_at_deadline_is_outside_window() {
+        let mut d = detector(2, 200);
+        d.on_pulse(0);
+        assert_eq!(
+            d.on_pulse(200_000),
+            Some(Transition::Aborted(FalseStart {
+                at_us: 0,
+                pulses: 1,
+                window_us: 200_000
+            }))
+        );
+
+        // the late pulse was not consumed and starts the next attempt
+        d.reset();
+        assert_eq!(d.on_pulse(200_000), Some(Transition::StartingUp));
+        assert_eq!(d.deadline(), Some(400_000));
+    }
+
+    #[test]
+    fn late_pulse_after_fulfilled_window_joins_session() {
+        let mut d = detector(2, 200);
+        feed(&mut d, 0, 10_000, 2);
+        assert_eq!(d.on_pulse(250_000), Some(Transition::Running));
+        assert_eq!(d.pulses(), 3);
+        assert_eq!(d.deadline(), Some(250_000 + IDLE_TIMEOUT_US));
+    }
+
+    #[test]
+    fn single_pulse_window_still_waits_full_length() {
+        let mut d = detector(1, 200);
+        d.on_pulse(0);
+        assert_eq!(d.on_tick(100_000), None);
+        assert_eq!(d.on_tick(200_000), Some(Transition::Running));
+    }
+
+    #[test]
+    fn zero_length_window_resolves_immediately() {
+        let mut d = detector(1, 0);
+        d.on_pulse(5_000);
+        assert_eq!(d.deadline(), Some(5_000));
+        assert_eq!(d.on_tick(5_000), Some(Transition::Running));
+
+        let mut d = detector(2, 0);
+        d.on_pulse(5_000);
+        assert!(matches!(d.on_tick(5_000), Some(Transition::Aborted(_))));
+    }
+
+    #[test]
+    fn pulses_extend_idle_timeout() {
+        let mut d = detector(2, 200);
+        feed(&mut d, 0, 40_000, 5);
+        assert_eq!(d.on_tick(200_000), Some(Transition::Running));
+
+        d.on_pulse(600_000);
+        assert_eq!(d.on_tick(600_000 + IDLE_TIMEOUT_US - 1), None);
+        assert_eq!(
+            d.on_tick(600_000 + IDLE_TIMEOUT_US),
+            Some(Transition::Finished(DetectedSession {
+                pulses: 6,
+                first_us: 0,
+                last_us: 600_000,
+            }))
+        );
+        assert_eq!(d.state(), SessionState::Finished);
+    }
+
+    #[test]
+    fn pulse_after_idle_timeout_finishes_without_consuming() {
+        let mut d = detector(2, 200);
+        feed(&mut d, 0, 100_000, 3);
+        d.on_tick(200_000);
+
+        let transition = d.on_pulse(200_000 + IDLE_TIMEOUT_US);
+        assert_eq!(
+            transition,
+            Some(Transition::Finished(DetectedSession {
+                pulses: 3,
+                first_us: 0,
+                last_us: 200_000,
+            }))
+        );
+    }
+
+    #[test]
+    fn duration_includes_one_mean_interval() {
+        let session = DetectedSession {
+            pulses: 5,
+            first_us: 1_000,
+            last_us: 401_000,
+        };
+        assert_eq!(session.span_us(), 400_000);
+        assert_eq!(session.duration_us(), 500_000);
+
+        let single = DetectedSession {
+            pulses: 1,
+            first_us: 1_000,
+            last_us: 1_000,
+        };
+        assert_eq!(single.duration_us(), 0);
+    }
+
+    #[test]
+    fn final_states_ignore_input_until_reset() {
+        let mut d = detector(5, 200);
+        d.on_pulse(0);
+        d.on_tick(200_000);
+        assert_eq!(d.state(), SessionState::Aborted);
+        assert_eq!(d.on_pulse(300_000), None);
+        assert_eq!(d.on_tick(1_000_000), None);
+
+        d.reset();
+        assert_eq!(d.state(), SessionState::Idle);
+        assert_eq!(d.pulses(), 0);
+    }
+
+    #[test]
+    fn cut_off_ends_running_session() {
+        let mut d = detector(5, 200);
+        assert_eq!(d.cut_off(), None);
+        feed(&mut d, 0, 10_000, 30);
+        assert_eq!(d.state(), SessionState::Running);
+        assert_eq!(
+            d.cut_off(),
+            Some(DetectedSession {
+                pulses: 30,
+                first_us: 0,
+                last_us: 290_000,
+            })
+        );
+        assert_eq!(d.state(), SessionState::Finished);
+        assert_eq!(d.deadline(), None);
+    }
+}
@@ -0,0 +1,294 @@
+//! Session parameters that can be tuned at runtime.
+//!
+//! The settings are persisted in their own record slot, prefixed with the
+//! [`VERSION`] of the layout, and can be changed
+//! with form encoded updates (`idle_timeout_ms=800&startup_pulses=4`). Every
+//! change is validated against the bounds below before it is applied.
+
+use core::ops::RangeInclusive;
+
+use crate::{meter::SessionLimits, record::Persist, session::StartupWindow};
+
+pub const IDLE_TIMEOUT_MS: RangeInclusive<u32> = 100..=10_000;
+pub const STARTUP_PULSES: RangeInclusive<u32> = 1..=100;
+pub const STARTUP_WINDOW_MS: RangeInclusive<u32> = 10..=5_000;
+pub const MAX_DURATION_MS: RangeInclusive<u32> = 5_000..=600_000;
+pub const MIN_VOLUME_ML: RangeInclusive<u32> = 0..=5_000;
+pub const MIN_PULSE_INTERVAL_US: RangeInclusive<u32> = 0..=10_000;
+
+/// Layout of the persisted settings. Records of another version are not
+/// decoded, the defaults apply until the settings are stored again.
+pub const VERSION: u8 = 2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct SessionSettings {
+    /// A running session ends after this long without a pulse.
+    pub idle_timeout_ms: u32,
+    /// Pulses required within `startup_window_ms` to start a session.
+    pub startup_pulses: u32,
+    pub startup_window_ms: u32,
+    /// Sessions are cut off after this long.
+    pub max_duration_ms: u32,
+    /// Shorter sessions are discarded, 0 keeps every session.
+    pub min_volume_ml: u32,
+    /// Pulses closer together are rejected as glitches, 0 disables the filter.
+    pub min_pulse_interval_us: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum SettingsError {
+    IdleTimeout,
+    StartupPulses,
+    StartupWindow,
+    /// Out of bounds or not longer than the startup window and idle timeout.
+    MaxDuration,
+    MinVolume,
+    MinPulseInterval,
+    UnknownKey,
+    InvalidValue,
+}
+
+impl SessionSettings {
+    pub fn startup_window(&self) -> StartupWindow {
+        StartupWindow::new(self.startup_pulses, self.startup_window_ms as u64)
+    }
+
+    pub fn limits(&self) -> SessionLimits {
+        SessionLimits {
+            startup_window: self.startup_window(),
+            idle_timeout_us: self.idle_timeout_ms as u64 * 1_000,
+            max_duration_us: self.max_duration_ms as u64 * 1_000,
+            min_pulse_interval_us: self.min_pulse_interval_us as u64,
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), SettingsError> {
+        if !IDLE_TIMEOUT_MS.contains(&self.idle_timeout_ms) {
+            return Err(SettingsError::IdleTimeout);
+        }
+        if !STARTUP_PULSES.contains(&self.startup_pulses) {
+            return Err(SettingsError::StartupPulses);
+        }
+        if !STARTUP_WINDOW_MS.contains(&self.startup_window_ms) {
+            return Err(SettingsError::StartupWindow);
+        }
+        if !MAX_DURATION_MS.contains(&self.max_duration_ms)
+            || self.max_duration_ms <= self.startup_window_ms
+            || self.max_duration_ms <= self.idle_timeout_ms
+        {
+            return Err(SettingsError::MaxDuration);
+        }
+        if !MIN_VOLUME_ML.contains(&self.min_volume_ml) {
+            return Err(SettingsError::MinVolume);
+        }
+        if !MIN_PULSE_INTERVAL_US.contains(&self.min_pulse_interval_us) {
+            return Err(SettingsError::MinPulseInterval);
+        }
+        Ok(())
+    }
+
+    /// Sets a single value by its field name, without validating it.
+    pub fn set(&mut self, key: &str, value: &str) -> Result<(), SettingsError> {
+        let field = match key {
+            "idle_timeout_ms" => &mut self.idle_timeout_ms,
+            "startup_pulses" => &mut self.startup_pulses,
+            "startup_window_ms" => &mut self.startup_window_ms,
+            "max_duration_ms" => &mut self.max_duration_ms,
+            "min_volume_ml" => &mut self.min_volume_ml,
+            "min_pulse_interval_us" => &mut self.min_pulse_interval_us,
+            _ => return Err(SettingsError::UnknownKey),
+        };
+        *field = value
+            .trim()
+            .parse()
+            .map_err(|_| SettingsError::InvalidValue)?;
+        Ok(())
+    }
+
+    /// Applies a form encoded update and returns the new settings if they
+    /// are valid as a whole. `self` is left untouched either way.
+    pub fn update(&self, form: &str) -> Result<Self, SettingsError> {
+        let mut updated = *self;
+        for pair in form.trim().split('&').filter(|pair| !pair.is_empty()) {
+            let (key, value) = pair.split_once('=').ok_or(SettingsError::InvalidValue)?;
+            updated.set(key.trim(), value)?;
+        }
+        updated.validate()?;
+        Ok(updated)
+    }
+}
+
+impl Default for SessionSettings {
+    fn default() -> Self {
+        Self {
+            idle_timeout_ms: 500,
+            startup_pulses: 5,
+            startup_window_ms: 200,
+            max_duration_ms: 120_000,
+            min_volume_ml: 0,
+            min_pulse_interval_us: 2_000,
+        }
+    }
+}
+
+impl Persist for SessionSettings {
+    const SLOT: u8 = 1;
+    const MAX_LEN: usize = 1 + 6 * 4;
+
+    fn encode(&self, buf: &mut [u8]) -> Option<usize> {
+        let fields = [
+            self.idle_timeout_ms,
+            self.startup_pulses,
+            self.startup_window_ms,
+            self.max_duration_ms,
+            self.min_volume_ml,
+            self.min_pulse_interval_us,
+        ];
+        let buf = buf.get_mut(..Self::MAX_LEN)?;
+        buf[0] = VERSION;
+        for (field, chunk) in fields.iter().zip(buf[1..].chunks_exact_mut(4)) {
+            chunk.copy_from_slice(&field.to_le_bytes());
+        }
+        Some(Self::MAX_LEN)
+    }
+
+    fn decode(buf: &[u8]) -> Option<Self> {
+        let (&version, buf) = buf.get(..Self::MAX_LEN)?.split_first()?;
+        if version != VERSION {
+            return None;
+        }
+        let mut fields = buf
+            .chunks_exact(4)
+            .map(|chunk| u32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]));
+        let settings = Self {
+            idle_timeout_ms: fields.next()?,
+            startup_pulses: fields.next()?,
+            startup_window_ms: fields.next()?,
+            max_duration_ms: fields.next()?,
+            min_volume_ml: fields.next()?,
+            min_pulse_interval_us: fields.next()?,
+        };
+        settings.validate().ok()?;
+        Some(settings)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn default_is_valid() {
+        assert_eq!(SessionSettings::default().validate(), Ok(()));
+    }
+
+    #[test]
+    fn update_applies_all_pairs() {
+        let settings = SessionSettings::default()
+            .update("idle_timeout_ms=800&startup_pulses=4&min_volume_ml=50\r\n")
+            .unwrap();
+        assert_eq!(settings.idle_timeout_ms, 800);
+        assert_eq!(settings.startup_pulses, 4);
+        assert_eq!(settings.min_volume_ml, 50);
+        assert_eq!(settings.startup_window_ms, 200);
+    }
+
+    #[test]
+    fn update_rejects_unknown_keys_and_garbage() {
+        let settings = SessionSettings::default();
+        assert_eq!(settings.update("foo=1"), Err(SettingsError::UnknownKey));
+        assert_eq!(
+            settings.update("idle_timeout_ms=fast"),
+            Err(SettingsError::InvalidValue)
+        );
+        assert_eq!(
+            settings.update("idle_timeout_ms"),
+            Err(SettingsError::InvalidValue)
+        );
+        assert_eq!(
+            settings.update("startup_pulses=-1"),
+            Err(SettingsError::InvalidValue)
+        );
+    }
+
+    #[test]
+    fn update_is_validated_as_a_whole() {
+        let settings = SessionSettings::default();
+        assert_eq!(
+            settings.update("idle_timeout_ms=50"),
+            Err(SettingsError::IdleTimeout)
+        );
+        assert_eq!(
+            settings.update("startup_pulses=0"),
+            Err(SettingsError::StartupPulses)
+        );
+        assert_eq!(
+            settings.update("min_pulse_interval_us=20000"),
+            Err(SettingsError::MinPulseInterval)
+        );
+        // fine on its own, but not shorter than the idle timeout
+        assert_eq!(
+            settings.update("idle_timeout_ms=9000&max_duration_ms=8000"),
+            Err(SettingsError::MaxDuration)
+        );
+        assert_eq!(
+            settings.update("max_duration_ms=8000&idle_timeout_ms=2000"),
+            Ok(SessionSettings {
+                idle_timeout_ms: 2_000,
+                max_duration_ms: 8_000,
+                ..settings
+            })
+        );
+    }
+
+    #[test]
+    fn empty_update_keeps_settings() {
+        let settings = SessionSettings::default();
+        assert_eq!(settings.update(""), Ok(settings));
+    }
+
+    #[test]
+    fn persist_roundtrip() {
+        let settings = SessionSettings {
+            idle_timeout_ms: 750,
+            startup_pulses: 3,
+            startup_window_ms: 150,
+            max_duration_ms: 60_000,
+            min_volume_ml: 100,
+            min_pulse_interval_us: 1_500,
+        };
+        let mut buf = [0; SessionSettings::MAX_LEN];
+        let len = settings.encode(&mut buf).unwrap();
+        assert_eq!(SessionSettings::decode(&buf[..len]), Some(settings));
+    }
+
+    #[test]
+    fn decode_rejects_invalid_settings() {
+        let mut buf = [0; SessionSettings::MAX_LEN];
+        SessionSettings::default().encode(&mut buf).unwrap();
+        assert_eq!(SessionSettings::decode(&buf[..4]), None);
+        // startup_pulses = 0
+        buf[5..9].copy_from_slice(&0u32.to_le_bytes());
+        assert_eq!(SessionSettings::decode(&buf), None);
+    }
+
+    #[test]
+    fn decode_rejects_other_versions() {
+        let mut buf = [0; SessionSettings::MAX_LEN];
+        SessionSettings::default().encode(&mut buf).unwrap();
+        buf[0] = VERSION + 1;
+        assert_eq!(SessionSettings::decode(&buf), None);
+
+        // the unversioned layout with five fields
+        let mut unversioned = [0; 5 * 4];
+        for (field, chunk) in [500u32, 5, 250, 60_000, 0]
+            .iter()
+            .zip(unversioned.chunks_exact_mut(4))
+        {
+            chunk.copy_from_slice(&field.to_le_bytes());
+        }
+        assert_eq!(SessionSettings::decode(&unversioned), None);
+    }
+}
@@ -0,0 +1,25 @@
+//! Abstraction over where sensor pulses come from.
+//!
+//! On the device this is the GPIO or PCNT input, on the host a
+//! [`ReplaySource`](crate::replay::ReplaySource) playing back recorded traces.
+
+/// What a [`PulseSource`] observed while waiting.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum PulseEvent {
+    /// `count` pulses arrived at `at_us`.
+    Pulses { at_us: u64, count: u32 },
+    /// The deadline passed without pulses, `at_us` is the current time.
+    Deadline { at_us: u64 },
+    /// The source will never produce pulses again.
+    End,
+}
+
+#[allow(async_fn_in_trait)]
+pub trait PulseSource {
+    /// Waits for the next pulses, or until `deadline_us` if given.
+    async fn wait(&mut self, deadline_us: Option<u64>) -> PulseEvent;
+
+    /// Drops pulses that arrived while nobody was waiting.
+    fn clear(&mut self);
+}
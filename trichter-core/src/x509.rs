@@ -0,0 +1,571 @@
+//! Just enough X.509 to pin the backend's certificate.
+//!
+//! Certificates are parsed in place from DER into the parts [`verify_chain`]
+//! needs. The chain the server presents is trusted if the leaf is issued for
+//! the host the device connects to, and either the leaf's public key matches
+//! a pinned fingerprint or the chain leads up to a pinned CA through valid
+//! signatures of CA certificates. Signatures are checked by the caller, this
+//! crate has no public key crypto.
+//!
+//! Validity periods are not checked, the device has no trustworthy clock
+//! before it is online. The pin is what makes a certificate trusted.
+
+use core::net::Ipv4Addr;
+
+use sha2::{Digest, Sha256};
+
+/// Length of a [`Pin::PublicKey`] fingerprint.
+pub const DIGEST_LEN: usize = 32;
+
+/// Object identifiers, as the content bytes of the DER encoding.
+pub mod oid {
+    /// ecdsa-with-SHA256, 1.2.840.10045.4.3.2
+    pub const ECDSA_WITH_SHA256: &[u8] = &[0x2a, 0x86, 0x48, 0xce, 0x3d, 0x04, 0x03, 0x02];
+    /// id-ecPublicKey, 1.2.840.10045.2.1
+    pub const EC_PUBLIC_KEY: &[u8] = &[0x2a, 0x86, 0x48, 0xce, 0x3d, 0x02, 0x01];
+    /// prime256v1, 1.2.840.10045.3.1.7
+    pub const PRIME256V1: &[u8] = &[0x2a, 0x86, 0x48, 0xce, 0x3d, 0x03, 0x01, 0x07];
+    /// id-at-commonName, 2.5.4.3
+    pub const COMMON_NAME: &[u8] = &[0x55, 0x04, 0x03];
+    /// id-ce-subjectAltName, 2.5.29.17
+    pub const SUBJECT_ALT_NAME: &[u8] = &[0x55, 0x1d, 0x11];
+    /// id-ce-basicConstraints, 2.5.29.19
+    pub const BASIC_CONSTRAINTS: &[u8] = &[0x55, 0x1d, 0x13];
+}
+
+const SEQUENCE: u8 = 0x30;
+const SET: u8 = 0x31;
+const BOOLEAN: u8 = 0x01;
+const INTEGER: u8 = 0x02;
+const BIT_STRING: u8 = 0x03;
+const OCTET_STRING: u8 = 0x04;
+const OBJECT_IDENTIFIER: u8 = 0x06;
+const VERSION: u8 = 0xa0;
+const EXTENSIONS: u8 = 0xa3;
+/// The GeneralName choices of a subject alternative name that are matched.
+const DNS_NAME: u8 = 0x82;
+const IP_ADDRESS: u8 = 0x87;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum X509Error {
+    /// Not a DER encoded certificate.
+    Malformed,
+    /// The server sent no certificate.
+    Empty,
+    /// A certificate was not issued by the next one in the chain.
+    BrokenChain,
+    /// A signature in the chain did not verify.
+    BadSignature,
+    /// A certificate in the chain was issued by one that is not a CA.
+    NotACa,
+    /// The leaf is not issued for the host the device connected to.
+    WrongHost,
+    /// The chain is fine, but neither the leaf key nor the CA is pinned.
+    NotPinned,
+}
+
+/// What the backend's certificate is checked against.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Pin<'a> {
+    /// SHA-256 of the leaf certificate's DER encoded SubjectPublicKeyInfo,
+    /// as printed by
+    /// `openssl x509 -pubkey -noout | openssl pkey -pubin -outform DER | openssl dgst -sha256`.
+    PublicKey([u8; DIGEST_LEN]),
+    /// DER encoded certificate of the CA that issued the chain. It does not
+    /// have to be part of the chain the server sends.
+    Ca(&'a [u8]),
+}
+
+/// The parts of a certificate needed to check a chain.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Certificate<'a> {
+    /// The DER encoded TBSCertificate, which is what the issuer signed.
+    pub tbs: &'a [u8],
+    pub signature_algorithm: &'a [u8],
+    pub signature: &'a [u8],
+    /// DER encoded issuer name.
+    pub issuer: &'a [u8],
+    /// DER encoded subject name.
+    pub subject: &'a [u8],
+    /// DER encoded SubjectPublicKeyInfo.
+    pub spki: &'a [u8],
+    pub key_algorithm: &'a [u8],
+    /// The curve for EC keys, `None` if the parameters are not an OID.
+    pub key_curve: Option<&'a [u8]>,
+    /// The key itself, an uncompressed point for EC keys.
+    pub public_key: &'a [u8],
+    /// Whether the basic constraints allow the certificate to issue others.
+    pub is_ca: bool,
+    /// The DER encoded GeneralNames of the subject alternative name
+    /// extension, without their sequence.
+    pub alt_names: Option<&'a [u8]>,
+}
+
+impl<'a> Certificate<'a> {
+    pub fn parse(der: &'a [u8]) -> Result<Self, X509Error> {
+        let mut input = der;
+        let mut certificate = expect(&mut input, SEQUENCE)?;
+        if !input.is_empty() {
+            return Err(X509Error::Malformed);
+        }
+
+        let tbs_der = raw(&mut certificate, SEQUENCE)?;
+        let mut signature_algorithm = expect(&mut certificate, SEQUENCE)?;
+        let signature = bit_string(expect(&mut certificate, BIT_STRING)?)?;
+
+        let mut tbs = tbs_der;
+        let mut tbs = expect(&mut tbs, SEQUENCE)?;
+        if tbs.first() == Some(&VERSION) {
+            read(&mut tbs)?;
+        }
+        expect(&mut tbs, INTEGER)?;
+        expect(&mut tbs, SEQUENCE)?;
+        let issuer = raw(&mut tbs, SEQUENCE)?;
+        expect(&mut tbs, SEQUENCE)?;
+        let subject = raw(&mut tbs, SEQUENCE)?;
+        let spki = raw(&mut tbs, SEQUENCE)?;
+
+        let mut key_info = spki;
+        let mut key_info = expect(&mut key_info, SEQUENCE)?;
+        let mut key_algorithm = expect(&mut key_info, SEQUENCE)?;
+        let public_key = bit_string(expect(&mut key_info, BIT_STRING)?)?;
+        let algorithm = expect(&mut key_algorithm, OBJECT_IDENTIFIER)?;
+        let key_curve = expect(&mut key_algorithm, OBJECT_IDENTIFIER).ok();
+
+        let mut is_ca = false;
+        let mut alt_names = None;
+        while !tbs.is_empty() {
+            let (tag, mut extensions) = read(&mut tbs)?;
+            if tag != EXTENSIONS {
+                // the unique IDs, which nobody uses
+                continue;
+            }
+            let mut extensions = expect(&mut extensions, SEQUENCE)?;
+            while !extensions.is_empty() {
+                let mut extension = expect(&mut extensions, SEQUENCE)?;
+                let id = expect(&mut extension, OBJECT_IDENTIFIER)?;
+                if extension.first() == Some(&BOOLEAN) {
+                    // critical or not, only the known extensions matter
+                    read(&mut extension)?;
+                }
+                let mut value = expect(&mut extension, OCTET_STRING)?;
+                if id == oid::BASIC_CONSTRAINTS {
+                    let mut constraints = expect(&mut value, SEQUENCE)?;
+                    is_ca = matches!(read(&mut constraints), Ok((BOOLEAN, [flag])) if *flag != 0);
+                } else if id == oid::SUBJECT_ALT_NAME {
+                    alt_names = Some(expect(&mut value, SEQUENCE)?);
+                }
+            }
+        }
+
+        Ok(Self {
+            tbs: tbs_der,
+            signature_algorithm: expect(&mut signature_algorithm, OBJECT_IDENTIFIER)?,
+            signature,
+            issuer,
+            subject,
+            spki,
+            key_algorithm: algorithm,
+            key_curve,
+            public_key,
+            is_ca,
+            alt_names,
+        })
+    }
+
+    /// The last common name of the subject, if any.
+    pub fn common_name(&self) -> Option<&'a [u8]> {
+        let mut subject = self.subject;
+        let mut names = expect(&mut subject, SEQUENCE).ok()?;
+        let mut common_name = None;
+        while !names.is_empty() {
+            let mut attributes = expect(&mut names, SET).ok()?;
+            while !attributes.is_empty() {
+                let mut attribute = expect(&mut attributes, SEQUENCE).ok()?;
+                if expect(&mut attribute, OBJECT_IDENTIFIER).ok()? == oid::COMMON_NAME {
+                    common_name = Some(read(&mut attribute).ok()?.1);
+                }
+            }
+        }
+        common_name
+    }
+
+    /// Whether the certificate is issued for `host`, a name or an IPv4
+    /// address.
+    ///
+    /// Names are matched against the DNS names of the subject alternative
+    /// names, where a wildcard may stand for the leftmost label, and
+    /// addresses against their IP addresses. Only certificates without
+    /// alternative names are matched by the common name (RFC 6125).
+    pub fn matches_host(&self, host: &str) -> bool {
+        let address = host.parse::<Ipv4Addr>().ok();
+        let Some(mut names) = self.alt_names else {
+            return address.is_none()
+                && self.common_name().is_some_and(|cn| matches_name(cn, host));
+        };
+        while let Ok((tag, name)) = read(&mut names) {
+            let matches = match (tag, address) {
+                (DNS_NAME, None) => matches_name(name, host),
+                (IP_ADDRESS, Some(address)) => name == address.octets(),
+                _ => false,
+            };
+            if matches {
+                return true;
+            }
+        }
+        false
+    }
+
+    /// SHA-256 of the SubjectPublicKeyInfo, see [`Pin::PublicKey`].
+    pub fn spki_fingerprint(&self) -> [u8; DIGEST_LEN] {
+        Sha256::digest(self.spki).into()
+    }
+}
+
+/// Checks the chain the server sent, leaf first, against `pin` and `host`.
+///
+/// `verify(certificate, issuer)` has to check the signature of `certificate`
+/// with the public key of `issuer`. Returns the leaf, whose key the caller
+/// uses to check the handshake signature.
+pub fn verify_chain<'a>(
+    pin: &Pin<'_>,
+    host: &str,
+    chain: &[&'a [u8]],
+    mut verify: impl FnMut(&Certificate<'_>, &Certificate<'_>) -> bool,
+) -> Result<Certificate<'a>, X509Error> {
+    let (&leaf, intermediates) = chain.split_first().ok_or(X509Error::Empty)?;
+    let leaf = Certificate::parse(leaf)?;
+    if !leaf.matches_host(host) {
+        return Err(X509Error::WrongHost);
+    }
+    let ca = match pin {
+        Pin::PublicKey(fingerprint) => {
+            return if leaf.spki_fingerprint() == *fingerprint {
+                Ok(leaf)
+            } else {
+                Err(X509Error::NotPinned)
+            };
+        }
+        Pin::Ca(der) => Certificate::parse(der)?,
+    };
+
+    let mut certificate = leaf;
+    let mut intermediates = intermediates.iter();
+    loop {
+        if certificate.spki == ca.spki {
+            // the server sent the CA itself, everything below is checked
+            return Ok(leaf);
+        }
+        if certificate.issuer == ca.subject {
+            if !ca.is_ca {
+                return Err(X509Error::NotACa);
+            }
+            return if verify(&certificate, &ca) {
+                Ok(leaf)
+            } else {
+                Err(X509Error::BadSignature)
+            };
+        }
+        let Some(&issuer) = intermediates.next() else {
+            return Err(X509Error::NotPinned);
+        };
+        let issuer = Certificate::parse(issuer)?;
+        if certificate.issuer != issuer.subject {
+            return Err(X509Error::BrokenChain);
+        }
+        if !issuer.is_ca {
+            return Err(X509Error::NotACa);
+        }
+        if !verify(&certificate, &issuer) {
+            return Err(X509Error::BadSignature);
+        }
+        certificate = issuer;
+    }
+}
+
+/// Whether the DNS name or pattern `name` from a certificate matches `host`,
+/// ignoring case and a trailing dot of the host.
+fn matches_name(name: &[u8], host: &str) -> bool {
+    let host = host.strip_suffix('.').unwrap_or(host).as_bytes();
+    match name.strip_prefix(b"*.") {
+        Some(parent) => match host.iter().position(|&b| b == b'.') {
+            Some(dot) => dot > 0 && host[dot + 1..].eq_ignore_ascii_case(parent),
+            None => false,
+        },
+        None => host.eq_ignore_ascii_case(name),
+    }
+}
+
+/// Reads one TLV, returns its tag and value.
+fn read<'a>(input: &mut &'a [u8]) -> Result<(u8, &'a [u8]), X509Error> {
+    let (&tag, rest) = input.split_first().ok_or(X509Error::Malformed)?;
+    let (&first, mut rest) = rest.split_first().ok_or(X509Error::Malformed)?;
+    let len = if first < 0x80 {
+        first as usize
+    } else {
+        let octets = (first & 0x7f) as usize;
+        if octets == 0 || octets > 3 || rest.len() < octets {
+            return Err(X509Error::Malformed);
+        }
+        let (len, after) = rest.split_at(octets);
+        rest = after;
+        len.iter().fold(0, |len, &b| (len << 8) | b as usize)
+    };
+    if rest.len() < len {
+        return Err(X509Error::Malformed);
+    }
+    let (value, rest) = rest.split_at(len);
+    *input = rest;
+    Ok((tag, value))
+}
+
+/// Reads one TLV with the tag `tag`, returns its value.
+fn expect<'a>(input: &mut &'a [u8], tag: u8) -> Result<&'a [u8], X509Error> {
+    match read(input)? {
+        (found, value) if found == tag => Ok(value),
+        _ => Err(X509Error::Malformed),
+    }
+}
+
+/// Reads one TLV with the tag `tag`, returns all of its encoding.
+fn raw<'a>(input: &mut &'a [u8], tag: u8) -> Result<&'a [u8], X509Error> {
+    let start = *input;
+    expect(input, tag)?;
+    Ok(&start[..start.len() - input.len()])
+}
+
+/// The bytes of a bit string without unused bits.
+fn bit_string(value: &[u8]) -> Result<&[u8], X509Error> {
+    match value.split_first() {
+        Some((0, bits)) => Ok(bits),
+        _ => Err(X509Error::Malformed),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    //! The certificates in `tests/certs` are P-256 certificates made with
+    //! `openssl req -x509` and `openssl x509 -req`: a CA, a leaf it issued
+    //! directly, an intermediate CA and a leaf the intermediate issued, all
+    //! for `backend.example`. Two self-signed ones have the intermediate's
+    //! name without being a CA, and alternative names for a leaf.
+
+    use super::*;
+    use std::vec::Vec;
+
+    const CA: &[u8] = include_bytes!("../tests/certs/ca.der");
+    const INTERMEDIATE: &[u8] = include_bytes!("../tests/certs/intermediate.der");
+    const LEAF: &[u8] = include_bytes!("../tests/certs/leaf.der");
+    const LEAF_VIA_INTERMEDIATE: &[u8] = include_bytes!("../tests/certs/leaf_via_intermediate.der");
+    const NOT_A_CA: &[u8] = include_bytes!("../tests/certs/not_a_ca.der");
+    /// `DNS:backend.example, DNS:*.trichter.example, IP:4.231.40.213`, with
+    /// the common name `ignored.example`.
+    const LEAF_WITH_NAMES: &[u8] = include_bytes!("../tests/certs/leaf_with_names.der");
+    const HOST: &str = "backend.example";
+
+    /// openssl's SPKI fingerprint of `LEAF`.
+    const LEAF_FINGERPRINT: [u8; 32] = [
+        0xd4, 0xbe, 0xdb, 0xf1, 0x17, 0x3c, 0x66, 0x0a, 0xfc, 0xb1, 0xff, 0xab, 0x6d, 0x3f, 0x92,
+        0x0e, 0x54, 0x58, 0xb0, 0xb8, 0xc6, 0x50, 0x1b, 0x93, 0xb2, 0xc3, 0xb5, 0x3d, 0x55, 0x0b,
+        0x2a, 0x5d,
+    ];
+
+    /// Records which certificate was checked against which, accepts all.
+    fn record<'a>(
+        checked: &'a mut Vec<(Vec<u8>, Vec<u8>)>,
+    ) -> impl FnMut(&Certificate<'_>, &Certificate<'_>) -> bool + 'a {
+        |certificate, issuer| {
+            checked.push((certificate.subject.to_vec(), issuer.subject.to_vec()));
+            true
+        }
+    }
+
+    fn subject(der: &[u8]) -> Vec<u8> {
+        Certificate::parse(der).unwrap().subject.to_vec()
+    }
+
+    #[test]
+    fn parses_p256_certificates() {
+        let leaf = Certificate::parse(LEAF).unwrap();
+        let ca = Certificate::parse(CA).unwrap();
+        assert_eq!(leaf.issuer, ca.subject);
+        assert_eq!(ca.issuer, ca.subject);
+        assert_eq!(leaf.signature_algorithm, oid::ECDSA_WITH_SHA256);
+        assert_eq!(leaf.key_algorithm, oid::EC_PUBLIC_KEY);
+        assert_eq!(leaf.key_curve, Some(oid::PRIME256V1));
+        assert_eq!(leaf.public_key.len(), 65);
+        assert_eq!(leaf.public_key[0], 0x04);
+        // an ECDSA signature is a DER sequence of r and s
+        assert_eq!(leaf.signature[0], SEQUENCE);
+        assert_eq!(leaf.tbs[0], SEQUENCE);
+        assert_eq!(leaf.spki_fingerprint(), LEAF_FINGERPRINT);
+    }
+
+    #[test]
+    fn public_key_pin_matches_the_leaf_only() {
+        let pin = Pin::PublicKey(LEAF_FINGERPRINT);
+        let leaf = verify_chain(&pin, HOST, &[LEAF, CA], |_, _| false).unwrap();
+        assert_eq!(leaf.spki_fingerprint(), LEAF_FINGERPRINT);
+
+        let other = Pin::PublicKey(Certificate::parse(CA).unwrap().spki_fingerprint());
+        assert_eq!(
+            verify_chain(&other, HOST, &[LEAF, CA], |_, _| true),
+            Err(X509Error::NotPinned)
+        );
+        assert_eq!(
+            verify_chain(&pin, HOST, &[], |_, _| true),
+            Err(X509Error::Empty)
+        );
+    }
+
+    #[test]
+    fn ca_pin_checks_the_signature_of_the_leaf() {
+        let pin = Pin::Ca(CA);
+        let mut checked = Vec::new();
+        verify_chain(&pin, HOST, &[LEAF], record(&mut checked)).unwrap();
+        assert_eq!(checked, [(subject(LEAF), subject(CA))]);
+
+        // a server may send the CA along
+        let mut checked = Vec::new();
+        verify_chain(&pin, HOST, &[LEAF, CA], record(&mut checked)).unwrap();
+        assert_eq!(checked, [(subject(LEAF), subject(CA))]);
+
+        assert_eq!(
+            verify_chain(&pin, HOST, &[LEAF], |_, _| false),
+            Err(X509Error::BadSignature)
+        );
+    }
+
+    #[test]
+    fn ca_pin_walks_up_through_intermediates() {
+        let pin = Pin::Ca(CA);
+        let mut checked = Vec::new();
+        verify_chain(
+            &pin,
+            HOST,
+            &[LEAF_VIA_INTERMEDIATE, INTERMEDIATE],
+            record(&mut checked),
+        )
+        .unwrap();
+        assert_eq!(
+            checked,
+            [
+                (subject(LEAF_VIA_INTERMEDIATE), subject(INTERMEDIATE)),
+                (subject(INTERMEDIATE), subject(CA)),
+            ]
+        );
+
+        let mut calls = 0;
+        let result = verify_chain(
+            &pin,
+            HOST,
+            &[LEAF_VIA_INTERMEDIATE, INTERMEDIATE],
+            |_, _| {
+                calls += 1;
+                calls == 1
+            },
+        );
+        assert_eq!(result, Err(X509Error::BadSignature));
+
+        assert_eq!(
+            verify_chain(&pin, HOST, &[LEAF_VIA_INTERMEDIATE], |_, _| true),
+            Err(X509Error::NotPinned)
+        );
+        assert_eq!(
+            verify_chain(&pin, HOST, &[LEAF_VIA_INTERMEDIATE, CA], |_, _| true),
+            Err(X509Error::BrokenChain)
+        );
+        assert_eq!(
+            verify_chain(&Pin::Ca(INTERMEDIATE), HOST, &[LEAF], |_, _| true),
+            Err(X509Error::NotPinned)
+        );
+    }
+
+    #[test]
+    fn issuers_have_to_be_cas() {
+        assert!(Certificate::parse(CA).unwrap().is_ca);
+        assert!(Certificate::parse(INTERMEDIATE).unwrap().is_ca);
+        assert!(!Certificate::parse(LEAF).unwrap().is_ca);
+        assert!(!Certificate::parse(LEAF_WITH_NAMES).unwrap().is_ca);
+
+        assert_eq!(
+            verify_chain(
+                &Pin::Ca(CA),
+                HOST,
+                &[LEAF_VIA_INTERMEDIATE, NOT_A_CA],
+                |_, _| true
+            ),
+            Err(X509Error::NotACa)
+        );
+        assert_eq!(
+            verify_chain(
+                &Pin::Ca(NOT_A_CA),
+                HOST,
+                &[LEAF_VIA_INTERMEDIATE],
+                |_, _| true
+            ),
+            Err(X509Error::NotACa)
+        );
+    }
+
+    #[test]
+    fn leaf_has_to_be_issued_for_the_host() {
+        let leaf = Certificate::parse(LEAF).unwrap();
+        assert_eq!(leaf.common_name(), Some(&b"backend.example"[..]));
+        assert_eq!(leaf.alt_names, None);
+        assert!(leaf.matches_host("backend.example"));
+        assert!(leaf.matches_host("Backend.Example."));
+        assert!(!leaf.matches_host("other.example"));
+        assert!(!leaf.matches_host("api.backend.example"));
+
+        assert_eq!(
+            verify_chain(&Pin::Ca(CA), "other.example", &[LEAF], |_, _| true),
+            Err(X509Error::WrongHost)
+        );
+        let pin = Pin::PublicKey(LEAF_FINGERPRINT);
+        assert_eq!(
+            verify_chain(&pin, "other.example", &[LEAF], |_, _| true),
+            Err(X509Error::WrongHost)
+        );
+    }
+
+    #[test]
+    fn alternative_names_take_precedence() {
+        let leaf = Certificate::parse(LEAF_WITH_NAMES).unwrap();
+        assert_eq!(leaf.common_name(), Some(&b"ignored.example"[..]));
+        assert!(leaf.matches_host("backend.example"));
+        assert!(leaf.matches_host("eu.trichter.example"));
+        assert!(leaf.matches_host("4.231.40.213"));
+        assert!(!leaf.matches_host("ignored.example"));
+        assert!(!leaf.matches_host("trichter.example"));
+        assert!(!leaf.matches_host(".trichter.example"));
+        assert!(!leaf.matches_host("a.eu.trichter.example"));
+        assert!(!leaf.matches_host("4.231.40.214"));
+        // an address is not matched against DNS names, nor names against addresses
+        assert!(!Certificate::parse(LEAF)
+            .unwrap()
+            .matches_host("4.231.40.213"));
+    }
+
+    #[test]
+    fn truncated_and_corrupted_certificates_are_rejected() {
+        for len in 0..LEAF.len() {
+            assert_eq!(
+                Certificate::parse(&LEAF[..len]),
+                Err(X509Error::Malformed),
+                "truncated to {}",
+                len
+            );
+        }
+        let mut der = LEAF.to_vec();
+        for i in 0..der.len() {
+            for flip in [0x01, 0x80, 0xff] {
+                der[i] ^= flip;
+                // must not panic, whatever it returns
+                let _ = Certificate::parse(&der);
+                der[i] ^= flip;
+            }
+        }
+    }
+}
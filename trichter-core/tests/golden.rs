@@ -0,0 +1,172 @@
+//! Regression suite of recorded pulse traces with known results.
+//!
+//! Every trace in `tests/traces` is replayed through the same pipeline the
+//! firmware uses with its default settings. To add a trace, drop the file
+//! next to the others and add a row to [`GOLDEN`].
+
+use trichter_core::{
+    block_on,
+    calibration::Calibration,
+    foam::{FoamLimits, FOAM_WEIGHT_PERCENT},
+    meter::{measure_session, MeterEvent, SessionMeter},
+    overrun::{OverrunReason, SteadyFlow},
+    replay::{parse_trace, ReplaySource},
+    session::SessionOutcome,
+    settings::SessionSettings,
+};
+
+struct Golden {
+    name: &'static str,
+    trace: &'static str,
+    pulses: u32,
+    volume_ml: u32,
+    duration_ms: u64,
+    false_starts: usize,
+    glitches: u32,
+    foam_pulses: u32,
+    gulps: u32,
+    outcome: SessionOutcome,
+}
+
+macro_rules! trace {
+    ($name:literal) => {
+        include_str!(concat!("traces/", $name, ".trace"))
+    };
+}
+
+const GOLDEN: &[Golden] = &[
+    Golden {
+        name: "half_litre_steady",
+        trace: trace!("half_litre_steady"),
+        pulses: 198,
+        volume_ml: 500,
+        duration_ms: 2_483,
+        false_starts: 0,
+        glitches: 0,
+        foam_pulses: 0,
+        gulps: 1,
+        outcome: SessionOutcome::Completed,
+    },
+    Golden {
+        name: "litre_with_swallow_pauses",
+        trace: trace!("litre_with_swallow_pauses"),
+        pulses: 396,
+        volume_ml: 1_000,
+        duration_ms: 4_784,
+        false_starts: 0,
+        glitches: 0,
+        foam_pulses: 0,
+        gulps: 4,
+        outcome: SessionOutcome::Completed,
+    },
+    Golden {
+        name: "false_start_then_pour",
+        trace: trace!("false_start_then_pour"),
+        pulses: 300,
+        volume_ml: 758,
+        duration_ms: 3_306,
+        false_starts: 1,
+        glitches: 0,
+        foam_pulses: 0,
+        gulps: 1,
+        outcome: SessionOutcome::Completed,
+    },
+    Golden {
+        name: "noisy_line",
+        trace: trace!("noisy_line"),
+        pulses: 250,
+        volume_ml: 631,
+        duration_ms: 2_987,
+        false_starts: 0,
+        glitches: 72,
+        foam_pulses: 0,
+        gulps: 1,
+        outcome: SessionOutcome::Completed,
+    },
+    Golden {
+        name: "tap_left_running",
+        trace: trace!("tap_left_running"),
+        pulses: 601,
+        volume_ml: 1_518,
+        duration_ms: 15_026,
+        false_starts: 0,
+        glitches: 0,
+        foam_pulses: 0,
+        gulps: 1,
+        outcome: SessionOutcome::Overrun(OverrunReason::ContinuousFlow),
+    },
+    Golden {
+        name: "foamy_tail",
+        trace: trace!("foamy_tail"),
+        pulses: 300,
+        volume_ml: 407,
+        duration_ms: 4_268,
+        false_starts: 0,
+        glitches: 0,
+        foam_pulses: 139,
+        gulps: 1,
+        outcome: SessionOutcome::Completed,
+    },
+    Golden {
+        name: "pcnt_batches",
+        trace: trace!("pcnt_batches"),
+        pulses: 400,
+        volume_ml: 1_010,
+        duration_ms: 5_012,
+        false_starts: 0,
+        glitches: 0,
+        foam_pulses: 0,
+        gulps: 1,
+        outcome: SessionOutcome::Completed,
+    },
+];
+
+#[test]
+fn golden_traces() {
+    let limits = SessionSettings::default().limits();
+    let calibration = Calibration::default();
+
+    for golden in GOLDEN {
+        let trace: Vec<u64> = parse_trace(golden.trace)
+            .collect::<Result<_, _>>()
+            .unwrap_or_else(|e| panic!("{}: malformed trace: {e:?}", golden.name));
+
+        let mut source = ReplaySource::new(&trace, 0);
+        let mut meter = SessionMeter::new(limits, SteadyFlow::DEFAULT, FoamLimits::DEFAULT);
+        let mut false_starts = 0;
+        let measured = block_on(measure_session(&mut source, &mut meter, |event| {
+            if let MeterEvent::FalseStart(_) = event {
+                false_starts += 1;
+            }
+        }))
+        .unwrap_or_else(|| panic!("{}: no session detected", golden.name));
+
+        let duration_us = measured.session.duration_us();
+        let counted = measured.foam.counted_pulses(FOAM_WEIGHT_PERCENT);
+        let actual = (
+            measured.session.pulses,
+            calibration.volume_ml(counted, duration_us),
+            duration_us / 1_000,
+            false_starts,
+            measured.glitches,
+            measured.foam.foam_pulses,
+            measured.gulps.count(),
+            measured.outcome,
+        );
+        let expected = (
+            golden.pulses,
+            golden.volume_ml,
+            golden.duration_ms,
+            golden.false_starts,
+            golden.glitches,
+            golden.foam_pulses,
+            golden.gulps,
+            golden.outcome,
+        );
+        assert_eq!(
+            actual, expected,
+            "{}: (pulses, volume_ml, duration_ms, false_starts, glitches, foam_pulses, gulps, outcome)",
+            golden.name
+        );
+    }
+}
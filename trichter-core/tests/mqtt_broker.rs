@@ -0,0 +1,189 @@
+//! The MQTT packets against a real broker.
+//!
+//! These tests need a broker on `localhost:1883`, or wherever `MQTT_BROKER`
+//! points, that allows anonymous clients. Start one and run them with
+//!
+//! ```text
+//! mosquitto -p 1883 &
+//! cargo test --test mqtt_broker -- --ignored
+//! ```
+//!
+//! CI runs them against a mosquitto service in the core tests job.
+
+use std::{
+    io::{Read, Write},
+    net::TcpStream,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use trichter_core::mqtt::{
+    decode, encode_connect, encode_disconnect, encode_pingreq, encode_publish, encode_subscribe,
+    packet_len, Connect, Message, Packet, QoS, Version,
+};
+
+struct Client {
+    stream: TcpStream,
+    version: Version,
+    buf: Vec<u8>,
+}
+
+impl Client {
+    fn connect(version: Version, client_id: &str, will: Option<Message>) -> Self {
+        let broker = std::env::var("MQTT_BROKER").unwrap_or_else(|_| "localhost:1883".into());
+        let stream = TcpStream::connect(&broker).expect("no MQTT broker, see the module docs");
+        stream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let mut client = Self {
+            stream,
+            version,
+            buf: Vec::new(),
+        };
+
+        let connect = Connect {
+            client_id,
+            keep_alive_secs: 30,
+            clean_start: true,
+            will,
+            username: None,
+            password: None,
+        };
+        client.send(|buf| encode_connect(version, &connect, buf));
+        let packet = client.receive();
+        match decode(version, &packet).unwrap() {
+            Packet::ConnAck { code: 0, .. } => client,
+            other => panic!("connect failed: {:?}", other),
+        }
+    }
+
+    fn send(
+        &mut self,
+        encode: impl FnOnce(&mut [u8]) -> Result<usize, trichter_core::mqtt::MqttError>,
+    ) {
+        let mut packet = [0; 512];
+        let len = encode(&mut packet).unwrap();
+        self.stream.write_all(&packet[..len]).unwrap();
+    }
+
+    /// The next packet, raw since the decoded one borrows from it.
+    fn receive(&mut self) -> Vec<u8> {
+        loop {
+            if let Some(len) = packet_len(&self.buf).unwrap() {
+                if self.buf.len() >= len {
+                    return self.buf.drain(..len).collect();
+                }
+            }
+            let mut chunk = [0; 256];
+            let n = self.stream.read(&mut chunk).expect("broker did not answer");
+            assert!(n > 0, "broker closed the connection");
+            self.buf.extend_from_slice(&chunk[..n]);
+        }
+    }
+
+    /// The topic, payload and retain flag of the next publish.
+    fn receive_publish(&mut self) -> (String, Vec<u8>, bool) {
+        let packet = self.receive();
+        match decode(self.version, &packet).unwrap() {
+            Packet::Publish { message, .. } => (
+                message.topic.to_string(),
+                message.payload.to_vec(),
+                message.retain,
+            ),
+            other => panic!("expected a publish, got {:?}", other),
+        }
+    }
+
+    fn subscribe(&mut self, filter: &str) {
+        let version = self.version;
+        self.send(|buf| encode_subscribe(version, 1, filter, QoS::AtLeastOnce, buf));
+        let packet = self.receive();
+        assert_eq!(
+            decode(version, &packet),
+            Ok(Packet::SubAck {
+                packet_id: 1,
+                code: 1
+            })
+        );
+    }
+
+    fn publish(&mut self, packet_id: u16, topic: &str, payload: &[u8], retain: bool) {
+        let version = self.version;
+        let message = Message {
+            topic,
+            payload,
+            qos: QoS::AtLeastOnce,
+            retain,
+        };
+        self.send(|buf| encode_publish(version, &message, packet_id, false, buf));
+        // publishes to our own subscriptions may come first
+        loop {
+            let packet = self.receive();
+            match decode(version, &packet).unwrap() {
+                Packet::Publish { .. } => continue,
+                ack => {
+                    assert_eq!(ack, Packet::PubAck { packet_id, code: 0 });
+                    return;
+                }
+            }
+        }
+    }
+}
+
+/// A topic no other run of the tests uses.
+fn unique_topic(version: Version) -> String {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_nanos();
+    format!("trichter-test/{:?}/{}", version, nanos)
+}
+
+#[test]
+#[ignore = "needs an MQTT broker"]
+fn publishes_retained_results_and_receives_commands() {
+    for version in [Version::V311, Version::V5] {
+        let base = unique_topic(version);
+        let status = format!("{base}/status");
+        let mut device = Client::connect(
+            version,
+            &format!("{base}-device").replace('/', "-"),
+            Some(Message {
+                topic: &status,
+                payload: b"{\"online\": false}",
+                qos: QoS::AtLeastOnce,
+                retain: true,
+            }),
+        );
+        device.subscribe(&format!("{base}/commands"));
+
+        device.publish(7, &format!("{base}/runs"), b"{\"volume_ml\": 500}", true);
+        device.send(encode_pingreq);
+        let packet = device.receive();
+        assert_eq!(decode(version, &packet), Ok(Packet::PingResp));
+
+        // a later subscriber gets the retained last result
+        let mut backend =
+            Client::connect(version, &format!("{base}-backend").replace('/', "-"), None);
+        backend.subscribe(&format!("{base}/runs"));
+        let (topic, payload, retain) = backend.receive_publish();
+        assert_eq!(topic, format!("{base}/runs"));
+        assert_eq!(payload, b"{\"volume_ml\": 500}");
+        assert!(retain);
+        backend.subscribe(&status);
+
+        backend.publish(1, &format!("{base}/commands"), b"event active=true", false);
+        let (_, payload, _) = device.receive_publish();
+        assert_eq!(payload, b"event active=true");
+
+        // the will goes out when the device drops off without a DISCONNECT
+        drop(device);
+        let (topic, payload, _) = backend.receive_publish();
+        assert_eq!(topic, status);
+        assert_eq!(payload, b"{\"online\": false}");
+
+        // clear the retained messages again
+        backend.publish(2, &format!("{base}/runs"), b"", true);
+        backend.publish(3, &status, b"", true);
+        backend.send(encode_disconnect);
+    }
+}